@@ -0,0 +1,220 @@
+use crate::error::TllmError;
+
+// anything bigger is more likely a log file than something meant for the prompt
+pub const DEFAULT_MAX_SIZE_KB: u64 = 256;
+
+pub fn read(path: &str, max_size_kb: u64) -> Result<String, TllmError> {
+    let size = match std::fs::metadata(path) {
+        Ok(m) if m.is_file() => m.len(),
+        Ok(_) => return Err(TllmError::Config(format!("{} is not a file", path))),
+        Err(e) => return Err(TllmError::Config(format!("Can't read {}: {}", path, e))),
+    };
+
+    if size > max_size_kb * 1024 {
+        return Err(TllmError::Config(format!(
+            "{} is {} KB, over the {} KB limit, raise it with --max-file-size",
+            path,
+            size.div_ceil(1024),
+            max_size_kb
+        )));
+    }
+
+    let bytes = std::fs::read(path)?;
+    if bytes.contains(&0) {
+        return Err(TllmError::Config(format!(
+            "{} looks like a binary file",
+            path
+        )));
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok(content),
+        Err(_) => Err(TllmError::Config(format!("{} isn't valid UTF-8", path))),
+    }
+}
+
+// a fence longer than any run of backticks in the content so it can't be closed early
+pub fn fence(label: &str, content: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in content.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+
+    let ticks = "`".repeat((longest + 1).max(3));
+    format!(
+        "{}{}\n{}\n{}",
+        ticks,
+        label,
+        content.trim_end_matches('\n'),
+        ticks
+    )
+}
+
+// a lone file is sent as is so it can carry its own front matter,
+// otherwise each file goes below the text in a block labeled with its path
+pub fn build_message(text: &str, files: &[String], max_size_kb: u64) -> Result<String, TllmError> {
+    if text.is_empty() && files.len() == 1 {
+        return read(&files[0], max_size_kb);
+    }
+
+    let mut parts = Vec::new();
+    if !text.is_empty() {
+        parts.push(text.to_string());
+    }
+
+    for file in files {
+        parts.push(fence(file, &read(file, max_size_kb)?));
+    }
+
+    Ok(parts.join("\n\n"))
+}
+
+// above this the total is confirmed before anything is sent
+const CONFIRM_THRESHOLD_KB: u64 = 100;
+
+pub struct Attachment {
+    // relative to the working directory where possible
+    pub path: String,
+    pub content: String,
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+fn get_display_path(path: &std::path::Path) -> String {
+    let relative = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(|p| p.to_path_buf()))
+        .unwrap_or_else(|| path.to_path_buf());
+
+    relative.to_string_lossy().to_string()
+}
+
+// whatever git says is ignored, nothing when we're not in a repo or git isn't around
+fn get_ignored(paths: &[std::path::PathBuf]) -> std::collections::HashSet<std::path::PathBuf> {
+    let output = match std::process::Command::new("git")
+        .arg("check-ignore")
+        .arg("--")
+        .args(paths)
+        .stderr(std::process::Stdio::null())
+        .output()
+    {
+        Ok(o) => o,
+        Err(_) => return std::collections::HashSet::new(),
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(std::path::PathBuf::from)
+        .collect()
+}
+
+// a plain path has to be readable like with -f,
+// files matched by a glob are skipped with a warning when they aren't
+fn expand(pattern: &str, max_size_kb: u64) -> Result<Vec<Attachment>, TllmError> {
+    if !is_glob(pattern) {
+        return Ok(vec![Attachment {
+            path: get_display_path(std::path::Path::new(pattern)),
+            content: read(pattern, max_size_kb)?,
+        }]);
+    }
+
+    let options = glob::MatchOptions {
+        require_literal_leading_dot: true,
+        ..Default::default()
+    };
+
+    let paths = match glob::glob_with(pattern, options) {
+        Ok(paths) => paths
+            .filter_map(|p| p.ok())
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            return Err(TllmError::Config(format!(
+                "Invalid glob {}: {}",
+                pattern, e
+            )))
+        }
+    };
+
+    let ignored = get_ignored(&paths);
+    let mut attachments = Vec::new();
+    for path in paths.iter().filter(|p| !ignored.contains(*p)) {
+        match read(&path.to_string_lossy(), max_size_kb) {
+            Ok(content) => attachments.push(Attachment {
+                path: get_display_path(path),
+                content,
+            }),
+            Err(e) => eprintln!("Skipping {}", e),
+        }
+    }
+
+    if attachments.is_empty() {
+        return Err(TllmError::Config(format!(
+            "No attachable files match {}",
+            pattern
+        )));
+    }
+
+    Ok(attachments)
+}
+
+pub fn expand_all(patterns: &[String], max_size_kb: u64) -> Result<Vec<Attachment>, TllmError> {
+    let mut attachments: Vec<Attachment> = Vec::new();
+    for pattern in patterns {
+        for attachment in expand(pattern, max_size_kb)? {
+            if !attachments.iter().any(|a| a.path == attachment.path) {
+                attachments.push(attachment);
+            }
+        }
+    }
+
+    Ok(attachments)
+}
+
+// the token estimate is the usual ~4 bytes per token
+pub fn confirm(attachments: &[Attachment], yes: bool) -> Result<(), TllmError> {
+    let bytes = attachments
+        .iter()
+        .map(|a| a.content.len() as u64)
+        .sum::<u64>();
+
+    eprintln!(
+        "Attaching {} file{}, {} bytes (~{} tokens)",
+        attachments.len(),
+        if attachments.len() == 1 { "" } else { "s" },
+        bytes,
+        bytes / 4
+    );
+
+    if yes || bytes <= CONFIRM_THRESHOLD_KB * 1024 {
+        return Ok(());
+    }
+
+    eprint!(
+        "That's over {} KB, send it anyway? [y/N] ",
+        CONFIRM_THRESHOLD_KB
+    );
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(TllmError::Config(
+            "Not sending, pass --yes to skip this check".to_string(),
+        )),
+    }
+}
+
+pub fn prepend(attachments: &[Attachment], text: &str) -> String {
+    let mut parts = attachments
+        .iter()
+        .map(|a| fence(&a.path, &a.content))
+        .collect::<Vec<String>>();
+    parts.push(text.to_string());
+
+    parts.join("\n\n")
+}
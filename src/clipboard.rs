@@ -0,0 +1,15 @@
+use copypasta::{ClipboardContext, ClipboardProvider};
+
+// there's no clipboard to open on headless systems, so these fail rather than panic
+
+pub fn get() -> Result<String, String> {
+    let mut ctx = ClipboardContext::new().map_err(|e| format!("No clipboard available: {}", e))?;
+    ctx.get_contents()
+        .map_err(|e| format!("Couldn't read the clipboard: {}", e))
+}
+
+pub fn set(content: &str) -> Result<(), String> {
+    let mut ctx = ClipboardContext::new().map_err(|e| format!("No clipboard available: {}", e))?;
+    ctx.set_contents(content.to_string())
+        .map_err(|e| format!("Couldn't write to the clipboard: {}", e))
+}
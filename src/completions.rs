@@ -0,0 +1,181 @@
+use crate::{config, providers, FlagSpec, FLAGS};
+
+enum Values {
+    None,
+    Files,
+    Words(Vec<String>),
+    Conversations,
+}
+
+// only the first argument of a flag gets completed
+fn get_values(flag: &FlagSpec) -> Values {
+    match flag.arg.split_whitespace().next() {
+        Some("API") => Values::Words(providers::PROVIDERS.iter().map(|p| p.to_string()).collect()),
+        // a new title shouldn't offer the existing ones
+        Some("TITLE") if flag.long == "--title" => Values::None,
+        Some("TITLE") | Some("OLD") => Values::Conversations,
+        Some("FMT") => Values::Words(vec!["txt".into(), "md".into(), "json".into()]),
+        Some("SHELL") => Values::Words(vec!["bash".into(), "zsh".into(), "fish".into()]),
+        _ if flag.arg.contains("FILE") => Values::Files,
+        _ => Values::None,
+    }
+}
+
+fn get_names(flag: &FlagSpec) -> Vec<&'static str> {
+    [flag.short, flag.long]
+        .iter()
+        .filter(|n| !n.is_empty())
+        .cloned()
+        .collect()
+}
+
+fn list_conversations_command() -> String {
+    format!(
+        "ls {:?} 2>/dev/null | sed -n 's/\\.json$//p'",
+        config::get_conversations_dir()
+    )
+}
+
+fn bash() -> String {
+    let mut cases = String::new();
+    for flag in FLAGS {
+        let reply = match get_values(flag) {
+            Values::None => continue,
+            Values::Files => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+            Values::Words(words) => {
+                format!(
+                    "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+                    words.join(" ")
+                )
+            }
+            Values::Conversations => format!(
+                "COMPREPLY=($(compgen -W \"$({})\" -- \"$cur\"))",
+                list_conversations_command()
+            ),
+        };
+
+        cases.push_str(&format!(
+            "        {})\n            {}\n            return\n            ;;\n",
+            get_names(flag).join("|"),
+            reply
+        ));
+    }
+
+    let all_flags = FLAGS
+        .iter()
+        .flat_map(get_names)
+        .collect::<Vec<&str>>()
+        .join(" ");
+
+    format!(
+        "_tllm() {{
+    local cur prev
+    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"
+    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+
+    case \"$prev\" in
+{}    esac
+
+    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))
+}}
+
+complete -F _tllm tllm
+",
+        cases, all_flags
+    )
+}
+
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh() -> String {
+    let mut specs = String::new();
+    for flag in FLAGS {
+        let action = match get_values(flag) {
+            Values::None if flag.arg.is_empty() => String::new(),
+            Values::None => format!(":{}:", flag.arg),
+            Values::Files => format!(":{}:_files", flag.arg),
+            Values::Words(words) => format!(":{}:({})", flag.arg, words.join(" ")),
+            Values::Conversations => format!(":{}:_tllm_conversations", flag.arg),
+        };
+
+        let names = get_names(flag);
+        let description = zsh_escape(flag.description);
+        if names.len() > 1 {
+            specs.push_str(&format!(
+                "    '({})'{{{}}}'[{}]{}' \\\n",
+                names.join(" "),
+                names.join(","),
+                description,
+                action
+            ));
+        } else {
+            specs.push_str(&format!(
+                "    '{}[{}]{}' \\\n",
+                names[0], description, action
+            ));
+        }
+    }
+
+    format!(
+        "#compdef tllm
+
+_tllm_conversations() {{
+    local -a titles
+    titles=(${{(f)\"$({})\"}})
+    _describe 'conversation' titles
+}}
+
+_arguments \\
+{}    '*:message:'
+",
+        list_conversations_command(),
+        specs
+    )
+}
+
+fn fish() -> String {
+    let mut output = String::new();
+    for flag in FLAGS {
+        let mut line = "complete -c tllm".to_string();
+        if let Some(short) = flag.short.strip_prefix('-') {
+            line.push_str(&format!(" -s {}", short));
+        }
+
+        if let Some(long) = flag.long.strip_prefix("--") {
+            line.push_str(&format!(" -l {}", long));
+        }
+
+        match get_values(flag) {
+            Values::None if flag.arg.is_empty() => {}
+            Values::None => line.push_str(" -x"),
+            Values::Files => line.push_str(" -r -F"),
+            Values::Words(words) => line.push_str(&format!(" -x -a \"{}\"", words.join(" "))),
+            Values::Conversations => line.push_str(&format!(
+                " -x -a \"({})\"",
+                list_conversations_command().replace('"', "\\\"")
+            )),
+        }
+
+        line.push_str(&format!(
+            " -d '{}'\n",
+            flag.description.replace('\'', "\\'")
+        ));
+        output.push_str(&line);
+    }
+
+    output
+}
+
+pub fn generate(shell: &str) -> Option<String> {
+    match shell {
+        "bash" => Some(bash()),
+        "zsh" => Some(zsh()),
+        "fish" => Some(fish()),
+        _ => None,
+    }
+}
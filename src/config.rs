@@ -1,3 +1,16 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use crate::logger::Logger;
+use crate::warn;
+
+// set by --no-config, everything then runs on the built-in defaults
+static IGNORED: AtomicBool = AtomicBool::new(false);
+// set by --profile, layered over the base config wherever it's read
+static PROFILE: OnceLock<String> = OnceLock::new();
+// `data_dir` from the config, it's needed for every conversation path so it's only read once
+static DATA_DIR: Mutex<Option<std::path::PathBuf>> = Mutex::new(None);
+
 #[cfg(debug_assertions)]
 const DEBUG: bool = true;
 #[cfg(not(debug_assertions))]
@@ -34,14 +47,429 @@ pub fn get_home_dir() -> std::path::PathBuf {
     }
 }
 
+// every path tllm uses comes from these three, in order of precedence:
+// - `$TLLM_HOME`, holding everything, e.g. to keep work and personal histories apart
+// - the XDG base directories, when they're set
+// - `~/.config/tllm` for the config and `~/.local/tllm` for the rest
+// an existing `~/.local/tllm` keeps being used so setting the XDG variables doesn't hide it
+
+// the spec says relative paths are to be ignored
+fn get_env_dir(name: &str) -> Option<std::path::PathBuf> {
+    std::env::var_os(name)
+        .map(std::path::PathBuf::from)
+        .filter(|p| p.is_absolute())
+}
+
+fn get_legacy_local_dir() -> Option<std::path::PathBuf> {
+    let legacy = get_home_dir().join(".local/tllm");
+    match legacy.is_dir() {
+        true => Some(legacy),
+        false => None,
+    }
+}
+
 pub fn get_config_dir() -> std::path::PathBuf {
-    let home_dir = get_home_dir();
-    home_dir.join(".config/tllm")
+    if let Some(home) = get_env_dir("TLLM_HOME") {
+        return home.join("config");
+    }
+
+    match get_env_dir("XDG_CONFIG_HOME") {
+        Some(dir) => dir.join("tllm"),
+        None => get_home_dir().join(".config/tllm"),
+    }
+}
+
+pub fn get_config_file() -> std::path::PathBuf {
+    get_config_dir().join("config")
+}
+
+pub fn get_toml_config_file() -> std::path::PathBuf {
+    get_config_dir().join("config.toml")
+}
+
+// `profiles/<name>` holds the same `key=value` lines as `config`
+pub fn get_profiles_dir() -> std::path::PathBuf {
+    get_config_dir().join("profiles")
+}
+
+// what `[providers.<name>]` can set, each becomes `<key>.<name>` like the flat file's keys
+pub const PROVIDER_KEYS: &[&str] = &[
+    "model",
+    "max_tokens",
+    "api_base",
+    "api_key",
+    "api_key_env",
+    "extra_headers",
+];
+
+// a value from config.toml, flattened to the same `key=value` form as the flat file
+pub struct ConfigEntry {
+    pub key: String,
+    pub value: String,
+    // e.g. `config.toml line 4, [providers.openai] model`
+    pub location: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlConfig {
+    #[serde(default)]
+    defaults: toml::Table,
+    #[serde(default)]
+    providers: std::collections::BTreeMap<String, toml::Table>,
+    // the same keys as `[defaults]`, for `--profile <name>`
+    #[serde(default)]
+    profiles: std::collections::BTreeMap<String, toml::Table>,
+}
+
+// where `key` is set under the `[section]` header, for error messages
+// the parser has already accepted the file, so this only has to be good enough to point at it
+fn get_line(contents: &str, section: &str, key: &str) -> Option<usize> {
+    let mut current = String::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            current = line
+                .trim_matches(|c| c == '[' || c == ']')
+                .replace([' ', '"'], "");
+            continue;
+        }
+
+        let name = match line.split_once('=') {
+            Some((name, _)) => name.trim().replace([' ', '"'], ""),
+            None => continue,
+        };
+
+        if current == section && (name == key || name.starts_with(&format!("{}.", key))) {
+            return Some(i + 1);
+        }
+    }
+
+    None
+}
+
+// e.g. `config.toml line 4, [providers.openai] model`
+fn get_location(contents: &str, section: &str, key: &str) -> String {
+    match get_line(contents, section, key) {
+        Some(line) => format!("config.toml line {}, [{}] {}", line, section, key),
+        None => format!("config.toml [{}] {}", section, key),
+    }
+}
+
+// tables only come from dotted keys like `api_base.openai = ...` under `[defaults]`
+// lists only make sense for headers, they're kept one a line like the flat file's `\n`
+fn flatten_value(
+    key: &str,
+    value: &toml::Value,
+    location: &str,
+    entries: &mut Vec<ConfigEntry>,
+) -> Result<(), String> {
+    let value = match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => f.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        toml::Value::Table(table) => {
+            for (k, v) in table {
+                flatten_value(&format!("{}.{}", key, k), v, location, entries)?;
+            }
+
+            return Ok(());
+        }
+        toml::Value::Array(items) if key.starts_with("extra_headers.") => {
+            let mut lines = Vec::new();
+            for item in items {
+                match item.as_str() {
+                    Some(line) => lines.push(line.to_string()),
+                    None => return Err(format!("{}: expected a list of strings", location)),
+                }
+            }
+
+            lines.join("\n")
+        }
+        _ => {
+            return Err(format!(
+                "{}: expected a string, number, or boolean",
+                location
+            ))
+        }
+    };
+
+    entries.push(ConfigEntry {
+        key: key.to_string(),
+        value,
+        location: location.to_string(),
+    });
+
+    Ok(())
 }
 
+// `[defaults]` takes the same keys as the flat file,
+// `[providers.<name>]` takes `PROVIDER_KEYS` for that provider
+// nothing here is validated beyond its shape, that's up to whoever reads the keys
+pub fn read_toml_config() -> Result<Vec<ConfigEntry>, String> {
+    if IGNORED.load(Ordering::Relaxed) {
+        return Ok(Vec::new());
+    }
+
+    let path = get_toml_config_file();
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Can't read {}: {}", path.display(), e)),
+    };
+
+    let config: TomlConfig =
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let mut entries = Vec::new();
+    for (key, value) in config.defaults.iter() {
+        let location = get_location(&contents, "defaults", key);
+        flatten_value(key, value, &location, &mut entries)?;
+    }
+
+    for (provider, table) in config.providers.iter() {
+        for (key, value) in table.iter() {
+            let location = get_location(&contents, &format!("providers.{}", provider), key);
+
+            if !PROVIDER_KEYS.contains(&key.as_str()) {
+                return Err(format!(
+                    "{}: unknown key, expected one of {}",
+                    location,
+                    PROVIDER_KEYS.join(", ")
+                ));
+            }
+
+            flatten_value(
+                &format!("{}.{}", key, provider),
+                value,
+                &location,
+                &mut entries,
+            )?;
+        }
+    }
+
+    // last, so they win over everything above
+    if let Some(table) = get_profile().and_then(|name| config.profiles.get(&name)) {
+        let section = format!("profiles.{}", get_profile().unwrap_or_default());
+        for (key, value) in table.iter() {
+            let location = get_location(&contents, &section, key);
+            flatten_value(key, value, &location, &mut entries)?;
+        }
+    }
+
+    Ok(entries)
+}
+
+pub fn ignore() {
+    IGNORED.store(true, Ordering::Relaxed);
+    *DATA_DIR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    create_data_dirs();
+}
+
+// files in `profiles/` and tables under `[profiles]` in config.toml
+pub fn list_profiles() -> Vec<String> {
+    let mut profiles = std::fs::read_dir(get_profiles_dir())
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().is_file())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+
+    let contents = std::fs::read_to_string(get_toml_config_file()).unwrap_or_default();
+    if let Ok(config) = toml::from_str::<TomlConfig>(&contents) {
+        profiles.extend(config.profiles.into_keys());
+    }
+
+    profiles.sort();
+    profiles.dedup();
+    profiles
+}
+
+pub fn set_profile(name: &str) -> Result<(), String> {
+    let profiles = list_profiles();
+    if !profiles.iter().any(|p| p == name) {
+        return Err(match profiles.is_empty() {
+            true => format!(
+                "Unknown profile {}, there are none in {} or under [profiles] in config.toml",
+                name,
+                get_profiles_dir().display()
+            ),
+            false => format!(
+                "Unknown profile {}, available profiles: {}",
+                name,
+                profiles.join(", ")
+            ),
+        });
+    }
+
+    let _ = PROFILE.set(name.to_string());
+    *DATA_DIR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    create_data_dirs();
+    Ok(())
+}
+
+pub fn get_profile() -> Option<String> {
+    PROFILE.get().cloned()
+}
+
+// the base config, then the profile's file when there is one
+pub fn get_flat_config_files() -> Vec<std::path::PathBuf> {
+    let mut files = vec![get_config_file()];
+    if let Some(profile) = get_profile() {
+        files.push(get_profiles_dir().join(profile));
+    }
+
+    files
+}
+
+// `key=value` pairs, one per line
+// blank lines and lines starting with `#` are skipped
+pub fn read_flat_config(path: &std::path::Path) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once('=') {
+            Some((key, value)) => {
+                entries.push((key.trim().to_string(), value.trim().to_string()));
+            }
+            None => {
+                warn!("Malformed config line in {}: {}", path.display(), line);
+            }
+        }
+    }
+
+    entries
+}
+
+// the flat file, with config.toml on top, and a profile's own file on top of that
+pub fn read_config() -> std::collections::HashMap<String, String> {
+    let mut config = std::collections::HashMap::new();
+    if IGNORED.load(Ordering::Relaxed) {
+        return config;
+    }
+
+    config.extend(read_flat_config(&get_config_file()));
+
+    // a broken config.toml is reported when the flags are merged, here it's just skipped
+    match read_toml_config() {
+        Ok(entries) => {
+            for entry in entries {
+                config.insert(entry.key, entry.value);
+            }
+        }
+        Err(e) => {
+            warn!("{}", e);
+        }
+    }
+
+    if let Some(profile) = get_profile() {
+        config.extend(read_flat_config(&get_profiles_dir().join(profile)));
+    }
+
+    config
+}
+
+// once `tllm auth set` has put a key in the config, only its owner should read it
+#[cfg(unix)]
+pub fn restrict_permissions(path: &std::path::Path) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+pub fn restrict_permissions(_path: &std::path::Path) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+// rewrites every line setting `key`, appending one if there are none
+// comments and unrelated lines are left as they are
+pub fn write_config_value(key: &str, value: &str) -> Result<(), std::io::Error> {
+    let path = get_config_file();
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut found = false;
+    let mut lines = Vec::new();
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        let is_key = !trimmed.starts_with('#')
+            && trimmed
+                .split_once('=')
+                .map(|(k, _)| k.trim() == key)
+                .unwrap_or(false);
+
+        if is_key {
+            lines.push(format!("{}={}", key, value));
+            found = true;
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+
+    if !found {
+        lines.push(format!("{}={}", key, value));
+    }
+
+    std::fs::write(path, lines.join("\n") + "\n")
+}
+
+// where conversations and drafts go when nothing sets `data_dir`
+fn get_default_local_dir() -> std::path::PathBuf {
+    if let Some(home) = get_env_dir("TLLM_HOME") {
+        return home;
+    }
+
+    if let Some(legacy) = get_legacy_local_dir() {
+        return legacy;
+    }
+
+    match get_env_dir("XDG_DATA_HOME") {
+        Some(dir) => dir.join("tllm"),
+        None => get_home_dir().join(".local/tllm"),
+    }
+}
+
+// conversations and drafts, `data_dir` in the config moves them, e.g. per profile
 pub fn get_local_dir() -> std::path::PathBuf {
-    let home_dir = get_home_dir();
-    home_dir.join(".local/tllm")
+    let mut data_dir = DATA_DIR.lock().unwrap_or_else(|e| e.into_inner());
+    data_dir
+        .get_or_insert_with(|| {
+            read_config()
+                .get("data_dir")
+                .map(std::path::PathBuf::from)
+                .filter(|p| p.is_absolute())
+                .unwrap_or_else(get_default_local_dir)
+        })
+        .clone()
+}
+
+// the logs stay put whatever `data_dir` says, they're opened before the config is read
+pub fn get_logs_dir() -> std::path::PathBuf {
+    if get_env_dir("TLLM_HOME").is_none() && get_legacy_local_dir().is_none() {
+        if let Some(dir) = get_env_dir("XDG_STATE_HOME") {
+            return dir.join("tllm/logs");
+        }
+    }
+
+    get_default_local_dir().join("logs")
+}
+
+// where unsent messages go when the request for them fails
+pub fn get_drafts_dir() -> std::path::PathBuf {
+    get_local_dir().join("drafts")
 }
 
 pub fn get_conversations_dir() -> std::path::PathBuf {
@@ -49,24 +477,23 @@ pub fn get_conversations_dir() -> std::path::PathBuf {
     local_dir.join("conversations")
 }
 
-pub fn setup() {
-    if std::env::var("OPENAI_API_KEY").is_err()
-        && std::env::var("ANTHROPIC_API_KEY").is_err()
-        && std::env::var("GEMINI_API_KEY").is_err()
-    {
-        panic!("TLLM requires at least $OPENAI_API_KEY, $ANTHROPIC_API_KEY, or $GEMINI_API_KEY to be set");
-    }
-
+// again whenever a profile or --no-config changes where they are
+fn create_data_dirs() {
     let local_path = get_local_dir();
-    let config_path = get_config_dir();
+    create_if_nonexistent(&local_path);
+    create_if_nonexistent(&local_path.join("conversations"));
+    create_if_nonexistent(&local_path.join("drafts"));
+}
 
-    let conversations_path = local_path.join("conversations");
-    let logging_path = local_path.join("logs");
-    crate::logger::Logger::init(format!("{}/debug.log", logging_path.to_str().unwrap()));
+// API keys are checked in `main` once we know which provider is in use
+pub fn setup() {
+    let config_path = get_config_dir();
+    let logging_path = get_logs_dir();
 
-    create_if_nonexistent(&local_path);
     create_if_nonexistent(&config_path);
-
-    create_if_nonexistent(&conversations_path);
     create_if_nonexistent(&logging_path);
+    create_data_dirs();
+
+    // the log file can only be opened once its directory exists
+    crate::logger::Logger::init(format!("{}/debug.log", logging_path.to_str().unwrap()));
 }
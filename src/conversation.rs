@@ -0,0 +1,656 @@
+use crate::error::TllmError;
+use crate::logger::Logger;
+use crate::network;
+use crate::{config, prompts, template, warn};
+
+pub const MESSAGE_SEPARATOR: &str = "───";
+
+// archived conversations keep their file, just moved into this subdirectory
+// their titles carry the prefix, e.g. `archive/1731111111`
+pub const ARCHIVE_PREFIX: &str = "archive/";
+
+// conversations live as `<title>.json` files in the conversations directory
+pub fn get_path(title: &str) -> std::path::PathBuf {
+    config::get_conversations_dir().join(format!("{}.json", title))
+}
+
+pub fn get_title(filename: &str) -> String {
+    filename
+        .strip_suffix(".json")
+        .unwrap_or(filename)
+        .to_string()
+}
+
+fn validate_title(title: &str) -> Result<(), std::io::Error> {
+    if title.trim().is_empty() || title.contains('/') || title.contains('\\') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid conversation title: {:?}", title),
+        ));
+    }
+
+    Ok(())
+}
+
+// where a brand new conversation with the given title should be saved
+pub fn get_new_path(title: &str) -> Result<std::path::PathBuf, std::io::Error> {
+    validate_title(title)?;
+
+    let path = get_path(title);
+    if path.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!(
+                "A conversation titled {} already exists, use -c to continue it",
+                title
+            ),
+        ));
+    }
+
+    Ok(path)
+}
+
+pub fn rename(old_title: &str, new_title: &str) -> Result<(), std::io::Error> {
+    validate_title(new_title)?;
+
+    let old_path = get_path(old_title);
+    if !old_path.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No conversation titled {}", old_title),
+        ));
+    }
+
+    let new_path = get_path(new_title);
+    if new_path.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("A conversation titled {} already exists", new_title),
+        ));
+    }
+
+    std::fs::rename(old_path, new_path)
+}
+
+pub fn is_archived(title: &str) -> bool {
+    title.starts_with(ARCHIVE_PREFIX)
+}
+
+// moves a conversation in or out of the archive, nothing in the file changes
+pub fn set_archived(title: &str, archived: bool) -> Result<String, std::io::Error> {
+    let title = title.strip_prefix(ARCHIVE_PREFIX).unwrap_or(title);
+    let (old_title, new_title) = match archived {
+        true => (title.to_string(), format!("{}{}", ARCHIVE_PREFIX, title)),
+        false => (format!("{}{}", ARCHIVE_PREFIX, title), title.to_string()),
+    };
+
+    let old_path = get_path(&old_title);
+    if !old_path.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No conversation titled {}", old_title),
+        ));
+    }
+
+    let new_path = get_path(&new_title);
+    if new_path.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("A conversation titled {} already exists", new_title),
+        ));
+    }
+
+    if let Some(parent) = new_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    std::fs::rename(old_path, new_path)?;
+    Ok(new_title)
+}
+
+// most recently active first, with anything archived after everything that isn't
+pub fn list_titles(include_archived: bool) -> Result<Vec<String>, std::io::Error> {
+    let mut titles = Vec::new();
+    for file in std::fs::read_dir(config::get_conversations_dir())? {
+        let file = file?;
+        if file.path().is_file() {
+            titles.push(get_title(&file.file_name().to_string_lossy()));
+        }
+    }
+
+    let archive_dir = config::get_conversations_dir().join(ARCHIVE_PREFIX);
+    if include_archived && archive_dir.exists() {
+        for file in std::fs::read_dir(archive_dir)? {
+            let file = file?;
+            if file.path().is_file() {
+                titles.push(format!(
+                    "{}{}",
+                    ARCHIVE_PREFIX,
+                    get_title(&file.file_name().to_string_lossy())
+                ));
+            }
+        }
+    }
+
+    // titles break ties so the order is stable on filesystems with coarse timestamps
+    let mut titles = titles
+        .into_iter()
+        .map(|t| {
+            (
+                is_archived(&t),
+                std::cmp::Reverse(get_updated_at(&t).ok()),
+                t,
+            )
+        })
+        .collect::<Vec<_>>();
+    titles.sort_unstable_by(|a, b| (a.0, &a.1, &b.2).cmp(&(b.0, &b.1, &a.2)));
+
+    Ok(titles.into_iter().map(|t| t.2).collect())
+}
+
+// a new conversation with the system prompt and first `exchanges` user messages of `title`,
+// along with their responses
+pub fn branch(
+    title: &str,
+    exchanges: usize,
+    new_title: &str,
+) -> Result<std::path::PathBuf, std::io::Error> {
+    let (system_prompt, messages) = split_system_prompt(load(title)?);
+
+    let total = messages
+        .iter()
+        .filter(|m| m.message_type == network::MessageType::User)
+        .count();
+    if exchanges == 0 || exchanges > total {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} has {} exchanges, can't branch at {}",
+                title, total, exchanges
+            ),
+        ));
+    }
+
+    // everything before the user message that starts the next exchange
+    let end = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.message_type == network::MessageType::User)
+        .nth(exchanges)
+        .map(|(i, _)| i)
+        .unwrap_or(messages.len());
+
+    let mut branched = messages[..end].to_vec();
+    if let Some(last) = branched.last_mut() {
+        last.branched_from = Some(title.to_string());
+    }
+
+    let path = get_new_path(new_title)?;
+    std::fs::write(
+        &path,
+        serde_json::to_string(&join_system_prompt(
+            &system_prompt.unwrap_or_default(),
+            &branched,
+        ))?,
+    )?;
+
+    Ok(path)
+}
+
+// the most recent branch point, conversations can be branched from branches
+pub fn get_parent(messages: &[network::Message]) -> Option<&str> {
+    messages
+        .iter()
+        .rev()
+        .find_map(|m| m.branched_from.as_deref())
+}
+
+// throwaway questions, a single user message and its response that haven't been touched since
+pub fn list_single_exchanges(older_than_days: u64) -> Result<Vec<String>, std::io::Error> {
+    let cutoff = chrono::Local::now() - chrono::Duration::days(older_than_days as i64);
+
+    let mut titles = Vec::new();
+    for title in list_titles(true)? {
+        if get_updated_at(&title)? > cutoff {
+            continue;
+        }
+
+        // unreadable files are left for the user to look at
+        let messages = match load(&title) {
+            Ok(messages) => split_system_prompt(messages).1,
+            Err(_) => continue,
+        };
+
+        let user_messages = messages
+            .iter()
+            .filter(|m| m.message_type == network::MessageType::User)
+            .count();
+        if user_messages == 1 && messages.len() <= 2 {
+            titles.push(title);
+        }
+    }
+
+    Ok(titles)
+}
+
+// an exact title wins, otherwise the prefix has to narrow things down to a single conversation
+// archived conversations only match when the prefix includes `archive/`
+pub fn resolve(prefix: &str) -> Result<String, std::io::Error> {
+    let titles = list_titles(is_archived(prefix))?;
+    if titles.iter().any(|t| t == prefix) {
+        return Ok(prefix.to_string());
+    }
+
+    let matches = titles
+        .iter()
+        .filter(|t| t.starts_with(prefix))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    match matches.len() {
+        1 => Ok(matches[0].clone()),
+        0 => {
+            let mut candidates = titles.clone();
+            candidates.sort_by_key(|t| {
+                std::cmp::Reverse(
+                    t.chars()
+                        .zip(prefix.chars())
+                        .take_while(|(a, b)| a == b)
+                        .count(),
+                )
+            });
+            candidates.truncate(5);
+
+            Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "No conversation matches {}, closest candidates:\n\t{}",
+                    prefix,
+                    candidates.join("\n\t")
+                ),
+            ))
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} matches more than one conversation:\n\t{}",
+                prefix,
+                matches.join("\n\t")
+            ),
+        )),
+    }
+}
+
+pub fn load(title: &str) -> Result<Vec<network::Message>, std::io::Error> {
+    let contents = std::fs::read_to_string(get_path(title))?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+// the system prompt, along with the `--prompt` name it was picked by
+#[derive(Clone, Debug, Default)]
+pub struct SystemPrompt {
+    pub content: String,
+    pub name: Option<String>,
+}
+
+impl SystemPrompt {
+    pub fn new(content: String) -> Self {
+        Self {
+            content,
+            name: None,
+        }
+    }
+}
+
+// the system prompt is saved as a leading `System` message so older conversations still load
+pub fn split_system_prompt(
+    mut messages: Vec<network::Message>,
+) -> (Option<SystemPrompt>, Vec<network::Message>) {
+    match messages.first() {
+        Some(m) if m.message_type == network::MessageType::System => {
+            let message = messages.remove(0);
+            (
+                Some(SystemPrompt {
+                    content: message.content,
+                    name: message.prompt,
+                }),
+                messages,
+            )
+        }
+        _ => (None, messages),
+    }
+}
+
+// what a conversation goes on with: `given` when it's meant to replace the stored prompt or there isn't one,
+// otherwise the stored prompt, with a named one read again so edits to it carry over
+// only freshly read prompts are expanded, anything stored already was
+pub fn choose_system_prompt(
+    stored: Option<SystemPrompt>,
+    given: &SystemPrompt,
+    override_stored: bool,
+    max_size_kb: u64,
+) -> Result<SystemPrompt, TllmError> {
+    let stored = match stored {
+        Some(stored) if !override_stored => stored,
+        _ => {
+            return Ok(SystemPrompt {
+                content: template::expand(&given.content, max_size_kb)?,
+                name: given.name.clone(),
+            })
+        }
+    };
+
+    let name = match &stored.name {
+        Some(name) => name,
+        None => return Ok(stored),
+    };
+
+    match prompts::load(name).and_then(|content| template::expand(&content, max_size_kb)) {
+        Ok(content) => Ok(SystemPrompt {
+            content,
+            name: stored.name.clone(),
+        }),
+        Err(e) => {
+            warn!("Keeping the saved copy of prompt {}: {}", name, e);
+            Ok(stored)
+        }
+    }
+}
+
+pub fn join_system_prompt(
+    system_prompt: &SystemPrompt,
+    messages: &[network::Message],
+) -> Vec<network::Message> {
+    let mut joined = Vec::new();
+    if !system_prompt.content.is_empty() {
+        let mut message =
+            network::Message::new(network::MessageType::System, system_prompt.content.clone());
+        message.prompt = system_prompt.name.clone();
+        joined.push(message);
+    }
+
+    joined.extend_from_slice(messages);
+    joined
+}
+
+// case-insensitive, against the title and then every message
+pub fn matches(title: &str, pattern: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    if title.to_lowercase().contains(&pattern) {
+        return true;
+    }
+
+    match load(title) {
+        Ok(messages) => messages
+            .iter()
+            .any(|m| m.content.to_lowercase().contains(&pattern)),
+        Err(_) => false,
+    }
+}
+
+pub fn total_usage(messages: &[network::Message]) -> network::Usage {
+    let mut total = network::Usage::default();
+    for usage in messages.iter().filter_map(|m| m.usage.as_ref()) {
+        total.prompt_tokens += usage.prompt_tokens;
+        total.completion_tokens += usage.completion_tokens;
+        total.cache_creation_tokens += usage.cache_creation_tokens;
+        total.cache_read_tokens += usage.cache_read_tokens;
+    }
+
+    total
+}
+
+// e.g. `openai/gpt-4o-mini`, older conversations didn't record either
+fn get_source(message: &network::Message) -> Option<String> {
+    match (&message.provider, &message.model) {
+        (Some(provider), Some(model)) => Some(format!("{}/{}", provider, model)),
+        (Some(source), None) | (None, Some(source)) => Some(source.clone()),
+        (None, None) => None,
+    }
+}
+
+// e.g. `Assistant (2024-05-03 14:22, openai/gpt-4o-mini)`
+pub fn get_header(message: &network::Message) -> String {
+    let role = match message.message_type {
+        network::MessageType::System => "System",
+        network::MessageType::User => "User",
+        network::MessageType::Assistant => "Assistant",
+        network::MessageType::Tool => "Tool",
+    };
+
+    let details = message
+        .created_at
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .into_iter()
+        .chain(get_source(message))
+        .collect::<Vec<String>>();
+
+    match details.is_empty() {
+        true => role.to_string(),
+        false => format!("{} ({})", role, details.join(", ")),
+    }
+}
+
+// the file is rewritten whenever a message is added, so this is the last activity
+pub fn get_updated_at(title: &str) -> Result<chrono::DateTime<chrono::Local>, std::io::Error> {
+    Ok(std::fs::metadata(get_path(title))?.modified()?.into())
+}
+
+// falls back to the last modification time on filesystems without creation times
+pub fn get_created_at(title: &str) -> Result<chrono::DateTime<chrono::Local>, std::io::Error> {
+    let metadata = std::fs::metadata(get_path(title))?;
+    let created = metadata.created().or_else(|_| metadata.modified())?;
+
+    Ok(created.into())
+}
+
+// which way conversation histories read in previews and exports
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HistoryOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl HistoryOrder {
+    pub fn parse(order: &str) -> Option<Self> {
+        match order.trim() {
+            "asc" => Some(HistoryOrder::Asc),
+            "desc" => Some(HistoryOrder::Desc),
+            _ => None,
+        }
+    }
+}
+
+// set with `history_order` in the config file, oldest first otherwise
+pub fn get_history_order() -> HistoryOrder {
+    config::read_config()
+        .get("history_order")
+        .and_then(|order| HistoryOrder::parse(order))
+        .unwrap_or_default()
+}
+
+// the system prompt stays on top either way
+pub fn order_messages(
+    messages: &[network::Message],
+    order: HistoryOrder,
+) -> Vec<&network::Message> {
+    let (system, rest): (Vec<_>, Vec<_>) = messages
+        .iter()
+        .partition(|m| m.message_type == network::MessageType::System);
+
+    match order {
+        HistoryOrder::Asc => system.into_iter().chain(rest).collect(),
+        HistoryOrder::Desc => system.into_iter().chain(rest.into_iter().rev()).collect(),
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Conversation {
+    pub title: String,
+    pub created_at: chrono::DateTime<chrono::Local>,
+    pub messages: Vec<network::Message>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Text,
+    Markdown,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "txt" => Some(ExportFormat::Text),
+            "md" => Some(ExportFormat::Markdown),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// each message under its header, the way the chat view and text exports show them
+// written after the message a rolling summary was stored on, see `summary::update`
+const SUMMARY_HEADER: &str = "Summary (generated, of the conversation up to here)";
+
+// images are only stored as paths, so that's what's shown
+fn get_content(message: &network::Message) -> String {
+    let mut content = message.content.clone();
+    for path in message.images.iter() {
+        content.push_str(&format!("\n[image: {}]", path));
+    }
+
+    content
+}
+
+pub fn to_text<'a>(messages: impl IntoIterator<Item = &'a network::Message>) -> String {
+    let mut output = String::new();
+    for message in messages {
+        output.push_str(&format!(
+            "{}:\n{}\n{}\n",
+            get_header(message),
+            get_content(message),
+            MESSAGE_SEPARATOR
+        ));
+
+        if let Some(summary) = &message.summary {
+            output.push_str(&format!(
+                "{}:\n{}\n{}\n",
+                SUMMARY_HEADER, summary, MESSAGE_SEPARATOR
+            ));
+        }
+    }
+
+    output
+}
+
+pub fn export(
+    title: &str,
+    format: ExportFormat,
+    order: HistoryOrder,
+) -> Result<String, std::io::Error> {
+    let conversation = Conversation {
+        title: title.to_string(),
+        created_at: get_created_at(title)?,
+        messages: load(title)?,
+    };
+
+    let created_at = conversation.created_at.format("%Y-%m-%d %H:%M:%S");
+    let output = match format {
+        ExportFormat::Text => {
+            format!(
+                "Title: {}\nCreated: {}\n\n{}",
+                title,
+                created_at,
+                to_text(order_messages(&conversation.messages, order))
+            )
+        }
+        ExportFormat::Markdown => {
+            let mut output = format!("# {}\n\n_Created {}_\n", title, created_at);
+            for message in order_messages(&conversation.messages, order) {
+                output.push_str(&format!(
+                    "\n## {}\n\n{}\n",
+                    get_header(message),
+                    get_content(message)
+                ));
+
+                if let Some(summary) = &message.summary {
+                    output.push_str(&format!("\n## {}\n\n{}\n", SUMMARY_HEADER, summary));
+                }
+            }
+
+            output
+        }
+        // an array so that single and bulk exports share a shape
+        ExportFormat::Json => serde_json::to_string_pretty(&vec![conversation])?,
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn get_messages() -> Vec<network::Message> {
+        [
+            (network::MessageType::System, "prompt"),
+            (network::MessageType::User, "first"),
+            (network::MessageType::Assistant, "second"),
+            (network::MessageType::User, "third"),
+        ]
+        .into_iter()
+        .map(|(message_type, content)| network::Message::new(message_type, content.to_string()))
+        .collect()
+    }
+
+    // where each message turned up in `text`
+    fn get_order(text: &str) -> Vec<usize> {
+        ["prompt", "first", "second", "third"]
+            .iter()
+            .map(|content| text.find(content).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn history_order() {
+        let messages = get_messages();
+
+        let asc = to_text(order_messages(&messages, HistoryOrder::Asc));
+        let order = get_order(&asc);
+        assert!(order[0] < order[1] && order[1] < order[2] && order[2] < order[3]);
+        assert!(asc.starts_with("System:\nprompt\n"));
+
+        // the system prompt stays first
+        let desc = to_text(order_messages(&messages, HistoryOrder::Desc));
+        let order = get_order(&desc);
+        assert!(order[0] < order[3] && order[3] < order[2] && order[2] < order[1]);
+        assert!(desc.starts_with("System:\nprompt\n"));
+
+        assert_eq!(HistoryOrder::default(), HistoryOrder::Asc);
+        assert_eq!(HistoryOrder::parse(" desc "), Some(HistoryOrder::Desc));
+        assert_eq!(HistoryOrder::parse("newest"), None);
+    }
+
+    #[test]
+    fn export_order() {
+        testing::setup();
+        let title = "export-order-test";
+        std::fs::create_dir_all(config::get_conversations_dir()).unwrap();
+        std::fs::write(
+            get_path(title),
+            serde_json::to_string(&get_messages()).unwrap(),
+        )
+        .unwrap();
+
+        for format in [ExportFormat::Text, ExportFormat::Markdown] {
+            let order = get_order(&export(title, format, HistoryOrder::Asc).unwrap());
+            assert!(order[1] < order[2] && order[2] < order[3]);
+
+            let order = get_order(&export(title, format, HistoryOrder::Desc).unwrap());
+            assert!(order[0] < order[3] && order[3] < order[2] && order[2] < order[1]);
+        }
+    }
+}
@@ -1,16 +1,328 @@
-use copypasta::{ClipboardContext, ClipboardProvider};
-use std::io::Read;
-
 use ratatui::{
-    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    crossterm::event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, KeyModifiers,
+        MouseEventKind,
+    },
     layout::{Constraint, Direction, Layout, Position, Rect},
     style::{Color, Modifier, Style, Stylize},
     text::{Line, Span},
-    widgets::{Block, List, ListState, Paragraph, Wrap},
+    widgets::{Block, Clear, List, ListState, Paragraph, Wrap},
 };
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 use crate::logger::Logger;
-use crate::{error, info, network};
+use crate::{debug, error, info, network, providers};
+
+// lines moved per mouse wheel event
+const SCROLL_LINES: usize = 3;
+
+// what `?` shows for each view, kept next to each other so they're updated together
+struct Binding {
+    keys: &'static str,
+    description: &'static str,
+}
+
+const CHAT_BINDINGS: &[Binding] = &[
+    Binding {
+        keys: "i, a",
+        description: "Start typing a message",
+    },
+    Binding {
+        keys: "Esc",
+        description: "Stop typing",
+    },
+    Binding {
+        keys: "Enter",
+        description: "Send the message",
+    },
+    Binding {
+        keys: "Ctrl+W",
+        description: "Delete the previous word while typing",
+    },
+    Binding {
+        keys: "Ctrl+U/K",
+        description: "Delete to the start or end of the line while typing",
+    },
+    Binding {
+        keys: "Ctrl+Y",
+        description: "Put back the last deleted text",
+    },
+    Binding {
+        keys: "Ctrl+V",
+        description: "Paste while typing",
+    },
+    Binding {
+        keys: "Ctrl+Left/Right",
+        description: "Move by word while typing",
+    },
+    Binding {
+        keys: "Home/End, Ctrl+A/E",
+        description: "Jump to the start or end of the line while typing",
+    },
+    Binding {
+        keys: "Ctrl+Home/End",
+        description: "Jump to the start or end of the message while typing",
+    },
+    Binding {
+        keys: "Arrows, h j k l",
+        description: "Move the cursor",
+    },
+    Binding {
+        keys: "0, $, Home, End",
+        description: "Jump to the start or end of the line",
+    },
+    Binding {
+        keys: "gg, G, Ctrl+Home/End",
+        description: "Jump to the top or bottom of the chat",
+    },
+    Binding {
+        keys: "Ctrl+U/D",
+        description: "Scroll up or down half a page",
+    },
+    Binding {
+        keys: "Shift+Up/Down",
+        description: "Page through the chat",
+    },
+    Binding {
+        keys: "Mouse wheel",
+        description: "Scroll the chat",
+    },
+    Binding {
+        keys: "/",
+        description: "Search the chat",
+    },
+    Binding {
+        keys: "n, N",
+        description: "Jump to the next or previous match",
+    },
+    Binding {
+        keys: "Esc",
+        description: "Clear the search",
+    },
+    Binding {
+        keys: "y",
+        description: "Copy the message under the cursor",
+    },
+    Binding {
+        keys: "Y",
+        description: "Copy the whole conversation",
+    },
+    Binding {
+        keys: "Ctrl+C",
+        description: "Stop a streaming response and exit",
+    },
+    Binding {
+        keys: "m",
+        description: "Pick the provider and model for the next messages",
+    },
+    Binding {
+        keys: "L",
+        description: "Load a conversation",
+    },
+    Binding {
+        keys: "Tab",
+        description: "Switch to the directory view",
+    },
+    Binding {
+        keys: "q",
+        description: "Quit",
+    },
+];
+
+const DIRECTORY_BINDINGS: &[Binding] = &[
+    Binding {
+        keys: "s",
+        description: "Search files",
+    },
+    Binding {
+        keys: "Enter",
+        description: "Run the search",
+    },
+    Binding {
+        keys: "Esc",
+        description: "Back to the results",
+    },
+    Binding {
+        keys: "Up/Down, wheel",
+        description: "Move through the results",
+    },
+    Binding {
+        keys: "Enter",
+        description: "Add the selected snippet to the chat input",
+    },
+    Binding {
+        keys: "o",
+        description: "Open the selected file in $EDITOR",
+    },
+    Binding {
+        keys: "f",
+        description: "Edit the search filters, e.g. extension:rs,path:src/",
+    },
+    Binding {
+        keys: "Tab",
+        description: "Switch to the chat view",
+    },
+    Binding {
+        keys: "q",
+        description: "Quit",
+    },
+];
+
+const LOAD_BINDINGS: &[Binding] = &[
+    Binding {
+        keys: "s",
+        description: "Search titles and messages",
+    },
+    Binding {
+        keys: "Esc",
+        description: "Back to the results",
+    },
+    Binding {
+        keys: "Up/Down, wheel",
+        description: "Move through the conversations",
+    },
+    Binding {
+        keys: "Enter",
+        description: "Open the conversation",
+    },
+    Binding {
+        keys: "r",
+        description: "Rename the conversation",
+    },
+    Binding {
+        keys: "d d",
+        description: "Delete the conversation",
+    },
+    Binding {
+        keys: "a",
+        description: "Archive or restore the conversation",
+    },
+    Binding {
+        keys: "Tab",
+        description: "Switch views",
+    },
+    Binding {
+        keys: "q",
+        description: "Quit",
+    },
+];
+
+// a popup over the middle of the screen, any key closes it
+// centered in `area`, shrunk to fit
+fn get_popup(area: Rect, width: u16, height: u16) -> Rect {
+    let width = std::cmp::min(area.width, width);
+    let height = std::cmp::min(area.height, height);
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+fn render_help(frame: &mut ratatui::Frame, title: &str, bindings: &[Binding]) {
+    let width = bindings.iter().map(|b| b.keys.len()).max().unwrap_or(0);
+
+    let mut lines = bindings
+        .iter()
+        .map(|b| {
+            Line::from(vec![
+                Span::styled(format!("{:<width$}  ", b.keys), Style::new().bold()),
+                Span::raw(b.description),
+            ])
+        })
+        .collect::<Vec<Line>>();
+    lines.push(Line::raw(""));
+    lines.push(Line::styled(
+        "Press any key to close",
+        Style::new().dark_gray(),
+    ));
+
+    let popup = get_popup(frame.area(), 64, lines.len() as u16 + 2);
+
+    frame.render_widget(Clear, popup);
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(format!("{} keys", title))),
+        popup,
+    );
+}
+
+struct ModelChoice {
+    provider: String,
+    model: String,
+    api_base: Option<network::ApiBase>,
+}
+
+// what `m` offers: the current model first, then the usual models of every provider
+// with a key set or an `api_base.<provider>` configured
+fn get_model_choices(api: &str, options: &network::Options) -> Vec<ModelChoice> {
+    let config = crate::config::read_config();
+    let mut choices = vec![ModelChoice {
+        provider: api.to_string(),
+        model: options.get_model(api),
+        api_base: options.api_base.clone(),
+    }];
+
+    for name in providers::PROVIDERS {
+        let provider = providers::get_provider(name);
+        let api_base = if *name == api {
+            options.api_base.clone()
+        } else {
+            config
+                .get(&format!("api_base.{}", name))
+                .and_then(|value| network::parse_api_base(value).ok())
+        };
+
+        if *name != api && providers::resolve_api_key(name).is_none() && api_base.is_none() {
+            continue;
+        }
+
+        let models = config
+            .get(&format!("model.{}", name))
+            .cloned()
+            .into_iter()
+            .chain([
+                provider.default_model().to_string(),
+                provider.title_model().to_string(),
+            ]);
+
+        for model in models {
+            if !choices
+                .iter()
+                .any(|c| c.provider == *name && c.model == model)
+            {
+                choices.push(ModelChoice {
+                    provider: name.to_string(),
+                    model,
+                    api_base: api_base.clone(),
+                });
+            }
+        }
+    }
+
+    choices
+}
+
+fn render_model_picker(frame: &mut ratatui::Frame, choices: &[ModelChoice], state: &mut ListState) {
+    let items = choices
+        .iter()
+        .map(|c| format!("{}/{}", c.provider, c.model))
+        .collect::<Vec<String>>();
+
+    let width = items.iter().map(|i| get_width(i)).max().unwrap_or(0) as u16 + 4;
+    let popup = get_popup(frame.area(), width.max(24), items.len() as u16 + 2);
+
+    frame.render_widget(Clear, popup);
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::bordered().title("Model"))
+            .highlight_style(Style::new().fg(Color::Blue).add_modifier(Modifier::BOLD))
+            .highlight_symbol(">"),
+        popup,
+        state,
+    );
+}
 
 #[derive(Eq, PartialEq)]
 enum ChatInputMode {
@@ -18,6 +330,60 @@ enum ChatInputMode {
     Insert,
 }
 
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+// how the latest request is getting along, for the status bar
+enum RequestStatus {
+    Idle,
+    Waiting {
+        provider: String,
+        since: std::time::Instant,
+    },
+    Streaming {
+        since: std::time::Instant,
+    },
+    Finished {
+        elapsed: std::time::Duration,
+        usage: Option<network::Usage>,
+    },
+}
+
+impl RequestStatus {
+    fn is_pending(&self) -> bool {
+        matches!(
+            self,
+            RequestStatus::Waiting { .. } | RequestStatus::Streaming { .. }
+        )
+    }
+
+    fn display(&self) -> String {
+        let spinner = |since: &std::time::Instant| {
+            SPINNER[(since.elapsed().as_millis() / 100) as usize % SPINNER.len()]
+        };
+
+        match self {
+            RequestStatus::Idle => String::new(),
+            RequestStatus::Waiting { provider, since } => {
+                format!("{} waiting for {}…", spinner(since), provider)
+            }
+            RequestStatus::Streaming { since } => format!("{} streaming…", spinner(since)),
+            RequestStatus::Finished { elapsed, usage } => match usage {
+                Some(usage) => format!(
+                    "done in {:.1}s, {} tokens",
+                    elapsed.as_secs_f64(),
+                    usage.completion_tokens
+                ),
+                None => format!("done in {:.1}s", elapsed.as_secs_f64()),
+            },
+        }
+    }
+}
+
+// in terminal cells, measured per grapheme like ratatui does when it draws the text
+fn get_width(text: &str) -> usize {
+    text.graphemes(true).map(|g| g.width()).sum()
+}
+
 #[derive(Debug)]
 struct WrappedText {
     content: String,
@@ -26,76 +392,195 @@ struct WrappedText {
     window_size: (usize, usize),
 }
 
+// wrapping never touches `content`, each entry of `line_lengths` is how many of its bytes
+// a display line covers, including the newline or whitespace it was broken at
 impl WrappedText {
-    fn is_byte_boundary(&self, offset: usize) -> bool {
-        offset == 0
-            || offset == self.content.len()
-            || (self.content.as_bytes()[offset] & 0xC0) != 0x80
+    // where each display line starts in `content`
+    fn get_line_start(&self, line: usize) -> usize {
+        self.line_lengths[..line.min(self.line_lengths.len())]
+            .iter()
+            .sum()
     }
 
-    fn find_prev_byte_boundary(&self, mut offset: usize) -> usize {
-        while offset > 0 && !self.is_byte_boundary(offset) {
-            offset -= 1;
-        }
+    // a display line without the newline ending it
+    fn get_line(&self, line: usize) -> &str {
+        let start = self.get_line_start(line);
+        let end = start + self.line_lengths.get(line).copied().unwrap_or(0);
+        self.content[start..end]
+            .strip_suffix('\n')
+            .unwrap_or(&self.content[start..end])
+    }
 
-        offset
+    // broken by the wrapping rather than by a newline in the text
+    fn is_wrapped(&self, line: usize) -> bool {
+        line + 1 < self.line_lengths.len()
+            && !self.content[..self.get_line_start(line + 1)].ends_with('\n')
     }
 
-    fn find_next_byte_boundary(&self, mut offset: usize) -> usize {
-        while offset < self.content.len() && !self.is_byte_boundary(offset) {
-            offset += 1;
+    // the last column the cursor can sit in,
+    // the end of a wrapped line is the same spot as the start of the next one
+    fn get_line_width(&self, line: usize) -> usize {
+        let text = self.get_line(line);
+        match text.graphemes(true).next_back() {
+            Some(last) if self.is_wrapped(line) => get_width(text) - last.width(),
+            _ => get_width(text),
         }
+    }
+
+    // byte offset of a (line, column) position, with columns counted in terminal cells
+    fn get_flat(&self, line: usize, column: usize) -> usize {
+        let text = self.get_line(line);
+        let mut width = 0;
+        for (i, grapheme) in text.grapheme_indices(true) {
+            // a column partway through a wide char lands on its start
+            if width + grapheme.width() > column {
+                return self.get_line_start(line) + i;
+            }
 
-        offset
+            width += grapheme.width();
+        }
+
+        self.get_line_start(line) + text.len()
     }
 
-    fn get_flat(&self, line: usize, mut offset: usize, previous_byte_boundary: bool) -> usize {
-        for l in 0..line {
-            offset += self.line_lengths[l];
+    // the (line, column) position of a byte offset
+    fn get_position(&self, offset: usize) -> (usize, usize) {
+        let mut start = 0;
+        for (line, length) in self.line_lengths.iter().enumerate() {
+            if offset < start + length || line + 1 == self.line_lengths.len() {
+                let end = offset.clamp(start, self.content.len());
+                return (line, get_width(&self.content[start..end]));
+            }
+
+            start += length;
         }
 
-        if previous_byte_boundary {
-            self.find_prev_byte_boundary(offset)
-        } else {
-            self.find_next_byte_boundary(offset)
+        (0, 0)
+    }
+
+    // the cursor for a byte offset, moving the page up if the line is above it
+    // the render loop pages down if it's below the box
+    fn get_cursor(&mut self, offset: usize) -> (usize, usize) {
+        let (line, column) = self.get_position(offset);
+        if line < self.page {
+            self.page = line;
         }
+
+        (line - self.page, column)
     }
 
-    // no idea what these +1s are for lol
-    pub fn next_whitespace_distance(&self, line: usize, column: usize) -> usize {
-        let offset = self.get_flat(line, column + 1, false);
-        if let Some(rest) = self.content.get(offset..) {
-            match rest.find(char::is_whitespace) {
-                Some(d) => d,
-                None => rest.len(),
+    // rewraps for a new width, keeping the same text at the top of the page and under the cursor
+    fn reflow(&mut self, cursor: (usize, usize)) -> (usize, usize) {
+        let top = self.get_line_start(self.page);
+        let offset = self.get_flat(cursor.0 + self.page, cursor.1);
+
+        self.rewrap();
+        self.page = self.get_position(top).0;
+
+        self.get_cursor(offset)
+    }
+
+    // breaks at the last whitespace that fits when it isn't too far back,
+    // anything without one close enough (urls, code) is cut at the width
+    fn rewrap(&mut self) {
+        self.rewrap_from(0);
+    }
+
+    // the lines above `line` are kept as they are, which only holds when it starts after a newline
+    fn rewrap_from(&mut self, line: usize) {
+        let width = self.window_size.0.saturating_sub(2).max(1);
+
+        let line = line.min(self.line_lengths.len());
+        let begin = self.get_line_start(line);
+        let mut lengths = self.line_lengths[..line].to_vec();
+        let mut start = begin;
+        let mut column = 0;
+        // the offset just past the last whitespace on the line, and the column there
+        let mut last_break: Option<(usize, usize)> = None;
+        for (i, grapheme) in self.content[begin..].grapheme_indices(true) {
+            let i = begin + i;
+            // \r\n is a single grapheme
+            if grapheme.ends_with('\n') {
+                lengths.push(i + grapheme.len() - start);
+                start = i + grapheme.len();
+                column = 0;
+                last_break = None;
+                continue;
+            }
+
+            if column > 0 && column + grapheme.width() > width {
+                match last_break {
+                    Some((end, break_column)) if column - break_column <= width / 2 => {
+                        lengths.push(end - start);
+                        start = end;
+                        column -= break_column;
+                    }
+                    _ => {
+                        lengths.push(i - start);
+                        start = i;
+                        column = 0;
+                    }
+                }
+
+                last_break = None;
+            }
+
+            column += grapheme.width();
+            if grapheme.chars().all(char::is_whitespace) {
+                last_break = Some((i + grapheme.len(), column));
             }
-        } else {
-            0
         }
+
+        // a full last line leaves the cursor somewhere to go at the end
+        if column >= width {
+            lengths.push(self.content.len() - start);
+            start = self.content.len();
+        }
+
+        lengths.push(self.content.len() - start);
+
+        self.line_lengths = lengths;
     }
 
-    pub fn prev_whitespace_distance(&self, line: usize, column: usize) -> usize {
-        let offset = self.get_flat(line, column + 1, false);
-        if let Some(rest) = self.content.get(..offset) {
-            match rest.rfind(char::is_whitespace) {
-                Some(d) => offset - d,
-                None => rest.len(),
-            }
-        } else {
-            0
+    fn prev_grapheme(&self, offset: usize) -> usize {
+        self.content[..offset]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_grapheme(&self, offset: usize) -> usize {
+        self.content[offset..]
+            .graphemes(true)
+            .next()
+            .map_or(offset, |g| offset + g.len())
+    }
+
+    // the start of the word before `offset`
+    fn prev_word_start(&self, offset: usize) -> usize {
+        let before = &self.content[..offset];
+        let trimmed = before.trim_end();
+        match trimmed.rfind(char::is_whitespace) {
+            Some(i) => i + trimmed[i..].chars().next().unwrap().len_utf8(),
+            None => 0,
         }
     }
 
-    pub fn insert(&mut self, substring: &str, line: usize, column: usize) {
-        let offset = self.get_flat(line, column + if line > 0 { 1 } else { 0 }, false);
+    // the end of the word after `offset`
+    fn next_word_end(&self, offset: usize) -> usize {
+        let after = &self.content[offset..];
+        let skipped = after.len() - after.trim_start().len();
+        match after[skipped..].find(char::is_whitespace) {
+            Some(i) => offset + skipped + i,
+            None => self.content.len(),
+        }
+    }
 
+    pub fn insert(&mut self, substring: &str, offset: usize) -> usize {
         let sanitized = substring.replace("\t", "    ");
+        self.content.insert_str(offset, &sanitized);
 
-        if offset >= self.content.len() {
-            self.content.push_str(&sanitized);
-        } else {
-            self.content.insert_str(offset, &sanitized);
-        }
+        offset + sanitized.len()
     }
 
     // clamping for these two paging functions is done in the main render loop
@@ -114,44 +599,48 @@ impl WrappedText {
         self.page += self.window_size.1;
     }
 
-    pub fn delete_word(&mut self, line: usize, column: usize) -> String {
-        let mut end = self.get_flat(line, column, true);
-        if end == 0 {
-            return String::new();
-        }
+    // the grapheme before `offset`, returning where it started
+    pub fn delete_char(&mut self, offset: usize) -> usize {
+        let begin = self.prev_grapheme(offset);
+        self.content.drain(begin..offset);
 
-        if column < self.line_lengths[line] {
-            end += 1;
-        }
-
-        let mut begin = end - 1;
-        let bytes = self.content.as_bytes();
-        while begin > 0 && (!self.is_byte_boundary(begin) || !bytes[begin].is_ascii_whitespace()) {
-            begin -= 1;
-        }
+        begin
+    }
 
-        self.content.drain(begin..end).as_str().to_string()
+    // the grapheme after `offset`
+    pub fn delete_next_char(&mut self, offset: usize) {
+        let end = self.next_grapheme(offset);
+        self.content.drain(offset..end);
     }
 
-    pub fn display(&self) -> &str {
-        if self.line_lengths.len() == 0 {
-            return "";
-        }
+    pub fn delete_word(&mut self, offset: usize) -> String {
+        let begin = self.prev_word_start(offset);
+        self.content.drain(begin..offset).collect()
+    }
 
-        let mut begin = 0;
-        for p in 0..self.page {
-            begin += self.line_lengths[p];
-        }
+    // back to the last newline, lines broken by the wrapping don't count
+    pub fn delete_to_line_start(&mut self, offset: usize) -> String {
+        let begin = self.content[..offset].rfind('\n').map_or(0, |i| i + 1);
+        self.content.drain(begin..offset).collect()
+    }
 
-        let mut end = begin;
-        for p in self.page..self.line_lengths.len() {
-            end += self.line_lengths[p];
-        }
+    // up to the next newline, or the newline itself when the cursor's already there
+    pub fn delete_to_line_end(&mut self, offset: usize) -> String {
+        let end = match self.content[offset..].find('\n') {
+            Some(0) => offset + 1,
+            Some(i) => offset + i,
+            None => self.content.len(),
+        };
 
-        begin = self.find_prev_byte_boundary(begin);
-        end = self.find_next_byte_boundary(end);
+        self.content.drain(offset..end).collect()
+    }
 
-        &self.content[begin..end].trim()
+    // the wrapped lines from the current page on
+    pub fn display(&self) -> String {
+        (self.page..self.line_lengths.len())
+            .map(|l| self.get_line(l))
+            .collect::<Vec<&str>>()
+            .join("\n")
     }
 
     pub fn len(&self) -> usize {
@@ -161,7 +650,104 @@ impl WrappedText {
     pub fn clear(&mut self) {
         self.content = String::new();
         self.line_lengths = Vec::new();
+        self.page = 0;
+    }
+}
+
+// byte offsets into the chat pane's text
+struct RenderedMessage {
+    start: usize,
+    header: usize,
+    body: usize,
+}
+
+// case-insensitive, as byte ranges of `text`
+fn find_matches(text: &str, query: &str) -> Vec<std::ops::Range<usize>> {
+    let query = query.to_lowercase();
+    let mut matches = Vec::new();
+    if query.is_empty() {
+        return matches;
+    }
+
+    for (start, _) in text.char_indices() {
+        let mut lowered = String::new();
+        for (i, c) in text[start..].char_indices() {
+            lowered.extend(c.to_lowercase());
+            if !query.starts_with(&lowered) {
+                break;
+            }
+
+            if lowered.len() == query.len() {
+                matches.push(start..start + i + c.len_utf8());
+                break;
+            }
+        }
     }
+
+    matches
+}
+
+// restyles whatever parts of a line starting at byte `start` fall in one of `ranges`
+fn highlight_ranges<'a>(
+    line: Line<'a>,
+    start: usize,
+    ranges: &[std::ops::Range<usize>],
+    style: Style,
+) -> Line<'a> {
+    let end = start + line.spans.iter().map(|s| s.content.len()).sum::<usize>();
+    if !ranges.iter().any(|r| r.start < end && r.end > start) {
+        return line;
+    }
+
+    let line_style = line.style;
+    let mut spans = Vec::new();
+    let mut offset = start;
+    for span in line.spans {
+        let span_end = offset + span.content.len();
+        let mut cuts = ranges
+            .iter()
+            .flat_map(|r| [r.start, r.end])
+            .filter(|c| *c > offset && *c < span_end)
+            .collect::<Vec<usize>>();
+        cuts.push(span_end);
+        cuts.sort();
+        cuts.dedup();
+
+        let mut from = offset;
+        for cut in cuts {
+            let text = span.content[from - offset..cut - offset].to_string();
+            if ranges.iter().any(|r| r.start <= from && from < r.end) {
+                spans.push(Span::styled(text, span.style.patch(style)));
+            } else {
+                spans.push(Span::styled(text, span.style));
+            }
+
+            from = cut;
+        }
+
+        offset = span_end;
+    }
+
+    Line::from(spans).style(line_style)
+}
+
+fn get_role_style(message_type: &network::MessageType) -> Style {
+    match message_type {
+        network::MessageType::User => Style::new().blue().bold(),
+        network::MessageType::Assistant => Style::new().green().bold(),
+        network::MessageType::System => Style::new().dark_gray().bold(),
+        network::MessageType::Tool => Style::new().yellow().bold(),
+    }
+}
+
+// the unsent message, kept while the other views are open
+#[derive(Default)]
+pub struct ChatInput {
+    content: String,
+    // byte offset of the cursor in `content`
+    offset: usize,
+    // picked in the directory view, inserted at the cursor when the chat opens again
+    pending: Option<String>,
 }
 
 pub struct ChatState {
@@ -169,48 +755,220 @@ pub struct ChatState {
     chat_wrapped: WrappedText,
     input_mode: ChatInputMode,
     pending_changes: bool,
+    // where the input cursor goes once the input is wrapped for the first time
+    pending_input_offset: Option<usize>,
+    // both panes are still wrapped for the old terminal size
+    pending_resize: bool,
+    // the last text deleted with Ctrl+W/U/K, for Ctrl+Y
+    kill_register: String,
     pub chat_messages: Vec<network::Message>,
     input_cursor: (usize, usize),
     chat_cursor: (usize, usize),
     pending_page_up: bool,
-    pending_chat_update: String,
-    pending_deletions: usize,
-    last_message_instant: std::time::Instant,
+    // where each message's text starts in `chat_wrapped`, in the same order as `chat_messages`
+    rendered_messages: Vec<RenderedMessage>,
     next_window: WindowView,
+    // set while a response is streaming in, cleared by the worker thread
+    streaming: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    cancel: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    // printed once the terminal is restored
+    exit_note: String,
+    // shown in the status bar until the next key press
+    status_error: String,
+    status_note: String,
+    // keep the newest text in view as a response streams in, off while scrolled up
+    follow: bool,
+    // text arrived below the view while not following
+    unseen_output: bool,
+    // what's being typed after /, the search only runs once it's entered
+    search_input: Option<String>,
+    search_query: String,
+    search_matches: Vec<std::ops::Range<usize>>,
+    search_index: usize,
+    show_help: bool,
+    // open while picking a model with `m`
+    model_picker: Option<ListState>,
+    model_choices: Vec<ModelChoice>,
+    // the first g of gg
+    pending_g: bool,
+    request_status: RequestStatus,
 }
 
 // there's probably a better abstraction for these interactive boxes
 impl ChatState {
-    pub fn rewrap(&mut self, window: Rect) {
-        let new_wrapped = match self.input_mode {
-            ChatInputMode::Normal => &mut self.chat_wrapped,
-            ChatInputMode::Insert => &mut self.input_wrapped,
-        };
+    // byte offset of the input cursor in the input text
+    fn get_input_offset(&self) -> usize {
+        self.input_wrapped.get_flat(
+            self.input_cursor.0 + self.input_wrapped.page,
+            self.input_cursor.1,
+        )
+    }
 
-        let mut wrapped_content = String::new();
-        let mut lengths = Vec::new();
-        let mut column: usize = 0;
-        for c in new_wrapped.content.chars() {
-            if c == '\n' || column as u16 >= window.width - 2 {
-                wrapped_content.push('\n');
-                lengths.push(column as usize);
+    // after an edit, rewraps the input and puts its cursor on `offset`
+    fn set_input_offset(&mut self, offset: usize) {
+        self.input_wrapped.rewrap();
+        self.input_cursor = self.input_wrapped.get_cursor(offset);
+    }
 
-                column = 0;
+    // rewrites the chat pane's text from message `from` on, the lines above it are left alone
+    fn render_messages(&mut self, from: usize) {
+        let start = self
+            .rendered_messages
+            .get(from)
+            .map_or(self.chat_wrapped.content.len(), |r| r.start);
+        let first_line = self.chat_wrapped.get_position(start).0;
+
+        self.chat_wrapped.content.truncate(start);
+        self.rendered_messages.truncate(from);
+        for (i, message) in self.chat_messages.iter().enumerate().skip(from) {
+            let content = &mut self.chat_wrapped.content;
+            let start = content.len();
+            if i > 0 {
+                content.push_str(crate::conversation::MESSAGE_SEPARATOR);
+                content.push('\n');
             }
 
-            if c != '\n' {
-                wrapped_content.push(c);
-            }
+            let header = content.len();
+            content.push_str(&format!("{}:\n", crate::conversation::get_header(message)));
+
+            let body = content.len();
+            content.push_str(&message.content);
+            content.push('\n');
+
+            self.rendered_messages.push(RenderedMessage {
+                start,
+                header,
+                body,
+            });
+        }
+
+        self.chat_wrapped.rewrap_from(first_line);
 
-            column += c.len_utf8();
+        // new text can bring new matches, the ones above it haven't moved
+        if !self.search_query.is_empty() {
+            self.search_matches = find_matches(&self.chat_wrapped.content, &self.search_query);
         }
+    }
 
-        if column > 0 {
-            lengths.push(column);
+    // searches from the cursor on, wrapping around to the top
+    fn search(&mut self, query: String) {
+        self.search_matches = find_matches(&self.chat_wrapped.content, &query);
+        self.search_query = query;
+        if self.search_matches.is_empty() {
+            self.status_error = format!("No matches for {}", self.search_query);
+            return;
         }
 
-        new_wrapped.line_lengths = lengths;
-        new_wrapped.content = wrapped_content;
+        let cursor = self.chat_wrapped.get_flat(
+            self.chat_cursor.0 + self.chat_wrapped.page,
+            self.chat_cursor.1,
+        );
+        self.search_index = self
+            .search_matches
+            .iter()
+            .position(|m| m.start >= cursor)
+            .unwrap_or(0);
+        self.jump_to_match();
+    }
+
+    fn next_match(&mut self, forward: bool) {
+        let count = self.search_matches.len();
+        if count == 0 {
+            return;
+        }
+
+        self.search_index = if forward {
+            (self.search_index + 1) % count
+        } else {
+            (self.search_index + count - 1) % count
+        };
+        self.jump_to_match();
+    }
+
+    // the render loop pages down if the match is below the pane
+    fn jump_to_match(&mut self) {
+        let offset = self.search_matches[self.search_index].start;
+        self.chat_cursor = self.chat_wrapped.get_cursor(offset);
+        self.follow = false;
+    }
+
+    fn clear_search(&mut self) {
+        self.search_query.clear();
+        self.search_matches.clear();
+        self.search_index = 0;
+    }
+
+    fn get_message_at_offset(&self, offset: usize) -> Option<usize> {
+        self.rendered_messages
+            .iter()
+            .rposition(|r| r.start <= offset)
+    }
+
+    // which message a line of the chat pane falls under
+    fn get_message_at(&self, line: usize) -> Option<usize> {
+        self.get_message_at_offset(self.chat_wrapped.get_line_start(line))
+    }
+
+    // the visible part of the chat pane, styled by which part of a message each line is in
+    fn get_chat_lines(&self) -> Vec<Line<'_>> {
+        let wrapped = &self.chat_wrapped;
+        let visible = wrapped.window_size.1.saturating_sub(2);
+
+        let mut lines = Vec::new();
+        // whether the next line of a message is inside a code block
+        let mut code_state: Option<(usize, bool)> = None;
+        for l in (wrapped.page..wrapped.line_lengths.len()).take(visible) {
+            let start = wrapped.get_line_start(l);
+            let text = wrapped.get_line(l);
+            let i = match self.get_message_at_offset(start) {
+                Some(i) => i,
+                None => {
+                    lines.push(Line::raw(text));
+                    continue;
+                }
+            };
+
+            let rendered = &self.rendered_messages[i];
+            let line = if start < rendered.header {
+                Line::styled(text, Style::new().dark_gray())
+            } else if start < rendered.body {
+                Line::styled(text, get_role_style(&self.chat_messages[i].message_type))
+            } else {
+                // a code block can start above the page
+                let in_code = match code_state {
+                    Some((m, in_code)) if m == i => in_code,
+                    _ => crate::highlight::ends_in_code(&wrapped.content[rendered.body..start]),
+                };
+
+                code_state = Some((i, in_code ^ crate::highlight::ends_in_code(text)));
+                crate::highlight::highlight(text, in_code).remove(0)
+            };
+
+            // the current match stands out from the rest
+            let line = highlight_ranges(
+                line,
+                start,
+                &self.search_matches,
+                Style::new().fg(Color::Black).bg(Color::Yellow),
+            );
+            lines.push(highlight_ranges(
+                line,
+                start,
+                self.search_matches
+                    .get(self.search_index..self.search_index + 1)
+                    .unwrap_or_default(),
+                Style::new().fg(Color::Black).bg(Color::LightRed),
+            ));
+        }
+
+        lines
+    }
+
+    fn copy(&mut self, content: &str) {
+        match crate::clipboard::set(content) {
+            Ok(_) => self.status_note = format!("Copied {} chars", content.chars().count()),
+            Err(e) => self.status_error = e,
+        }
     }
 
     // the output clamped cursor of this should refer to
@@ -226,38 +984,46 @@ impl ChatState {
             return;
         }
 
-        let new_row = std::cmp::min(cursor.0, wrapped.line_lengths.len() - 1);
+        let new_row = std::cmp::min(
+            cursor.0,
+            wrapped.line_lengths.len().saturating_sub(wrapped.page + 1),
+        );
         let new_row = std::cmp::min(new_row, wrapped.window_size.1 - 2);
-        let line_index = new_row + wrapped.page;
-        let col_bound = if wrapped.line_lengths[line_index] > 0 {
-            wrapped.line_lengths[line_index]
-                - (if cursor.0 > 0 && self.input_mode == ChatInputMode::Insert {
-                    1
-                } else {
-                    0
-                })
-        } else {
-            0
-        };
+
+        // the column can't land inside a wide char
+        let line = new_row + wrapped.page;
+        let column = std::cmp::min(cursor.1, wrapped.get_line_width(line));
+        let line_start = wrapped.get_line_start(line);
 
         cursor.0 = new_row;
-        cursor.1 = std::cmp::min(std::cmp::max(cursor.1, 0), col_bound);
+        cursor.1 = get_width(&wrapped.content[line_start..wrapped.get_flat(line, column)]);
     }
 }
 
+// `override_system_prompt` is set when `-s` or `--prompt` was given and should replace the stored prompt
 pub fn chat(
     terminal: &mut ratatui::DefaultTerminal,
-    system_prompt: &str,
-    api: &str,
+    session: &SessionOptions,
     conversation_path: &str,
-) -> Result<WindowView, Box<dyn std::error::Error>> {
-    let conversation = match std::path::Path::new(conversation_path).exists() {
-        true => {
-            let contents = std::fs::read_to_string(conversation_path)?;
-            serde_json::from_str(&contents)?
-        }
-        false => Vec::new(),
-    };
+    draft: Option<std::path::PathBuf>,
+    attachments: &mut Vec<crate::attachment::Attachment>,
+    input: &mut ChatInput,
+) -> Result<(WindowView, String), Box<dyn std::error::Error>> {
+    let (stored_system_prompt, conversation) =
+        match std::path::Path::new(conversation_path).exists() {
+            true => {
+                let contents = std::fs::read_to_string(conversation_path)?;
+                crate::conversation::split_system_prompt(serde_json::from_str(&contents)?)
+            }
+            false => (None, Vec::new()),
+        };
+
+    let system_prompt = crate::conversation::choose_system_prompt(
+        stored_system_prompt,
+        session.system_prompt,
+        session.override_system_prompt,
+        session.max_file_size,
+    )?;
 
     let mut state = ChatState {
         input_wrapped: WrappedText {
@@ -274,22 +1040,65 @@ pub fn chat(
         },
         chat_messages: conversation.clone(),
         pending_changes: false,
+        pending_input_offset: None,
+        // wrapped before the first frame, when the size isn't known yet
+        pending_resize: true,
+        kill_register: String::new(),
         input_mode: ChatInputMode::Normal,
         input_cursor: (0, 0),
         chat_cursor: (0, 0),
         pending_page_up: false,
-        pending_chat_update: String::new(),
-        pending_deletions: 0,
-        last_message_instant: std::time::Instant::now() - std::time::Duration::from_secs(60),
+        rendered_messages: Vec::new(),
         next_window: WindowView::Chat,
+        streaming: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        cancel: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        exit_note: String::new(),
+        status_error: String::new(),
+        status_note: String::new(),
+        follow: true,
+        unseen_output: false,
+        search_input: None,
+        search_query: String::new(),
+        search_matches: Vec::new(),
+        search_index: 0,
+        show_help: false,
+        model_picker: None,
+        model_choices: Vec::new(),
+        pending_g: false,
+        request_status: RequestStatus::Idle,
     };
 
-    for chat in state.chat_messages.iter() {
-        state.pending_chat_update.push_str(&chat.content);
-        state.pending_chat_update.push_str("\n───\n");
+    // switched with `m`, only the messages sent afterwards use the new model
+    let mut api = session.api.to_string();
+    let mut options = session.options.clone();
+
+    state.render_messages(0);
+
+    state.input_wrapped.content = std::mem::take(&mut input.content);
+    let mut offset = std::cmp::min(input.offset, state.input_wrapped.len());
+    if let Some(snippet) = input.pending.take() {
+        // the block gets lines of its own
+        let before = match state.input_wrapped.content[..offset].ends_with('\n') || offset == 0 {
+            true => "",
+            false => "\n",
+        };
+
+        offset = state
+            .input_wrapped
+            .insert(&format!("{}{}\n", before, snippet), offset);
+        state.input_mode = ChatInputMode::Insert;
     }
 
-    let (tx, rx) = std::sync::mpsc::channel::<String>();
+    state.pending_input_offset = Some(offset);
+    state.pending_changes = true;
+
+    // removed once it's sent, a failed send saves a fresh one
+    if let Some(path) = &draft {
+        state.input_wrapped.content = std::fs::read_to_string(path)?;
+        state.pending_changes = true;
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel::<network::StreamEvent>();
 
     loop {
         terminal.draw(|frame| {
@@ -300,13 +1109,27 @@ pub fn chat(
             ])
             .areas(frame.area());
 
+            state.chat_wrapped.window_size = (chat_box.width.into(), chat_box.height.into());
+            state.input_wrapped.window_size = (input_box.width.into(), input_box.height.into());
+
             if state.pending_changes {
-                state.rewrap(input_box);
+                state.input_wrapped.rewrap();
                 state.pending_changes = false;
+
+                if let Some(offset) = state.pending_input_offset.take() {
+                    state.input_cursor = state.input_wrapped.get_cursor(offset);
+                }
             }
 
-            state.chat_wrapped.window_size = (chat_box.width.into(), chat_box.height.into());
-            state.input_wrapped.window_size = (input_box.width.into(), input_box.height.into());
+            if state.pending_resize {
+                state.chat_cursor = state.chat_wrapped.reflow(state.chat_cursor);
+                state.input_cursor = state.input_wrapped.reflow(state.input_cursor);
+                if state.follow {
+                    state.chat_cursor.0 = state.chat_wrapped.line_lengths.len();
+                }
+
+                state.pending_resize = false;
+            }
 
             {
                 let (cursor, page_check) = match state.input_mode {
@@ -330,47 +1153,13 @@ pub fn chat(
                 state.pending_page_up = false;
             }
 
-            if state.pending_chat_update.len() > 0 {
-                state
-                    .chat_wrapped
-                    .content
-                    .push_str(&state.pending_chat_update);
-                state.rewrap(chat_box);
-                state.pending_chat_update = String::new();
-            }
-
-            // if we have pending deletions, the cursors should already be valid
-            if state.pending_deletions > 0 {
-                let offset = state.input_wrapped.get_flat(
-                    state.input_cursor.0 + state.input_wrapped.page,
-                    state.input_cursor.1 + (if state.input_cursor.0 > 0 { 1 } else { 0 }),
-                    true,
-                );
-
-                if offset < state.pending_deletions {
-                    state.pending_deletions = offset;
-                }
-
-                if state.pending_deletions > 0 {
-                    let lower = std::cmp::max(offset - state.pending_deletions, 0);
-                    let upper = offset;
-
-                    state.input_wrapped.content.drain(lower..upper);
-
-                    if state.input_cursor.0 > 0 && state.input_cursor.1 == 0 {
-                        state.input_cursor.0 -= 1;
-                        state.input_cursor.1 = usize::MAX;
-                    } else {
-                        state.input_cursor.1 -= 1;
-                    }
-
-                    state.pending_deletions = 0;
-                }
-
-                state.rewrap(input_box);
-            }
-
             state.clamp_cursor();
+            if state.follow
+                || state.chat_wrapped.line_lengths.len()
+                    <= state.chat_wrapped.page + state.chat_wrapped.window_size.1 - 2
+            {
+                state.unseen_output = false;
+            }
 
             // the cursor should always be clamped before reaching here
             let (display_cursor, focused_area) = match state.input_mode {
@@ -379,52 +1168,177 @@ pub fn chat(
             };
 
             frame.render_widget(
-                Paragraph::new(state.chat_wrapped.display()).block(Block::bordered().title("Chat")),
+                Paragraph::new(state.get_chat_lines()).block(Block::bordered().title("Chat")),
                 chat_box,
             );
 
+            // sent along with the next message
+            let input_title = if attachments.is_empty() {
+                "Input".to_string()
+            } else {
+                format!(
+                    "Input (attached: {})",
+                    attachments
+                        .iter()
+                        .map(|a| a.path.clone())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+            };
+
             frame.render_widget(
                 Paragraph::new(state.input_wrapped.display())
-                    .block(Block::bordered().title("Input")),
+                    .block(Block::bordered().title(input_title)),
                 input_box,
             );
 
-            frame.render_widget(
-                Paragraph::new(match state.input_mode {
-                    ChatInputMode::Insert => "Insert",
-                    ChatInputMode::Normal => "Command",
-                })
-                .style(Style::default().fg(Color::Black).bg(
-                    match state.input_mode {
-                        ChatInputMode::Insert => Color::LightYellow,
-                        ChatInputMode::Normal => Color::LightCyan,
-                    },
+            if let Some(input) = &state.search_input {
+                frame.render_widget(Paragraph::new(format!("/{}", input)), status_bar);
+            } else if !state.status_note.is_empty() && state.status_error.is_empty() {
+                frame.render_widget(
+                    Paragraph::new(state.status_note.clone())
+                        .style(Style::default().fg(Color::Black).bg(Color::LightGreen)),
+                    status_bar,
+                );
+            } else if state.status_error.is_empty() {
+                frame.render_widget(
+                    Paragraph::new(
+                        [
+                            match state.input_mode {
+                                ChatInputMode::Insert => "Insert".to_string(),
+                                ChatInputMode::Normal => "Command".to_string(),
+                            },
+                            format!("{}/{}", api, options.get_model(&api)),
+                            state.request_status.display(),
+                            if state.unseen_output {
+                                "↓ new output below".to_string()
+                            } else {
+                                String::new()
+                            },
+                            if state.search_matches.is_empty() {
+                                String::new()
+                            } else {
+                                format!(
+                                    "/{} {}/{}",
+                                    state.search_query,
+                                    state.search_index + 1,
+                                    state.search_matches.len()
+                                )
+                            },
+                        ]
+                        .into_iter()
+                        .filter(|s| !s.is_empty())
+                        .collect::<Vec<String>>()
+                        .join("  "),
+                    )
+                    .style(Style::default().fg(Color::Black).bg(
+                        match state.input_mode {
+                            ChatInputMode::Insert => Color::LightYellow,
+                            ChatInputMode::Normal => Color::LightCyan,
+                        },
+                    )),
+                    status_bar,
+                );
+            } else {
+                frame.render_widget(
+                    Paragraph::new(state.status_error.clone())
+                        .style(Style::default().fg(Color::White).bg(Color::Red)),
+                    status_bar,
+                );
+            }
+
+            match &state.search_input {
+                Some(input) => frame.set_cursor_position(Position::new(
+                    status_bar.x + get_width(input) as u16 + 1,
+                    status_bar.y,
                 )),
-                status_bar,
-            );
+                None => frame.set_cursor_position(Position::new(
+                    focused_area.x + display_cursor.1 as u16 + 1,
+                    focused_area.y + display_cursor.0 as u16 + 1,
+                )),
+            }
 
-            frame.set_cursor_position(Position::new(
-                focused_area.x + display_cursor.1 as u16 + 1,
-                focused_area.y + display_cursor.0 as u16 + 1,
-            ));
+            if state.show_help {
+                render_help(frame, "Chat", CHAT_BINDINGS);
+            }
+
+            if let Some(picker) = &mut state.model_picker {
+                render_model_picker(frame, &state.model_choices, picker);
+            }
         })?;
 
+        // anything received has to be drawn before blocking on the next key
+        let mut received = true;
         match rx.try_recv() {
-            Ok(message) => {
+            Ok(network::StreamEvent::Started) => {
+                if let RequestStatus::Waiting { since, .. } = state.request_status {
+                    state.request_status = RequestStatus::Streaming { since };
+                }
+            }
+            Ok(network::StreamEvent::Delta(message)) => {
                 let last_message = state.chat_messages.last_mut().unwrap();
-                last_message.content.push_str(&message.clone());
+                last_message.content.push_str(&message);
 
-                state.pending_chat_update = message;
-                state.chat_cursor.0 = state.chat_wrapped.line_lengths.len();
-                state.last_message_instant = std::time::Instant::now();
+                state.render_messages(state.chat_messages.len() - 1);
+                if state.follow {
+                    state.chat_cursor.0 = state.chat_wrapped.line_lengths.len();
+                } else {
+                    state.unseen_output = true;
+                }
             }
-            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            Ok(network::StreamEvent::Done(usage)) => {
+                if let RequestStatus::Waiting { since, .. } | RequestStatus::Streaming { since } =
+                    state.request_status
+                {
+                    state.request_status = RequestStatus::Finished {
+                        elapsed: since.elapsed(),
+                        usage: usage.clone(),
+                    };
+                }
+
+                state.chat_messages.last_mut().unwrap().usage = usage;
+            }
+            Ok(network::StreamEvent::Summary(i, summary)) => {
+                if let Some(message) = state.chat_messages.get_mut(i) {
+                    message.summary = Some(summary);
+                    state.status_note = "Summarized the older messages".to_string();
+                }
+            }
+            Ok(network::StreamEvent::Error(e)) => {
+                state.request_status = RequestStatus::Idle;
+                if e.is_partial() {
+                    state.chat_messages.last_mut().unwrap().truncated = true;
+                    state.status_error = format!("Response interrupted: {}", e);
+                } else {
+                    // nothing came back, so the exchange is dropped and the message goes back to the input
+                    state.chat_messages.pop();
+                    let unsent = state.chat_messages.pop().unwrap();
+
+                    let draft_note = match crate::draft::save(&unsent.content) {
+                        Ok(path) => format!("draft saved to {}", path.display()),
+                        Err(e) => {
+                            error!("Failed to save draft: {}", e);
+                            "the draft couldn't be saved".to_string()
+                        }
+                    };
+
+                    state.render_messages(state.chat_messages.len());
+
+                    state.input_wrapped.content = unsent.content;
+                    state.pending_changes = true;
+                    state.status_error = format!("Request failed: {} ({})", e, draft_note);
+                }
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => received = false,
             Err(e) => panic!("{}", e),
         };
 
         if {
-            if (std::time::Instant::now() - state.last_message_instant).as_millis() < 5000 {
+            if received || matches!(state.request_status, RequestStatus::Streaming { .. }) {
                 event::poll(std::time::Duration::from_millis(5))?
+            } else if state.request_status.is_pending() {
+                // slow enough to idle, quick enough to keep the spinner going
+                event::poll(std::time::Duration::from_millis(100))?
             } else {
                 true
             }
@@ -432,8 +1346,168 @@ pub fn chat(
             match event::read() {
                 Ok(Event::Key(key)) => {
                     if key.kind == KeyEventKind::Press {
-                        if state.input_mode == ChatInputMode::Normal {
+                        state.status_error.clear();
+                        state.status_note.clear();
+
+                        if state.show_help {
+                            state.show_help = false;
+                            continue;
+                        }
+
+                        // cuts off an in-flight response, keeping what's arrived so far
+                        if key.code == KeyCode::Char('c')
+                            && key.modifiers.contains(KeyModifiers::CONTROL)
+                        {
+                            if state.streaming.load(std::sync::atomic::Ordering::Relaxed) {
+                                state
+                                    .cancel
+                                    .store(true, std::sync::atomic::Ordering::Relaxed);
+
+                                while let Ok(event) = rx.try_recv() {
+                                    match event {
+                                        network::StreamEvent::Started => {}
+                                        network::StreamEvent::Delta(delta) => state
+                                            .chat_messages
+                                            .last_mut()
+                                            .unwrap()
+                                            .content
+                                            .push_str(&delta),
+                                        network::StreamEvent::Summary(i, summary) => {
+                                            if let Some(message) = state.chat_messages.get_mut(i) {
+                                                message.summary = Some(summary);
+                                            }
+                                        }
+                                        network::StreamEvent::Done(_)
+                                        | network::StreamEvent::Error(_) => break,
+                                    }
+                                }
+
+                                state.chat_messages.last_mut().unwrap().truncated = true;
+                                state.exit_note = if conversation_path.is_empty() {
+                                    "Response cancelled".to_string()
+                                } else {
+                                    format!(
+                                        "Response cancelled, the partial response was saved to {}",
+                                        conversation_path
+                                    )
+                                };
+                            }
+
+                            state.next_window = WindowView::Exit;
+                            break;
+                        }
+
+                        if let Some(picker) = &mut state.model_picker {
                             match key.code {
+                                KeyCode::Char('j') | KeyCode::Down => picker.select_next(),
+                                KeyCode::Char('k') | KeyCode::Up => picker.select_previous(),
+                                KeyCode::Enter => {
+                                    if let Some(choice) =
+                                        picker.selected().and_then(|i| state.model_choices.get(i))
+                                    {
+                                        api = choice.provider.clone();
+                                        options.model = Some(choice.model.clone());
+                                        options.api_base = choice.api_base.clone();
+                                    }
+
+                                    state.model_picker = None;
+                                }
+                                KeyCode::Esc | KeyCode::Char('q') => {
+                                    state.model_picker = None;
+                                }
+                                _ => {}
+                            }
+
+                            continue;
+                        }
+
+                        if let Some(input) = &mut state.search_input {
+                            match key.code {
+                                KeyCode::Esc => {
+                                    state.search_input = None;
+                                    state.clear_search();
+                                }
+                                KeyCode::Enter => {
+                                    let query = state.search_input.take().unwrap_or_default();
+                                    state.search(query);
+                                }
+                                KeyCode::Backspace => {
+                                    input.pop();
+                                }
+                                KeyCode::Char(c) => {
+                                    input.push(c);
+                                }
+                                _ => {}
+                            }
+
+                            continue;
+                        }
+
+                        if state.input_mode == ChatInputMode::Normal {
+                            let pending_g = std::mem::take(&mut state.pending_g);
+
+                            // vim movement goes through the arrow key handling below
+                            let code = match key.code {
+                                KeyCode::Char('h') => KeyCode::Left,
+                                KeyCode::Char('j') => KeyCode::Down,
+                                KeyCode::Char('k') => KeyCode::Up,
+                                KeyCode::Char('l') => KeyCode::Right,
+                                code => code,
+                            };
+
+                            let half_page = state.chat_wrapped.window_size.1.saturating_sub(2) / 2;
+                            let last_page =
+                                state.chat_wrapped.line_lengths.len().saturating_sub(
+                                    state.chat_wrapped.window_size.1.saturating_sub(2),
+                                );
+
+                            match code {
+                                KeyCode::Char('u')
+                                    if key.modifiers.contains(KeyModifiers::CONTROL) =>
+                                {
+                                    state.chat_wrapped.page =
+                                        state.chat_wrapped.page.saturating_sub(half_page);
+                                    state.follow = false;
+                                }
+                                KeyCode::Char('d')
+                                    if key.modifiers.contains(KeyModifiers::CONTROL) =>
+                                {
+                                    state.chat_wrapped.page = std::cmp::min(
+                                        state.chat_wrapped.page + half_page,
+                                        last_page,
+                                    );
+                                    state.follow = state.chat_wrapped.page == last_page;
+                                }
+                                KeyCode::Char('g') if pending_g => {
+                                    state.chat_wrapped.page = 0;
+                                    state.chat_cursor = (0, 0);
+                                    state.follow = false;
+                                }
+                                KeyCode::Home if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                    state.chat_wrapped.page = 0;
+                                    state.chat_cursor = (0, 0);
+                                    state.follow = false;
+                                }
+                                KeyCode::Char('g') => {
+                                    state.pending_g = true;
+                                }
+                                // the render loop pages down to wherever the cursor lands
+                                KeyCode::Char('G') => {
+                                    state.chat_cursor.0 = state.chat_wrapped.line_lengths.len();
+                                    state.follow = true;
+                                }
+                                KeyCode::End if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                    state.chat_cursor.0 = state.chat_wrapped.line_lengths.len();
+                                    state.follow = true;
+                                }
+                                KeyCode::Char('0') | KeyCode::Home => {
+                                    state.chat_cursor.1 = 0;
+                                }
+                                KeyCode::Char('$') | KeyCode::End => {
+                                    state.chat_cursor.1 = state.chat_wrapped.get_line_width(
+                                        state.chat_cursor.0 + state.chat_wrapped.page,
+                                    );
+                                }
                                 KeyCode::Tab => {
                                     state.next_window = state.next_window.next();
                                     break;
@@ -445,46 +1519,217 @@ pub fn chat(
                                 KeyCode::Char('i') | KeyCode::Char('a') => {
                                     state.input_mode = ChatInputMode::Insert;
                                 }
-                                KeyCode::Char('l') => {
+                                KeyCode::Char('L') => {
                                     state.next_window = WindowView::Load;
                                     break;
                                 }
+                                KeyCode::Char('?') => {
+                                    state.show_help = true;
+                                }
+                                KeyCode::Char('/') => {
+                                    state.search_input = Some(String::new());
+                                }
+                                KeyCode::Char('m') => {
+                                    state.model_choices = get_model_choices(&api, &options);
+                                    state.model_picker =
+                                        Some(ListState::default().with_selected(Some(0)));
+                                }
+                                KeyCode::Char('n') => {
+                                    state.next_match(true);
+                                }
+                                KeyCode::Char('N') => {
+                                    state.next_match(false);
+                                }
+                                KeyCode::Esc => {
+                                    state.clear_search();
+                                }
+                                KeyCode::Char('y') => {
+                                    let line = state.chat_wrapped.page + state.chat_cursor.0;
+                                    match state.get_message_at(line) {
+                                        Some(i) => {
+                                            let content = state.chat_messages[i].content.clone();
+                                            state.copy(&content);
+                                        }
+                                        None => {
+                                            state.status_error =
+                                                "No message under the cursor".to_string()
+                                        }
+                                    }
+                                }
+                                KeyCode::Char('Y') => {
+                                    let content =
+                                        crate::conversation::to_text(&state.chat_messages);
+                                    state.copy(&content);
+                                }
                                 KeyCode::Enter => {
                                     if state.input_wrapped.len() > 0 {
-                                        state.chat_messages.push(network::Message {
-                                            message_type: network::MessageType::User,
-                                            content: state.input_wrapped.content.clone(),
-                                        });
+                                        // a bad block keeps the message in the input for fixing
+                                        let (front_matter, content) =
+                                            match crate::front_matter::parse(
+                                                &state.input_wrapped.content,
+                                            ) {
+                                                Ok(parsed) => parsed,
+                                                Err(e) => {
+                                                    state.status_error =
+                                                        format!("Message not sent: {}", e);
+                                                    continue;
+                                                }
+                                            };
 
-                                        state.pending_chat_update =
-                                            (if state.chat_messages.len() > 1 {
-                                                "\n───\n".to_string()
-                                            } else {
-                                                "".to_string()
-                                            }) + &state.input_wrapped.content.clone()
-                                                + "\n───\n";
-
-                                        state.chat_messages.push(network::Message {
-                                            message_type: network::MessageType::Assistant,
-                                            content: String::new(),
-                                        });
+                                        let content = match crate::template::expand(
+                                            &content,
+                                            session.max_file_size,
+                                        ) {
+                                            Ok(content) => content,
+                                            Err(e) => {
+                                                state.status_error =
+                                                    format!("Message not sent: {}", e);
+                                                continue;
+                                            }
+                                        };
+
+                                        let mut warnings = front_matter.warnings.clone();
+                                        if front_matter.title.is_some() {
+                                            warnings.push("Ignoring the front matter title, it only applies to new conversations started with -i".to_string());
+                                        }
+
+                                        state.status_error = warnings.join("; ");
+
+                                        let mut api = api.to_string();
+                                        let mut options = options.clone();
+                                        front_matter.apply(&mut api, &mut options);
+
+                                        if let Some(path) = &draft {
+                                            if let Err(e) = std::fs::remove_file(path) {
+                                                error!("Failed to remove draft {:?}: {}", path, e);
+                                            }
+                                        }
 
-                                        state.last_message_instant = std::time::Instant::now();
+                                        let content = if attachments.is_empty() {
+                                            content
+                                        } else {
+                                            crate::attachment::prepend(
+                                                &std::mem::take(attachments),
+                                                &content,
+                                            )
+                                        };
+
+                                        let mut message = network::Message::new(
+                                            network::MessageType::User,
+                                            content.clone(),
+                                        );
+                                        message.created_at = Some(chrono::Local::now());
 
-                                        let messages = state.chat_messages.clone();
-                                        let prompt = system_prompt.to_string();
-                                        let api = api.to_string();
+                                        let mut response = network::Message::new(
+                                            network::MessageType::Assistant,
+                                            String::new(),
+                                        );
+                                        response.provider = Some(api.clone());
+                                        response.model = Some(options.get_model(&api));
+                                        response.created_at = message.created_at;
+
+                                        state.chat_messages.push(message);
+                                        state.chat_messages.push(response);
+                                        state.render_messages(state.chat_messages.len() - 2);
+                                        state.follow = true;
+
+                                        let mut messages = state.chat_messages.clone();
+                                        let prompt = system_prompt.content.clone();
+
+                                        // the request works this out again, this is only for the note
+                                        // with summaries on, nothing's left out that isn't summarized
+                                        let summarize = crate::summary::is_enabled();
+                                        let omitted = crate::tokens::get_omitted(
+                                            &prompt,
+                                            &messages,
+                                            &options,
+                                            &options.get_model(&api),
+                                        );
+                                        if omitted > 0 && !summarize {
+                                            warnings.push(format!(
+                                                "{} older messages left out of the request",
+                                                omitted
+                                            ));
+                                            state.status_error = warnings.join("; ");
+                                        }
                                         let tx = tx.clone();
+
+                                        state.cancel = std::sync::Arc::new(
+                                            std::sync::atomic::AtomicBool::new(false),
+                                        );
+                                        state
+                                            .streaming
+                                            .store(true, std::sync::atomic::Ordering::Relaxed);
+                                        let cancel = state.cancel.clone();
+                                        let streaming = state.streaming.clone();
+                                        state.request_status = RequestStatus::Waiting {
+                                            provider: api.clone(),
+                                            since: std::time::Instant::now(),
+                                        };
                                         std::thread::spawn(move || {
-                                            match network::prompt_stream(prompt, &messages, api, tx)
-                                            {
-                                                Ok(_) => {}
+                                            // the request is still sent if this fails, just trimmed
+                                            if summarize {
+                                                match crate::summary::update(
+                                                    &prompt,
+                                                    &mut messages,
+                                                    &api,
+                                                    &options,
+                                                ) {
+                                                    Ok(Some(i)) => {
+                                                        let summary = messages[i]
+                                                            .summary
+                                                            .clone()
+                                                            .unwrap_or_default();
+                                                        if let Err(e) =
+                                                            tx.send(network::StreamEvent::Summary(
+                                                                i, summary,
+                                                            ))
+                                                        {
+                                                            error!(
+                                                                "error reporting summary: {}",
+                                                                e
+                                                            );
+                                                        }
+                                                    }
+                                                    Ok(None) => {}
+                                                    Err(e) => {
+                                                        error!(
+                                                            "Failed to summarize the conversation: {}",
+                                                            e
+                                                        );
+                                                    }
+                                                }
+                                            }
+
+                                            let response = providers::get_provider(&api).stream(
+                                                &prompt,
+                                                &messages,
+                                                &options,
+                                                tx.clone(),
+                                                cancel,
+                                            );
+                                            streaming
+                                                .store(false, std::sync::atomic::Ordering::Relaxed);
+
+                                            match response {
+                                                Ok(response) => {
+                                                    if let Err(e) = tx.send(
+                                                        network::StreamEvent::Done(response.usage),
+                                                    ) {
+                                                        error!("error reporting completion: {}", e);
+                                                    }
+                                                }
                                                 Err(e) => {
                                                     error!(
                                                         "error sending message to GPT endpoint: {}",
                                                         e
                                                     );
-                                                    std::process::exit(1);
+
+                                                    if let Err(e) =
+                                                        tx.send(network::StreamEvent::Error(e))
+                                                    {
+                                                        error!("error reporting failure: {}", e);
+                                                    }
                                                 }
                                             }
                                         });
@@ -493,13 +1738,22 @@ pub fn chat(
                                     state.input_wrapped.clear();
                                 }
                                 KeyCode::Left => {
-                                    // underflow
-                                    if state.chat_cursor.1 > 0 {
-                                        state.chat_cursor.1 -= 1;
-                                    }
+                                    let offset = state.chat_wrapped.get_flat(
+                                        state.chat_cursor.0 + state.chat_wrapped.page,
+                                        state.chat_cursor.1,
+                                    );
+                                    state.chat_cursor = state
+                                        .chat_wrapped
+                                        .get_cursor(state.chat_wrapped.prev_grapheme(offset));
                                 }
                                 KeyCode::Right => {
-                                    state.chat_cursor.1 += 1;
+                                    let offset = state.chat_wrapped.get_flat(
+                                        state.chat_cursor.0 + state.chat_wrapped.page,
+                                        state.chat_cursor.1,
+                                    );
+                                    state.chat_cursor = state
+                                        .chat_wrapped
+                                        .get_cursor(state.chat_wrapped.next_grapheme(offset));
                                 }
                                 KeyCode::Up => {
                                     if key.modifiers.contains(KeyModifiers::SHIFT) {
@@ -512,13 +1766,19 @@ pub fn chat(
                                             state.pending_page_up = true;
                                         }
                                     }
+
+                                    state.follow = false;
                                 }
                                 KeyCode::Down => {
                                     if key.modifiers.contains(KeyModifiers::SHIFT) {
                                         state.chat_wrapped.page_down();
                                         state.chat_cursor.0 = state.chat_wrapped.window_size.1;
+                                        state.follow = state.chat_wrapped.page >= last_page;
                                     } else {
                                         state.chat_cursor.0 += 1;
+                                        state.follow =
+                                            state.chat_wrapped.page + state.chat_cursor.0 + 1
+                                                >= state.chat_wrapped.line_lengths.len();
                                     }
                                 }
                                 _ => {}
@@ -532,125 +1792,123 @@ pub fn chat(
                                     if key.modifiers.contains(KeyModifiers::CONTROL) {
                                         match c {
                                             'w' => {
-                                                let deleted = state.input_wrapped.delete_word(
-                                                    state.input_cursor.0,
-                                                    state.input_cursor.1,
+                                                let offset = state.get_input_offset();
+                                                let deleted =
+                                                    state.input_wrapped.delete_word(offset);
+                                                state.set_input_offset(offset - deleted.len());
+                                                state.kill_register = deleted;
+                                            }
+                                            'u' => {
+                                                let offset = state.get_input_offset();
+                                                let deleted = state
+                                                    .input_wrapped
+                                                    .delete_to_line_start(offset);
+                                                state.set_input_offset(offset - deleted.len());
+                                                state.kill_register = deleted;
+                                            }
+                                            'k' => {
+                                                let offset = state.get_input_offset();
+                                                state.kill_register =
+                                                    state.input_wrapped.delete_to_line_end(offset);
+                                                state.set_input_offset(offset);
+                                            }
+                                            'a' => {
+                                                state.input_cursor.1 = 0;
+                                            }
+                                            'e' => {
+                                                state.input_cursor.1 =
+                                                    state.input_wrapped.get_line_width(
+                                                        state.input_cursor.0
+                                                            + state.input_wrapped.page,
+                                                    );
+                                            }
+                                            'y' => {
+                                                let offset = state.input_wrapped.insert(
+                                                    &state.kill_register,
+                                                    state.get_input_offset(),
                                                 );
-
-                                                if deleted.contains('\n') {
-                                                    state.input_cursor.1 =
-                                                        state.input_wrapped.window_size.0;
-                                                }
+                                                state.set_input_offset(offset);
                                             }
                                             'v' => {
-                                                let mut ctx = ClipboardContext::new().unwrap();
-                                                let clip_contents = ctx.get_contents().unwrap();
-
-                                                let lines = clip_contents
-                                                    .chars()
-                                                    .filter(|c| *c == '\n')
-                                                    .count();
-
-                                                state.input_wrapped.insert(
+                                                let clip_contents = match crate::clipboard::get() {
+                                                    Ok(contents) => contents,
+                                                    Err(e) => {
+                                                        state.status_error = e;
+                                                        continue;
+                                                    }
+                                                };
+
+                                                let offset = state.input_wrapped.insert(
                                                     &clip_contents,
-                                                    state.input_cursor.0 + state.input_wrapped.page,
-                                                    state.input_cursor.1,
+                                                    state.get_input_offset(),
                                                 );
-
-                                                state.input_cursor.0 += lines;
-                                                state.input_cursor.1 =
-                                                    state.input_wrapped.window_size.0;
+                                                state.set_input_offset(offset);
                                             }
                                             _ => {}
                                         }
                                     } else {
-                                        state.input_wrapped.insert(
-                                            &c.to_string(),
-                                            state.input_cursor.0 + state.input_wrapped.page,
-                                            state.input_cursor.1,
-                                        );
-
-                                        // TODO: structs with row/col/width/height values instead
-                                        //       of these stupid fucking tuples
-                                        if state.input_cursor.1
-                                            >= state.input_wrapped.window_size.0 - 3
-                                        {
-                                            state.input_wrapped.insert(
-                                                &"\n".to_string(),
-                                                state.input_cursor.0 + state.input_wrapped.page,
-                                                state.input_cursor.1,
-                                            );
-
-                                            state.input_cursor.0 += 1;
-                                            state.input_cursor.1 = 1;
-                                        } else {
-                                            state.input_cursor.1 += 1;
-                                        }
+                                        let offset = state
+                                            .input_wrapped
+                                            .insert(&c.to_string(), state.get_input_offset());
+                                        state.set_input_offset(offset);
                                     }
-
-                                    state.pending_changes = true;
                                 }
                                 KeyCode::Enter => {
-                                    state.input_wrapped.insert(
-                                        &'\n'.to_string(),
+                                    let offset = state
+                                        .input_wrapped
+                                        .insert(&'\n'.to_string(), state.get_input_offset());
+                                    state.set_input_offset(offset);
+                                }
+                                KeyCode::Backspace => {
+                                    let offset =
+                                        state.input_wrapped.delete_char(state.get_input_offset());
+                                    state.set_input_offset(offset);
+                                }
+                                KeyCode::Home if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                    state.input_wrapped.page = 0;
+                                    state.input_cursor = (0, 0);
+                                }
+                                KeyCode::End if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                    state.set_input_offset(state.input_wrapped.len());
+                                }
+                                KeyCode::Home => {
+                                    state.input_cursor.1 = 0;
+                                }
+                                KeyCode::End => {
+                                    state.input_cursor.1 = state.input_wrapped.get_line_width(
                                         state.input_cursor.0 + state.input_wrapped.page,
-                                        state.input_cursor.1,
                                     );
-
-                                    state.input_cursor.0 += 1;
-                                    state.input_cursor.1 = 0;
-                                    state.pending_changes = true;
                                 }
-                                KeyCode::Backspace => {
-                                    if state.input_wrapped.len() > 0 {
-                                        state.pending_deletions += 1;
-                                    }
+                                KeyCode::Delete => {
+                                    let offset = state.get_input_offset();
+                                    state.input_wrapped.delete_next_char(offset);
+                                    state.set_input_offset(offset);
                                 }
                                 KeyCode::Left => {
                                     if key.modifiers.contains(KeyModifiers::CONTROL) {
-                                        state.input_cursor.1 -= std::cmp::min(
-                                            state.input_cursor.1,
-                                            state.input_wrapped.prev_whitespace_distance(
-                                                state.input_cursor.0,
-                                                state.input_cursor.1,
-                                            ),
-                                        );
-
-                                        if state.input_cursor.0 > 0 && state.input_cursor.1 == 0 {
-                                            state.input_cursor.0 -= 1;
-
-                                            // this gets caught by the cursor clamping
-                                            state.input_cursor.1 = usize::MAX;
-                                        }
-                                    }
-
-                                    // underflow
-                                    if state.input_cursor.1 > 0 {
-                                        state.input_cursor.1 -= 1;
+                                        let offset = state
+                                            .input_wrapped
+                                            .prev_word_start(state.get_input_offset());
+                                        state.set_input_offset(offset);
+                                    } else {
+                                        let offset = state
+                                            .input_wrapped
+                                            .prev_grapheme(state.get_input_offset());
+                                        state.set_input_offset(offset);
                                     }
                                 }
                                 KeyCode::Right => {
                                     if key.modifiers.contains(KeyModifiers::CONTROL) {
-                                        state.input_cursor.1 +=
-                                            state.input_wrapped.next_whitespace_distance(
-                                                state.input_cursor.0,
-                                                state.input_cursor.1,
-                                            );
-
-                                        if state.input_cursor.0 + state.input_wrapped.page
-                                            < state.input_wrapped.line_lengths.len()
-                                            && state.input_cursor.1
-                                                >= state.input_wrapped.line_lengths[state
-                                                    .input_cursor
-                                                    .0
-                                                    + state.input_wrapped.page]
-                                        {
-                                            state.input_cursor.0 += 1;
-                                            state.input_cursor.1 = 1;
-                                        }
+                                        let offset = state
+                                            .input_wrapped
+                                            .next_word_end(state.get_input_offset());
+                                        state.set_input_offset(offset);
+                                    } else {
+                                        let offset = state
+                                            .input_wrapped
+                                            .next_grapheme(state.get_input_offset());
+                                        state.set_input_offset(offset);
                                     }
-
-                                    state.input_cursor.1 += 1;
                                 }
                                 KeyCode::Up => {
                                     // underflow
@@ -668,16 +1926,54 @@ pub fn chat(
                         }
                     }
                 }
+                Ok(Event::Mouse(mouse)) => match mouse.kind {
+                    MouseEventKind::ScrollUp => {
+                        state.chat_wrapped.page =
+                            state.chat_wrapped.page.saturating_sub(SCROLL_LINES);
+                        state.follow = false;
+                    }
+                    MouseEventKind::ScrollDown => {
+                        let last_page = state
+                            .chat_wrapped
+                            .line_lengths
+                            .len()
+                            .saturating_sub(state.chat_wrapped.window_size.1.saturating_sub(2));
+                        state.chat_wrapped.page =
+                            std::cmp::min(state.chat_wrapped.page + SCROLL_LINES, last_page);
+                        state.follow = state.chat_wrapped.page == last_page;
+                    }
+                    _ => {}
+                },
+                Ok(Event::Resize(..)) => {
+                    state.pending_resize = true;
+                }
                 Err(e) => {
-                    panic!("error reading event: {}", e);
+                    return Err(e.into());
                 }
                 _ => {}
             }
         }
     }
 
-    if conversation_path.len() > 0 {
-        let messages_json = serde_json::to_string(&state.chat_messages).unwrap();
+    if matches!(state.next_window, WindowView::Exit) && state.input_wrapped.len() > 0 {
+        let note = match crate::draft::save(&state.input_wrapped.content) {
+            Ok(path) => format!("Your unsent message was saved to {}", path.display()),
+            Err(e) => format!("Your unsent message couldn't be saved: {}", e),
+        };
+
+        state.exit_note = match state.exit_note.is_empty() {
+            true => note,
+            false => format!("{}\n{}", state.exit_note, note),
+        };
+    }
+
+    // rewriting an untouched conversation would bump its last activity
+    if conversation_path.len() > 0 && state.chat_messages.len() != conversation.len() {
+        let messages_json = serde_json::to_string(&crate::conversation::join_system_prompt(
+            &system_prompt,
+            &state.chat_messages,
+        ))
+        .unwrap();
         match std::fs::write(conversation_path, messages_json) {
             Ok(_) => {
                 info!("Conversation saved to {}", conversation_path);
@@ -688,13 +1984,18 @@ pub fn chat(
         }
     }
 
-    Ok(state.next_window)
+    input.content = state.input_wrapped.content.clone();
+    input.offset = state.get_input_offset();
+
+    Ok((state.next_window, state.exit_note))
 }
 
 #[derive(PartialEq)]
 enum DirectoryInputMode {
     Search,
     Files,
+    Rename,
+    Filters,
 }
 
 struct DirectoryState {
@@ -706,10 +2007,94 @@ struct DirectoryState {
     search_results: Vec<network::DeweyResponseItem>,
     results_state: ListState,
     next_window: WindowView,
+    show_help: bool,
+}
+
+// Dewey returns byte ranges, which might not land on character boundaries if the file changed
+fn get_subset(item: &network::DeweyResponseItem) -> Result<String, String> {
+    let contents = std::fs::read_to_string(&item.filepath).map_err(|e| {
+        error!("error reading file {}: {}", item.filepath, e);
+        format!("error reading file {}: {}", item.filepath, e)
+    })?;
+
+    match contents.get(item.subset.0 as usize..item.subset.1 as usize) {
+        Some(subset) => Ok(subset.to_string()),
+        None => Err(format!(
+            "{} no longer has the range {}..{}",
+            item.filepath, item.subset.0, item.subset.1
+        )),
+    }
+}
+
+// comma-separated `key:value` pairs, passed to Dewey as they're written
+// an empty list clears the filters
+fn parse_filters(text: &str) -> Result<Vec<String>, String> {
+    let mut filters = Vec::new();
+    for filter in text.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+        match filter.split_once(':') {
+            Some((key, value))
+                if !key.trim().is_empty()
+                    && !value.trim().is_empty()
+                    && key.trim().chars().all(|c| c.is_alphanumeric() || c == '_') =>
+            {
+                filters.push(format!("{}:{}", key.trim(), value.trim()));
+            }
+            _ => {
+                return Err(format!(
+                    "Invalid filter {:?}, expected key:value like extension:rs",
+                    filter
+                ))
+            }
+        }
+    }
+
+    Ok(filters)
+}
+
+// runs $EDITOR on `path` and waits for it, the terminal has to be free already
+pub fn run_editor(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status();
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} exited with {}", editor, status).into()),
+        Err(e) => Err(format!("Couldn't run {}: {}", editor, e).into()),
+    }
+}
+
+// hands the terminal to $EDITOR until it exits
+fn open_in_editor(
+    terminal: &mut ratatui::DefaultTerminal,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    ratatui::crossterm::execute!(std::io::stdout(), DisableMouseCapture)?;
+    ratatui::restore();
+
+    let result = run_editor(path);
+
+    ratatui::crossterm::terminal::enable_raw_mode()?;
+    ratatui::crossterm::execute!(
+        std::io::stdout(),
+        ratatui::crossterm::terminal::EnterAlternateScreen,
+        EnableMouseCapture
+    )?;
+    terminal.clear()?;
+
+    result
 }
 
 pub fn directory(
     terminal: &mut ratatui::DefaultTerminal,
+    dewey: &network::DeweyAddress,
+    input: &mut ChatInput,
+    filters: &mut Vec<String>,
 ) -> Result<WindowView, Box<dyn std::error::Error>> {
     let mut state = DirectoryState {
         input_mode: DirectoryInputMode::Search,
@@ -719,9 +2104,15 @@ pub fn directory(
         search_results: Vec::new(),
         results_state: ListState::default(),
         next_window: WindowView::Directory,
+        show_help: false,
     };
 
-    let (tx, rx) = std::sync::mpsc::channel::<Vec<u8>>();
+    let (tx, rx) = std::sync::mpsc::channel::<Result<Vec<u8>, String>>();
+    // shown in place of the search title until the next search
+    let mut search_error: Option<String> = None;
+    // the search bar doubles as the input for the filters
+    let mut filters_content = filters.join(",");
+    let mut filters_error: Option<String> = None;
 
     loop {
         terminal.draw(|frame| {
@@ -748,31 +2139,44 @@ pub fn directory(
             .highlight_symbol(">")
             .repeat_highlight_symbol(true);
 
-            frame.render_widget(
-                Paragraph::new(state.search_content.clone())
-                    .block(Block::bordered().title("Search")),
-                main_layout[0],
-            );
+            if state.input_mode == DirectoryInputMode::Filters {
+                frame.render_widget(
+                    Paragraph::new(filters_content.clone()).block(Block::bordered().title(
+                        match &filters_error {
+                            Some(e) => Line::from(Span::styled(e.clone(), Style::new().red())),
+                            None => {
+                                Line::from("Filters (comma-separated key:value, empty to clear)")
+                            }
+                        },
+                    )),
+                    main_layout[0],
+                );
+            } else {
+                frame.render_widget(
+                    Paragraph::new(state.search_content.clone()).block(Block::bordered().title(
+                        match &search_error {
+                            Some(e) => Line::from(Span::styled(e.clone(), Style::new().red())),
+                            None if filters.is_empty() => Line::from("Search"),
+                            None => Line::from(format!("Search [{}]", filters.join(", "))),
+                        },
+                    )),
+                    main_layout[0],
+                );
+            }
 
             frame.render_stateful_widget(list, results_layout[0], &mut state.results_state);
 
             frame.render_widget(
-                Paragraph::new(match state.results_state.selected() {
-                    Some(i) => {
-                        let selected = state.search_results[i].clone();
-                        let contents = match std::fs::read_to_string(selected.filepath.clone()) {
-                            Ok(c) => c,
-                            Err(e) => {
-                                error!("error reading file {}: {}", selected.filepath.clone(), e);
-
-                                format!("error reading file {}: {}", selected.filepath, e)
-                            }
-                        };
-
-                        contents[selected.subset.0 as usize..selected.subset.1 as usize].to_string()
-                    }
-                    None => String::new(),
-                })
+                Paragraph::new(
+                    match state
+                        .results_state
+                        .selected()
+                        .and_then(|i| state.search_results.get(i))
+                    {
+                        Some(selected) => get_subset(selected).unwrap_or_else(|e| e),
+                        None => String::new(),
+                    },
+                )
                 .wrap(Wrap { trim: false })
                 .block(Block::bordered().title("Contents")),
                 results_layout[1],
@@ -780,30 +2184,43 @@ pub fn directory(
 
             let (display_cursor, focused_area) = match state.input_mode {
                 DirectoryInputMode::Search => ((0, state.search_cursor), main_layout[0]),
-                DirectoryInputMode::Files => ((0, 0), results_layout[0]),
+                DirectoryInputMode::Filters => ((0, get_width(&filters_content)), main_layout[0]),
+                DirectoryInputMode::Files | DirectoryInputMode::Rename => {
+                    ((0, 0), results_layout[0])
+                }
             };
 
-            if state.input_mode == DirectoryInputMode::Search {
+            if state.input_mode == DirectoryInputMode::Search
+                || state.input_mode == DirectoryInputMode::Filters
+            {
                 frame.set_cursor_position(Position::new(
                     focused_area.x + display_cursor.1 as u16 + 1,
                     focused_area.y + display_cursor.0 as u16 + 1,
                 ));
             }
+
+            if state.show_help {
+                render_help(frame, "Directory", DIRECTORY_BINDINGS);
+            }
         })?;
 
         match rx.try_recv() {
-            Ok(buffer) => {
+            Ok(Ok(buffer)) => {
                 let buffer = String::from_utf8_lossy(&buffer);
-                let response: network::DeweyResponse = match serde_json::from_str(&buffer) {
-                    Ok(resp) => resp,
+                match serde_json::from_str::<network::DeweyResponse>(&buffer) {
+                    Ok(response) => {
+                        state.search_results = response.results;
+                        state.results_state.select(None);
+                    }
                     Err(e) => {
                         error!("Failed to parse response: {}", e);
-                        error!("buffer: {:?}", buffer);
-                        return Err(e.into());
+                        debug!("buffer: {:?}", buffer);
+                        search_error = Some(format!("Unexpected response from Dewey: {}", e));
                     }
-                };
-
-                state.search_results = response.results.iter().map(|f| f.clone()).collect();
+                }
+            }
+            Ok(Err(e)) => {
+                search_error = Some(e);
             }
             Err(std::sync::mpsc::TryRecvError::Empty) => {}
             Err(e) => panic!("{}", e),
@@ -813,6 +2230,11 @@ pub fn directory(
             match event::read() {
                 Ok(Event::Key(key)) => {
                     if key.kind == KeyEventKind::Press {
+                        if state.show_help {
+                            state.show_help = false;
+                            continue;
+                        }
+
                         if state.input_mode == DirectoryInputMode::Files {
                             match key.code {
                                 KeyCode::Tab => {
@@ -826,12 +2248,52 @@ pub fn directory(
                                 KeyCode::Char('s') => {
                                     state.input_mode = DirectoryInputMode::Search;
                                 }
+                                KeyCode::Char('?') => {
+                                    state.show_help = true;
+                                }
                                 KeyCode::Up => {
                                     state.results_state.select_previous();
                                 }
                                 KeyCode::Down => {
                                     state.results_state.select_next();
                                 }
+                                KeyCode::Enter => {
+                                    if let Some(selected) = state
+                                        .results_state
+                                        .selected()
+                                        .and_then(|i| state.search_results.get(i))
+                                    {
+                                        match get_subset(selected) {
+                                            Ok(subset) => {
+                                                input.pending = Some(crate::attachment::fence(
+                                                    &selected.filepath,
+                                                    &subset,
+                                                ));
+                                                state.next_window = WindowView::Chat;
+                                                break;
+                                            }
+                                            Err(e) => search_error = Some(e),
+                                        }
+                                    }
+                                }
+                                KeyCode::Char('f') => {
+                                    filters_content = filters.join(",");
+                                    filters_error = None;
+                                    state.input_mode = DirectoryInputMode::Filters;
+                                }
+                                KeyCode::Char('o') => {
+                                    if let Some(selected) = state
+                                        .results_state
+                                        .selected()
+                                        .and_then(|i| state.search_results.get(i))
+                                    {
+                                        if let Err(e) = open_in_editor(terminal, &selected.filepath)
+                                        {
+                                            error!("{}", e);
+                                            search_error = Some(e.to_string());
+                                        }
+                                    }
+                                }
                                 _ => {}
                             }
                         } else if state.input_mode == DirectoryInputMode::Search {
@@ -852,26 +2314,27 @@ pub fn directory(
                                     let request = serde_json::to_string(&network::DeweyRequest {
                                         k: 10,
                                         query: state.search_content.clone(),
-                                        filters: Vec::new(),
+                                        filters: filters.clone(),
                                     })?
                                     .into_bytes();
 
-                                    let mut payload = Vec::new();
-                                    payload
-                                        .extend_from_slice(&(request.len() as u32).to_be_bytes());
-                                    payload.extend_from_slice(&request);
+                                    search_error = None;
 
                                     let tx = tx.clone();
+                                    let dewey = dewey.clone();
                                     std::thread::spawn(move || {
-                                        match network::tcp_request("5051", payload, tx) {
-                                            Ok(_) => {}
-                                            Err(e) => {
-                                                error!(
-                                                    "error sending message to GPT endpoint: {}",
-                                                    e
-                                                );
-                                                std::process::exit(1);
-                                            }
+                                        let response = network::tcp_request(
+                                            &dewey,
+                                            &request,
+                                            network::DEWEY_TIMEOUT,
+                                        )
+                                        .map_err(|e| {
+                                            error!("error reaching Dewey at {}: {}", dewey, e);
+                                            format!("Dewey unreachable at {}: {}", dewey, e)
+                                        });
+
+                                        if let Err(e) = tx.send(response) {
+                                            error!("error reporting Dewey response: {}", e);
                                         }
                                     });
                                 }
@@ -898,11 +2361,39 @@ pub fn directory(
                                 }
                                 _ => {}
                             }
+                        } else if state.input_mode == DirectoryInputMode::Filters {
+                            match key.code {
+                                KeyCode::Esc => {
+                                    state.input_mode = DirectoryInputMode::Files;
+                                }
+                                KeyCode::Char(c) => {
+                                    filters_content.push(c);
+                                    filters_error = None;
+                                }
+                                KeyCode::Backspace => {
+                                    filters_content.pop();
+                                    filters_error = None;
+                                }
+                                // a bad filter keeps the input open for fixing
+                                KeyCode::Enter => match parse_filters(&filters_content) {
+                                    Ok(parsed) => {
+                                        *filters = parsed;
+                                        state.input_mode = DirectoryInputMode::Files;
+                                    }
+                                    Err(e) => filters_error = Some(e),
+                                },
+                                _ => {}
+                            }
                         }
                     }
                 }
+                Ok(Event::Mouse(mouse)) => match mouse.kind {
+                    MouseEventKind::ScrollUp => state.results_state.select_previous(),
+                    MouseEventKind::ScrollDown => state.results_state.select_next(),
+                    _ => {}
+                },
                 Err(e) => {
-                    panic!("error reading event: {}", e);
+                    return Err(e.into());
                 }
                 _ => {}
             }
@@ -912,24 +2403,126 @@ pub fn directory(
     Ok(state.next_window)
 }
 
+// how long typing has to pause before conversations are searched
+const FILTER_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(250);
+
+// searching every conversation means reading every file, so it runs on its own thread
+// each query gets a generation, anything older than the newest one is abandoned
+struct ConversationFilter {
+    generation: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    // the generation of the results on screen
+    received: u64,
+    tx: std::sync::mpsc::Sender<(u64, String, Vec<String>)>,
+    rx: std::sync::mpsc::Receiver<(u64, Vec<String>)>,
+}
+
+impl ConversationFilter {
+    fn spawn() -> Self {
+        let generation = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let (query_tx, query_rx) = std::sync::mpsc::channel::<(u64, String, Vec<String>)>();
+        let (result_tx, result_rx) = std::sync::mpsc::channel();
+
+        let current = generation.clone();
+        std::thread::spawn(move || {
+            while let Ok(mut next) = query_rx.recv() {
+                // every keystroke restarts the wait
+                loop {
+                    match query_rx.recv_timeout(FILTER_DEBOUNCE) {
+                        Ok(newer) => next = newer,
+                        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => break,
+                        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+
+                let (query_generation, query, filepaths) = next;
+                let mut results = Vec::new();
+                for filepath in filepaths {
+                    if current.load(std::sync::atomic::Ordering::Relaxed) != query_generation {
+                        break;
+                    }
+
+                    if crate::conversation::matches(
+                        &crate::conversation::get_title(&filepath),
+                        &query,
+                    ) {
+                        results.push(filepath);
+                    }
+                }
+
+                if current.load(std::sync::atomic::Ordering::Relaxed) == query_generation
+                    && result_tx.send((query_generation, results)).is_err()
+                {
+                    return;
+                }
+            }
+        });
+
+        ConversationFilter {
+            generation,
+            received: 0,
+            tx: query_tx,
+            rx: result_rx,
+        }
+    }
+
+    // an empty query has nothing to wait for, it just drops whatever is in flight
+    fn search(&mut self, query: &str, conversations: &[network::DeweyResponseItem]) {
+        let query_generation = self
+            .generation
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
+
+        if query.is_empty() {
+            self.received = query_generation;
+            return;
+        }
+
+        let filepaths = conversations
+            .iter()
+            .map(|c| c.filepath.clone())
+            .collect::<Vec<String>>();
+        if let Err(e) = self
+            .tx
+            .send((query_generation, query.to_string(), filepaths))
+        {
+            error!("error starting conversation search: {}", e);
+        }
+    }
+
+    // the results for the newest query, once they're in
+    fn latest(&mut self) -> Option<Vec<String>> {
+        let current = self.generation.load(std::sync::atomic::Ordering::Relaxed);
+        let mut latest = None;
+        while let Ok((query_generation, results)) = self.rx.try_recv() {
+            if query_generation == current {
+                self.received = query_generation;
+                latest = Some(results);
+            }
+        }
+
+        latest
+    }
+
+    fn is_pending(&self) -> bool {
+        self.received != self.generation.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+// the window to go to, the conversation picked, and what was deleted to print once the terminal is restored
 pub fn conversation_search(
     terminal: &mut ratatui::DefaultTerminal,
-) -> Result<(WindowView, String), Box<dyn std::error::Error>> {
+    show_archived: bool,
+) -> Result<(WindowView, String, String), Box<dyn std::error::Error>> {
     let conversation_path = crate::config::get_conversations_dir();
 
     let mut conversations = Vec::new();
-    for file in std::fs::read_dir(conversation_path.clone())? {
-        let file = file?;
-        if file.path().is_file() {
-            conversations.push(network::DeweyResponseItem {
-                filepath: file.file_name().to_string_lossy().into_owned(),
-                subset: (0, 0),
-            });
-        }
+    for title in crate::conversation::list_titles(show_archived)? {
+        conversations.push(network::DeweyResponseItem {
+            filepath: format!("{}.json", title),
+            subset: (0, 0),
+        });
     }
 
-    conversations.sort_unstable_by(|a, b| b.filepath.cmp(&a.filepath));
-
     let mut state = DirectoryState {
         input_mode: DirectoryInputMode::Search,
         search_max_width: 0,
@@ -938,14 +2531,34 @@ pub fn conversation_search(
         search_results: conversations.clone(),
         results_state: ListState::default(),
         next_window: WindowView::Directory,
+        show_help: false,
     };
 
-    let mut last_call = std::time::Instant::now();
-    let mut pending_search = false;
-    let mut filtered_results = Vec::new();
+    let mut filter = ConversationFilter::spawn();
+    // none while the search is empty
+    let mut filtered_results: Option<Vec<String>> = None;
+    // a search in flight was given the conversations from before a delete, rename, or archive
+    let mut conversations_changed = false;
+    let mut visible_results = Vec::new();
 
     let mut chosen_conversation = String::new();
 
+    // deletion takes two presses of `d` on the same conversation
+    let mut pending_delete: Option<String> = None;
+    let mut deleted = Vec::new();
+    // a delete or archive that failed, shown until the next key press
+    let mut action_error: Option<String> = None;
+
+    // the search bar doubles as the input for renaming the selected conversation
+    let mut rename_target = String::new();
+    let mut rename_content = String::new();
+    let mut rename_error: Option<String> = None;
+
+    // conversations are read once, the first time they're selected
+    let mut loaded: std::collections::HashMap<String, Result<Vec<network::Message>, String>> =
+        std::collections::HashMap::new();
+    let history_order = crate::conversation::get_history_order();
+
     loop {
         terminal.draw(|frame| {
             let main_layout = Layout::default()
@@ -960,160 +2573,159 @@ pub fn conversation_search(
 
             state.search_max_width = main_layout[0].width as usize;
 
-            if state.search_content.len() == 0 {
-                filtered_results = Vec::new();
+            if let Some(results) = filter.latest() {
+                filtered_results = Some(results);
             }
 
-            // this _needs_ to be multithreaded
-            let results = if pending_search
-                && std::time::Instant::now() - last_call > std::time::Duration::from_millis(500)
-            {
-                pending_search = false;
-                last_call = std::time::Instant::now();
-
-                filtered_results = state
-                    .search_results
-                    .iter()
-                    .filter_map(|response| {
-                        let path = conversation_path.join(response.filepath.clone());
-                        let file = match std::fs::File::open(path.clone()) {
-                            Ok(f) => Some(f),
-                            Err(e) => {
-                                error!("error opening file {}: {}", path.to_str().unwrap(), e);
-                                None
-                            }
-                        };
-
-                        if file.is_none() {
-                            return None;
-                        }
-
-                        let file = file.unwrap();
-                        let mut reader = std::io::BufReader::new(file);
-                        let mut buffer = Vec::new();
-
-                        loop {
-                            buffer.resize(state.search_content.len(), 0);
-                            let bytes_read = reader.read(&mut buffer).unwrap();
-                            if bytes_read == 0 {
-                                break;
-                            }
-
-                            buffer.truncate(bytes_read);
-
-                            if let Ok(text) = String::from_utf8(buffer.clone()) {
-                                if text.contains(&state.search_content) {
-                                    return Some(response.filepath.clone());
-                                }
-                            }
-                        }
-
-                        None
-                    })
-                    .collect::<Vec<String>>();
-
-                filtered_results.clone()
-            } else if state.search_content.len() > 0 {
-                filtered_results.clone()
-            } else {
-                state
+            // the previous results stay up until the search catches up with the typing
+            let results = match &filtered_results {
+                Some(results) => results.clone(),
+                None => state
                     .search_results
                     .iter()
                     .map(|response| response.filepath.clone())
-                    .collect::<Vec<String>>()
+                    .collect::<Vec<String>>(),
             };
 
-            let list = List::new(results.clone())
-                .block(Block::bordered().title("Conversations"))
-                .highlight_style(Style::new().fg(Color::Blue).add_modifier(Modifier::BOLD))
-                .highlight_symbol(">")
-                .repeat_highlight_symbol(true);
+            visible_results = results.clone();
 
-            frame.render_widget(
-                Paragraph::new(state.search_content.clone())
-                    .block(Block::bordered().title("Search")),
-                main_layout[0],
-            );
+            let searching = filter.is_pending();
+            let list = List::new(
+                if results.is_empty() && !state.search_content.is_empty() && !searching {
+                    vec![format!("No conversations match {:?}", state.search_content)]
+                } else {
+                    results.clone()
+                },
+            )
+            .block(
+                Block::bordered().title(match (deleted.is_empty(), searching) {
+                    (true, false) => "Conversations".to_string(),
+                    (true, true) => "Conversations (searching…)".to_string(),
+                    (false, false) => format!("Conversations ({} deleted)", deleted.len()),
+                    (false, true) => {
+                        format!("Conversations ({} deleted, searching…)", deleted.len())
+                    }
+                }),
+            )
+            .highlight_style(Style::new().fg(Color::Blue).add_modifier(Modifier::BOLD))
+            .highlight_symbol(">")
+            .repeat_highlight_symbol(true);
+
+            if state.input_mode == DirectoryInputMode::Rename {
+                frame.render_widget(
+                    Paragraph::new(rename_content.clone()).block(Block::bordered().title(
+                        match &rename_error {
+                            Some(e) => Line::from(Span::styled(e.clone(), Style::new().red())),
+                            None => Line::from(format!("Rename {}", rename_target)),
+                        },
+                    )),
+                    main_layout[0],
+                );
+            } else {
+                frame.render_widget(
+                    Paragraph::new(state.search_content.clone())
+                        .block(Block::bordered().title("Search")),
+                    main_layout[0],
+                );
+            }
 
             frame.render_stateful_widget(list, results_layout[0], &mut state.results_state);
 
             let mut lines = Vec::new();
 
-            match state.results_state.selected() {
+            // a narrower filter can leave the selection past the end of the results
+            match state
+                .results_state
+                .selected()
+                .filter(|i| *i < results.len())
+            {
                 Some(i) => {
-                    let selected = conversation_path.join(results[i].clone());
-                    let file = std::fs::File::open(selected.clone());
-                    if file.is_err() {
-                        lines.push(Line::from(Span::styled(
-                            format!(
-                                "error reading file {}: {}",
-                                selected.to_str().unwrap(),
-                                file.err().unwrap()
-                            ),
-                            Style::new().red().bold(),
-                        )));
-                    } else {
-                        let mut file = file.unwrap();
+                    let title = crate::conversation::get_title(&results[i]);
+                    let messages = loaded.entry(results[i].clone()).or_insert_with(|| {
+                        crate::conversation::load(&title).map_err(|e| e.to_string())
+                    });
+
+                    let usage = match messages {
+                        Ok(messages) => crate::conversation::total_usage(messages),
+                        Err(_) => network::Usage::default(),
+                    };
+
+                    let mut tokens = format!(
+                        "Tokens: {} prompt / {} completion",
+                        usage.prompt_tokens, usage.completion_tokens
+                    );
+                    if usage.cache_creation_tokens + usage.cache_read_tokens > 0 {
+                        tokens.push_str(&format!(
+                            " / {} cache write / {} cache read",
+                            usage.cache_creation_tokens, usage.cache_read_tokens
+                        ));
+                    }
 
-                        // limiting file reads to arbitrarily be 2kB
-                        let mut buffer = vec![0; 1024 * 2];
-                        let bytes_read = file.read(&mut buffer).unwrap();
-                        buffer.truncate(bytes_read);
+                    lines.push(Line::from(Span::styled(tokens, Style::new().dark_gray())));
 
-                        let mut contents = match String::from_utf8(buffer.clone()) {
-                            Ok(valid_string) => valid_string,
-                            Err(e) => {
-                                let e = e.utf8_error();
-                                let mut valid_length = e.valid_up_to();
+                    if let Ok(updated_at) = crate::conversation::get_updated_at(&title) {
+                        lines.push(Line::from(Span::styled(
+                            format!("Last active: {}", updated_at.format("%Y-%m-%d %H:%M")),
+                            Style::new().dark_gray(),
+                        )));
+                    }
 
-                                if valid_length > bytes_read {
-                                    valid_length = bytes_read;
+                    if let Some(parent) = messages
+                        .as_ref()
+                        .ok()
+                        .and_then(|m| crate::conversation::get_parent(m))
+                    {
+                        lines.push(Line::from(Span::styled(
+                            format!("Branched from: {}", parent),
+                            Style::new().dark_gray(),
+                        )));
+                    }
+                    lines.push(Line::raw("───"));
+
+                    match messages {
+                        Err(e) => {
+                            lines.push(Line::from(Span::styled(
+                                format!("error reading conversation {}: {}", title, e),
+                                Style::new().red().bold(),
+                            )));
+                        }
+                        Ok(messages) => {
+                            // there's no point rendering more than the pane can show,
+                            // and long conversations get slow to lay out every frame
+                            let mut budget = results_layout[1].width as usize
+                                * results_layout[1].height as usize;
+                            for message in
+                                crate::conversation::order_messages(messages, history_order)
+                            {
+                                if budget == 0 {
+                                    lines.push(Line::styled("…", Style::new().dark_gray()));
+                                    break;
                                 }
 
-                                let valid_bytes = &buffer[..valid_length];
-                                String::from_utf8(valid_bytes.to_vec()).unwrap()
-                            }
-                        };
+                                let content = match message.content.char_indices().nth(budget) {
+                                    Some((cut, _)) => {
+                                        budget = 0;
+                                        format!("{}…", &message.content[..cut])
+                                    }
+                                    None => {
+                                        budget -= message.content.chars().count();
+                                        message.content.clone()
+                                    }
+                                };
 
-                        if !contents.ends_with("\"}]") {
-                            contents.push_str("\"}]");
-                        }
+                                let mut line = Vec::new();
+                                let header =
+                                    format!("{}: ", crate::conversation::get_header(message));
+                                line.push(Span::styled(
+                                    header,
+                                    get_role_style(&message.message_type),
+                                ));
 
-                        contents = contents.replace("\t", "    ");
+                                line.push(Span::raw(content));
 
-                        let messages: Vec<network::Message> = match serde_json::from_str(&contents)
-                        {
-                            Ok(v) => v,
-                            Err(e) => {
-                                lines.push(Line::from(Span::styled(
-                                    format!(
-                                        "error parsing conversation json {}: {}",
-                                        selected.to_str().unwrap(),
-                                        e
-                                    ),
-                                    Style::new().red(),
-                                )));
-
-                                Vec::new()
+                                lines.push(Line::from(line));
+                                lines.push(Line::raw("───"));
                             }
-                        };
-
-                        for message in messages.iter() {
-                            let mut line = Vec::new();
-                            line.push(match message.message_type {
-                                network::MessageType::User => {
-                                    Span::styled("User: ", Style::new().blue().bold())
-                                }
-                                network::MessageType::Assistant => {
-                                    Span::styled("Assistant: ", Style::new().green().bold())
-                                }
-                                _ => Span::raw(""),
-                            });
-
-                            line.push(Span::raw(message.content.clone()));
-
-                            lines.push(Line::from(line));
-                            lines.push(Line::raw("───"));
                         }
                     }
                 }
@@ -1123,28 +2735,53 @@ pub fn conversation_search(
             frame.render_widget(
                 Paragraph::new(lines)
                     .wrap(Wrap { trim: false })
-                    .block(Block::bordered().title("Contents")),
+                    .block(
+                        Block::bordered().title(match (&action_error, &pending_delete) {
+                            (Some(e), _) => Line::from(Span::styled(e.clone(), Style::new().red())),
+                            (None, Some(title)) => {
+                                Line::from(format!("Press d again to delete {}", title))
+                            }
+                            (None, None) => Line::from("Contents"),
+                        }),
+                    ),
                 results_layout[1],
             );
 
             let (display_cursor, focused_area) = match state.input_mode {
                 DirectoryInputMode::Search => ((0, state.search_cursor), main_layout[0]),
-                DirectoryInputMode::Files => ((0, 0), results_layout[0]),
+                DirectoryInputMode::Rename => ((0, rename_content.chars().count()), main_layout[0]),
+                DirectoryInputMode::Files | DirectoryInputMode::Filters => {
+                    ((0, 0), results_layout[0])
+                }
             };
 
-            if state.input_mode == DirectoryInputMode::Search {
+            if state.input_mode != DirectoryInputMode::Files {
                 frame.set_cursor_position(Position::new(
                     focused_area.x + display_cursor.1 as u16 + 1,
                     focused_area.y + display_cursor.0 as u16 + 1,
                 ));
             }
+
+            if state.show_help {
+                render_help(frame, "Load", LOAD_BINDINGS);
+            }
         })?;
 
         if event::poll(std::time::Duration::from_millis(25))? {
             match event::read() {
                 Ok(Event::Key(key)) => {
                     if key.kind == KeyEventKind::Press {
+                        if state.show_help {
+                            state.show_help = false;
+                            continue;
+                        }
+
+                        action_error = None;
                         if state.input_mode == DirectoryInputMode::Files {
+                            if key.code != KeyCode::Char('d') {
+                                pending_delete = None;
+                            }
+
                             match key.code {
                                 KeyCode::Tab => {
                                     state.next_window = state.next_window.next();
@@ -1157,11 +2794,126 @@ pub fn conversation_search(
                                 KeyCode::Char('s') => {
                                     state.input_mode = DirectoryInputMode::Search;
                                 }
+                                KeyCode::Char('?') => {
+                                    state.show_help = true;
+                                }
+                                KeyCode::Char('r') => {
+                                    if let Some(i) = state.results_state.selected() {
+                                        if i < visible_results.len() {
+                                            rename_target = visible_results[i].clone();
+                                            rename_content =
+                                                crate::conversation::get_title(&rename_target);
+                                            rename_error = None;
+                                            state.input_mode = DirectoryInputMode::Rename;
+                                        }
+                                    }
+                                }
+                                KeyCode::Char('d') => {
+                                    if let Some(i) = state.results_state.selected() {
+                                        if i >= visible_results.len() {
+                                            continue;
+                                        }
+
+                                        let title = visible_results[i].clone();
+                                        if pending_delete.as_ref() != Some(&title) {
+                                            pending_delete = Some(title);
+                                            continue;
+                                        }
+
+                                        pending_delete = None;
+                                        let path = conversation_path.join(&title);
+                                        match std::fs::remove_file(&path) {
+                                            Ok(_) => {
+                                                info!("Deleted conversation {:?}", path);
+                                                state
+                                                    .search_results
+                                                    .retain(|r| r.filepath != title);
+                                                for results in filtered_results.iter_mut() {
+                                                    results.retain(|r| *r != title);
+                                                }
+                                                conversations_changed = true;
+                                                deleted.push(title);
+                                                state.results_state.select(None);
+                                            }
+                                            Err(e) => {
+                                                error!(
+                                                    "error deleting conversation {:?}: {}",
+                                                    path, e
+                                                );
+                                                action_error = Some(format!(
+                                                    "Couldn't delete {}: {}",
+                                                    title, e
+                                                ));
+                                            }
+                                        }
+                                    }
+                                }
+                                KeyCode::Char('a') => {
+                                    if let Some(i) = state.results_state.selected() {
+                                        if i >= visible_results.len() {
+                                            continue;
+                                        }
+
+                                        let filepath = visible_results[i].clone();
+                                        let title = crate::conversation::get_title(&filepath);
+                                        let archived = crate::conversation::is_archived(&title);
+                                        match crate::conversation::set_archived(&title, !archived) {
+                                            Ok(new_title) => {
+                                                info!(
+                                                    "Moved conversation {} to {}",
+                                                    title, new_title
+                                                );
+                                                let new_filepath = format!("{}.json", new_title);
+                                                if show_archived {
+                                                    for r in state.search_results.iter_mut() {
+                                                        if r.filepath == filepath {
+                                                            r.filepath = new_filepath.clone();
+                                                        }
+                                                    }
+
+                                                    for r in filtered_results.iter_mut().flatten() {
+                                                        if *r == filepath {
+                                                            *r = new_filepath.clone();
+                                                        }
+                                                    }
+                                                } else {
+                                                    state
+                                                        .search_results
+                                                        .retain(|r| r.filepath != filepath);
+                                                    for results in filtered_results.iter_mut() {
+                                                        results.retain(|r| *r != filepath);
+                                                    }
+                                                    state.results_state.select(None);
+                                                }
+                                                conversations_changed = true;
+                                            }
+                                            Err(e) => {
+                                                error!(
+                                                    "error archiving conversation {}: {}",
+                                                    title, e
+                                                );
+                                                action_error = Some(format!(
+                                                    "Couldn't {} {}: {}",
+                                                    match archived {
+                                                        true => "restore",
+                                                        false => "archive",
+                                                    },
+                                                    title,
+                                                    e
+                                                ));
+                                            }
+                                        }
+                                    }
+                                }
                                 KeyCode::Enter => {
-                                    match state.results_state.selected() {
-                                        Some(i) => {
-                                            let selected = conversation_path
-                                                .join(state.search_results[i].filepath.clone());
+                                    // indexes into what's on screen, which may be filtered
+                                    match state
+                                        .results_state
+                                        .selected()
+                                        .and_then(|i| visible_results.get(i))
+                                    {
+                                        Some(filepath) => {
+                                            let selected = conversation_path.join(filepath);
 
                                             chosen_conversation =
                                                 selected.to_string_lossy().to_string();
@@ -1193,7 +2945,7 @@ pub fn conversation_search(
                                             state.search_cursor += 1;
                                         }
 
-                                        pending_search = true;
+                                        filter.search(&state.search_content, &state.search_results);
                                     }
                                 }
                                 KeyCode::Backspace => {
@@ -1203,7 +2955,10 @@ pub fn conversation_search(
                                             .drain(state.search_cursor - 1..state.search_cursor);
 
                                         state.search_cursor -= 1;
-                                        pending_search = true;
+                                        filter.search(&state.search_content, &state.search_results);
+                                        if state.search_content.is_empty() {
+                                            filtered_results = None;
+                                        }
                                     }
                                 }
                                 KeyCode::Left => {
@@ -1220,18 +2975,76 @@ pub fn conversation_search(
                                 }
                                 _ => {}
                             }
+                        } else if state.input_mode == DirectoryInputMode::Rename {
+                            match key.code {
+                                KeyCode::Esc => {
+                                    state.input_mode = DirectoryInputMode::Files;
+                                }
+                                KeyCode::Char(c) => {
+                                    rename_content.push(c);
+                                }
+                                KeyCode::Backspace => {
+                                    rename_content.pop();
+                                }
+                                KeyCode::Enter => {
+                                    let old_title = crate::conversation::get_title(&rename_target);
+                                    match crate::conversation::rename(&old_title, &rename_content) {
+                                        Ok(_) => {
+                                            info!("Renamed {} to {}", old_title, rename_content);
+                                            let new_filename = format!("{}.json", rename_content);
+                                            for result in state.search_results.iter_mut() {
+                                                if result.filepath == rename_target {
+                                                    result.filepath = new_filename.clone();
+                                                }
+                                            }
+
+                                            for result in filtered_results.iter_mut().flatten() {
+                                                if *result == rename_target {
+                                                    *result = new_filename.clone();
+                                                }
+                                            }
+                                            conversations_changed = true;
+
+                                            state.input_mode = DirectoryInputMode::Files;
+                                        }
+                                        Err(e) => {
+                                            rename_error = Some(e.to_string());
+                                        }
+                                    }
+                                }
+                                _ => {}
+                            }
                         }
                     }
                 }
+                Ok(Event::Mouse(mouse)) => match mouse.kind {
+                    MouseEventKind::ScrollUp => state.results_state.select_previous(),
+                    MouseEventKind::ScrollDown => state.results_state.select_next(),
+                    _ => {}
+                },
                 Err(e) => {
-                    panic!("error reading event: {}", e);
+                    return Err(e.into());
                 }
                 _ => {}
             }
         }
+
+        if std::mem::take(&mut conversations_changed) && filter.is_pending() {
+            filter.search(&state.search_content, &state.search_results);
+        }
+    }
+
+    let mut note = String::new();
+    if !deleted.is_empty() {
+        note = format!(
+            "Deleted {} conversation(s): {}",
+            deleted.len(),
+            deleted.join(", ")
+        );
+        info!("{}", note);
     }
 
-    Ok((state.next_window, chosen_conversation))
+    Ok((state.next_window, chosen_conversation, note))
 }
 
 pub enum WindowView {
@@ -1252,47 +3065,384 @@ impl WindowView {
     }
 }
 
+// what stays the same across every view of a session
+pub struct SessionOptions<'a> {
+    pub system_prompt: &'a crate::conversation::SystemPrompt,
+    pub override_system_prompt: bool,
+    pub show_archived: bool,
+    // for files named in `{{file:...}}`
+    pub max_file_size: u64,
+    pub api: &'a str,
+    pub options: &'a network::Options,
+    pub dewey: &'a network::DeweyAddress,
+}
+
+// the terminal has to be usable again before the panic message gets printed
+fn set_panic_hook(restore: fn()) {
+    let panic_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore();
+        panic_hook(info);
+    }));
+}
+
 pub fn display_manager(
     window: WindowView,
-    system_prompt: &str,
-    api: &str,
+    session: &SessionOptions,
     mut conversation_path: String,
-) -> Result<(), std::io::Error> {
+    mut draft: Option<std::path::PathBuf>,
+    mut attachments: Vec<crate::attachment::Attachment>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let mut terminal = ratatui::init();
+    Logger::suspend_stderr(true);
+
+    ratatui::crossterm::execute!(std::io::stdout(), EnableMouseCapture)?;
+    set_panic_hook(|| {
+        let _ = ratatui::crossterm::execute!(std::io::stdout(), DisableMouseCapture);
+        ratatui::restore();
+    });
 
     let mut window = window;
-    loop {
+    let mut input = ChatInput::default();
+    let mut dewey_filters = Vec::new();
+    let mut exit_notes = Vec::new();
+    // an error leaving a view ends the session, it's returned once the terminal is restored
+    let mut result = Ok(());
+    while result.is_ok() {
         match window {
             WindowView::Chat => {
-                match chat(&mut terminal, system_prompt, api, &conversation_path) {
-                    Ok(w) => {
+                match chat(
+                    &mut terminal,
+                    session,
+                    &conversation_path,
+                    draft.take(),
+                    &mut attachments,
+                    &mut input,
+                ) {
+                    Ok((w, note)) => {
                         window = w;
+                        if !note.is_empty() {
+                            exit_notes.push(note);
+                        }
                     }
-                    Err(e) => panic!("error leaving chat: {}", e),
+                    Err(e) => result = Err(format!("error leaving chat: {}", e)),
                 };
             }
             WindowView::Directory => {
-                match directory(&mut terminal) {
+                match directory(&mut terminal, session.dewey, &mut input, &mut dewey_filters) {
                     Ok(w) => window = w,
-                    Err(e) => panic!("error leaving directory: {}", e),
+                    Err(e) => result = Err(format!("error leaving directory: {}", e)),
                 };
             }
             WindowView::Load => {
-                match conversation_search(&mut terminal) {
-                    Ok(wc) => {
-                        window = wc.0;
-                        if wc.1.len() > 0 {
-                            conversation_path = wc.1;
+                match conversation_search(&mut terminal, session.show_archived) {
+                    Ok((w, chosen, note)) => {
+                        window = w;
+                        if chosen.len() > 0 {
+                            conversation_path = chosen;
+                        }
+                        if !note.is_empty() {
+                            exit_notes.push(note);
                         }
                     }
-                    Err(e) => panic!("error leaving conversation search: {}", e),
+                    Err(e) => result = Err(format!("error leaving conversation search: {}", e)),
                 };
             }
             _ => break,
         };
     }
 
+    ratatui::crossterm::execute!(std::io::stdout(), DisableMouseCapture)?;
     ratatui::restore();
+    Logger::suspend_stderr(false);
+
+    for note in exit_notes {
+        eprintln!("{}", note);
+    }
+
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `width` columns of text, the box takes the other two
+    fn get_wrapped(content: &str, width: usize) -> WrappedText {
+        let mut wrapped = WrappedText {
+            content: content.to_string(),
+            line_lengths: Vec::new(),
+            page: 0,
+            window_size: (width + 2, 10),
+        };
+        wrapped.rewrap();
+
+        wrapped
+    }
+
+    // typed a grapheme at a time, the cursor ending up where the text says it should
+    fn type_text(text: &str, width: usize) -> (WrappedText, usize) {
+        let mut wrapped = get_wrapped("", width);
+        let mut offset = 0;
+        for grapheme in text.graphemes(true) {
+            offset = wrapped.insert(grapheme, offset);
+            wrapped.rewrap();
+        }
+
+        (wrapped, offset)
+    }
+
+    #[test]
+    fn wide_emoji() {
+        let (mut wrapped, offset) = type_text("a👍b", 20);
+        assert_eq!(wrapped.content, "a👍b");
+        assert_eq!(wrapped.get_position(offset), (0, 4));
+        // the emoji takes two cells
+        assert_eq!(wrapped.get_position(1), (0, 1));
+        assert_eq!(wrapped.get_position(5), (0, 3));
+        // either of its cells is the emoji
+        assert_eq!(wrapped.get_flat(0, 1), 1);
+        assert_eq!(wrapped.get_flat(0, 2), 1);
+        assert_eq!(wrapped.get_flat(0, 3), 5);
+
+        // backspace after it takes the whole thing
+        assert_eq!(wrapped.delete_char(5), 1);
+        assert_eq!(wrapped.content, "ab");
+    }
+
+    #[test]
+    fn cjk() {
+        let (wrapped, offset) = type_text("日本語", 20);
+        assert_eq!(offset, 9);
+        assert_eq!(wrapped.get_position(offset), (0, 6));
+        assert_eq!(wrapped.get_position(3), (0, 2));
+        assert_eq!(wrapped.get_flat(0, 3), 3);
+        assert_eq!(wrapped.get_flat(0, 4), 6);
+
+        // a wide char that wouldn't fit goes to the next line whole
+        let wrapped = get_wrapped("日本語", 5);
+        assert_eq!(wrapped.line_lengths, [6, 3]);
+        assert_eq!(wrapped.display(), "日本\n語");
+        assert_eq!(wrapped.get_position(6), (1, 0));
+    }
+
+    #[test]
+    fn combining_accents() {
+        let text = "e\u{301}x\u{301}\u{323}";
+        let (mut wrapped, offset) = type_text(text, 20);
+        assert_eq!(offset, text.len());
+        assert_eq!(wrapped.get_position(offset), (0, 2));
+        assert_eq!(wrapped.get_position(3), (0, 1));
+        assert_eq!(wrapped.next_grapheme(0), 3);
+        assert_eq!(wrapped.prev_grapheme(offset), 3);
+        assert_eq!(wrapped.get_flat(0, 1), 3);
+
+        // the accents go with their letters
+        wrapped.delete_next_char(0);
+        assert_eq!(wrapped.content, "x\u{301}\u{323}");
+        assert_eq!(wrapped.delete_char(wrapped.len()), 0);
+        assert_eq!(wrapped.content, "");
+    }
+
+    #[test]
+    fn delete_word() {
+        let mut wrapped = get_wrapped("hi 👍日本 e\u{301}", 20);
+        let end = wrapped.len();
+        assert_eq!(wrapped.delete_word(end), "e\u{301}");
+        let end = wrapped.len();
+        assert_eq!(wrapped.delete_word(end), "👍日本 ");
+        assert_eq!(wrapped.content, "hi ");
+    }
+
+    static CALLS: std::sync::Mutex<Vec<&str>> = std::sync::Mutex::new(Vec::new());
+    static PANIC_THREAD: std::sync::OnceLock<std::thread::ThreadId> = std::sync::OnceLock::new();
+
+    // the hook is the whole process's, so panics in tests running alongside are left out
+    fn record(call: &'static str) {
+        if PANIC_THREAD.get() == Some(&std::thread::current().id()) {
+            CALLS.lock().unwrap().push(call);
+        }
+    }
+
+    // the terminal is put back before whatever hook was there prints the panic
+    #[test]
+    fn panic_hook() {
+        PANIC_THREAD.set(std::thread::current().id()).unwrap();
+        std::panic::set_hook(Box::new(|_| record("previous hook")));
+        set_panic_hook(|| record("restore"));
+
+        let result = std::panic::catch_unwind(|| panic!("deliberate"));
+        let _ = std::panic::take_hook();
+
+        assert!(result.is_err());
+        assert_eq!(*CALLS.lock().unwrap(), ["restore", "previous hook"]);
+    }
+
+    const PARAGRAPHS: &str = "The quick brown fox jumps over the lazy dog.\nA verylongtokenthatdoesntfitanywhere, 日本語, and 👍 too.\r\n\nend";
+
+    // every line fits, and together they're the text exactly
+    fn check_lines(wrapped: &WrappedText, width: usize) {
+        assert_eq!(wrapped.line_lengths.iter().sum::<usize>(), wrapped.len());
+
+        let mut joined = String::new();
+        for line in 0..wrapped.line_lengths.len() {
+            let start = wrapped.get_line_start(line);
+            joined.push_str(&wrapped.content[start..start + wrapped.line_lengths[line]]);
+            assert!(
+                get_width(wrapped.get_line(line).trim_end()) <= width,
+                "{:?}",
+                wrapped.get_line(line)
+            );
+        }
+        assert_eq!(joined, wrapped.content);
+    }
+
+    #[test]
+    fn rewrap_widths() {
+        let mut wrapped = get_wrapped(PARAGRAPHS, 30);
+        check_lines(&wrapped, 30);
+        let wide = wrapped.line_lengths.clone();
+
+        wrapped.window_size.0 = 12 + 2;
+        wrapped.rewrap();
+        assert_eq!(wrapped.content, PARAGRAPHS);
+        check_lines(&wrapped, 12);
+        assert!(wrapped.line_lengths.len() > wide.len());
+
+        wrapped.window_size.0 = 30 + 2;
+        wrapped.rewrap();
+        assert_eq!(wrapped.content, PARAGRAPHS);
+        assert_eq!(wrapped.line_lengths, wide);
+    }
+
+    // a resize keeps the cursor on the same text
+    #[test]
+    fn reflow() {
+        let mut wrapped = get_wrapped(PARAGRAPHS, 30);
+        for word in ["lazy", "日本語", "👍", "end"] {
+            let offset = PARAGRAPHS.find(word).unwrap();
+            let cursor = wrapped.get_cursor(offset);
+
+            wrapped.window_size.0 = 9 + 2;
+            let cursor = wrapped.reflow(cursor);
+            assert_eq!(wrapped.get_flat(cursor.0 + wrapped.page, cursor.1), offset);
+
+            wrapped.window_size.0 = 30 + 2;
+            let cursor = wrapped.reflow(cursor);
+            assert_eq!(wrapped.get_flat(cursor.0 + wrapped.page, cursor.1), offset);
+        }
+    }
+
+    #[test]
+    fn word_wrap() {
+        let wrapped = get_wrapped("hello world foo", 8);
+        assert_eq!(wrapped.line_lengths, [6, 6, 3]);
+        assert_eq!(wrapped.display(), "hello \nworld \nfoo");
+        assert!(wrapped.is_wrapped(0));
+        assert_eq!(wrapped.get_line_width(0), 5);
+
+        // a break too far back leaves the word to be cut
+        let wrapped = get_wrapped("a https://example.com/path", 10);
+        assert_eq!(wrapped.display(), "a https://\nexample.co\nm/path");
+
+        let wrapped = get_wrapped("abcdefghijkl", 5);
+        assert_eq!(wrapped.line_lengths, [5, 5, 2]);
+    }
 
-    Ok(())
+    // the multibyte chars straddle the width
+    #[test]
+    fn multibyte_wrap() {
+        let wrapped = get_wrapped("abcdé fgh", 5);
+        assert_eq!(wrapped.display(), "abcdé\n fgh");
+
+        let wrapped = get_wrapped("ab 日本", 5);
+        assert_eq!(wrapped.display(), "ab \n日本");
+        assert_eq!(wrapped.line_lengths, [3, 6]);
+
+        // wider than the line, so it's cut between the chars
+        let wrapped = get_wrapped("ab 日本語", 5);
+        assert_eq!(wrapped.display(), "ab \n日本\n語");
+
+        let wrapped = get_wrapped("abcd👍", 5);
+        assert_eq!(wrapped.display(), "abcd\n👍");
+    }
+
+    // every boundary maps to a position and back, at widths around the multibyte chars
+    #[test]
+    fn cursor_round_trip() {
+        for text in [
+            "hello world foo",
+            "abcdé fgh",
+            "ab 日本語 e\u{301}e\u{301}",
+            "a👍b 👍👍 c\nd",
+        ] {
+            for width in 3..12 {
+                let wrapped = get_wrapped(text, width);
+                let mut offsets = text
+                    .grapheme_indices(true)
+                    .map(|(i, _)| i)
+                    .collect::<Vec<_>>();
+                offsets.push(text.len());
+
+                for offset in offsets {
+                    let (line, column) = wrapped.get_position(offset);
+                    assert_eq!(
+                        wrapped.get_flat(line, column),
+                        offset,
+                        "{:?} at width {}",
+                        text,
+                        width
+                    );
+                }
+            }
+        }
+    }
+
+    // typing into the middle of wrapped words keeps the cursor just past what was typed
+    #[test]
+    fn insert_wrapped() {
+        let mut wrapped = get_wrapped("hello world", 8);
+        let offset = wrapped.insert("é👍", 8);
+        wrapped.rewrap();
+
+        assert_eq!(wrapped.content, "hello woé👍rld");
+        // the line's full, so the cursor has an empty one after it to go to
+        assert_eq!(wrapped.display(), "hello \nwoé👍rld\n");
+        assert_eq!(wrapped.get_position(offset), (1, 5));
+        assert_eq!(wrapped.get_flat(1, 5), offset);
+    }
+
+    #[test]
+    fn find_matches() {
+        assert_eq!(
+            super::find_matches("Foo food FOO", "foo"),
+            [0..3, 4..7, 9..12]
+        );
+        assert_eq!(super::find_matches("aaa", "aa"), [0..2, 1..3]);
+        assert!(super::find_matches("foo", "").is_empty());
+        assert!(super::find_matches("foo", "bar").is_empty());
+
+        // ranges are bytes of the text, whatever lowercasing does to the lengths
+        assert_eq!(
+            super::find_matches("日本語 ÉTÉ été", "été"),
+            [10..15, 16..21]
+        );
+        assert_eq!(super::find_matches("İx", "i\u{307}x"), vec![0..3]);
+    }
+
+    #[test]
+    fn highlighted_matches() {
+        let text = "`x` 日本語";
+        let line = crate::highlight::highlight(text, false).remove(0);
+        let matches = super::find_matches(text, "語");
+        let line = highlight_ranges(line, 0, &matches, Style::new().bg(Color::Yellow));
+
+        let found = line
+            .spans
+            .iter()
+            .filter(|s| s.style.bg == Some(Color::Yellow))
+            .map(|s| s.content.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(found, ["語"]);
+    }
 }
@@ -0,0 +1,375 @@
+use crate::network::{self, Message, MessageType, Options};
+use crate::{config, conversation, providers};
+
+// the probes are one token each, anything slower than this is a problem in itself
+const PROBE_TIMEOUT: u64 = 20;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+    // nothing to check, e.g. a provider without a key
+    Skip,
+}
+
+impl Status {
+    fn marker(&self) -> &'static str {
+        match self {
+            Status::Pass => "[pass]",
+            Status::Warn => "[warn]",
+            Status::Fail => "[FAIL]",
+            Status::Skip => "[skip]",
+        }
+    }
+}
+
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+}
+
+fn check(name: &str, status: Status, detail: impl Into<String>) -> Check {
+    Check {
+        name: name.to_string(),
+        status,
+        detail: detail.into(),
+    }
+}
+
+// the flat files only warn about bad lines when tllm runs, so they're warnings here too
+fn check_flat_config(name: &str, path: &std::path::Path) -> Vec<Check> {
+    let mut checks = Vec::new();
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            let mut problems = Vec::new();
+            for (i, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                match line.split_once('=') {
+                    Some((key, value)) => {
+                        if let Err(e) = crate::validate_config(key.trim(), value.trim()) {
+                            problems.push(format!("line {}: {}", i + 1, e));
+                        }
+                    }
+                    None => problems.push(format!("line {}: expected key=value", i + 1)),
+                }
+            }
+
+            if problems.is_empty() {
+                checks.push(check(name, Status::Pass, path.display().to_string()));
+            }
+
+            for problem in problems {
+                checks.push(check(
+                    name,
+                    Status::Warn,
+                    format!("{} {}", path.display(), problem),
+                ));
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => checks.push(check(
+            name,
+            Status::Pass,
+            format!("{} not found, using defaults", path.display()),
+        )),
+        Err(e) => checks.push(check(
+            name,
+            Status::Fail,
+            format!("Can't read {}: {}", path.display(), e),
+        )),
+    }
+
+    checks
+}
+
+fn check_config() -> Vec<Check> {
+    let mut checks = check_flat_config("config", &config::get_config_file());
+
+    // a profile set in config.toml has no file of its own
+    if let Some(profile) = config::get_profile() {
+        let path = config::get_profiles_dir().join(&profile);
+        match path.exists() {
+            true => checks.extend(check_flat_config("profile", &path)),
+            false => checks.push(check(
+                "profile",
+                Status::Pass,
+                format!("{}, from config.toml", profile),
+            )),
+        }
+    }
+
+    // unlike the flat file, anything wrong here stops tllm
+    let path = config::get_toml_config_file();
+    match config::read_toml_config() {
+        Ok(_) if !path.exists() => checks.push(check(
+            "config.toml",
+            Status::Pass,
+            format!("{} not found, using defaults", path.display()),
+        )),
+        Ok(entries) => {
+            let problems = entries
+                .iter()
+                .filter_map(|entry| {
+                    crate::validate_config(&entry.key, &entry.value)
+                        .err()
+                        .map(|e| format!("{}: {}", entry.location, e))
+                })
+                .collect::<Vec<String>>();
+
+            if problems.is_empty() {
+                checks.push(check(
+                    "config.toml",
+                    Status::Pass,
+                    path.display().to_string(),
+                ));
+            }
+
+            for problem in problems {
+                checks.push(check("config.toml", Status::Fail, problem));
+            }
+        }
+        Err(e) => checks.push(check("config.toml", Status::Fail, e)),
+    }
+
+    checks
+}
+
+// writes and removes a scratch file, permissions alone don't say much on every platform
+fn check_writable(name: &str, dir: &std::path::Path) -> Check {
+    let path = dir.join(".tllm-doctor");
+    match std::fs::write(&path, "").and_then(|_| std::fs::remove_file(&path)) {
+        Ok(_) => check(name, Status::Pass, dir.display().to_string()),
+        Err(e) => check(
+            name,
+            Status::Fail,
+            format!("Can't write to {}: {}", dir.display(), e),
+        ),
+    }
+}
+
+fn check_storage() -> Vec<Check> {
+    let mut checks = vec![
+        check_writable("data dir", &config::get_local_dir()),
+        check_writable("conversations dir", &config::get_conversations_dir()),
+        check_writable("logs dir", &config::get_logs_dir()),
+    ];
+
+    let titles = match conversation::list_titles(true) {
+        Ok(t) => t,
+        Err(e) => {
+            checks.push(check(
+                "conversations",
+                Status::Fail,
+                format!("Can't list conversations: {}", e),
+            ));
+            return checks;
+        }
+    };
+
+    let broken = titles
+        .iter()
+        .filter(|title| conversation::load(title).is_err())
+        .cloned()
+        .collect::<Vec<String>>();
+
+    if broken.is_empty() {
+        checks.push(check(
+            "conversations",
+            Status::Pass,
+            format!("{} saved, all readable", titles.len()),
+        ));
+    } else {
+        checks.push(check(
+            "conversations",
+            Status::Warn,
+            format!(
+                "{} of {} can't be read: {}",
+                broken.len(),
+                titles.len(),
+                broken.join(", ")
+            ),
+        ));
+    }
+
+    checks
+}
+
+fn find_program(program: &str) -> Option<std::path::PathBuf> {
+    if program.contains(std::path::MAIN_SEPARATOR) {
+        let path = std::path::PathBuf::from(program);
+        return path.is_file().then_some(path);
+    }
+
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+// the directory view opens files with $EDITOR, falling back to vi
+fn check_editor() -> Check {
+    let (editor, set) = match std::env::var("EDITOR") {
+        Ok(e) if !e.trim().is_empty() => (e, true),
+        _ => ("vi".to_string(), false),
+    };
+
+    let program = editor.split_whitespace().next().unwrap_or("vi");
+    match (find_program(program), set) {
+        (Some(path), true) => check("$EDITOR", Status::Pass, path.display().to_string()),
+        (Some(path), false) => check(
+            "$EDITOR",
+            Status::Warn,
+            format!("not set, falling back to {}", path.display()),
+        ),
+        (None, true) => check(
+            "$EDITOR",
+            Status::Fail,
+            format!("{} isn't on the PATH", program),
+        ),
+        (None, false) => check(
+            "$EDITOR",
+            Status::Warn,
+            "not set, and there's no vi to fall back to",
+        ),
+    }
+}
+
+fn probe(api: &str, options: &Options) -> Check {
+    let name = format!("{} request", api);
+    let provider = providers::get_provider(api);
+    let started = std::time::Instant::now();
+    let messages = vec![Message::new(MessageType::User, "ping".to_string())];
+    match provider.complete("", &messages, options) {
+        Ok(_) => check(
+            &name,
+            Status::Pass,
+            format!(
+                "{} answered in {}ms",
+                options.get_model(api),
+                started.elapsed().as_millis()
+            ),
+        ),
+        Err(e) => check(
+            &name,
+            Status::Fail,
+            format!("{}: {}", options.get_model(api), e),
+        ),
+    }
+}
+
+// providers without a key are skipped, except the selected one, which tllm can't run without
+fn check_providers(api: &str, api_base: Option<network::ApiBase>, offline: bool) -> Vec<Check> {
+    let config = config::read_config();
+    let mut checks = Vec::new();
+
+    for name in providers::PROVIDERS {
+        let api_base = match (*name == api, &api_base) {
+            (true, Some(api_base)) => Some(api_base.clone()),
+            _ => config
+                .get(&format!("api_base.{}", name))
+                .and_then(|value| network::parse_api_base(value).ok()),
+        };
+
+        let key_name = format!("{} key", name);
+        let needs_key = providers::get_provider(name).needs_key();
+        match (providers::find_api_key(name), &api_base) {
+            (Some((_, source)), _) => {
+                checks.push(check(&key_name, Status::Pass, format!("from {}", source)))
+            }
+            // a local server, only worth probing when it's the one in use or has been pointed somewhere
+            (None, None) if !needs_key && *name != api => {
+                checks.push(check(&key_name, Status::Skip, "none needed, not in use"));
+                continue;
+            }
+            (None, _) if !needs_key => checks.push(check(&key_name, Status::Pass, "none needed")),
+            (None, Some(_)) => checks.push(check(
+                &key_name,
+                Status::Pass,
+                "none, the custom API base may not need one",
+            )),
+            (None, None) if *name == api => {
+                checks.push(check(
+                    &key_name,
+                    Status::Fail,
+                    providers::missing_api_key(name).to_string(),
+                ));
+                continue;
+            }
+            (None, None) => {
+                checks.push(check(&key_name, Status::Skip, "not set"));
+                continue;
+            }
+        }
+
+        if offline {
+            checks.push(check(
+                &format!("{} request", name),
+                Status::Skip,
+                "--offline",
+            ));
+            continue;
+        }
+
+        // the cheap model, unless this is a custom server that probably doesn't have it
+        let model = match &api_base {
+            Some(_) => config.get(&format!("model.{}", name)).cloned(),
+            None => Some(providers::get_provider(name).title_model().to_string()),
+        };
+
+        let options = Options {
+            max_tokens: Some(1),
+            max_attempts: Some(1),
+            timeout: Some(PROBE_TIMEOUT),
+            proxy: config.get("proxy").cloned(),
+            api_base,
+            model,
+            ..Default::default()
+        };
+
+        checks.push(probe(name, &options));
+    }
+
+    checks
+}
+
+// `--doctor`, a table of everything tllm needs and whether it's there
+pub fn run(
+    api: &str,
+    api_base: Option<network::ApiBase>,
+    offline: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut checks = check_config();
+    checks.extend(check_storage());
+    checks.push(check_editor());
+    checks.extend(check_providers(api, api_base, offline));
+
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    for c in checks.iter() {
+        println!(
+            "{} {:<width$}  {}",
+            c.status.marker(),
+            c.name,
+            c.detail,
+            width = width
+        );
+    }
+
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    let failed = count(Status::Fail);
+    println!(
+        "\n{} passed, {} warnings, {} failed",
+        count(Status::Pass),
+        count(Status::Warn),
+        failed
+    );
+
+    if failed > 0 {
+        return Err(format!("{} checks failed", failed).into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use crate::config;
+
+// one file per unsent message, named by when it was saved so the newest sorts last
+pub fn save(content: &str) -> Result<std::path::PathBuf, std::io::Error> {
+    let path =
+        config::get_drafts_dir().join(format!("{}.md", chrono::Local::now().timestamp_micros()));
+    std::fs::write(&path, content)?;
+
+    Ok(path)
+}
+
+pub fn latest() -> Result<Option<std::path::PathBuf>, std::io::Error> {
+    let mut newest = None;
+    for file in std::fs::read_dir(config::get_drafts_dir())? {
+        let path = file?.path();
+        if path.is_file() && newest.as_ref().is_none_or(|n| path > *n) {
+            newest = Some(path);
+        }
+    }
+
+    Ok(newest)
+}
@@ -0,0 +1,96 @@
+use crate::network;
+
+#[derive(Debug)]
+pub enum TllmError {
+    // something the user needs to set or fix before trying again
+    Config(String),
+    // the provider answered with a non-2xx status
+    Network { status: u16, body: String },
+    // the provider answered, but not with anything we can use
+    Provider(String),
+    Io(std::io::Error),
+}
+
+impl TllmError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // rate limits outlast the retries as `io::Error`s, see `network::with_retries`
+            TllmError::Io(e)
+                if e.get_ref()
+                    .map(|inner| inner.is::<network::RateLimitError>())
+                    .unwrap_or(false) =>
+            {
+                3
+            }
+            TllmError::Io(_) => 1,
+            TllmError::Config(_) => 2,
+            TllmError::Network { .. } => 3,
+            TllmError::Provider(_) => 4,
+        }
+    }
+
+    // what arrived before a stream died
+    pub fn get_partial(&self) -> Option<network::Message> {
+        match self {
+            TllmError::Io(e) => e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<network::PartialResponse>())
+                .map(|partial| partial.message.clone()),
+            _ => None,
+        }
+    }
+
+    // a stream that died partway through, see `network::PartialResponse`
+    pub fn is_partial(&self) -> bool {
+        match self {
+            TllmError::Io(e) => e
+                .get_ref()
+                .map(|inner| inner.is::<network::PartialResponse>())
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for TllmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TllmError::Config(message) => write!(f, "{}", message),
+            TllmError::Network { status, body } => write!(
+                f,
+                "provider responded with {}: {}",
+                status,
+                network::get_error_message(body)
+            ),
+            TllmError::Provider(message) => write!(f, "{}", message),
+            TllmError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TllmError {}
+
+// the transport works in `io::Error`s so it can retry on them
+// the status of anything that made it to the provider is pulled back out here
+impl From<std::io::Error> for TllmError {
+    fn from(e: std::io::Error) -> Self {
+        let is_http = e
+            .get_ref()
+            .map(|inner| inner.is::<network::HttpError>())
+            .unwrap_or(false);
+
+        if !is_http {
+            return TllmError::Io(e);
+        }
+
+        let http = *e
+            .into_inner()
+            .unwrap()
+            .downcast::<network::HttpError>()
+            .unwrap();
+        TllmError::Network {
+            status: http.status,
+            body: http.body,
+        }
+    }
+}
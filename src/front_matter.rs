@@ -0,0 +1,116 @@
+use crate::{network, providers};
+
+// per-message overrides from a block like this at the top of a message:
+//
+// ---
+// model: gpt-4o
+// temperature: 0.2
+// ---
+#[derive(Debug, Default)]
+pub struct FrontMatter {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub title: Option<String>,
+    // unknown keys, they're skipped rather than failing the message
+    pub warnings: Vec<String>,
+}
+
+impl FrontMatter {
+    pub fn apply(&self, api: &mut String, options: &mut network::Options) {
+        // the model and API base picked for the other provider don't carry over
+        if let Some(provider) = self.provider.as_ref().filter(|p| *p != api) {
+            *api = provider.clone();
+            options.model = None;
+            options.api_base = None;
+        }
+
+        if self.model.is_some() {
+            options.model = self.model.clone();
+        }
+
+        if self.temperature.is_some() {
+            options.temperature = self.temperature;
+        }
+
+        if self.max_tokens.is_some() {
+            options.max_tokens = self.max_tokens;
+        }
+    }
+}
+
+// splits a message into its front matter and the text that actually gets sent
+// a message that doesn't start with `---` is returned as is
+pub fn parse(message: &str) -> Result<(FrontMatter, String), String> {
+    let mut front_matter = FrontMatter::default();
+
+    let mut lines = message.lines();
+    if lines.next().map(|l| l.trim()) != Some("---") {
+        return Ok((front_matter, message.to_string()));
+    }
+
+    let mut closed = false;
+    let mut consumed = 1;
+    for line in lines.by_ref() {
+        consumed += 1;
+        let line = line.trim();
+        if line == "---" {
+            closed = true;
+            break;
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                return Err(format!(
+                    "Expected key: value in front matter, got {:?}",
+                    line
+                ))
+            }
+        };
+
+        match key {
+            "provider" => {
+                if !providers::PROVIDERS.contains(&value) {
+                    return Err(format!("Unknown provider in front matter: {}", value));
+                }
+
+                front_matter.provider = Some(value.to_string());
+            }
+            "model" => front_matter.model = Some(value.to_string()),
+            "temperature" => {
+                front_matter.temperature =
+                    Some(crate::parse_temperature(value).map_err(|e| e.to_string())?)
+            }
+            "max_tokens" => {
+                front_matter.max_tokens =
+                    Some(crate::parse_max_tokens(value).map_err(|e| e.to_string())?)
+            }
+            "title" => front_matter.title = Some(value.to_string()),
+            _ => front_matter
+                .warnings
+                .push(format!("Ignoring unknown front matter key: {}", key)),
+        }
+    }
+
+    if !closed {
+        return Err("Front matter is missing its closing ---".to_string());
+    }
+
+    let body = message
+        .lines()
+        .skip(consumed)
+        .collect::<Vec<&str>>()
+        .join("\n");
+
+    if body.trim().is_empty() {
+        return Err("There's no message after the front matter".to_string());
+    }
+
+    Ok((front_matter, body.trim_start_matches('\n').to_string()))
+}
@@ -0,0 +1,149 @@
+use ratatui::{
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+};
+
+// a rough cut across the usual languages, this isn't trying to be a parser
+const KEYWORDS: &str = "\
+    as async await break case class const continue def defer do elif else enum export \
+    extends false fn for from func function go if impl import in interface let loop match \
+    mod mut new nil None null package pub return self Self static struct switch trait true \
+    True False try type use var where while with yield";
+
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+// whether `text` stops inside a code block, i.e. the next line should be highlighted as code
+pub fn ends_in_code(text: &str) -> bool {
+    text.lines().filter(|l| is_fence(l)).count() % 2 == 1
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn highlight_code(line: &str) -> Line<'_> {
+    let mut spans = Vec::new();
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let trimmed = rest.trim_start();
+        if rest.starts_with("//")
+            || (spans.is_empty() && (trimmed == "#" || trimmed.starts_with("# ")))
+        {
+            spans.push(Span::styled(rest, Style::new().fg(Color::DarkGray)));
+            break;
+        }
+
+        let (end, style) = if c == '"' || c == '`' {
+            // an unclosed string runs to the end of the line
+            let end = rest[1..].find(c).map(|i| i + 2).unwrap_or(rest.len());
+            (end, Style::new().fg(Color::Green))
+        } else if is_word_char(c) {
+            let end = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+            let word = &rest[..end];
+            let style = if KEYWORDS.split_whitespace().any(|k| k == word) {
+                Style::new().fg(Color::Magenta).add_modifier(Modifier::BOLD)
+            } else if c.is_ascii_digit() {
+                Style::new().fg(Color::Yellow)
+            } else {
+                Style::new()
+            };
+            (end, style)
+        } else {
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| is_word_char(*c) || *c == '"' || *c == '`' || *c == '/')
+                .map(|(i, _)| i)
+                .unwrap_or(rest.len());
+            (end, Style::new())
+        };
+
+        spans.push(Span::styled(&rest[..end], style));
+        rest = &rest[end..];
+    }
+
+    Line::from(spans)
+}
+
+// `inline code` outside of code blocks gets a background, backticks included so the width stays the same
+fn highlight_prose(line: &str) -> Line<'_> {
+    let mut spans = Vec::new();
+    let mut rest = line;
+    while let Some(open) = rest.find('`') {
+        // an unmatched backtick is just a backtick
+        let close = match rest[open + 1..].find('`') {
+            Some(i) => open + 1 + i,
+            None => break,
+        };
+
+        if open > 0 {
+            spans.push(Span::raw(&rest[..open]));
+        }
+        spans.push(Span::styled(
+            &rest[open..=close],
+            Style::new().fg(Color::White).bg(Color::DarkGray),
+        ));
+        rest = &rest[close + 1..];
+    }
+
+    if !rest.is_empty() {
+        spans.push(Span::raw(rest));
+    }
+
+    Line::from(spans)
+}
+
+// styling never changes widths, so the lines still match the wrapping done on the raw text
+// `in_code` says whether the text starts inside a code block opened further up
+pub fn highlight(text: &str, mut in_code: bool) -> Vec<Line<'_>> {
+    let mut lines = Vec::new();
+    for line in text.split('\n') {
+        if is_fence(line) {
+            in_code = !in_code;
+            lines.push(Line::styled(line, Style::new().fg(Color::DarkGray)));
+        } else if in_code {
+            lines.push(highlight_code(line));
+        } else {
+            lines.push(highlight_prose(line));
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_text(line: &Line) -> String {
+        line.spans.iter().map(|s| s.content.as_ref()).collect()
+    }
+
+    #[test]
+    fn widths_are_kept() {
+        let text = "`x` 日本語\nsome `inline` code and `more`\nan `unmatched backtick\n``\n```rust\nlet s = \"`\"; // done\n```";
+        let lines = highlight(text, false);
+
+        assert_eq!(lines.len(), text.split('\n').count());
+        for (line, raw) in lines.iter().zip(text.split('\n')) {
+            assert_eq!(get_text(line), raw);
+        }
+    }
+
+    #[test]
+    fn inline_code() {
+        let line = highlight_prose("a `b` c");
+        let spans = line
+            .spans
+            .iter()
+            .map(|s| (s.content.as_ref(), s.style.bg))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            spans,
+            [("a ", None), ("`b`", Some(Color::DarkGray)), (" c", None)]
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use crate::error::TllmError;
+use crate::logger::Logger;
+use crate::{error, network, tokens};
+
+// anthropic's limit, the strictest of the providers
+const MAX_SIZE_KB: u64 = 5 * 1024;
+
+// by model name prefix, anything on a custom API base is assumed to know what it's doing
+const VISION_MODELS: &[&str] = &[
+    "gpt-4o",
+    "gpt-4-turbo",
+    "gpt-4.1",
+    "o1-2024",
+    "o1-preview",
+    "claude-3",
+    "gemini",
+    "llama-3.2-11b-vision",
+    "llama-3.2-90b-vision",
+    "llama3.2-vision",
+    "llava",
+];
+
+pub fn supports_vision(model: &str) -> bool {
+    let model = tokens::get_base_model(model);
+    VISION_MODELS.iter().any(|prefix| model.starts_with(prefix))
+}
+
+// by the file's first bytes rather than its extension
+fn get_media_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+// (media type, base64 data)
+pub fn load(path: &str) -> Result<(&'static str, String), TllmError> {
+    let size = match std::fs::metadata(path) {
+        Ok(m) if m.is_file() => m.len(),
+        Ok(_) => return Err(TllmError::Config(format!("{} is not a file", path))),
+        Err(e) => return Err(TllmError::Config(format!("Can't read {}: {}", path, e))),
+    };
+
+    if size > MAX_SIZE_KB * 1024 {
+        return Err(TllmError::Config(format!(
+            "{} is {} KB, images over {} KB are refused",
+            path,
+            size.div_ceil(1024),
+            MAX_SIZE_KB
+        )));
+    }
+
+    let bytes = std::fs::read(path)?;
+    match get_media_type(&bytes) {
+        Some(media_type) => Ok((media_type, network::base64_encode(&bytes))),
+        None => Err(TllmError::Config(format!(
+            "{} isn't a PNG, JPEG, or WebP image",
+            path
+        ))),
+    }
+}
+
+// `--image PATH`, checked up front and stored as an absolute path so the conversation can be continued from anywhere
+pub fn check(path: &str) -> Result<String, TllmError> {
+    load(path)?;
+
+    match std::fs::canonicalize(path) {
+        Ok(path) => Ok(path.to_string_lossy().to_string()),
+        Err(e) => Err(TllmError::Config(format!("Can't read {}: {}", path, e))),
+    }
+}
+
+// only the path is saved, so an image from earlier in a conversation can be gone by now
+// it's mentioned in the text instead of failing the whole request
+pub fn load_for_request(path: &str) -> Option<(&'static str, String)> {
+    match load(path) {
+        Ok(image) => Some(image),
+        Err(e) => {
+            error!("leaving out image {}: {}", path, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// API keys in the system keychain, only built with `--features keyring`
+// without it there's nothing stored and `tllm auth set` falls back to the config file
+
+#[cfg(feature = "keyring")]
+use crate::logger::Logger;
+
+#[cfg(feature = "keyring")]
+const SERVICE: &str = "tllm";
+
+#[cfg(feature = "keyring")]
+pub const AVAILABLE: bool = true;
+
+#[cfg(not(feature = "keyring"))]
+pub const AVAILABLE: bool = false;
+
+#[cfg(feature = "keyring")]
+fn get_entry(api: &str) -> Result<keyring::Entry, String> {
+    keyring::Entry::new(SERVICE, api).map_err(|e| format!("Can't open the keychain: {}", e))
+}
+
+// a missing or locked keychain is the same as no key, the caller says what to do about it
+#[cfg(feature = "keyring")]
+pub fn get_key(api: &str) -> Option<String> {
+    match get_entry(api).and_then(|entry| entry.get_password().map_err(|e| e.to_string())) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            crate::debug!("no keychain entry for {}: {}", api, e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn get_key(_api: &str) -> Option<String> {
+    None
+}
+
+#[cfg(feature = "keyring")]
+pub fn set_key(api: &str, key: &str) -> Result<(), String> {
+    get_entry(api)?
+        .set_password(key)
+        .map_err(|e| format!("Can't save the key to the keychain: {}", e))
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn set_key(_api: &str, _key: &str) -> Result<(), String> {
+    Err("tllm was built without keychain support, rebuild with --features keyring".to_string())
+}
@@ -1,51 +1,738 @@
+mod attachment;
+mod clipboard;
+mod completions;
 mod config;
+mod conversation;
 mod display;
+mod doctor;
+mod draft;
+mod error;
+mod front_matter;
+mod highlight;
+mod image;
+mod keychain;
 mod logger;
+mod mock;
 mod network;
+mod prompts;
+mod providers;
+mod stats;
+mod summary;
+mod template;
+#[cfg(test)]
+mod testing;
+mod title;
+mod tokens;
+mod tools;
+
+use std::io::{IsTerminal, Write};
 
 use crate::logger::Logger;
 
+// anything past this is almost certainly a typo
+const MAX_TOKENS_LIMIT: u32 = 200_000;
+const MAX_ATTEMPTS_LIMIT: u32 = 10;
+const TIMEOUT_LIMIT: u64 = 3600;
+
+const DEFAULT_API: &str = "anthropic";
+
 struct Flags {
     save_conversation: bool,
     api: String,
     adhoc: String,
+    files: Vec<String>,
+    max_file_size: Option<u64>,
+    attach: Vec<String>,
+    images: Vec<String>,
+    yes: bool,
+    paste: bool,
+    copy: bool,
+    quiet: bool,
+    // print the -i response as it arrives
+    stream: bool,
+    // also write the -i response here
+    output: Option<String>,
+    prune_unsaved: Option<u64>,
+    // (title, exchanges)
+    branch: Option<(String, usize)>,
     help: bool,
     system_prompt: String,
+    // a file in the prompts directory, instead of -s
+    prompt: Option<String>,
     load_conversation: String,
+    max_tokens: Option<u32>,
+    max_attempts: Option<u32>,
+    timeout: Option<u64>,
+    // exchanges of a loaded conversation to send
+    last: Option<usize>,
+    // config file only
+    context_budget: Option<u64>,
+    // config file only, the environment is checked in `network`
+    proxy: Option<String>,
+    api_base: Option<network::ApiBase>,
+    headers: Vec<String>,
+    model: Option<String>,
+    // config file only
+    max_retry_wait: Option<u64>,
+    temperature: Option<f64>,
+    // `--response-format json`
+    json_response: bool,
+    // a JSON file of tool schemas
+    tools: Option<String>,
+    allow_shell: bool,
+    // repeatable
+    stop: Vec<String>,
+    prefill: Option<String>,
+    cache_prompt: bool,
+    rename: Option<(String, String)>,
+    // (title, archive or unarchive)
+    archive: Option<(String, bool)>,
+    all: bool,
+    resume_draft: bool,
+    title: String,
+    json: bool,
+    export: Option<(String, String)>,
+    export_format: conversation::ExportFormat,
+    stats: bool,
+    no_config: bool,
+    profile: Option<String>,
+    doctor: bool,
+    // skips the provider requests in --doctor
+    offline: bool,
+    completions: String,
+    dewey_host: Option<String>,
+    dewey_port: Option<u16>,
+    // how many times -v was given
+    verbose: u8,
 }
 
 impl Flags {
     fn new() -> Self {
         Self {
             save_conversation: true,
-            api: "anthropic".to_string(),
+            // filled in from the config or `DEFAULT_API` once it's read
+            api: String::new(),
             adhoc: String::new(),
+            files: Vec::new(),
+            max_file_size: None,
+            attach: Vec::new(),
+            images: Vec::new(),
+            yes: false,
+            paste: false,
+            copy: false,
+            quiet: false,
+            stream: false,
+            output: None,
+            prune_unsaved: None,
+            branch: None,
             help: false,
             system_prompt: String::new(),
+            prompt: None,
             load_conversation: String::new(),
+            max_tokens: None,
+            max_attempts: None,
+            timeout: None,
+            last: None,
+            context_budget: None,
+            proxy: None,
+            api_base: None,
+            headers: Vec::new(),
+            model: None,
+            max_retry_wait: None,
+            temperature: None,
+            json_response: false,
+            tools: None,
+            allow_shell: false,
+            stop: Vec::new(),
+            prefill: None,
+            cache_prompt: false,
+            rename: None,
+            archive: None,
+            all: false,
+            resume_draft: false,
+            title: String::new(),
+            json: false,
+            export: None,
+            export_format: conversation::ExportFormat::Text,
+            stats: false,
+            no_config: false,
+            profile: None,
+            doctor: false,
+            offline: false,
+            completions: String::new(),
+            dewey_host: None,
+            dewey_port: None,
+            verbose: 0,
         }
     }
+
+    // files and the clipboard are sent the same way -i text is
+    fn is_adhoc(&self) -> bool {
+        !self.adhoc.is_empty() || !self.files.is_empty() || self.paste
+    }
+
+    // either replaces the prompt a loaded conversation was started with
+    fn overrides_system_prompt(&self) -> bool {
+        !self.system_prompt.is_empty() || self.prompt.is_some()
+    }
+}
+
+pub struct FlagSpec {
+    pub short: &'static str,
+    pub long: &'static str,
+    pub arg: &'static str,
+    pub description: &'static str,
 }
 
+// the single source for both the help text and the generated shell completions
+pub const FLAGS: &[FlagSpec] = &[
+    FlagSpec {
+        short: "-n",
+        long: "--no-save",
+        arg: "",
+        description: "Don't save the conversation",
+    },
+    FlagSpec {
+        short: "",
+        long: "--prune-unsaved",
+        arg: "DAYS",
+        description: "Delete single-message conversations inactive for DAYS",
+    },
+    FlagSpec {
+        short: "-a",
+        long: "",
+        arg: "API",
+        description: "Use the specified API (anthropic, openai, gemini, groq, openrouter, ollama, mistral, deepseek, mock)",
+    },
+    FlagSpec {
+        short: "-i",
+        long: "",
+        arg: "TEXT",
+        description: "Use the specified text as an ad-hoc prompt",
+    },
+    FlagSpec {
+        short: "-f",
+        long: "--file",
+        arg: "FILE",
+        description: "Send the contents of FILE, below the -i text if given (repeatable)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--max-file-size",
+        arg: "KB",
+        description: "Refuse files larger than KB for -f and --attach (default 256)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--attach",
+        arg: "FILE",
+        description: "Add a file, or every file matching a glob, above the message (repeatable)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--image",
+        arg: "FILE",
+        description: "Send a PNG, JPEG, or WebP image with -i to a vision model (repeatable)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--yes",
+        arg: "",
+        description: "Skip the confirmation for large attachments and --prune-unsaved",
+    },
+    FlagSpec {
+        short: "",
+        long: "--paste",
+        arg: "",
+        description: "Add the clipboard contents below the -i text",
+    },
+    FlagSpec {
+        short: "",
+        long: "--copy",
+        arg: "",
+        description: "Copy the response to the clipboard after printing it",
+    },
+    FlagSpec {
+        short: "-q",
+        long: "--quiet",
+        arg: "",
+        description: "Print only the response, for scripts",
+    },
+    FlagSpec {
+        short: "-S",
+        long: "--stream",
+        arg: "",
+        description: "Print the -i response as it arrives",
+    },
+    FlagSpec {
+        short: "",
+        long: "--output",
+        arg: "FILE",
+        description: "Also write the -i response to FILE",
+    },
+    FlagSpec {
+        short: "-v",
+        long: "--verbose",
+        arg: "",
+        description: "Also print warnings and errors to stderr, -vv adds info",
+    },
+    FlagSpec {
+        short: "-x",
+        long: "--no-config",
+        arg: "",
+        description: "Ignore config and config.toml, running on the defaults",
+    },
+    FlagSpec {
+        short: "",
+        long: "--profile",
+        arg: "NAME",
+        description: "Layer profiles/NAME or [profiles.NAME] over the config",
+    },
+    FlagSpec {
+        short: "-h",
+        long: "",
+        arg: "",
+        description: "Display this help message",
+    },
+    FlagSpec {
+        short: "-l",
+        long: "",
+        arg: "FILE",
+        description: "Load a conversation from the specified file",
+    },
+    FlagSpec {
+        short: "-c",
+        long: "--continue",
+        arg: "TITLE",
+        description: "Continue the conversation whose title starts with TITLE",
+    },
+    FlagSpec {
+        short: "-t",
+        long: "--title",
+        arg: "TITLE",
+        description: "Title a new conversation (fails if TITLE is taken)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--json",
+        arg: "",
+        description: "Print the -i exchange as a JSON object, after a line per delta with -S",
+    },
+    FlagSpec {
+        short: "",
+        long: "--branch",
+        arg: "TITLE@N",
+        description: "Start a new conversation from the first N exchanges of TITLE",
+    },
+    FlagSpec {
+        short: "",
+        long: "--export",
+        arg: "TITLE FILE",
+        description: "Write the conversation whose title starts with TITLE to FILE",
+    },
+    FlagSpec {
+        short: "",
+        long: "--export-format",
+        arg: "FMT",
+        description: "Format for --export: txt (default), md, or json",
+    },
+    FlagSpec {
+        short: "",
+        long: "--stats",
+        arg: "",
+        description: "Report token usage and estimated cost per conversation and model",
+    },
+    FlagSpec {
+        short: "",
+        long: "--doctor",
+        arg: "",
+        description: "Check the config, keys, storage, $EDITOR, and each provider",
+    },
+    FlagSpec {
+        short: "",
+        long: "--offline",
+        arg: "",
+        description: "Skip the provider requests in --doctor",
+    },
+    FlagSpec {
+        short: "-s",
+        long: "",
+        arg: "TEXT or FILE",
+        description: "Use the specified text/file as the system prompt",
+    },
+    FlagSpec {
+        short: "",
+        long: "--prompt",
+        arg: "NAME",
+        description: "Use the system prompt saved as NAME in the prompts directory",
+    },
+    FlagSpec {
+        short: "",
+        long: "--max-tokens",
+        arg: "N",
+        description: "Cap the length of each response at N tokens",
+    },
+    FlagSpec {
+        short: "",
+        long: "--api-base",
+        arg: "URL",
+        description: "Send requests to URL instead, e.g. http://localhost:11434/v1",
+    },
+    FlagSpec {
+        short: "",
+        long: "--header",
+        arg: "HEADER",
+        description: "Add a \"Name: Value\" header to requests, e.g. for a gateway (repeatable)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--model",
+        arg: "NAME",
+        description: "Use the model NAME instead of the provider's default",
+    },
+    FlagSpec {
+        short: "",
+        long: "--max-attempts",
+        arg: "N",
+        description: "Try a failed request up to N times (default 3)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--last",
+        arg: "N",
+        description: "Send only the last N exchanges of the conversation, it's still saved whole",
+    },
+    FlagSpec {
+        short: "",
+        long: "--timeout",
+        arg: "SECS",
+        description: "Give up on a request after SECS (default 120 streaming, 60 otherwise)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--temperature",
+        arg: "T",
+        description: "Sampling temperature between 0.0 and 2.0",
+    },
+    FlagSpec {
+        short: "",
+        long: "--response-format",
+        arg: "FMT",
+        description: "text (default), or json to require a single JSON object from -i",
+    },
+    FlagSpec {
+        short: "",
+        long: "--tools",
+        arg: "FILE",
+        description: "Offer the tools described in FILE to the model, for -i",
+    },
+    FlagSpec {
+        short: "",
+        long: "--allow-shell",
+        arg: "",
+        description: "Let the model run shell commands for -i, each one confirmed first",
+    },
+    FlagSpec {
+        short: "",
+        long: "--stop",
+        arg: "SEQ",
+        description: "End the response before SEQ, \\n and \\t are unescaped (repeatable)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--prefill",
+        arg: "TEXT",
+        description: "Start the response with TEXT for the model to continue (Anthropic only)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--cache-prompt",
+        arg: "",
+        description:
+            "Cache the system prompt and first large message between turns (Anthropic only)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--rename",
+        arg: "OLD NEW",
+        description: "Rename the conversation titled OLD to NEW",
+    },
+    FlagSpec {
+        short: "",
+        long: "--archive",
+        arg: "TITLE",
+        description: "Hide the conversation TITLE from the load view",
+    },
+    FlagSpec {
+        short: "",
+        long: "--unarchive",
+        arg: "TITLE",
+        description: "Restore an archived conversation",
+    },
+    FlagSpec {
+        short: "",
+        long: "--all",
+        arg: "",
+        description: "Include archived conversations in the load view",
+    },
+    FlagSpec {
+        short: "",
+        long: "--resume-draft",
+        arg: "",
+        description: "Start with the most recently saved unsent message in the input",
+    },
+    FlagSpec {
+        short: "",
+        long: "--dewey-host",
+        arg: "HOST",
+        description: "Send directory searches to Dewey on HOST (default 127.0.0.1)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--dewey-port",
+        arg: "PORT",
+        description: "Send directory searches to Dewey on PORT (default 5051)",
+    },
+    FlagSpec {
+        short: "",
+        long: "--completions",
+        arg: "SHELL",
+        description: "Print a completion script for bash, zsh, or fish",
+    },
+];
+
 fn man() {
     println!("Usage: tllm [OPTIONS] [TEXT]");
     println!("\nOptions:");
-    println!("\t-n\t\tDo not save the file");
-    println!("\t-a API\t\tUse the specified API (anthropic, openai)");
-    println!("\t-i TEXT\t\tUse the specified text as an ad-hoc prompt");
-    println!("\t-h\t\tDisplay this help message");
-    println!("\t-l FILE\t\tLoad a conversation from the specified file");
-    println!("\t-s TEXT or FILE\t\tUse the specified text/file as the system prompt");
+    for flag in FLAGS {
+        let names = [flag.short, flag.long]
+            .iter()
+            .filter(|n| !n.is_empty())
+            .cloned()
+            .collect::<Vec<&str>>()
+            .join(", ");
+
+        println!(
+            "\t{:<28}{}",
+            format!("{} {}", names, flag.arg).trim(),
+            flag.description
+        );
+    }
+
+    println!("\nCommands:");
+    println!("\tconfig set KEY VALUE        Validate and save a config value");
+    println!("\tconfig get KEY              Print a config value");
+    println!("\tconfig list                 Print every config value");
+    println!("\tauth set PROVIDER           Save an API key read from stdin");
+    println!("\tprompts list                List the saved system prompts");
+    println!("\tprompts edit NAME           Open a saved system prompt in $EDITOR");
+}
+
+fn parse_max_tokens(value: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    let max_tokens = match value.trim().parse::<u32>() {
+        Ok(n) => n,
+        Err(_) => return Err(format!("Invalid max tokens value: {}", value).into()),
+    };
+
+    if max_tokens == 0 || max_tokens > MAX_TOKENS_LIMIT {
+        return Err(format!(
+            "Max tokens must be between 1 and {}, got {}",
+            MAX_TOKENS_LIMIT, max_tokens
+        )
+        .into());
+    }
+
+    Ok(max_tokens)
+}
+
+fn parse_last(value: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    match value.trim().parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("--last takes a positive number of exchanges, got {}", value).into()),
+    }
+}
+
+fn parse_context_budget(value: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    match value.trim().parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!(
+            "Invalid context budget {}, expected a number of tokens",
+            value
+        )
+        .into()),
+    }
+}
+
+fn parse_max_attempts(value: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    let max_attempts = match value.trim().parse::<u32>() {
+        Ok(n) => n,
+        Err(_) => return Err(format!("Invalid max attempts value: {}", value).into()),
+    };
+
+    if max_attempts == 0 || max_attempts > MAX_ATTEMPTS_LIMIT {
+        return Err(format!(
+            "Max attempts must be between 1 and {}, got {}",
+            MAX_ATTEMPTS_LIMIT, max_attempts
+        )
+        .into());
+    }
+
+    Ok(max_attempts)
+}
+
+fn parse_timeout(value: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let timeout = match value.trim().parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => return Err(format!("Invalid timeout value: {}", value).into()),
+    };
+
+    if timeout == 0 || timeout > TIMEOUT_LIMIT {
+        return Err(format!(
+            "Timeout must be between 1 and {} seconds, got {}",
+            TIMEOUT_LIMIT, timeout
+        )
+        .into());
+    }
+
+    Ok(timeout)
+}
+
+fn parse_temperature(value: &str) -> Result<f64, Box<dyn std::error::Error>> {
+    let temperature = match value.trim().parse::<f64>() {
+        Ok(t) => t,
+        Err(_) => return Err(format!("Invalid temperature value: {}", value).into()),
+    };
+
+    if !(0.0..=2.0).contains(&temperature) {
+        return Err(format!(
+            "Temperature must be between 0.0 and 2.0, got {}",
+            temperature
+        )
+        .into());
+    }
+
+    Ok(temperature)
+}
+
+// a newline is awkward to quote in most shells, so `\n`, `\t`, and `\\` are unescaped
+// anything else after a backslash is kept as typed
+fn parse_stop(value: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut stop = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            stop.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => stop.push('\n'),
+            Some('t') => stop.push('\t'),
+            Some('\\') => stop.push('\\'),
+            Some(other) => {
+                stop.push('\\');
+                stop.push(other);
+            }
+            None => stop.push('\\'),
+        }
+    }
+
+    if stop.is_empty() {
+        return Err("--stop can't be empty".into());
+    }
+
+    Ok(stop)
+}
+
+fn parse_port(value: &str) -> Result<u16, Box<dyn std::error::Error>> {
+    match value.trim().parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!("Invalid port {}, expected 1-65535", value).into()),
+    }
+}
+
+fn parse_log_level(value: &str) -> Result<logger::LogLevel, Box<dyn std::error::Error>> {
+    match logger::LogLevel::parse(value) {
+        Some(level) => Ok(level),
+        None => Err(format!(
+            "Invalid log level {}, expected one of {}",
+            value,
+            logger::LogLevel::NAMES.join(", ")
+        )
+        .into()),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    match value.trim() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("Expected true or false, got {}", value).into()),
+    }
+}
+
+// `--no-config` and `--profile`, found ahead of the rest since they decide which config is read
+fn parse_config_flags(
+    args: &[String],
+) -> Result<(bool, Option<String>), Box<dyn std::error::Error>> {
+    let mut no_config = false;
+    let mut profile = None;
+    for i in 1..args.len() {
+        match args[i].as_str() {
+            "-x" | "--no-config" => {
+                no_config = true;
+            }
+            "--profile" => {
+                if i + 1 < args.len() {
+                    profile = Some(args[i + 1].trim().to_string());
+                } else {
+                    man();
+                    return Err("API flag --profile requires a name".into());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if no_config && profile.is_some() {
+        return Err("--profile can't be combined with --no-config".into());
+    }
+
+    Ok((no_config, profile))
 }
 
-fn parse_flags() -> Result<Flags, Box<dyn std::error::Error>> {
+fn parse_flags(args: &[String]) -> Result<Flags, Box<dyn std::error::Error>> {
     let mut flags = Flags::new();
-    let args: Vec<String> = std::env::args().collect();
+
+    // these decide which config is read, and with it where conversations live,
+    // so they're applied before any titles below are resolved
+    (flags.no_config, flags.profile) = parse_config_flags(args)?;
+    if flags.no_config {
+        config::ignore();
+    }
+
+    if let Some(profile) = &flags.profile {
+        config::set_profile(profile).map_err(error::TllmError::Config)?;
+    }
 
     for i in 1..args.len() {
         match args[i].as_str() {
-            "-n" => {
-                flags.save_conversation = !flags.save_conversation;
+            "-n" | "--no-save" => {
+                flags.save_conversation = false;
+            }
+            "--prune-unsaved" => {
+                if i + 1 < args.len() {
+                    flags.prune_unsaved = match args[i + 1].trim().parse::<u64>() {
+                        Ok(days) => Some(days),
+                        Err(_) => {
+                            error!("Invalid number of days: {}", args[i + 1]);
+                            return Err("--prune-unsaved takes a number of days".into());
+                        }
+                    };
+                } else {
+                    man();
+                    return Err("API flag --prune-unsaved requires a number of days".into());
+                }
             }
             "-a" => {
                 if i + 1 < args.len() {
@@ -63,6 +750,107 @@ fn parse_flags() -> Result<Flags, Box<dyn std::error::Error>> {
                     return Err("API flag -i requires an argument".into());
                 }
             }
+            "-f" | "--file" => {
+                if i + 1 < args.len() {
+                    flags.files.push(args[i + 1].clone());
+                } else {
+                    man();
+                    return Err("API flag -f requires a filepath argument".into());
+                }
+            }
+            "--attach" => {
+                if i + 1 < args.len() {
+                    flags.attach.push(args[i + 1].clone());
+                } else {
+                    man();
+                    return Err("API flag --attach requires a path or glob argument".into());
+                }
+            }
+            "--image" => {
+                if i + 1 < args.len() {
+                    flags.images.push(args[i + 1].clone());
+                } else {
+                    man();
+                    return Err("API flag --image requires a filepath argument".into());
+                }
+            }
+            "--yes" => {
+                flags.yes = true;
+            }
+            "--paste" => {
+                flags.paste = true;
+            }
+            "--copy" => {
+                flags.copy = true;
+            }
+            "-q" | "--quiet" => {
+                flags.quiet = true;
+            }
+            "-S" | "--stream" => {
+                flags.stream = true;
+            }
+            "--output" => {
+                if i + 1 < args.len() {
+                    flags.output = Some(args[i + 1].clone());
+                } else {
+                    man();
+                    return Err("API flag --output requires a file argument".into());
+                }
+            }
+            "-v" | "--verbose" => {
+                flags.verbose += 1;
+            }
+            "-vv" => {
+                flags.verbose += 2;
+            }
+            "--max-file-size" => {
+                if i + 1 < args.len() {
+                    flags.max_file_size = match args[i + 1].trim().parse::<u64>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => {
+                            error!("Invalid max file size: {}", args[i + 1]);
+                            return Err("Max file size must be a positive number of KB".into());
+                        }
+                    };
+                } else {
+                    man();
+                    return Err("API flag --max-file-size requires an argument".into());
+                }
+            }
+            "--completions" => {
+                if i + 1 < args.len() {
+                    flags.completions = args[i + 1].clone();
+                } else {
+                    man();
+                    return Err("API flag --completions requires a shell argument".into());
+                }
+            }
+            "--stats" => {
+                flags.stats = true;
+            }
+            "--doctor" => {
+                flags.doctor = true;
+            }
+            "--offline" => {
+                flags.offline = true;
+            }
+            "--archive" | "--unarchive" => {
+                if i + 1 < args.len() {
+                    flags.archive = Some((args[i + 1].clone(), args[i] == "--archive"));
+                } else {
+                    man();
+                    return Err(format!("API flag {} requires a title argument", args[i]).into());
+                }
+            }
+            "--all" => {
+                flags.all = true;
+            }
+            "--resume-draft" => {
+                flags.resume_draft = true;
+            }
+            "--json" => {
+                flags.json = true;
+            }
             "-h" => {
                 flags.help = true;
             }
@@ -80,6 +868,80 @@ fn parse_flags() -> Result<Flags, Box<dyn std::error::Error>> {
                     return Err("API flag -l requires a filepath argument".into());
                 }
             }
+            "-c" | "--continue" => {
+                if i + 1 < args.len() {
+                    let title = match conversation::resolve(&args[i + 1]) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            return Err("No matching conversation".into());
+                        }
+                    };
+
+                    flags.load_conversation =
+                        conversation::get_path(&title).to_string_lossy().to_string();
+                } else {
+                    man();
+                    return Err("API flag -c requires a title argument".into());
+                }
+            }
+            "--branch" => {
+                if i + 1 < args.len() {
+                    let (prefix, exchanges) = match args[i + 1]
+                        .rsplit_once('@')
+                        .map(|(t, n)| (t, n.trim().parse::<usize>()))
+                    {
+                        Some((t, Ok(n))) if !t.is_empty() => (t, n),
+                        _ => {
+                            error!("Invalid branch: {}", args[i + 1]);
+                            return Err("--branch expects TITLE@N, like notes@2".into());
+                        }
+                    };
+
+                    let title = match conversation::resolve(prefix) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            return Err("No matching conversation".into());
+                        }
+                    };
+
+                    flags.branch = Some((title, exchanges));
+                } else {
+                    man();
+                    return Err("API flag --branch requires a TITLE@N argument".into());
+                }
+            }
+            "--export" => {
+                if i + 2 < args.len() {
+                    flags.export = Some((args[i + 1].clone(), args[i + 2].clone()));
+                } else {
+                    man();
+                    return Err("API flag --export requires a title and a filepath".into());
+                }
+            }
+            "--export-format" => {
+                if i + 1 < args.len() {
+                    flags.export_format = match conversation::ExportFormat::parse(&args[i + 1]) {
+                        Some(f) => f,
+                        None => {
+                            error!("Invalid export format: {}", args[i + 1]);
+                            return Err("Export format must be one of txt, md, json".into());
+                        }
+                    };
+                } else {
+                    man();
+                    return Err("API flag --export-format requires an argument".into());
+                }
+            }
+            "-t" | "--title" => {
+                if i + 1 < args.len() {
+                    flags.title = args[i + 1].clone();
+                } else {
+                    man();
+                    return Err("API flag -t requires a title argument".into());
+                }
+            }
             "-s" => {
                 if i + 1 < args.len() {
                     flags.system_prompt = args[i + 1].clone();
@@ -88,36 +950,912 @@ fn parse_flags() -> Result<Flags, Box<dyn std::error::Error>> {
                     return Err("API flag -s requires an argument".into());
                 }
             }
+            "--prompt" => {
+                if i + 1 < args.len() {
+                    flags.prompt = Some(args[i + 1].trim().to_string());
+                } else {
+                    man();
+                    return Err("API flag --prompt requires a name".into());
+                }
+            }
+            "--max-tokens" => {
+                if i + 1 < args.len() {
+                    flags.max_tokens = Some(parse_max_tokens(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --max-tokens requires an argument".into());
+                }
+            }
+            "--api-base" => {
+                if i + 1 < args.len() {
+                    flags.api_base = Some(network::parse_api_base(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --api-base requires a URL argument".into());
+                }
+            }
+            "--header" => {
+                if i + 1 < args.len() {
+                    flags.headers.push(network::parse_header(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --header requires a \"Name: Value\" argument".into());
+                }
+            }
+            "--model" => {
+                if i + 1 < args.len() {
+                    flags.model = Some(args[i + 1].trim().to_string());
+                } else {
+                    man();
+                    return Err("API flag --model requires an argument".into());
+                }
+            }
+            "--last" => {
+                if i + 1 < args.len() {
+                    flags.last = Some(parse_last(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --last requires a number of exchanges".into());
+                }
+            }
+            "--max-attempts" => {
+                if i + 1 < args.len() {
+                    flags.max_attempts = Some(parse_max_attempts(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --max-attempts requires an argument".into());
+                }
+            }
+            "--timeout" => {
+                if i + 1 < args.len() {
+                    flags.timeout = Some(parse_timeout(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --timeout requires an argument".into());
+                }
+            }
+            "--temperature" => {
+                if i + 1 < args.len() {
+                    flags.temperature = Some(parse_temperature(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --temperature requires an argument".into());
+                }
+            }
+            "--response-format" => {
+                if i + 1 < args.len() {
+                    flags.json_response = match args[i + 1].trim() {
+                        "json" => true,
+                        "text" => false,
+                        other => {
+                            return Err(format!(
+                                "Invalid response format {}, expected text or json",
+                                other
+                            )
+                            .into())
+                        }
+                    };
+                } else {
+                    man();
+                    return Err("API flag --response-format requires text or json".into());
+                }
+            }
+            "--tools" => {
+                if i + 1 < args.len() {
+                    flags.tools = Some(args[i + 1].clone());
+                } else {
+                    man();
+                    return Err("API flag --tools requires a file argument".into());
+                }
+            }
+            "--allow-shell" => {
+                flags.allow_shell = true;
+            }
+            "--stop" => {
+                if i + 1 < args.len() {
+                    flags.stop.push(parse_stop(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --stop requires a sequence".into());
+                }
+            }
+            "--cache-prompt" => {
+                flags.cache_prompt = true;
+            }
+            "--prefill" => {
+                if i + 1 < args.len() {
+                    if args[i + 1].trim().is_empty() {
+                        return Err("--prefill can't be empty".into());
+                    }
+
+                    flags.prefill = Some(args[i + 1].clone());
+                } else {
+                    man();
+                    return Err("API flag --prefill requires an argument".into());
+                }
+            }
+            "--dewey-host" => {
+                if i + 1 < args.len() {
+                    flags.dewey_host = Some(args[i + 1].trim().to_string());
+                } else {
+                    man();
+                    return Err("API flag --dewey-host requires an argument".into());
+                }
+            }
+            "--dewey-port" => {
+                if i + 1 < args.len() {
+                    flags.dewey_port = Some(parse_port(&args[i + 1])?);
+                } else {
+                    man();
+                    return Err("API flag --dewey-port requires an argument".into());
+                }
+            }
+            "--rename" => {
+                if i + 2 < args.len() {
+                    flags.rename = Some((args[i + 1].clone(), args[i + 2].clone()));
+                } else {
+                    man();
+                    return Err("API flag --rename requires two arguments".into());
+                }
+            }
             _ => (),
         }
     }
 
-    match flags.api.as_str() {
-        "anthropic" => {}
-        "openai" => {}
-        "gemini" => {}
-        "groq" => {}
-        _ => {
-            error!("Invalid API flag: {}", flags.api);
-            return Err("Invalid API".into());
-        }
+    if flags.prompt.is_some() && !flags.system_prompt.is_empty() {
+        return Err("--prompt and -s can't be used together".into());
     }
 
-    Ok(flags)
-}
+    if !flags.title.is_empty() && !flags.load_conversation.is_empty() {
+        return Err("--title only applies to new conversations".into());
+    }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let now: String = chrono::Local::now().timestamp_micros().to_string();
-    config::setup();
+    if flags.branch.is_some() && !flags.load_conversation.is_empty() {
+        return Err(
+            "--branch starts a new conversation, it can't be combined with -c or -l".into(),
+        );
+    }
 
-    let config_path = config::get_config_dir();
-    let conversations_path = config::get_conversations_dir();
+    if flags.branch.is_some() && !flags.save_conversation {
+        return Err(
+            "--branch saves the new conversation, it can't be combined with --no-save".into(),
+        );
+    }
 
-    let flags = parse_flags()?;
+    // the point of loading one is to add to it
+    if !flags.save_conversation && !flags.load_conversation.is_empty() {
+        return Err("--no-save doesn't apply to a loaded conversation".into());
+    }
+
+    if flags.quiet && !flags.is_adhoc() {
+        return Err("--quiet only applies to messages sent with -i, -f, or --paste".into());
+    }
+
+    if flags.quiet && flags.json {
+        return Err("--quiet and --json can't be used together".into());
+    }
+
+    if flags.stream && !flags.is_adhoc() {
+        return Err("--stream only applies to messages sent with -i, -f, or --paste, the chat always streams".into());
+    }
+
+    if flags.json_response && !flags.is_adhoc() {
+        return Err(
+            "--response-format json only applies to messages sent with -i, -f, or --paste".into(),
+        );
+    }
+
+    // the response is checked whole before it's printed
+    if flags.json_response && flags.stream {
+        return Err("--response-format json can't be combined with --stream".into());
+    }
+
+    let uses_tools = flags.tools.is_some() || flags.allow_shell;
+    if uses_tools && !flags.is_adhoc() {
+        return Err(
+            "--tools and --allow-shell only apply to messages sent with -i, -f, or --paste".into(),
+        );
+    }
+
+    if uses_tools && flags.stream {
+        return Err("--tools and --allow-shell can't be combined with --stream".into());
+    }
+
+    if !flags.images.is_empty() && !flags.is_adhoc() {
+        return Err("--image only applies to messages sent with -i, -f, or --paste".into());
+    }
+
+    if flags.output.is_some() && !flags.is_adhoc() {
+        return Err("--output only applies to messages sent with -i, -f, or --paste".into());
+    }
+
+    if flags.copy && !flags.is_adhoc() {
+        return Err("--copy only applies to messages sent with -i, -f, or --paste".into());
+    }
+
+    if flags.resume_draft && flags.is_adhoc() {
+        return Err("--resume-draft only applies to the interactive chat".into());
+    }
+
+    if !flags.api.is_empty() && !providers::PROVIDERS.contains(&flags.api.as_str()) {
+        error!("Invalid API flag: {}", flags.api);
+        return Err("Invalid API".into());
+    }
+
+    Ok(flags)
+}
+
+// command line flags take precedence over anything in the config file
+// `price.` takes the model name as a suffix
+const CONFIG_KEYS: &[&str] = &[
+    "max_tokens",
+    "max_attempts",
+    "timeout",
+    "max_retry_wait",
+    "temperature",
+    "proxy",
+    "history_order",
+    "no_save",
+    "auto_title",
+    "dewey_host",
+    "dewey_port",
+    "log_level",
+    "context_budget",
+    "summarize_history",
+    "json_correction",
+    "api",
+    "system_prompt",
+    "data_dir",
+    "api_base.<provider>",
+    "model.<provider>",
+    "max_tokens.<provider>",
+    "api_key.<provider>",
+    "api_key_env.<provider>",
+    "extra_headers.<provider>",
+    "price.<model>",
+];
+
+// the part after the dot in keys like `api_base.openai`
+fn get_key_suffix<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    key.strip_prefix(prefix)
+        .and_then(|k| k.strip_prefix('.'))
+        .filter(|k| !k.is_empty())
+}
+
+fn validate_config(key: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+    match key {
+        "max_tokens" => {
+            parse_max_tokens(value)?;
+        }
+        "max_attempts" => {
+            parse_max_attempts(value)?;
+        }
+        "timeout" => {
+            parse_timeout(value)?;
+        }
+        "max_retry_wait" => {
+            parse_timeout(value)?;
+        }
+        "temperature" => {
+            parse_temperature(value)?;
+        }
+        "proxy" => {
+            network::parse_proxy(value)?;
+        }
+        "no_save" | "auto_title" | "summarize_history" => {
+            parse_bool(value)?;
+        }
+        "dewey_host" => {
+            if value.trim().is_empty() {
+                return Err("The Dewey host can't be empty".into());
+            }
+        }
+        "dewey_port" => {
+            parse_port(value)?;
+        }
+        "log_level" => {
+            parse_log_level(value)?;
+        }
+        "context_budget" => {
+            parse_context_budget(value)?;
+        }
+        "api" => {
+            if !providers::PROVIDERS.contains(&value.trim()) {
+                return Err(format!(
+                    "Invalid API {}, expected one of: {}",
+                    value,
+                    providers::PROVIDERS.join(", ")
+                )
+                .into());
+            }
+        }
+        "json_correction" => {
+            if value.trim().is_empty() {
+                return Err("The JSON correction message can't be empty".into());
+            }
+        }
+        "system_prompt" => {
+            if value.trim().is_empty() {
+                return Err("The system prompt path can't be empty".into());
+            }
+        }
+        "data_dir" => {
+            if !std::path::Path::new(value.trim()).is_absolute() {
+                return Err(format!("data_dir must be an absolute path, got {}", value).into());
+            }
+        }
+        "history_order" => {
+            if conversation::HistoryOrder::parse(value).is_none() {
+                return Err(
+                    format!("Invalid history order {}, expected asc or desc", value).into(),
+                );
+            }
+        }
+        _ if get_key_suffix(key, "api_base")
+            .map(|p| providers::PROVIDERS.contains(&p))
+            .unwrap_or(false) =>
+        {
+            network::parse_api_base(value)?;
+        }
+        _ if get_key_suffix(key, "model")
+            .map(|p| providers::PROVIDERS.contains(&p))
+            .unwrap_or(false) =>
+        {
+            if value.trim().is_empty() {
+                return Err("Model names can't be empty".into());
+            }
+        }
+        _ if get_key_suffix(key, "max_tokens")
+            .map(|p| providers::PROVIDERS.contains(&p))
+            .unwrap_or(false) =>
+        {
+            parse_max_tokens(value)?;
+        }
+        _ if get_key_suffix(key, "api_key")
+            .map(|p| providers::PROVIDERS.contains(&p))
+            .unwrap_or(false) =>
+        {
+            if value.trim().is_empty() {
+                return Err("API keys can't be empty".into());
+            }
+        }
+        _ if get_key_suffix(key, "api_key_env")
+            .map(|p| providers::PROVIDERS.contains(&p))
+            .unwrap_or(false) =>
+        {
+            if value.trim().is_empty() || value.contains(['=', '\0']) {
+                return Err(format!("Invalid environment variable name {:?}", value).into());
+            }
+        }
+        _ if get_key_suffix(key, "extra_headers")
+            .map(|p| providers::PROVIDERS.contains(&p))
+            .unwrap_or(false) =>
+        {
+            network::parse_headers(value)?;
+        }
+        _ if get_key_suffix(key, "price").is_some() => {
+            if stats::parse_price(value).is_none() {
+                return Err(format!(
+                    "Invalid price {}, expected <prompt>,<completion> in USD per million tokens",
+                    value
+                )
+                .into());
+            }
+        }
+        _ => {
+            return Err(format!(
+                "Unknown config key {}, valid keys are: {}",
+                key,
+                CONFIG_KEYS.join(", ")
+            )
+            .into())
+        }
+    }
+
+    Ok(())
+}
+
+// `tllm config set <key> <value>`, `tllm config get <key>`, `tllm config list`
+fn config_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let config = config::read_config();
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        ["set", key, value] => {
+            validate_config(key, value)?;
+            config::write_config_value(key, value.trim())?;
+        }
+        ["get", key] => match config.get(key) {
+            Some(value) => println!("{}", value),
+            None => return Err(format!("{} is not set", key).into()),
+        },
+        ["list"] => {
+            let mut keys = config.keys().collect::<Vec<&String>>();
+            keys.sort();
+            for key in keys {
+                // `config get` still prints it for anyone who asks
+                if get_key_suffix(key, "api_key").is_some() {
+                    println!("{}=[hidden]", key);
+                    continue;
+                }
+
+                println!("{}={}", key, config[key]);
+            }
+        }
+        _ => {
+            man();
+            return Err("Usage: tllm config set KEY VALUE | get KEY | list".into());
+        }
+    }
+
+    Ok(())
+}
+
+// sent when a `--response-format json` response doesn't parse, `json_correction` in the config replaces it
+const DEFAULT_JSON_CORRECTION: &str =
+    "That wasn't valid JSON. Reply again with only the corrected JSON object.";
+
+// records whether the response parsed on the message itself
+fn check_json(response: &mut network::Message) -> Result<(), String> {
+    let parsed = serde_json::from_str::<serde_json::Value>(response.content.trim());
+    response.json_valid = Some(parsed.is_ok());
+
+    parsed.map(|_| ()).map_err(|e| e.to_string())
+}
+
+// `--stream`, each delta is flushed so a pipe sees it right away
+// with `json` each is a `{"delta": ...}` line instead, for whatever's reading to parse as they come
+// if stdout is closed, e.g. by `| head`, the request is cancelled and what arrived is kept
+// also returns whether that happened, so nothing else is written to it
+// Ctrl+C stops it the same way without waiting on the provider, a second one quits outright
+fn stream_response(
+    api: &str,
+    system_prompt: &str,
+    chat_history: &[network::Message],
+    options: &network::Options,
+    json: bool,
+    stdout: &mut impl Write,
+) -> Result<(network::Message, bool), error::TllmError> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let interrupted = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    {
+        let cancel = cancel.clone();
+        let interrupted = interrupted.clone();
+        if let Err(e) = ctrlc::set_handler(move || {
+            if interrupted.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+
+            cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        }) {
+            error!("Failed to set the Ctrl+C handler: {}", e);
+        }
+    }
+
+    let handle = {
+        let api = api.to_string();
+        let system_prompt = system_prompt.to_string();
+        let chat_history = chat_history.to_vec();
+        let options = options.clone();
+        let cancel = cancel.clone();
+        // the message comes back through the join, the end of the stream is reported over `tx` first
+        std::thread::spawn(move || {
+            let response = providers::get_provider(&api).stream(
+                &system_prompt,
+                &chat_history,
+                &options,
+                tx.clone(),
+                cancel,
+            );
+
+            let (event, message) = match response {
+                Ok(message) => (
+                    network::StreamEvent::Done(message.usage.clone()),
+                    Some(message),
+                ),
+                Err(e) => (network::StreamEvent::Error(e), None),
+            };
+            if let Err(e) = tx.send(event) {
+                error!("error reporting the end of the stream: {}", e);
+            }
+
+            message
+        })
+    };
+
+    let mut closed = false;
+    let mut received = String::new();
+    // `None` if the channel hung up without either, i.e. the thread panicked
+    let mut ended = None;
+    loop {
+        // the request can be stuck waiting on the provider, so what's arrived is kept without it
+        if interrupted.load(std::sync::atomic::Ordering::Relaxed) {
+            info!("interrupted, keeping {} characters", received.len());
+            eprintln!(
+                "\nCancelled, keeping what arrived so far. Ctrl+C again to quit without saving"
+            );
+
+            let mut response = network::Message::new(network::MessageType::Assistant, received);
+            response.truncated = true;
+            response.provider = Some(api.to_string());
+            response.model = Some(options.get_model(api));
+
+            return Ok((response, closed));
+        }
+
+        let event = match rx.recv_timeout(std::time::Duration::from_millis(100)) {
+            Ok(event) => event,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+
+        let delta = match event {
+            network::StreamEvent::Delta(delta) => delta,
+            network::StreamEvent::Done(_) => {
+                ended = Some(Ok(()));
+                break;
+            }
+            network::StreamEvent::Error(e) => {
+                ended = Some(Err(e));
+                break;
+            }
+            network::StreamEvent::Started | network::StreamEvent::Summary(..) => continue,
+        };
+
+        received.push_str(&delta);
+        if closed {
+            continue;
+        }
+
+        let output = match json {
+            true => format!("{}\n", serde_json::json!({ "delta": delta })),
+            false => delta,
+        };
+        if let Err(e) = stdout
+            .write_all(output.as_bytes())
+            .and_then(|_| stdout.flush())
+        {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                info!("stdout was closed, cancelling the stream");
+            } else {
+                error!("Failed to write the response: {}", e);
+                eprintln!("Couldn't print the response: {}", e);
+            }
+
+            cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+            closed = true;
+        }
+    }
+
+    match (ended, handle.join()) {
+        (Some(Err(e)), _) => Err(e),
+        (Some(Ok(())), Ok(Some(response))) => Ok((response, closed)),
+        _ => Err(error::TllmError::Provider(
+            "The streaming thread panicked".to_string(),
+        )),
+    }
+}
+
+// no echo when typed at a terminal, otherwise the first line of stdin
+fn read_secret(prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    use crossterm::event::{read, Event, KeyCode, KeyEventKind, KeyModifiers};
+    use std::io::IsTerminal;
+
+    if !std::io::stdin().is_terminal() {
+        let mut secret = String::new();
+        std::io::stdin().read_line(&mut secret)?;
+        return Ok(secret.trim().to_string());
+    }
+
+    eprint!("{}", prompt);
+    crossterm::terminal::enable_raw_mode()?;
+    let mut secret = String::new();
+    let result = loop {
+        let key = match read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(e) => break Err(e.into()),
+        };
+
+        match key.code {
+            KeyCode::Enter => break Ok(secret.trim().to_string()),
+            KeyCode::Esc => break Err("Cancelled, nothing was saved".into()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                break Err("Cancelled, nothing was saved".into())
+            }
+            KeyCode::Backspace => {
+                secret.pop();
+            }
+            KeyCode::Char(c) => secret.push(c),
+            _ => {}
+        }
+    };
+
+    crossterm::terminal::disable_raw_mode()?;
+    eprintln!();
+    result
+}
+
+// `tllm auth set <provider>`, the key is read from stdin so it stays out of shell history and `ps`
+fn auth_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let api = match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        ["set", api] if providers::PROVIDERS.contains(&api) => api,
+        ["set", api] => {
+            return Err(format!(
+                "Unknown provider {}, expected one of: {}",
+                api,
+                providers::PROVIDERS.join(", ")
+            )
+            .into())
+        }
+        _ => {
+            man();
+            return Err("Usage: tllm auth set PROVIDER".into());
+        }
+    };
+
+    let key = read_secret(&format!("API key for {}: ", api))?;
+    if key.is_empty() {
+        return Err("No key given, nothing was saved".into());
+    }
+
+    if keychain::AVAILABLE {
+        keychain::set_key(api, &key)?;
+        println!("Saved the {} key to the system keychain", api);
+    } else {
+        config::write_config_value(&format!("api_key.{}", api), &key)?;
+        config::restrict_permissions(&config::get_config_file())?;
+        println!(
+            "Saved the {} key to {}, build with --features keyring to use the system keychain instead",
+            api,
+            config::get_config_file().display()
+        );
+    }
+
+    Ok(())
+}
+
+// `tllm prompts list`, `tllm prompts edit <name>`
+fn prompts_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        ["list"] => {
+            for name in prompts::list() {
+                println!("{}", name);
+            }
+        }
+        ["edit", name] => prompts::edit(name)?,
+        _ => {
+            man();
+            return Err("Usage: tllm prompts list | edit NAME".into());
+        }
+    }
+
+    Ok(())
+}
+
+fn merge_with_config(flags: &mut Flags) -> Result<(), Box<dyn std::error::Error>> {
+    // unlike the flat file, anything wrong in config.toml stops us with where it is
+    for entry in config::read_toml_config()? {
+        validate_config(&entry.key, &entry.value)
+            .map_err(|e| format!("{}: {}", entry.location, e))?;
+    }
+
+    let config = config::read_config();
+
+    // $TLLM_LOG wins over the config file
+    match std::env::var("TLLM_LOG") {
+        Ok(value) => {
+            Logger::set_level(parse_log_level(&value).map_err(|e| format!("TLLM_LOG: {}", e))?)
+        }
+        Err(_) => {
+            if let Some(value) = config.get("log_level") {
+                Logger::set_level(parse_log_level(value)?);
+            }
+        }
+    }
+
+    // config.toml was checked above, so anything left is from the flat files
+    for path in config::get_flat_config_files() {
+        for (key, value) in config::read_flat_config(&path) {
+            if let Err(e) = validate_config(&key, &value) {
+                eprintln!("{}: {}", path.display(), e);
+            }
+        }
+    }
+
+    apply_config(flags, &config)
+}
+
+// flags given on the command line win over the config
+fn apply_config(
+    flags: &mut Flags,
+    config: &std::collections::HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if flags.max_tokens.is_none() {
+        if let Some(value) = config
+            .get(&format!("max_tokens.{}", flags.api))
+            .or_else(|| config.get("max_tokens"))
+        {
+            flags.max_tokens = Some(parse_max_tokens(value)?);
+        }
+    }
+
+    if flags.max_attempts.is_none() {
+        if let Some(value) = config.get("max_attempts") {
+            flags.max_attempts = Some(parse_max_attempts(value)?);
+        }
+    }
+
+    if let Some(value) = config.get("context_budget") {
+        flags.context_budget = Some(parse_context_budget(value)?);
+    }
+
+    if flags.timeout.is_none() {
+        if let Some(value) = config.get("timeout") {
+            flags.timeout = Some(parse_timeout(value)?);
+        }
+    }
+
+    if let Some(value) = config.get("proxy") {
+        network::parse_proxy(value)?;
+        flags.proxy = Some(value.clone());
+    }
+
+    if let Some(value) = config.get("max_retry_wait") {
+        flags.max_retry_wait = Some(parse_timeout(value)?);
+    }
+
+    if flags.api_base.is_none() {
+        if let Some(value) = config.get(&format!("api_base.{}", flags.api)) {
+            flags.api_base = Some(network::parse_api_base(value)?);
+        }
+    }
+
+    if flags.model.is_none() {
+        flags.model = config.get(&format!("model.{}", flags.api)).cloned();
+    }
+
+    if flags.temperature.is_none() {
+        if let Some(value) = config.get("temperature") {
+            flags.temperature = Some(parse_temperature(value)?);
+        }
+    }
+
+    if flags.dewey_host.is_none() {
+        flags.dewey_host = config.get("dewey_host").map(|h| h.trim().to_string());
+    }
+
+    if flags.dewey_port.is_none() {
+        if let Some(value) = config.get("dewey_port") {
+            flags.dewey_port = Some(parse_port(value)?);
+        }
+    }
+
+    // loaded conversations are always saved
+    if let Some(value) = config.get("no_save") {
+        if parse_bool(value)? && flags.load_conversation.is_empty() {
+            flags.save_conversation = false;
+        }
+    }
+
+    Ok(())
+}
+
+// `TllmError`s get their own exit codes, anything else exits like a plain `main` would
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Err(e) = run(&args, &mut std::io::stdout()) {
+        match e.downcast_ref::<error::TllmError>() {
+            Some(e) => {
+                eprintln!("{}", e);
+                std::process::exit(e.exit_code());
+            }
+            None => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// `stdout` is where the -i response goes, everything else prints as usual
+fn run(args: &[String], stdout: &mut impl Write) -> Result<(), Box<dyn std::error::Error>> {
+    let now: String = chrono::Local::now().timestamp_micros().to_string();
+    config::setup();
+
+    if args.get(1).map(|a| a.as_str()) == Some("config") {
+        return config_command(&args[2..]);
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("auth") {
+        return auth_command(&args[2..]);
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("prompts") {
+        return prompts_command(&args[2..]);
+    }
+
+    let config_path = config::get_config_dir();
+
+    let mut flags = parse_flags(args)?;
+    if flags.api.is_empty() {
+        flags.api = config::read_config()
+            .get("api")
+            .map(|api| api.trim().to_string())
+            .filter(|api| providers::PROVIDERS.contains(&api.as_str()))
+            .unwrap_or_else(|| DEFAULT_API.to_string());
+    }
+
+    // before the config is merged, a broken config is one of the things it reports
+    if flags.doctor {
+        return doctor::run(&flags.api, flags.api_base.clone(), flags.offline);
+    }
+
+    merge_with_config(&mut flags)?;
+
+    Logger::set_stderr_level(match flags.verbose {
+        0 => None,
+        1 => Some(logger::LogLevel::Warn),
+        _ => Some(logger::LogLevel::Info),
+    });
+
+    // read once up front so a bad image fails before anything is sent
+    let images = flags
+        .images
+        .iter()
+        .map(|path| image::check(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut tools = match &flags.tools {
+        Some(path) => tools::load(path)?,
+        None => Vec::new(),
+    };
+    if flags.allow_shell {
+        tools.push(tools::shell());
+    }
+
+    let options = network::Options {
+        max_tokens: flags.max_tokens,
+        max_attempts: flags.max_attempts,
+        timeout: flags.timeout,
+        proxy: flags.proxy.clone(),
+        api_base: flags.api_base.clone(),
+        model: flags.model.clone(),
+        max_retry_wait: flags.max_retry_wait,
+        last: flags.last,
+        context_budget: flags.context_budget,
+        // the TUI owns the terminal, so it only gets the log
+        print_notes: flags.is_adhoc() && !flags.json,
+        temperature: flags.temperature,
+        stop: flags.stop.clone(),
+        prefill: flags.prefill.clone(),
+        json_response: flags.json_response,
+        tools,
+        cache_prompt: flags.cache_prompt,
+        headers: flags.headers.clone(),
+    };
 
     let system_prompt = match flags.system_prompt.len() {
+        // read below, it's saved along with its name
+        0 if flags.prompt.is_some() => String::new(),
         0 => {
-            let system_prompt_path = config_path.join("system_prompt");
+            // relative paths in the config are from the config directory
+            let system_prompt_path = match config::read_config().get("system_prompt") {
+                Some(path) => {
+                    let path = config_path.join(path.trim());
+                    if !path.exists() {
+                        return Err(error::TllmError::Config(format!(
+                            "The system prompt {} set in the config doesn't exist",
+                            path.display()
+                        ))
+                        .into());
+                    }
+
+                    path
+                }
+                None => config_path.join("system_prompt"),
+            };
+
             if system_prompt_path.exists() {
                 match std::fs::read_to_string(system_prompt_path) {
                     Ok(s) => s,
@@ -146,93 +1884,907 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    let system_prompt = match &flags.prompt {
+        Some(name) => conversation::SystemPrompt {
+            content: prompts::load(name)?,
+            name: Some(name.clone()),
+        },
+        None => conversation::SystemPrompt::new(system_prompt),
+    };
+
     if flags.help {
         man();
         return Ok(());
     }
 
-    match flags.api.as_str() {
-        "anthropic" => match std::env::var("ANTHROPIC_API_KEY") {
-            Ok(_) => (),
-            Err(_) => panic!("ANTHROPIC_API_KEY environment variable not set"),
-        },
-        "openai" => match std::env::var("OPENAI_API_KEY") {
-            Ok(_) => (),
-            Err(_) => panic!("OPENAI_API_KEY environment variable not set"),
-        },
-        "gemini" => match std::env::var("GEMINI_API_KEY") {
-            Ok(_) => (),
-            Err(_) => panic!("GEMINI_API_KEY environment variable not set"),
-        },
-        "groq" => match std::env::var("GROQ_API_KEY") {
-            Ok(_) => (),
-            Err(_) => panic!("GROQ_API_KEY environment variable not set"),
-        },
-        _ => {}
+    if let Some((old_title, new_title)) = &flags.rename {
+        if let Err(e) = conversation::rename(old_title, new_title) {
+            eprintln!("{}", e);
+            return Err("Failed to rename conversation".into());
+        }
+
+        println!("Renamed {} to {}", old_title, new_title);
+        return Ok(());
+    }
+
+    if let Some(days) = flags.prune_unsaved {
+        let titles = conversation::list_single_exchanges(days)?;
+        if titles.is_empty() {
+            println!("No single-message conversations inactive for {} days", days);
+            return Ok(());
+        }
+
+        for title in titles.iter() {
+            println!("\t{}", title);
+        }
+
+        if !flags.yes {
+            eprint!("Delete these {} conversations? [y/N] ", titles.len());
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                return Err("Nothing was deleted".into());
+            }
+        }
+
+        for title in titles.iter() {
+            std::fs::remove_file(conversation::get_path(title))?;
+        }
+
+        println!("Deleted {} conversations", titles.len());
+        return Ok(());
+    }
+
+    if let Some((title, archived)) = &flags.archive {
+        match conversation::set_archived(title, *archived) {
+            Ok(new_title) if *archived => println!("Archived {} as {}", title, new_title),
+            Ok(new_title) => println!("Restored {} as {}", title, new_title),
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err("Failed to update the archive".into());
+            }
+        }
+
+        return Ok(());
+    }
+
+    if !flags.completions.is_empty() {
+        match completions::generate(&flags.completions) {
+            Some(script) => print!("{}", script),
+            None => return Err("Completions are available for bash, zsh, and fish".into()),
+        }
+
+        return Ok(());
+    }
+
+    if flags.stats {
+        print!("{}", stats::report()?);
+        return Ok(());
+    }
+
+    if let Some((prefix, destination)) = &flags.export {
+        let exported = match conversation::resolve(prefix).and_then(|t| {
+            conversation::export(&t, flags.export_format, conversation::get_history_order())
+        }) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err("Failed to export conversation".into());
+            }
+        };
+
+        std::fs::write(destination, exported)?;
+        println!("Conversation exported to {}", destination);
+        return Ok(());
+    }
+
+    // custom API bases are usually local servers without keys
+    if flags.api_base.is_none()
+        && providers::get_provider(&flags.api).needs_key()
+        && providers::resolve_api_key(&flags.api).is_none()
+    {
+        return Err(providers::missing_api_key(&flags.api).into());
+    }
+
+    let new_title = if flags.title.is_empty() {
+        now.clone()
+    } else {
+        flags.title.clone()
+    };
+
+    let new_conversation_path = match conversation::get_new_path(&new_title) {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err("Invalid conversation title".into());
+        }
+    };
+
+    // from here on the branch is just a loaded conversation
+    if let Some((title, exchanges)) = &flags.branch {
+        match conversation::branch(title, *exchanges, &new_title) {
+            Ok(path) => {
+                eprintln!(
+                    "Branched {} after {} exchanges into {}",
+                    title, exchanges, new_title
+                );
+                flags.load_conversation = path.to_string_lossy().to_string();
+                flags.save_conversation = true;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err("Failed to branch the conversation".into());
+            }
+        }
     }
 
-    if flags.adhoc.len() > 0 {
-        let adhoc = if std::path::PathBuf::from(&flags.adhoc).exists() {
-            std::fs::read_to_string(flags.adhoc.clone())?
+    let max_file_size = flags
+        .max_file_size
+        .unwrap_or(attachment::DEFAULT_MAX_SIZE_KB);
+    let attachments = attachment::expand_all(&flags.attach, max_file_size)?;
+    if !attachments.is_empty() {
+        attachment::confirm(&attachments, flags.yes)?;
+    }
+
+    if flags.is_adhoc() {
+        // kept whole for the draft, front matter included
+        // only what was typed is expanded, not the clipboard or files sent along with it
+        let mut text = template::expand(&flags.adhoc, max_file_size)?;
+        if flags.paste {
+            match clipboard::get() {
+                Ok(contents) if text.is_empty() => text = contents,
+                Ok(contents) => text = format!("{}\n\n{}", text, contents),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+
+        let message = attachment::build_message(&text, &flags.files, max_file_size)?;
+        if message.trim().is_empty() {
+            return Err(error::TllmError::Config("There's nothing to send".to_string()).into());
+        }
+
+        let (front_matter, adhoc) = match front_matter::parse(&message) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                if let Ok(path) = draft::save(&message) {
+                    eprintln!("Your message was saved to {}", path.display());
+                }
+
+                return Err(error::TllmError::Config(e).into());
+            }
+        };
+
+        for warning in front_matter.warnings.iter() {
+            eprintln!("{}", warning);
+        }
+
+        // below the front matter so it still has to come first
+        let adhoc = if attachments.is_empty() {
+            adhoc
+        } else {
+            attachment::prepend(&attachments, &adhoc)
+        };
+
+        let mut api = flags.api.clone();
+        let mut options = options.clone();
+        front_matter.apply(&mut api, &mut options);
+
+        // a custom API base can be serving anything, so it's left to the server to refuse
+        if !images.is_empty()
+            && options.api_base.is_none()
+            && !image::supports_vision(&options.get_model(&api))
+        {
+            return Err(error::TllmError::Config(format!(
+                "{} doesn't take images, pick a vision model with --model",
+                options.get_model(&api)
+            ))
+            .into());
+        }
+
+        let (new_title, new_conversation_path) = match &front_matter.title {
+            Some(title) if flags.load_conversation.is_empty() => {
+                match conversation::get_new_path(title) {
+                    Ok(p) => (title.clone(), p.to_string_lossy().to_string()),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return Err("Invalid conversation title".into());
+                    }
+                }
+            }
+            Some(_) => {
+                eprintln!("Ignoring the front matter title, it only applies to new conversations");
+                (new_title, new_conversation_path)
+            }
+            None => (new_title, new_conversation_path),
+        };
+
+        let (stored_system_prompt, mut chat_history) = if flags.load_conversation.is_empty() {
+            (None, Vec::new())
         } else {
-            flags.adhoc.clone()
+            conversation::split_system_prompt(serde_json::from_str(&std::fs::read_to_string(
+                &flags.load_conversation,
+            )?)?)
         };
 
-        let mut chat_history = vec![network::Message::new(network::MessageType::User, adhoc)];
+        // `-s` and `--prompt` replace whatever the conversation was started with
+        let system_prompt = conversation::choose_system_prompt(
+            stored_system_prompt,
+            &system_prompt,
+            flags.overrides_system_prompt(),
+            max_file_size,
+        )?;
 
-        let response = network::prompt(&flags.api, &system_prompt, &chat_history)?;
-        let content = response.content.replace("\\n", "\n");
+        let mut user_message = network::Message::new(network::MessageType::User, adhoc.clone());
+        user_message.created_at = Some(chrono::Local::now());
+        user_message.images = images.clone();
+        chat_history.push(user_message);
 
-        println!("{}\n\n", content);
+        // a failed summary isn't worth losing the message over, the request still trims what doesn't fit
+        if summary::is_enabled() {
+            if let Err(e) =
+                summary::update(&system_prompt.content, &mut chat_history, &api, &options)
+            {
+                error!("Failed to summarize the conversation: {}", e);
+                eprintln!("Couldn't summarize the older messages: {}", e);
+            }
+        }
 
+        let started_at = chrono::Local::now();
+        let response = match flags.stream {
+            true => stream_response(
+                &api,
+                &system_prompt.content,
+                &chat_history,
+                &options,
+                flags.json,
+                stdout,
+            ),
+            false => providers::get_provider(&api)
+                .complete(&system_prompt.content, &chat_history, &options)
+                .map(|response| (response, false)),
+        };
+
+        // a stream that breaks off is still saved, with the error reported once it is
+        let mut interrupted = None;
+        let (mut response, stdout_closed) = match response {
+            Ok(response) => response,
+            Err(e) if e.is_partial() => {
+                let mut response = e.get_partial().unwrap();
+                response.provider = Some(api.clone());
+                response.model = Some(options.get_model(&api));
+                interrupted = Some(e);
+                (response, false)
+            }
+            Err(e) => {
+                error!("Failed to get a response: {}", e);
+                match draft::save(&message) {
+                    Ok(path) => eprintln!("Your message was saved to {}", path.display()),
+                    Err(e) => {
+                        error!("Failed to save draft: {}", e);
+                    }
+                }
+
+                return Err(e.into());
+            }
+        };
+
+        // tools run until the model answers, one it asks for that tllm can't run ends it early
+        // calls left hanging still get a result saved, the APIs won't take a conversation without one
+        let mut rounds = 0;
+        let mut stopped = None;
+        let mut gave_up = false;
+        let mut pending_results = Vec::new();
+        // the follow-up with the results failed, they're saved without an answer
+        let mut unanswered = None;
+        // calls tllm can't run, for whatever ran tllm to act on
+        let mut handed_back = Vec::new();
+        while !response.tool_calls.is_empty() {
+            if response
+                .tool_calls
+                .iter()
+                .any(|call| !tools::can_run(call, flags.allow_shell))
+            {
+                // stdout only has room for them in the `--json` object
+                handed_back = response.tool_calls.clone();
+                if !flags.json {
+                    for call in handed_back.iter() {
+                        eprintln!("{}", serde_json::to_string(call)?);
+                    }
+                }
+
+                stopped = Some("tllm can't run this tool, the call was handed back to the user");
+            } else if rounds == tools::MAX_ROUNDS {
+                stopped = Some("tllm stopped running tools here");
+                gave_up = true;
+            }
+
+            let mut results = Vec::new();
+            for call in response.tool_calls.iter() {
+                let output = match stopped {
+                    Some(reason) => reason.to_string(),
+                    None => tools::run(call, flags.allow_shell).unwrap_or_default(),
+                };
+
+                let mut result = network::Message::new(network::MessageType::Tool, output);
+                result.tool_call_id = Some(call.id.clone());
+                result.created_at = Some(chrono::Local::now());
+                results.push(result);
+            }
+
+            // saved after the response, like the rest of the exchange
+            if stopped.is_some() {
+                pending_results = results;
+                break;
+            }
+
+            let result_count = results.len();
+            chat_history.push(response);
+            chat_history.extend(results);
+
+            rounds += 1;
+            response = match providers::get_provider(&api).complete(
+                &system_prompt.content,
+                &chat_history,
+                &options,
+            ) {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Failed to send the tool results: {}", e);
+
+                    // taken back out to be saved like a stopped round
+                    pending_results = chat_history.split_off(chat_history.len() - result_count);
+                    let response = chat_history.pop().unwrap();
+                    unanswered = Some(e);
+                    response
+                }
+            };
+
+            if unanswered.is_some() {
+                break;
+            }
+        }
+
+        // an invalid response gets one correction, after that it's saved and printed as it is
+        // and tllm exits with an error so scripts don't take it as JSON
+        // a cut-off response was never going to parse, so it's left as it is
+        let mut invalid_json = None;
+        if flags.json_response && stopped.is_none() && unanswered.is_none() && !response.truncated {
+            if let Err(e) = check_json(&mut response) {
+                eprintln!(
+                    "The response isn't valid JSON ({}), asking for a correction",
+                    e
+                );
+
+                let correction = config::read_config()
+                    .get("json_correction")
+                    .cloned()
+                    .unwrap_or_else(|| DEFAULT_JSON_CORRECTION.to_string());
+                let mut correction = network::Message::new(
+                    network::MessageType::User,
+                    format!("{}\n\nParse error: {}", correction, e),
+                );
+                correction.created_at = Some(chrono::Local::now());
+
+                chat_history.push(response);
+                chat_history.push(correction);
+
+                match providers::get_provider(&api).complete(
+                    &system_prompt.content,
+                    &chat_history,
+                    &options,
+                ) {
+                    Ok(corrected) => {
+                        response = corrected;
+                        if let Err(e) = check_json(&mut response) {
+                            invalid_json = Some(e);
+                        }
+                    }
+                    // the first response is kept instead, already marked as invalid
+                    Err(correction_error) => {
+                        error!("Failed to get a corrected response: {}", correction_error);
+                        eprintln!(
+                            "Couldn't get a correction ({}), keeping the first response",
+                            correction_error
+                        );
+
+                        chat_history.pop();
+                        response = chat_history.pop().unwrap();
+                        invalid_json = Some(e);
+                    }
+                }
+            }
+        }
+
+        let finished_at = chrono::Local::now();
+        let content = response.content.clone();
+
+        // everything else already goes to stderr
+        // the summary object ends a `--json` stream
+        if flags.stream && !flags.json {
+            // the response is already out, this only ends it
+            // anything reading from a pipe gets just the text
+            let end = match flags.quiet || !std::io::stdout().is_terminal() {
+                true if content.ends_with('\n') => "",
+                true => "\n",
+                false => "\n\n\n",
+            };
+
+            if !stdout_closed {
+                let _ = stdout
+                    .write_all(end.as_bytes())
+                    .and_then(|_| stdout.flush());
+            }
+        } else if flags.quiet {
+            writeln!(stdout, "{}", content)?;
+        } else if !flags.json {
+            writeln!(stdout, "{}\n\n", content)?;
+        }
+
+        if let Some(path) = &flags.output {
+            if let Err(e) = std::fs::write(path, &content) {
+                eprintln!("Couldn't write the response to {}: {}", path, e);
+            }
+        }
+
+        if flags.copy {
+            if let Err(e) = clipboard::set(&content) {
+                eprintln!("{}", e);
+            }
+        }
+
+        let mut saved_title = None;
         if flags.save_conversation {
             chat_history.push(response);
+            chat_history.extend(pending_results);
 
-            let messages_json = serde_json::to_string(&chat_history).unwrap();
-            let destination = conversations_path.join(now.clone());
-            let destination = match destination.to_str() {
-                Some(s) => format!("{}.json", s),
-                _ => panic!(
-                    "Failed to convert path to string: {:?} + {:?}",
-                    conversations_path, now
-                ),
+            let messages_json = serde_json::to_string(&conversation::join_system_prompt(
+                &system_prompt,
+                &chat_history,
+            ))
+            .unwrap();
+            let destination = if !flags.load_conversation.is_empty() {
+                flags.load_conversation.clone()
+            } else {
+                new_conversation_path
             };
 
             match std::fs::write(destination.clone(), messages_json) {
                 Ok(_) => {
                     info!("Conversation saved to {}", destination);
+                    saved_title = std::path::Path::new(&destination)
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().to_string());
+
+                    if flags.load_conversation.is_empty() {
+                        // titles given with -t or front matter are kept
+                        let title = if new_title == now && title::is_enabled() {
+                            title::apply(&new_title, &api, &options)
+                        } else {
+                            new_title.clone()
+                        };
+
+                        match config::get_profile() {
+                            Some(profile) => eprintln!(
+                                "New conversation started with title {} (profile {})",
+                                title, profile
+                            ),
+                            None => eprintln!("New conversation started with title {}", title),
+                        }
+                        saved_title = Some(title);
+                    }
                 }
                 Err(e) => {
                     info!("Error saving messages: {}", e);
                 }
             }
         }
+
+        if flags.json && !stdout_closed {
+            let output = serde_json::json!({
+                "title": saved_title,
+                "message": adhoc,
+                "response": content,
+                "provider": api,
+                "model": options.get_model(&api),
+                "started_at": started_at.to_rfc3339(),
+                "finished_at": finished_at.to_rfc3339(),
+                "tool_calls": handed_back,
+            });
+
+            writeln!(stdout, "{}", output)?;
+        }
+
+        if let Some(e) = interrupted {
+            return Err(e.into());
+        }
+
+        if let Some(e) = unanswered {
+            match flags.save_conversation {
+                true => eprintln!(
+                    "The tools ran but sending their results failed, the calls and results were saved with the conversation"
+                ),
+                false => match draft::save(&message) {
+                    Ok(path) => eprintln!("Your message was saved to {}", path.display()),
+                    Err(e) => {
+                        error!("Failed to save draft: {}", e);
+                    }
+                },
+            }
+
+            return Err(e.into());
+        }
+
+        if gave_up {
+            return Err(error::TllmError::Provider(format!(
+                "Stopped after {} rounds of tool calls without an answer",
+                rounds
+            ))
+            .into());
+        }
+
+        if let Some(e) = invalid_json {
+            return Err(error::TllmError::Provider(format!(
+                "The response isn't valid JSON: {}",
+                e
+            ))
+            .into());
+        }
     } else {
+        let draft = match flags.resume_draft {
+            true => match draft::latest()? {
+                Some(path) => Some(path),
+                None => return Err("There are no saved drafts to resume".into()),
+            },
+            false => None,
+        };
+
         let save_path = if std::path::Path::new(&flags.load_conversation).exists() {
-            flags.load_conversation
+            flags.load_conversation.clone()
         } else if !flags.save_conversation {
             String::new()
         } else {
-            let destination = conversations_path.join(now.clone());
-            match destination.to_str() {
-                Some(s) => format!("{}.json", s),
-                _ => panic!(
-                    "Failed to convert path to string: {:?} + {:?}",
-                    conversations_path, now
-                ),
-            }
+            new_conversation_path.clone()
         };
 
-        match display::display_manager(
-            display::WindowView::Chat,
-            &system_prompt,
-            &flags.api,
-            save_path,
-        ) {
-            Ok(_) => {}
-            Err(e) => panic!("error in display manager: {}", e),
+        let default_dewey = network::DeweyAddress::default();
+        let dewey = network::DeweyAddress {
+            host: flags.dewey_host.clone().unwrap_or(default_dewey.host),
+            port: flags.dewey_port.unwrap_or(default_dewey.port),
         };
+
+        display::display_manager(
+            display::WindowView::Chat,
+            &display::SessionOptions {
+                system_prompt: &system_prompt,
+                override_system_prompt: flags.overrides_system_prompt(),
+                show_archived: flags.all,
+                max_file_size,
+                api: &flags.api,
+                options: &options,
+                dewey: &dewey,
+            },
+            save_path.clone(),
+            draft,
+            attachments,
+        )?;
+
+        // the chat keeps saving to the path it was given, so this waits until it's closed
+        if save_path == new_conversation_path
+            && new_title == now
+            && std::path::Path::new(&save_path).exists()
+            && title::is_enabled()
+        {
+            eprintln!(
+                "New conversation saved with title {}",
+                title::apply(&new_title, &flags.api, &options)
+            );
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn get_args(args: &[&str]) -> Vec<String> {
+        std::iter::once("tllm")
+            .chain(args.iter().copied())
+            .map(|a| a.to_string())
+            .collect()
+    }
+
+    fn get_config(entries: &[(&str, &str)]) -> std::collections::HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    // `-x` switches the config off for the whole process, so it's only looked for here
+    #[test]
+    fn no_config() {
+        for flag in ["-x", "--no-config"] {
+            let (no_config, profile) = parse_config_flags(&get_args(&[flag, "-i", "hi"])).unwrap();
+            assert!(no_config);
+            assert_eq!(profile, None);
+        }
+
+        assert!(parse_config_flags(&get_args(&["-x", "--profile", "work"])).is_err());
+    }
+
+    #[test]
+    fn config_values() {
+        let config = get_config(&[
+            ("temperature", "0.3"),
+            ("max_tokens", "99"),
+            ("no_save", "true"),
+        ]);
+
+        let mut flags = parse_flags(&get_args(&["-i", "hi"])).unwrap();
+        apply_config(&mut flags, &config).unwrap();
+        assert_eq!(flags.temperature, Some(0.3));
+        assert_eq!(flags.max_tokens, Some(99));
+        assert!(!flags.save_conversation);
+
+        // what `-x` leaves it with
+        let mut flags = parse_flags(&get_args(&["-i", "hi"])).unwrap();
+        apply_config(&mut flags, &get_config(&[])).unwrap();
+        assert_eq!(flags.temperature, None);
+        assert_eq!(flags.max_tokens, None);
+        assert!(flags.save_conversation);
+
+        // flags still win over the config
+        let mut flags = parse_flags(&get_args(&["-i", "hi", "--temperature", "0.5"])).unwrap();
+        apply_config(&mut flags, &config).unwrap();
+        assert_eq!(flags.temperature, Some(0.5));
+    }
+
+    // the message is kept as a draft and nothing is written to the conversation it was for
+    #[test]
+    fn failed_request() {
+        testing::setup();
+        std::fs::create_dir_all(config::get_conversations_dir()).unwrap();
+        std::fs::create_dir_all(config::get_drafts_dir()).unwrap();
+
+        let loaded = conversation::get_path("failed-request-loaded");
+        let history = r#"[{"message_type":"User","content":"hi"},{"message_type":"Assistant","content":"hello"}]"#;
+        std::fs::write(&loaded, history).unwrap();
+        let loaded = loaded.to_string_lossy().to_string();
+
+        let cases = [
+            (
+                "a new one\n\twith \"quotes\"",
+                vec!["-t", "failed-request-new"],
+            ),
+            ("a loaded one", vec!["-l", &loaded]),
+        ];
+        for (message, flags) in cases {
+            let server = testing::FakeServer::start(vec![testing::Response::json(
+                "500 Internal Server Error",
+                &serde_json::json!({ "error": { "message": "down" } }),
+            )]);
+            let api_base = format!("http://127.0.0.1:{}", server.port);
+
+            let mut args = vec![
+                "-a",
+                "openai",
+                "--api-base",
+                &api_base,
+                "--max-attempts",
+                "1",
+            ];
+            args.extend(flags);
+            args.extend(["-i", message]);
+            assert!(run(&get_args(&args), &mut Vec::new()).is_err());
+            assert_eq!(server.requests().len(), 1);
+
+            let draft = draft::latest().unwrap().unwrap();
+            assert_eq!(std::fs::read_to_string(draft).unwrap(), message);
+        }
+
+        assert!(!conversation::get_path("failed-request-new").exists());
+        assert_eq!(std::fs::read_to_string(&loaded).unwrap(), history);
+    }
+
+    fn get_output(args: &[&str]) -> Vec<serde_json::Value> {
+        let mut stdout = Vec::new();
+        run(&get_args(args), &mut stdout).unwrap();
+
+        String::from_utf8(stdout)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn stream_json() {
+        testing::setup();
+        let args = [
+            "-a",
+            "mock",
+            "--model",
+            "upper",
+            "-n",
+            "-S",
+            "--json",
+            "-i",
+            "one two three",
+        ];
+        let output = get_output(&args);
+
+        let deltas = output[..3]
+            .iter()
+            .map(|event| event["delta"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(deltas, ["ONE ", "TWO ", "THREE"]);
+
+        let summary = &output[3];
+        assert_eq!(output.len(), 4);
+        assert_eq!(summary["message"], "one two three");
+        assert_eq!(summary["response"], "ONE TWO THREE");
+        assert_eq!(summary["provider"], "mock");
+        assert_eq!(summary["model"], "upper");
+        assert!(summary["title"].is_null());
+    }
+
+    #[test]
+    fn json() {
+        testing::setup();
+        let output = get_output(&["-a", "mock", "-n", "--json", "-i", "one two"]);
+
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0]["response"], "one two");
+        assert_eq!(output[0]["model"], "echo");
+    }
+
+    // a call tllm can't run goes in the `--json` object, or to stderr, never as extra lines on stdout
+    #[test]
+    fn handed_back_tool_calls() {
+        testing::setup();
+        let tools = std::env::temp_dir().join(format!("tllm-tools-{}.json", std::process::id()));
+        std::fs::write(
+            &tools,
+            r#"[{"name": "get_weather", "description": "The weather", "parameters": {"type": "object"}}]"#,
+        )
+        .unwrap();
+        let tools = tools.to_string_lossy().to_string();
+
+        let reply = serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "get_weather", "arguments": "{\"city\":\"Oslo\"}" }
+            }] } }]
+        });
+
+        for json in [true, false] {
+            let server =
+                testing::FakeServer::start(vec![testing::Response::json("200 OK", &reply)]);
+            let api_base = format!("http://127.0.0.1:{}", server.port);
+
+            let mut args = vec![
+                "-a",
+                "openai",
+                "--api-base",
+                &api_base,
+                "--max-attempts",
+                "1",
+            ];
+            args.extend(["--tools", &tools, "-n", "-i", "weather?"]);
+            match json {
+                true => args.push("--json"),
+                false => args.push("-q"),
+            }
+
+            let mut stdout = Vec::new();
+            run(&get_args(&args), &mut stdout).unwrap();
+            let stdout = String::from_utf8(stdout).unwrap();
+
+            if json {
+                assert_eq!(stdout.lines().count(), 1);
+                let output: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+                assert_eq!(output["tool_calls"][0]["name"], "get_weather");
+                assert_eq!(output["tool_calls"][0]["arguments"]["city"], "Oslo");
+            } else {
+                assert_eq!(stdout, "\n");
+            }
+        }
+    }
+
+    fn run_mock(args: &[&str]) -> String {
+        testing::setup();
+        let mut stdout = Vec::new();
+        run(&get_args(&[&["-a", "mock"], args].concat()), &mut stdout).unwrap();
+
+        String::from_utf8(stdout).unwrap()
+    }
+
+    #[test]
+    fn mock_saved() {
+        let stdout = run_mock(&[
+            "--model",
+            "upper",
+            "-t",
+            "mock-saved",
+            "-q",
+            "-i",
+            "hello there",
+        ]);
+        assert_eq!(stdout, "HELLO THERE\n");
+
+        let messages = conversation::load("mock-saved").unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "hello there");
+        assert_eq!(messages[1].content, "HELLO THERE");
+        assert_eq!(messages[1].provider.as_deref(), Some("mock"));
+        assert_eq!(messages[1].model.as_deref(), Some("upper"));
+        assert!(messages[1].usage.is_some());
+    }
+
+    // piped or quiet, it's the response and a newline, with nothing around it
+    #[test]
+    fn mock_stream_quiet() {
+        let stdout = run_mock(&["--model", "reverse", "-n", "-S", "-q", "-i", "ab cd"]);
+        assert_eq!(stdout, "dc ba\n");
+
+        // one that already ends with a newline doesn't get another
+        let stdout = run_mock(&["-n", "-S", "-q", "-i", "ab\n"]);
+        assert_eq!(stdout, "ab\n");
+    }
+
+    #[test]
+    fn mock_no_save() {
+        let stdout = run_mock(&["-n", "-t", "mock-unsaved", "-i", "hi"]);
+        assert_eq!(stdout, "hi\n\n\n");
+        assert!(!conversation::get_path("mock-unsaved").exists());
+    }
+
+    #[test]
+    fn mock_export() {
+        run_mock(&["-t", "mock-export", "-q", "-i", "hi there"]);
+
+        let destination =
+            std::env::temp_dir().join(format!("tllm-export-{}.json", std::process::id()));
+        let destination = destination.to_string_lossy().to_string();
+        run_mock(&[
+            "--export",
+            "mock-export",
+            &destination,
+            "--export-format",
+            "json",
+        ]);
+
+        let exported: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&destination).unwrap()).unwrap();
+        assert_eq!(exported[0]["title"], "mock-export");
+        let contents = exported[0]["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["hi there", "hi there"]);
+    }
+
+    #[test]
+    fn unknown_config_keys() {
+        assert!(validate_config("temperature", "0.3").is_ok());
+        assert!(validate_config("colour", "red").is_err());
+    }
+
+    #[test]
+    fn stop_escapes() {
+        assert_eq!(parse_stop("\\n\\nHuman:").unwrap(), "\n\nHuman:");
+        assert_eq!(parse_stop("a\\tb").unwrap(), "a\tb");
+        assert_eq!(parse_stop("C:\\\\dir").unwrap(), "C:\\dir");
+        // anything else is kept as typed
+        assert_eq!(parse_stop("\\d+\\").unwrap(), "\\d+\\");
+        // a real newline, e.g. from `$'\n'`, goes through untouched
+        assert_eq!(parse_stop("\nEND").unwrap(), "\nEND");
+        assert!(parse_stop("").is_err());
+    }
+}
@@ -0,0 +1,256 @@
+// the server behind `-a mock`, on loopback so its requests take the same path through `network` as any provider's
+use std::io::{BufRead, Write};
+
+use crate::logger::Logger;
+use crate::{error, info, tokens};
+
+const MODELS: &[&str] = &["echo", "upper", "reverse"];
+
+// started by the first request and left running until tllm exits
+pub fn get_port() -> u16 {
+    static PORT: std::sync::OnceLock<u16> = std::sync::OnceLock::new();
+    *PORT.get_or_init(|| {
+        let listener = match std::net::TcpListener::bind("127.0.0.1:0") {
+            Ok(listener) => listener,
+            Err(e) => {
+                // port 0 refuses the connection, which is reported like any other
+                error!("failed to start the mock server: {}", e);
+                return 0;
+            }
+        };
+
+        let port = listener.local_addr().map(|a| a.port()).unwrap_or(0);
+        info!("mock server listening on 127.0.0.1:{}", port);
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || serve(stream));
+            }
+        });
+
+        port
+    })
+}
+
+struct MockRequest {
+    headers: Vec<String>,
+    body: serde_json::Value,
+}
+
+// `None` once the connection is closed or sent something that isn't a request
+fn read_request(reader: &mut impl BufRead) -> Option<MockRequest> {
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        headers.push(line);
+    }
+
+    let length = get_header(&headers, "content-length")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(MockRequest {
+        headers,
+        body: serde_json::from_slice(&body).unwrap_or_default(),
+    })
+}
+
+fn get_header(headers: &[String], name: &str) -> Option<String> {
+    headers.iter().find_map(|h| {
+        h.split_once(':')
+            .filter(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim().to_string())
+    })
+}
+
+// `X-Mock-Delay-Ms` and `X-Mock-Fail`, filled in from `TLLM_MOCK_DELAY_MS` and `TLLM_MOCK_FAIL`
+fn get_settings(headers: &[String]) -> Result<(std::time::Duration, Option<usize>), String> {
+    let delay = match get_header(headers, "x-mock-delay-ms") {
+        Some(value) => value.parse::<u64>().map_err(|_| {
+            format!(
+                "TLLM_MOCK_DELAY_MS should be a number of milliseconds, not {}",
+                value
+            )
+        })?,
+        None => 0,
+    };
+
+    let fail_after = match get_header(headers, "x-mock-fail") {
+        Some(value) => Some(value.parse::<usize>().map_err(|_| {
+            format!(
+                "TLLM_MOCK_FAIL should be the number of words to send before failing, not {}",
+                value
+            )
+        })?),
+        None => None,
+    };
+
+    Ok((std::time::Duration::from_millis(delay), fail_after))
+}
+
+// the last user message, transformed by the model, along with token counts for the usage
+fn get_response(body: &serde_json::Value) -> Result<serde_json::Value, String> {
+    let model = body["model"].as_str().unwrap_or_default();
+    let messages = body["messages"].as_array().cloned().unwrap_or_default();
+    let last = messages
+        .iter()
+        .rev()
+        .find(|m| m["role"] == "user")
+        .and_then(|m| m["content"].as_str())
+        .unwrap_or_default();
+
+    let content = match model {
+        "echo" => last.to_string(),
+        "upper" => last.to_uppercase(),
+        "reverse" => last.chars().rev().collect(),
+        model => {
+            return Err(format!(
+                "The mock provider has no model {}, try one of {}",
+                model,
+                MODELS.join(", ")
+            ))
+        }
+    };
+
+    let prompt = messages
+        .iter()
+        .filter_map(|m| m["content"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(serde_json::json!({
+        "content": content,
+        "prompt_tokens": tokens::count(&prompt, model),
+        "completion_tokens": tokens::count(&content, model),
+    }))
+}
+
+fn write_json(
+    stream: &mut std::net::TcpStream,
+    status: &str,
+    body: &serde_json::Value,
+) -> std::io::Result<()> {
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn write_error(
+    stream: &mut std::net::TcpStream,
+    status: &str,
+    message: &str,
+) -> std::io::Result<()> {
+    write_json(
+        stream,
+        status,
+        &serde_json::json!({ "error": { "message": message } }),
+    )
+}
+
+// `{"delta": ...}` a word to a chunk, then `{"done": true, ...}` with the token counts
+// `Ok(false)` when it was cut off on purpose and the connection has to close
+fn write_stream(
+    stream: &mut std::net::TcpStream,
+    response: &serde_json::Value,
+    delay: std::time::Duration,
+    fail_after: Option<usize>,
+) -> std::io::Result<bool> {
+    let mut lines = response["content"]
+        .as_str()
+        .unwrap_or_default()
+        .split_inclusive(char::is_whitespace)
+        .map(|word| format!("{}\n", serde_json::json!({ "delta": word })))
+        .collect::<Vec<_>>();
+    lines.push(format!(
+        "{}\n",
+        serde_json::json!({
+            "done": true,
+            "prompt_tokens": response["prompt_tokens"],
+            "completion_tokens": response["completion_tokens"],
+        })
+    ));
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n\r\n"
+    )?;
+    stream.flush()?;
+
+    for (i, line) in lines.iter().enumerate() {
+        if fail_after == Some(i) {
+            info!("mock stream failing after {} words", i);
+            return Ok(false);
+        }
+
+        std::thread::sleep(delay);
+        write!(stream, "{:x}\r\n{}\r\n", line.len(), line)?;
+        stream.flush()?;
+    }
+
+    write!(stream, "0\r\n\r\n")?;
+    Ok(true)
+}
+
+// requests are answered in turn until either side closes the connection
+fn serve(mut stream: std::net::TcpStream) {
+    let mut reader = match stream.try_clone() {
+        Ok(clone) => std::io::BufReader::new(clone),
+        Err(e) => {
+            error!("mock server failed to read a connection: {}", e);
+            return;
+        }
+    };
+
+    while let Some(request) = read_request(&mut reader) {
+        let (delay, fail_after) = match get_settings(&request.headers) {
+            Ok(settings) => settings,
+            Err(e) => match write_error(&mut stream, "400 Bad Request", &e) {
+                Ok(_) => continue,
+                Err(_) => return,
+            },
+        };
+
+        let response = match get_response(&request.body) {
+            Ok(response) => response,
+            Err(e) => match write_error(&mut stream, "404 Not Found", &e) {
+                Ok(_) => continue,
+                Err(_) => return,
+            },
+        };
+
+        let written = match request.body["stream"].as_bool().unwrap_or(false) {
+            true => write_stream(&mut stream, &response, delay, fail_after),
+            false => {
+                std::thread::sleep(delay);
+                match fail_after {
+                    Some(_) => write_error(
+                        &mut stream,
+                        "500 Internal Server Error",
+                        "failed on purpose, TLLM_MOCK_FAIL is set",
+                    )
+                    .map(|_| true),
+                    None => write_json(&mut stream, "200 OK", &response).map(|_| true),
+                }
+            }
+        };
+
+        if !matches!(written, Ok(true)) {
+            return;
+        }
+    }
+}
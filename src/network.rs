@@ -1,17 +1,20 @@
-use native_tls::TlsStream;
 use std::env;
 use std::io::BufRead;
 use std::io::{Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
 
+use crate::error::TllmError;
 use crate::logger::Logger;
-use crate::{error, info};
+use crate::providers;
+use crate::{debug, error, info, trace};
 
 #[derive(PartialEq, Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub enum MessageType {
     System,
     User,
     Assistant,
+    // the output of a tool the assistant called, see `tools`
+    Tool,
 }
 
 impl MessageType {
@@ -20,14 +23,63 @@ impl MessageType {
             MessageType::System => "system".to_string(),
             MessageType::User => "user".to_string(),
             MessageType::Assistant => "assistant".to_string(),
+            MessageType::Tool => "tool".to_string(),
         }
     }
 }
 
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // anthropic's prompt caching, counted apart from `prompt_tokens`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub cache_creation_tokens: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub cache_read_tokens: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message {
     pub message_type: MessageType,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    // the response was cut short by the user
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    // left unset for system prompts and on messages saved before this was tracked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<chrono::DateTime<chrono::Local>>,
+    // on the last message copied by `--branch`, the title of the conversation it came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branched_from: Option<String>,
+    // on the system prompt, the name it was picked by with `--prompt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    // on the last message a rolling summary covers, the summary of everything up to here
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    // on a response to `--response-format json`, whether it parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_valid: Option<bool>,
+    // on an assistant message, the tools it asked to have run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    // on a tool message, the call it answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    // on a user message, absolute paths of the images sent with it, read again for every request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
 }
 
 impl Message {
@@ -35,10 +87,29 @@ impl Message {
         Self {
             message_type,
             content,
+            usage: None,
+            provider: None,
+            model: None,
+            truncated: false,
+            created_at: None,
+            branched_from: None,
+            prompt: None,
+            summary: None,
+            json_valid: None,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
         }
     }
 }
 
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
 // these two are copied from Dewey
 // this should really be all integrated into a single project
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -59,218 +130,519 @@ pub struct DeweyResponse {
     pub results: Vec<DeweyResponseItem>,
 }
 
+// where the directory view sends its searches, set with `dewey_host`/`dewey_port`
 #[derive(Clone, Debug)]
-struct RequestParams {
-    provider: String,
-    host: String,
-    path: String,
-    port: u16,
-    messages: Vec<Message>,
-    model: String,
-    stream: bool,
-    authorization_token: String,
-    max_tokens: Option<u16>,
-    system_prompt: Option<String>,
-}
-
-fn build_request(params: &RequestParams) -> String {
-    let body = match params.provider.as_str() {
-        "openai" => serde_json::json!({
-            "model": params.model,
-            "messages": params.messages.iter()
-                .map(|message| {
-                    serde_json::json!({
-                        "role": message.message_type.to_string(),
-                        "content": message.content
-                    })
-                }).collect::<Vec<serde_json::Value>>(),
-            "stream": params.stream,
-        }),
-        "groq" => serde_json::json!({
-            "model": params.model,
-            "messages": params.messages.iter()
-                .map(|message| {
-                    serde_json::json!({
-                        "role": message.message_type.to_string(),
-                        "content": message.content
-                    })
-                }).collect::<Vec<serde_json::Value>>(),
-            "stream": params.stream,
-        }),
-        "anthropic" => serde_json::json!({
-            "model": params.model,
-            "messages": params.messages.iter().map(|message| {
-                serde_json::json!({
-                    "role": message.message_type.to_string(),
-                    "content": message.content
-                })
-            }).collect::<Vec<serde_json::Value>>(),
-            "stream": params.stream,
-            "max_tokens": params.max_tokens.unwrap(),
-            "system": params.system_prompt.clone().unwrap(),
-        }),
-        "gemini" => serde_json::json!({
-            "contents": params.messages.iter().map(|m| {
-                serde_json::json!({
-                    "parts": [{
-                        "text": m.content
-                    }],
-                    "role": match m.message_type {
-                        MessageType::User => "user",
-                        MessageType::Assistant => "model",
-                        _ => panic!("what is happening")
-                    }
-                })
-            }).collect::<Vec<_>>(),
-            "systemInstruction": {
-                "parts": [{
-                    "text": params.system_prompt,
-                }]
-            }
-        }),
-        _ => panic!("Invalid provider for request_body: {}", params.provider),
+pub struct DeweyAddress {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for DeweyAddress {
+    fn default() -> Self {
+        DeweyAddress {
+            host: "127.0.0.1".to_string(),
+            port: 5051,
+        }
+    }
+}
+
+impl std::fmt::Display for DeweyAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+// searches are local, anything slower than this means Dewey is stuck
+pub const DEWEY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// per-request knobs set from the command line and config file
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f64>,
+    pub max_attempts: Option<u32>,
+    pub timeout: Option<u64>,
+    pub proxy: Option<String>,
+    pub api_base: Option<ApiBase>,
+    pub model: Option<String>,
+    // upper bound in seconds on how long a `Retry-After` can make us wait
+    pub max_retry_wait: Option<u64>,
+    // only the last this many exchanges are sent, see `tokens::get_omitted`
+    pub last: Option<usize>,
+    // in tokens, for the system prompt and history together
+    pub context_budget: Option<u64>,
+    // retries and trimmed history are always logged, this also prints them to stderr
+    pub print_notes: bool,
+    // the response ends before any of these
+    pub stop: Vec<String>,
+    // the start of the response, for the model to carry on from
+    pub prefill: Option<String>,
+    // `--response-format json`, asks for a single JSON object
+    pub json_response: bool,
+    // offered to the model, see `tools`
+    pub tools: Vec<crate::tools::Tool>,
+    // `--cache-prompt`, marks what's resent every turn for anthropic to cache
+    pub cache_prompt: bool,
+    // `--header`, sent to whichever provider after its `extra_headers`
+    pub headers: Vec<String>,
+}
+
+impl Options {
+    pub fn get_model(&self, api: &str) -> String {
+        self.model
+            .clone()
+            .unwrap_or_else(|| providers::get_provider(api).default_model().to_string())
+    }
+
+    // for titles and summaries, which go to the same provider but aren't one of the conversation's replies
+    pub fn for_side_request(&self) -> Options {
+        Options {
+            print_notes: false,
+            stop: Vec::new(),
+            prefill: None,
+            json_response: false,
+            tools: Vec::new(),
+            cache_prompt: false,
+            ..self.clone()
+        }
+    }
+}
+
+// where to send requests instead of the provider's own endpoint
+// e.g. `http://localhost:11434/v1` for ollama
+#[derive(Clone, Debug)]
+pub struct ApiBase {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+pub fn parse_api_base(url: &str) -> Result<ApiBase, String> {
+    let (tls, rest) = match url.trim().split_once("://") {
+        Some(("https", rest)) => (true, rest),
+        Some(("http", rest)) => (false, rest),
+        _ => {
+            return Err(format!(
+                "Invalid API base {}, expected http:// or https://",
+                url
+            ))
+        }
     };
 
-    let json = serde_json::json!(body);
-    let json_string = serde_json::to_string(&json).expect("Failed to serialize JSON");
+    let (address, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+        None => (rest, ""),
+    };
 
-    let (auth_string, api_version, path) = match params.provider.as_str() {
-        "openai" => (
-            format!("Authorization: Bearer {}\r\n", params.authorization_token),
-            "\r\n".to_string(),
-            params.path.clone(),
-        ),
-        "groq" => (
-            format!("Authorization: Bearer {}\r\n", params.authorization_token),
-            "\r\n".to_string(),
-            params.path.clone(),
-        ),
-        "anthropic" => (
-            format!("x-api-key: {}\r\n", params.authorization_token),
-            "anthropic-version: 2023-06-01\r\n\r\n".to_string(),
-            params.path.clone(),
-        ),
-        "gemini" => (
-            "\r\n".to_string(),
-            "\r\n".to_string(),
-            format!("{}?key={}", params.path, params.authorization_token),
-        ),
-        _ => panic!("Invalid provider: {}", params.provider),
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => return Err(format!("Invalid port in API base {}", url)),
+        },
+        None => (address, if tls { 443 } else { 80 }),
+    };
+
+    if host.is_empty() {
+        return Err(format!("Missing host in API base {}", url));
+    }
+
+    Ok(ApiBase {
+        tls,
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+// written by `build_request` itself
+const RESERVED_HEADERS: &[&str] = &[
+    "host",
+    "content-type",
+    "content-length",
+    "accept",
+    "accept-encoding",
+    "connection",
+    "transfer-encoding",
+];
+
+// `Name: Value`, from `--header` or `extra_headers.<provider>`
+// a line break in either would let a value start headers or a body of its own
+pub fn parse_header(header: &str) -> Result<String, String> {
+    if header.contains(['\r', '\n']) {
+        return Err(format!("Header {:?} can't contain line breaks", header));
+    }
+
+    let (name, value) = match header.split_once(':') {
+        Some((name, value)) => (name.trim(), value.trim()),
+        None => return Err(format!("Invalid header {:?}, expected Name: Value", header)),
+    };
+
+    let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if name.is_empty() || !name.chars().all(is_token) {
+        return Err(format!("Invalid header name {:?}", name));
+    }
+
+    if RESERVED_HEADERS.contains(&name.to_lowercase().as_str()) {
+        return Err(format!("{} is set by tllm itself", name));
+    }
+
+    Ok(format!("{}: {}", name, value))
+}
+
+// one header a line, written as `\n` in the flat config file
+pub fn parse_headers(value: &str) -> Result<Vec<String>, String> {
+    value
+        .replace("\\n", "\n")
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_header)
+        .collect()
+}
+
+// names like `Authorization` or `X-Api-Key` are masked wherever headers are logged
+pub fn is_secret_header(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ["key", "token", "authorization"]
+        .iter()
+        .any(|part| name.contains(part))
+}
+
+fn redact_headers(headers: &[String]) -> Vec<String> {
+    headers
+        .iter()
+        .map(|header| match header.split_once(':') {
+            Some((name, _)) if is_secret_header(name) => format!("{}: [redacted]", name),
+            _ => header.clone(),
+        })
+        .collect()
+}
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_MAX_RETRY_WAIT: u64 = 60;
+const RETRY_BASE_DELAY_MS: u64 = 500;
+
+// seconds, long enough for a slow model to finish a long answer
+const DEFAULT_STREAM_TIMEOUT: u64 = 120;
+const DEFAULT_TIMEOUT: u64 = 60;
+
+// a non-2xx response from the provider
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub reason: String,
+    pub body: String,
+    pub retry_after: Option<std::time::Duration>,
+}
+
+impl HttpError {
+    pub fn get_message(&self) -> String {
+        get_error_message(&self.body)
+    }
+}
+
+// every provider nests the details under `error`, with `message` and either `type` or `status`
+// openrouter has a numeric `code` instead, and `metadata` with whatever it passed along
+pub fn get_error_message(body: &str) -> String {
+    let json: serde_json::Value = match serde_json::from_str(body) {
+        Ok(json) => json,
+        Err(_) => return body.trim().to_string(),
+    };
+
+    // ollama's is just the message
+    if let Some(message) = json["error"].as_str() {
+        return message.to_string();
+    }
+
+    let error = &json["error"];
+    let kind = error["type"].as_str().or(error["status"].as_str());
+    let mut message = match (error["message"].as_str(), kind) {
+        (Some(message), Some(kind)) => format!("{} ({})", message, kind),
+        (Some(message), None) => message.to_string(),
+        _ => return body.trim().to_string(),
     };
 
+    // moderation refusals list why the input was flagged
+    let metadata = &error["metadata"];
+    if let Some(reasons) = metadata["reasons"].as_array() {
+        let reasons = reasons
+            .iter()
+            .filter_map(|r| r.as_str())
+            .collect::<Vec<&str>>();
+        if !reasons.is_empty() {
+            message.push_str(&format!(", flagged for {}", reasons.join(", ")));
+        }
+    }
+
+    // errors from the model's own provider, relayed as they came
+    if let Some(provider) = metadata["provider_name"].as_str() {
+        message.push_str(&format!(", from {}", provider));
+        if let Some(raw) = metadata["raw"].as_str() {
+            message.push_str(&format!(": {}", get_error_message(raw)));
+        }
+    }
+
+    message
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.reason, self.get_message())
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+// what's left of a 429 once the retries run out
+#[derive(Debug)]
+pub struct RateLimitError {
+    pub attempts: u32,
+    pub error: HttpError,
+}
+
+impl std::fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "rate limited after {} attempts: {}",
+            self.attempts,
+            self.error.get_message()
+        )
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+// header names are case-insensitive, and some servers and proxies send them lowercased
+fn get_header(headers: &[String], name: &str) -> Option<String> {
+    headers.iter().find_map(|h| {
+        h.split_once(':')
+            .filter(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim().to_string())
+    })
+}
+
+// `retry-after-ms` is anthropic/openai specific and more precise than the standard header
+// only the seconds form of `retry-after` is handled, not HTTP dates
+fn get_retry_after(headers: &[String]) -> Option<std::time::Duration> {
+    if let Some(ms) = get_header(headers, "retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        return Some(std::time::Duration::from_millis(ms.max(0.0) as u64));
+    }
+
+    get_header(headers, "retry-after")
+        .and_then(|v| v.parse::<f64>().ok())
+        .map(|secs| std::time::Duration::from_millis((secs.max(0.0) * 1000.0) as u64))
+}
+
+// a stream that failed after some of the response was already delivered
+#[derive(Debug)]
+pub struct PartialResponse {
+    pub message: Message,
+    pub error: std::io::Error,
+}
+
+impl std::fmt::Display for PartialResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "stream failed after {} characters: {}",
+            self.message.content.len(),
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for PartialResponse {}
+
+// connection failures, timeouts, rate limits, and server errors are worth another try
+// client errors and partially delivered streams are not
+fn is_retryable(e: &std::io::Error) -> bool {
+    if let Some(inner) = e.get_ref() {
+        if let Some(http) = inner.downcast_ref::<HttpError>() {
+            return http.status == 429 || http.status >= 500;
+        }
+
+        if inner.is::<PartialResponse>() {
+            return false;
+        }
+    }
+
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+// socket timeouts surface as `WouldBlock` on some platforms and `TimedOut` on others
+fn describe_timeout(mut e: std::io::Error, timeout: std::time::Duration) -> std::io::Error {
+    if let Some(partial) = e
+        .get_mut()
+        .and_then(|inner| inner.downcast_mut::<PartialResponse>())
+    {
+        let error = std::mem::replace(&mut partial.error, std::io::ErrorKind::Other.into());
+        partial.error = describe_timeout(error, timeout);
+        return e;
+    }
+
+    match e.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("request timed out after {}s", timeout.as_secs()),
+        ),
+        _ => e,
+    }
+}
+
+// exponential backoff with up to 50% jitter
+fn get_retry_delay(attempt: u32) -> std::time::Duration {
+    let delay = RETRY_BASE_DELAY_MS * 2u64.pow(attempt - 1);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+
+    std::time::Duration::from_millis(delay + nanos % (delay / 2 + 1))
+}
+
+fn with_retries<T>(
+    options: &Options,
+    mut request: impl FnMut() -> Result<T, std::io::Error>,
+) -> Result<T, std::io::Error> {
+    let max_attempts = options.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1);
+    let max_retry_wait =
+        std::time::Duration::from_secs(options.max_retry_wait.unwrap_or(DEFAULT_MAX_RETRY_WAIT));
+    let mut attempt = 1;
+    loop {
+        match request() {
+            Ok(response) => return Ok(response),
+            Err(e) if attempt < max_attempts && is_retryable(&e) => {
+                let rate_limit = e
+                    .get_ref()
+                    .and_then(|inner| inner.downcast_ref::<HttpError>())
+                    .filter(|http| http.status == 429);
+
+                let delay = match rate_limit.and_then(|http| http.retry_after) {
+                    Some(retry_after) => std::cmp::min(retry_after, max_retry_wait),
+                    None => get_retry_delay(attempt),
+                };
+
+                error!(
+                    "attempt {}/{} failed: {}, retrying in {}ms",
+                    attempt,
+                    max_attempts,
+                    e,
+                    delay.as_millis()
+                );
+
+                if options.print_notes {
+                    if rate_limit.is_some() {
+                        eprintln!(
+                            "rate limited, retrying in {}s...",
+                            delay.as_secs_f64().ceil()
+                        );
+                    } else {
+                        eprintln!("{}, retrying in {}s...", e, delay.as_secs_f64().ceil());
+                    }
+                }
+
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => {
+                let is_rate_limit = e
+                    .get_ref()
+                    .and_then(|inner| inner.downcast_ref::<HttpError>())
+                    .map(|http| http.status == 429)
+                    .unwrap_or(false);
+
+                if !is_rate_limit {
+                    return Err(e);
+                }
+
+                let error = *e.into_inner().unwrap().downcast::<HttpError>().unwrap();
+                return Err(std::io::Error::other(RateLimitError {
+                    attempts: attempt,
+                    error,
+                }));
+            }
+        }
+    }
+}
+
+// a provider-agnostic request, see `providers` for how these get built
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub provider: String,
+    pub model: String,
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    pub headers: Vec<String>,
+    pub body: serde_json::Value,
+}
+
+fn build_request(request: &Request) -> String {
+    let json_string = serde_json::to_string(&request.body).expect("Failed to serialize JSON");
+
+    let mut headers = String::new();
+    for header in request.headers.iter() {
+        headers.push_str(header);
+        headers.push_str("\r\n");
+    }
+
     format!(
         "POST {} HTTP/1.1\r\n\
         Host: {}\r\n\
         Content-Type: application/json\r\n\
         Content-Length: {}\r\n\
         Accept: */*\r\n\
+        Accept-Encoding: identity\r\n\
+        Connection: keep-alive\r\n\
         {}\
-        {}\
+        \r\n\
         {}",
-        path,
-        params.host,
-        json_string.len(),
-        auth_string,
-        if api_version == "\r\n" && auth_string == "\r\n" {
-            String::new()
-        } else {
-            api_version
+        request.path,
+        match request.port {
+            80 | 443 => request.host.clone(),
+            port => format!("{}:{}", request.host, port),
         },
-        json_string.trim()
+        json_string.len(),
+        headers,
+        json_string
     )
 }
 
-fn get_openai_request_params(
-    system_prompt: String,
-    chat_history: &Vec<Message>,
-    stream: bool,
-) -> RequestParams {
-    RequestParams {
-        provider: "openai".to_string(),
-        host: "api.openai.com".to_string(),
-        path: "/v1/chat/completions".to_string(),
-        port: 443,
-        messages: vec![Message::new(MessageType::System, system_prompt.clone())]
-            .iter()
-            .chain(chat_history.iter())
-            .cloned()
-            .collect::<Vec<Message>>(),
-        model: "gpt-4o-mini".to_string(),
-        stream,
-        authorization_token: env::var("OPENAI_API_KEY")
-            .expect("OPENAI_API_KEY environment variable not set"),
-        max_tokens: None,
-        system_prompt: None,
-    }
-}
-
-// this is basically a copy of the openai_request_params
-fn get_groq_request_params(
-    system_prompt: String,
-    chat_history: &Vec<Message>,
-    stream: bool,
-) -> RequestParams {
-    RequestParams {
-        provider: "groq".to_string(),
-        host: "api.groq.com".to_string(),
-        path: "/openai/v1/chat/completions".to_string(),
-        port: 443,
-        messages: vec![Message::new(MessageType::System, system_prompt.clone())]
-            .iter()
-            .chain(chat_history.iter())
-            .cloned()
-            .collect::<Vec<Message>>(),
-        model: "llama-3.2-90b-text-preview".to_string(),
-        stream,
-        authorization_token: env::var("GROQ_API_KEY")
-            .expect("GRQO_API_KEY environment variable not set"),
-        max_tokens: None,
-        system_prompt: None,
-    }
-}
-
-fn get_anthropic_request_params(
-    system_prompt: String,
-    chat_history: &Vec<Message>,
-    stream: bool,
-) -> RequestParams {
-    RequestParams {
-        provider: "anthropic".to_string(),
-        host: "api.anthropic.com".to_string(),
-        path: "/v1/messages".to_string(),
-        port: 443,
-        messages: chat_history.iter().cloned().collect::<Vec<Message>>(),
-        model: "claude-3-5-sonnet-latest".to_string(),
-        stream,
-        authorization_token: env::var("ANTHROPIC_API_KEY")
-            .expect("ANTHROPIC_API_KEY environment variable not set"),
-        max_tokens: Some(4096),
-        system_prompt: Some(system_prompt),
-    }
-}
-
-fn get_gemini_request_params(
-    system_prompt: String,
-    chat_history: &Vec<Message>,
-    stream: bool,
-) -> RequestParams {
-    RequestParams {
-        provider: "gemini".to_string(),
-        host: "generativelanguage.googleapis.com".to_string(),
-        path: "/v1beta/models/gemini-1.5-flash-latest:generateContent".to_string(),
-        port: 443,
-        messages: chat_history.iter().cloned().collect::<Vec<Message>>(),
-        model: String::new(),
-        stream,
-        authorization_token: env::var("GEMINI_API_KEY")
-            .expect("GEMINI_API_KEY environment variable not set"),
-        max_tokens: Some(4096),
-        system_prompt: Some(system_prompt),
-    }
-}
-
-fn send_delta(tx: &std::sync::mpsc::Sender<String>, delta: String) {
-    match tx.send(delta.clone()) {
+// what a provider makes of a single line of an SSE stream
+pub enum StreamLine {
+    Delta(String),
+    Skip,
+    Done,
+}
+
+// what the thread running a streamed request sends back
+pub enum StreamEvent {
+    // the provider answered and its response is on its way
+    Started,
+    Delta(String),
+    // the response is complete, with its usage if the provider reported any
+    Done(Option<Usage>),
+    // a rolling summary was stored on the message at this index, see `summary::update`
+    Summary(usize, String),
+    // the request failed, a partial error keeps what was streamed before it
+    Error(TllmError),
+}
+
+fn send_delta(tx: &std::sync::mpsc::Sender<StreamEvent>, delta: String) {
+    match tx.send(StreamEvent::Delta(delta)) {
         Ok(_) => {}
         Err(e) => {
             error!("error sending transmission error string: {}", e);
@@ -278,291 +650,852 @@ fn send_delta(tx: &std::sync::mpsc::Sender<String>, delta: String) {
     };
 }
 
-fn process_openai_stream(
-    stream: TlsStream<TcpStream>,
-    tx: &std::sync::mpsc::Sender<String>,
-) -> Result<String, std::io::Error> {
-    info!("processing openai stream");
-    let mut reader = std::io::BufReader::new(stream);
-    let mut headers = String::new();
-    while reader.read_line(&mut headers).unwrap() > 2 {
-        if headers == "\r\n" {
-            break;
-        }
-
-        headers.clear();
+// hands back whatever was streamed so far alongside the error once deltas have gone out
+fn interrupt(full_message: &str, error: std::io::Error) -> std::io::Error {
+    if full_message.is_empty() {
+        return error;
     }
 
+    let mut message = Message::new(MessageType::Assistant, full_message.to_string());
+    message.truncated = true;
+
+    std::io::Error::other(PartialResponse { message, error })
+}
+
+// feeds the stream to `parse_line` line by line, forwarding deltas over `tx`
+pub fn read_stream(
+    reader: &mut Reader,
+    tx: &std::sync::mpsc::Sender<StreamEvent>,
+    cancel: &std::sync::atomic::AtomicBool,
+    has_end_marker: bool,
+    mut parse_line: impl FnMut(&str, &mut Option<Usage>) -> Result<StreamLine, std::io::Error>,
+) -> Result<Message, std::io::Error> {
     let mut full_message = String::new();
+    let mut usage = None;
     let mut event_buffer = String::new();
-    while reader.read_line(&mut event_buffer).unwrap() > 0 {
-        if event_buffer.starts_with("data: ") {
-            let payload = event_buffer[6..].trim();
+    let mut truncated = false;
+    loop {
+        if cancel.load(std::sync::atomic::Ordering::Relaxed) {
+            truncated = true;
+            break;
+        }
 
-            if payload.is_empty() || payload == "[DONE]" {
-                break;
+        match reader.read_line(&mut event_buffer) {
+            Ok(0) if has_end_marker => {
+                return Err(interrupt(
+                    &full_message,
+                    std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "The stream closed before the response was finished",
+                    ),
+                ))
             }
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => return Err(interrupt(&full_message, e)),
+        }
 
-            let response_json: serde_json::Value = match serde_json::from_str(&payload) {
-                Ok(json) => json,
-                Err(e) => {
-                    error!("JSON parse error: {}", e);
-                    error!("Error payload: {}", payload);
+        match parse_line(&event_buffer, &mut usage).map_err(|e| interrupt(&full_message, e))? {
+            StreamLine::Delta(delta) => {
+                full_message.push_str(&delta);
+                send_delta(tx, delta);
+            }
+            StreamLine::Skip => {}
+            StreamLine::Done => break,
+        }
 
-                    serde_json::Value::Null
-                }
-            };
+        event_buffer.clear();
+    }
 
-            let delta = response_json["choices"][0]["delta"]["content"]
-                .to_string()
-                .replace("\\n", "\n")
-                .replace("\\\"", "\"")
-                .replace("\\'", "'")
-                .replace("\\\\", "\\");
+    let mut message = Message::new(MessageType::Assistant, full_message);
+    message.truncated = truncated;
+    message.usage = usage;
 
-            // remove quotes
-            let delta = delta[1..delta.len() - 1].to_string();
+    Ok(message)
+}
 
-            if delta != "null" {
-                send_delta(tx, delta.clone());
-                full_message.push_str(&delta);
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    host: String,
+    port: u16,
+    authorization: Option<String>,
+}
+
+pub fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::new();
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                output.push('=');
             }
         }
+    }
 
-        event_buffer.clear();
+    output
+}
+
+// `[http://][user:pass@]host[:port]`
+pub fn parse_proxy(url: &str) -> Result<Proxy, String> {
+    let rest = url.trim().trim_end_matches('/');
+    let rest = match rest.split_once("://") {
+        Some(("http", rest)) => rest,
+        Some((scheme, _)) => {
+            return Err(format!(
+                "Unsupported proxy scheme {}, only http:// proxies are supported",
+                scheme
+            ))
+        }
+        None => rest,
+    };
+
+    let (authorization, address) = match rest.rsplit_once('@') {
+        Some((credentials, address)) => {
+            if !credentials.contains(':') {
+                return Err(format!("Proxy credentials must be user:pass in {}", url));
+            }
+
+            (Some(base64_encode(credentials.as_bytes())), address)
+        }
+        None => (None, rest),
+    };
+
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => return Err(format!("Invalid proxy port in {}", url)),
+        },
+        None => (address, 80),
+    };
+
+    if host.is_empty() {
+        return Err(format!("Missing proxy host in {}", url));
     }
 
-    Ok(full_message)
+    Ok(Proxy {
+        host: host.to_string(),
+        port,
+        authorization,
+    })
 }
 
-fn process_anthropic_stream(
-    stream: TlsStream<TcpStream>,
-    tx: &std::sync::mpsc::Sender<String>,
-) -> Result<String, std::io::Error> {
-    info!("processing anthropic stream");
-    let mut reader = std::io::BufReader::new(stream);
-    let mut all_headers = Vec::new();
-    let mut headers = String::new();
-    while reader.read_line(&mut headers).unwrap() > 2 {
-        if headers == "\r\n" {
-            break;
+// `NO_PROXY` entries match the host itself or any of its subdomains
+// loopback never goes through a proxy, whose own loopback would be somewhere else
+fn is_proxy_excluded(host: &str) -> bool {
+    if host == "localhost" || host == "127.0.0.1" || host == "::1" {
+        return true;
+    }
+
+    let no_proxy = env::var("NO_PROXY")
+        .or_else(|_| env::var("no_proxy"))
+        .unwrap_or_default();
+
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.'))
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
+// the config file wins over the environment
+fn get_proxy(host: &str, options: &Options) -> Result<Option<Proxy>, TllmError> {
+    if is_proxy_excluded(host) {
+        return Ok(None);
+    }
+
+    let url = match options.proxy.clone().or_else(|| {
+        ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]
+            .iter()
+            .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()))
+    }) {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+
+    parse_proxy(&url).map(Some).map_err(TllmError::Config)
+}
+
+fn connect_tcp(
+    host: &str,
+    port: u16,
+    timeout: std::time::Duration,
+) -> Result<TcpStream, std::io::Error> {
+    let addr = (host, port)
+        .to_socket_addrs()
+        // lookups tend to fail for the same flaky reasons connections do
+        .map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                format!("Failed to resolve {}: {}", host, e),
+            )
+        })?
+        .find(|addr| addr.is_ipv4())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No IPv4 address found for {}", host),
+            )
+        })?;
+
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    Ok(stream)
+}
+
+// asks the proxy for a tunnel to `host:port` that the TLS handshake then runs through
+fn connect_tunnel(
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+    timeout: std::time::Duration,
+) -> Result<TcpStream, std::io::Error> {
+    let mut stream = connect_tcp(&proxy.host, proxy.port, timeout)?;
+
+    let mut request = format!(
+        "CONNECT {}:{} HTTP/1.1\r\nHost: {}:{}\r\n",
+        host, port, host, port
+    );
+    if let Some(authorization) = &proxy.authorization {
+        Logger::add_secret(authorization);
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", authorization));
+    }
+
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    // byte by byte so nothing past the proxy's headers gets swallowed
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "proxy closed the connection during CONNECT",
+            ));
         }
 
-        all_headers.push(headers.clone());
-        headers.clear();
+        response.push(byte[0]);
     }
 
-    info!("headers: {:?}", all_headers);
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => {
+            info!(
+                "tunnel to {}:{} established through {}",
+                host, port, proxy.host
+            );
+            Ok(stream)
+        }
+        Some("407") => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("proxy authentication failed: {}", status_line),
+        )),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("proxy refused CONNECT: {}", status_line),
+        )),
+    }
+}
 
-    let mut full_message = all_headers.join("");
-    let mut event_buffer = String::new();
-    while reader.read_line(&mut event_buffer).unwrap() > 0 {
-        if event_buffer.starts_with("event: message_stop") {
-            break;
-        } else if event_buffer.starts_with("data: ") {
-            let payload = event_buffer[6..].trim();
+pub trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+fn connect(
+    request: &Request,
+    proxy: Option<&Proxy>,
+    timeout: std::time::Duration,
+) -> Result<Box<dyn Connection>, std::io::Error> {
+    let stream = match proxy {
+        Some(proxy) => connect_tunnel(proxy, &request.host, request.port, timeout)?,
+        None => connect_tcp(&request.host, request.port, timeout)?,
+    };
 
-            if payload.is_empty() || payload == "[DONE]" {
-                break;
+    if !request.tls {
+        return Ok(Box::new(stream));
+    }
+
+    let connector = native_tls::TlsConnector::new().expect("TLS connector failed to create");
+    let stream = connector
+        .connect(&request.host, stream)
+        .map_err(|e| match e {
+            native_tls::HandshakeError::Failure(e) => {
+                std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e)
+            }
+            native_tls::HandshakeError::WouldBlock(_) => {
+                std::io::Error::new(std::io::ErrorKind::WouldBlock, "TLS handshake would block")
             }
+        })?;
+
+    Ok(Box::new(stream))
+}
+
+// the response body, with the transfer encoding already taken off
+pub type Reader = std::io::BufReader<Box<dyn Read + Send>>;
+
+// the only thing read off the connection itself is the status line and headers
+type ConnectionReader = std::io::BufReader<Box<dyn Connection>>;
+
+// servers close idle connections on their own schedule, past this one isn't worth trying
+const MAX_IDLE: std::time::Duration = std::time::Duration::from_secs(30);
+
+// connections the server left open, each under a key from `get_pool_key`
+static POOL: std::sync::Mutex<Vec<(String, std::time::Instant, ConnectionReader)>> =
+    std::sync::Mutex::new(Vec::new());
+
+// the socket timeouts are set on connecting, so they're part of what makes a connection fit
+fn get_pool_key(request: &Request, proxy: Option<&Proxy>, timeout: std::time::Duration) -> String {
+    format!(
+        "{}://{}:{} via {} ({}s)",
+        if request.tls { "https" } else { "http" },
+        request.host,
+        request.port,
+        proxy
+            .map(|p| format!("{}:{}", p.host, p.port))
+            .unwrap_or("direct".to_string()),
+        timeout.as_secs()
+    )
+}
+
+fn take_pooled(key: &str) -> Option<ConnectionReader> {
+    let mut pool = POOL.lock().unwrap_or_else(|e| e.into_inner());
+    pool.retain(|(_, expires, _)| *expires > std::time::Instant::now());
+    let index = pool.iter().position(|(k, _, _)| k == key)?;
 
-            let response_json: serde_json::Value = serde_json::from_str(&payload)?;
+    Some(pool.swap_remove(index).2)
+}
 
-            let mut delta = "null".to_string();
-            if response_json["type"] == "content_block_delta" {
-                delta = response_json["delta"]["text"]
-                    .to_string()
-                    .replace("\\n", "\n")
-                    .replace("\\\"", "\"")
-                    .replace("\\'", "'")
-                    .replace("\\\\", "\\");
+// how a body ends
+enum Framing {
+    // bytes left in the current chunk, and whether the last chunk has been read
+    Chunked { remaining: usize, done: bool },
+    // bytes left
+    Length(u64),
+    // whenever the server closes the connection
+    Close,
+}
 
-                // remove quotes
-                delta = delta[1..delta.len() - 1].to_string();
+// reads a body off the connection as it arrives, taking off `Transfer-Encoding: chunked`
+// streamed responses usually come chunked, and a chunk can end in the middle of an SSE line
+// once it's been read to the end, a connection the server keeps open goes back to the pool
+struct Body {
+    // only taken out when it's pooled
+    inner: Option<ConnectionReader>,
+    framing: Framing,
+    // the pool key and how long the server keeps the connection open
+    keep_alive: Option<(String, std::time::Duration)>,
+}
+
+impl Body {
+    fn inner(&mut self) -> &mut ConnectionReader {
+        self.inner
+            .as_mut()
+            .expect("body read after its connection was pooled")
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(
+            self.framing,
+            Framing::Chunked { done: true, .. } | Framing::Length(0)
+        )
+    }
+
+    fn next_chunk(&mut self) -> Result<(), std::io::Error> {
+        let mut line = String::new();
+        if self.inner().read_line(&mut line)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a chunked response",
+            ));
+        }
+
+        // e.g. `1a3` or `1a3;name=value`, the extensions are ignored
+        // `from_str_radix` takes a leading `+`, a size doesn't
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let valid = !size.is_empty() && size.chars().all(|c| c.is_ascii_hexdigit());
+        let size = usize::from_str_radix(size, 16)
+            .ok()
+            .filter(|_| valid)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Malformed chunk size: {:?}", line),
+                )
+            })?;
+
+        if size == 0 {
+            // any trailers, up through the blank line that ends the body
+            loop {
+                line.clear();
+                if self.inner().read_line(&mut line)? == 0 || line.trim().is_empty() {
+                    break;
+                }
             }
+        }
 
-            if delta != "null" {
-                send_delta(tx, delta.clone());
-                full_message.push_str(&delta);
+        self.framing = Framing::Chunked {
+            remaining: size,
+            done: size == 0,
+        };
+
+        Ok(())
+    }
+
+    fn read_chunked(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if let Framing::Chunked {
+            remaining: 0,
+            done: false,
+        } = self.framing
+        {
+            self.next_chunk()?;
+        }
+
+        let remaining = match self.framing {
+            Framing::Chunked { remaining, done } if !done => remaining,
+            _ => return Ok(0),
+        };
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let limit = std::cmp::min(buf.len(), remaining);
+        let read = self.inner().read(&mut buf[..limit])?;
+        if read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a chunked response",
+            ));
+        }
+
+        self.framing = Framing::Chunked {
+            remaining: remaining - read,
+            done: false,
+        };
+        if remaining == read {
+            // every chunk ends with a CRLF, anything else means the sizes were off
+            let mut end = [0u8; 2];
+            self.inner().read_exact(&mut end)?;
+            if &end != b"\r\n" {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Chunk ended with {:?} instead of a CRLF",
+                        String::from_utf8_lossy(&end)
+                    ),
+                ));
             }
         }
 
-        event_buffer.clear();
+        Ok(read)
     }
+}
 
-    Ok(full_message)
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        match self.framing {
+            Framing::Chunked { .. } => self.read_chunked(buf),
+            Framing::Length(0) => Ok(0),
+            Framing::Length(remaining) => {
+                let limit = std::cmp::min(buf.len() as u64, remaining) as usize;
+                let read = self.inner().read(&mut buf[..limit])?;
+                self.framing = match read {
+                    // cut short, there's nothing left to pool
+                    0 => Framing::Close,
+                    read => Framing::Length(remaining - read as u64),
+                };
+
+                Ok(read)
+            }
+            Framing::Close => self.inner().read(buf),
+        }
+    }
 }
 
-fn connect_https(host: &str, port: u16) -> native_tls::TlsStream<std::net::TcpStream> {
-    let addr = (host, port)
-        .to_socket_addrs()
-        .unwrap()
-        .find(|addr| addr.is_ipv4())
-        .expect("No IPv4 address found");
+impl Drop for Body {
+    fn drop(&mut self) {
+        if !self.is_finished() {
+            return;
+        }
 
-    let stream = TcpStream::connect(&addr).unwrap();
+        if let (Some((key, idle)), Some(inner)) = (self.keep_alive.take(), self.inner.take()) {
+            trace!("pooling the connection to {}", key);
+            POOL.lock().unwrap_or_else(|e| e.into_inner()).push((
+                key,
+                std::time::Instant::now() + idle,
+                inner,
+            ));
+        }
+    }
+}
 
-    let connector = native_tls::TlsConnector::new().expect("TLS connector failed to create");
-    connector.connect(host, stream).unwrap()
-}
-
-pub fn prompt_stream(
-    system_prompt: String,
-    chat_history: &Vec<Message>,
-    api: String,
-    tx: std::sync::mpsc::Sender<String>,
-) -> Result<(), std::io::Error> {
-    let params = match api.as_str() {
-        "anthropic" => get_anthropic_request_params(system_prompt.clone(), chat_history, true),
-        "openai" => get_openai_request_params(system_prompt.clone(), chat_history, true),
-        "groq" => get_groq_request_params(system_prompt.clone(), chat_history, true),
-        _ => panic!("Invalid API does not yet support streaming: {}", api),
+// HTTP/1.1 keeps connections open unless the server says otherwise
+// `Keep-Alive: timeout=5` is how long it will, some servers send it
+fn get_keep_alive(headers: &[String]) -> Option<std::time::Duration> {
+    let status_line = headers.first()?;
+    let closes = get_header(headers, "connection")
+        .map(|v| v.to_ascii_lowercase().contains("close"))
+        .unwrap_or(false);
+    if !status_line.starts_with("HTTP/1.1") || closes {
+        return None;
+    }
+
+    let timeout = get_header(headers, "keep-alive")
+        .and_then(|v| {
+            v.split(',')
+                .find_map(|param| param.trim().strip_prefix("timeout=")?.parse::<u64>().ok())
+        })
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(MAX_IDLE);
+
+    Some(std::cmp::min(timeout, MAX_IDLE))
+}
+
+// chunked, a content length, or everything until the connection closes
+// then decompressed, identity is asked for but some proxies compress anyway
+fn decode_body(
+    reader: ConnectionReader,
+    headers: &[String],
+    pool_key: String,
+) -> Result<Reader, std::io::Error> {
+    let chunked = get_header(headers, "transfer-encoding")
+        .map(|v| v.to_ascii_lowercase().contains("chunked"))
+        .unwrap_or(false);
+
+    let framing = if chunked {
+        Framing::Chunked {
+            remaining: 0,
+            done: false,
+        }
+    } else {
+        match get_header(headers, "content-length").and_then(|v| v.parse::<u64>().ok()) {
+            Some(length) => Framing::Length(length),
+            None => Framing::Close,
+        }
     };
 
-    // TODO: need error handling littered throughout here
-    //       how can we bubble errors back up to the display + show them properly?
-    let request = build_request(&params);
-    let mut stream = connect_https(&params.host, params.port);
-    stream
-        .write_all(request.as_bytes())
-        .expect("Failed to write to stream");
-    stream.flush().expect("Failed to flush stream");
-
-    info!("stream written");
-    let response = match api.as_str() {
-        "anthropic" => process_anthropic_stream(stream, &tx),
-        "openai" => process_openai_stream(stream, &tx),
-        "groq" => process_openai_stream(stream, &tx),
-        _ => panic!("Invalid API: {}--how'd this get here?", api),
+    let keep_alive = match framing {
+        Framing::Close => None,
+        _ => get_keep_alive(headers).map(|idle| (pool_key, idle)),
     };
 
-    match response {
-        Ok(_) => {}
-        Err(e) => {
-            error!("Failed to process stream: {}", e);
-            return Err(e);
+    let body: Box<dyn Read + Send> = Box::new(Body {
+        inner: Some(reader),
+        framing,
+        keep_alive,
+    });
+
+    let encoding = get_header(headers, "content-encoding")
+        .map(|v| v.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let body: Box<dyn Read + Send> = match encoding.as_str() {
+        "" | "identity" => body,
+        "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(body)),
+        "deflate" => Box::new(flate2::read::ZlibDecoder::new(body)),
+        other => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "The response is encoded with {}, which tllm can't decode",
+                    other
+                ),
+            ))
         }
-    }
+    };
 
-    Ok(())
+    Ok(std::io::BufReader::new(body))
 }
 
-pub fn prompt(
-    api: &String,
-    system_prompt: &String,
-    chat_history: &Vec<Message>,
-) -> Result<Message, std::io::Error> {
-    let params = match api.as_str() {
-        "anthropic" => get_anthropic_request_params(system_prompt.clone(), chat_history, false),
-        "openai" => get_openai_request_params(system_prompt.clone(), chat_history, false),
-        "gemini" => get_gemini_request_params(system_prompt.clone(), chat_history, false),
-        "groq" => get_groq_request_params(system_prompt.clone(), chat_history, false),
-        _ => panic!("Invalid API: {}--how'd this get here?", api),
+// enough for a path moving around on the same server
+const MAX_REDIRECTS: usize = 5;
+
+// points `request` at a redirect's `Location`, which can be absolute or just a path
+// other hosts and plain HTTP aren't followed, that would send the API key to them or in the clear
+fn follow_redirect(request: &mut Request, location: &str) -> Result<(), std::io::Error> {
+    if location.starts_with('/') {
+        request.path = location.to_string();
+        return Ok(());
+    }
+
+    let target = parse_api_base(location)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    if !target.host.eq_ignore_ascii_case(&request.host) {
+        return Err(std::io::Error::other(format!(
+            "{} redirected to {}, which isn't followed to another host, set api_base to it instead",
+            request.host, location
+        )));
+    }
+
+    if request.tls && !target.tls {
+        return Err(std::io::Error::other(format!(
+            "{} redirected to {}, which isn't followed from HTTPS to plain HTTP",
+            request.host, location
+        )));
+    }
+
+    // `parse_api_base` drops the trailing slash, which is often the whole point of the redirect
+    let rest = location
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or_default();
+    request.path = match rest.find('/') {
+        Some(i) => rest[i..].to_string(),
+        None => "/".to_string(),
     };
+    request.tls = target.tls;
+    request.port = target.port;
 
-    let request = build_request(&params);
-    let mut stream = connect_https(&params.host, params.port);
-    stream.write_all(request.as_bytes())?;
-    stream.flush()?;
+    Ok(())
+}
 
-    let mut reader = std::io::BufReader::new(stream);
-    let mut content_length = 0;
+// writes the request and reads the response headers, with the status line first
+// along with the time to the first byte of the response
+fn exchange(
+    mut reader: ConnectionReader,
+    payload: &str,
+) -> Result<(ConnectionReader, Vec<String>, std::time::Duration), std::io::Error> {
+    let started = std::time::Instant::now();
+    reader.get_mut().write_all(payload.as_bytes())?;
+    reader.get_mut().flush()?;
+
+    let mut first_byte = None;
     let mut headers = Vec::new();
     let mut line = String::new();
-    while reader.read_line(&mut line).unwrap() > 0 {
-        if line == "\r\n" {
-            info!("End of headers");
-            break;
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        first_byte.get_or_insert_with(|| started.elapsed());
+        if read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed before the response headers ended",
+            ));
         }
 
-        if line.contains("Content-Length") {
-            let parts: Vec<&str> = line.split(":").collect();
-            content_length = parts[1].trim().parse().unwrap();
+        if line == "\r\n" {
+            trace!("End of headers");
+            break;
         }
 
-        line = line.trim().to_string();
-        headers.push(line.clone());
-        line.clear();
+        headers.push(line.trim().to_string());
     }
 
-    let mut decoded_body = String::new();
+    Ok((reader, headers, first_byte.unwrap_or_default()))
+}
 
-    // they like to use this transfer encoding for long responses
-    if headers.contains(&"Transfer-Encoding: chunked".to_string()) {
-        let mut buffer = Vec::new();
-        loop {
-            let mut chunk_size = String::new();
-            reader.read_line(&mut chunk_size)?;
-            let chunk_size = usize::from_str_radix(chunk_size.trim(), 16)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+// the server closed a pooled connection while it sat idle
+fn is_stale(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
 
-            if chunk_size == 0 {
-                break;
+// writes the request and reads the status line and headers
+// on a pooled connection when there is one, and a new one if that's gone stale
+fn send_once(
+    request: &Request,
+    proxy: Option<&Proxy>,
+    timeout: std::time::Duration,
+) -> Result<(Reader, Vec<String>, u16, String), std::io::Error> {
+    let payload = build_request(request);
+    debug!("request headers: {:?}", redact_headers(&request.headers));
+    debug!("request body: {}", request.body);
+
+    let key = get_pool_key(request, proxy, timeout);
+    let pooled = match take_pooled(&key) {
+        Some(reader) => match exchange(reader, &payload) {
+            Ok(response) => Some(response),
+            Err(e) if is_stale(&e) => {
+                info!(
+                    "pooled connection to {} was closed ({}), reconnecting",
+                    request.host, e
+                );
+                None
             }
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
 
-            let mut chunk = vec![0; chunk_size];
-            reader.read_exact(&mut chunk)?;
-            buffer.extend_from_slice(&chunk);
+    let reused = pooled.is_some();
+    let started = std::time::Instant::now();
+    let (reader, headers, first_byte) = match pooled {
+        Some(response) => response,
+        None => {
+            let stream = connect(request, proxy, timeout)?;
+            exchange(std::io::BufReader::new(stream), &payload)?
+        }
+    };
 
-            // Read and discard the CRLF at the end of the chunk
-            reader.read_line(&mut String::new())?;
+    // connecting and the TLS handshake are what reusing a connection saves
+    info!(
+        "first byte from {} after {}ms, {}",
+        request.host,
+        first_byte.as_millis(),
+        match reused {
+            true => "on a reused connection".to_string(),
+            false => format!(
+                "{}ms counting the connection",
+                started.elapsed().as_millis()
+            ),
         }
+    );
+
+    // e.g. `HTTP/1.1 401 Unauthorized`
+    let status_line = headers.first().cloned().unwrap_or_default();
+    let mut status_parts = status_line.splitn(3, ' ').skip(1);
+    let status = status_parts
+        .next()
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Malformed status line: {:?}", status_line),
+            )
+        })?;
+    let reason = status_parts.next().unwrap_or_default().to_string();
+
+    Ok((decode_body(reader, &headers, key)?, headers, status, reason))
+}
 
-        decoded_body = String::from_utf8(buffer).unwrap();
-    } else {
-        if content_length > 0 {
-            reader
-                .take(content_length as u64)
-                .read_to_string(&mut decoded_body)?;
+// sends the request, following redirects, and reads up through the response headers
+// anything other than a 2xx comes back as an `HttpError`
+fn send_request(
+    request: &Request,
+    proxy: Option<&Proxy>,
+    timeout: std::time::Duration,
+) -> Result<(Reader, Vec<String>), std::io::Error> {
+    let mut request = std::borrow::Cow::Borrowed(request);
+    let mut redirects = 0;
+    loop {
+        let (mut reader, headers, status, reason) = send_once(&request, proxy, timeout)?;
+
+        // 303 means to switch to a GET, which none of the APIs would want
+        if [301, 302, 307, 308].contains(&status) && redirects < MAX_REDIRECTS {
+            if let Some(location) = get_header(&headers, "location") {
+                info!("{} redirected to {}", request.provider, location);
+                follow_redirect(request.to_mut(), &location)?;
+                redirects += 1;
+                continue;
+            }
         }
-    }
 
-    let response_json = serde_json::from_str(&decoded_body);
+        if !(200..300).contains(&status) {
+            let body = read_body(&mut reader).unwrap_or_default();
+            error!("{} responded with {}", request.provider, status);
+            debug!("response body: {}", body);
+            return Err(std::io::Error::other(HttpError {
+                status,
+                reason,
+                body,
+                retry_after: get_retry_after(&headers),
+            }));
+        }
 
-    if response_json.is_err() {
-        error!("Failed to parse JSON: {}", decoded_body);
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Failed to parse JSON",
-        ));
+        return Ok((reader, headers));
     }
+}
 
-    let response_json: serde_json::Value = response_json.unwrap();
+fn read_body(reader: &mut Reader) -> Result<String, std::io::Error> {
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer)?;
 
-    let mut content = match api.as_str() {
-        "openai" => response_json["choices"][0]["message"]["content"].to_string(),
-        "groq" => response_json["choices"][0]["message"]["content"].to_string(),
-        "anthropic" => response_json["content"][0]["text"].to_string(),
-        "gemini" => response_json["candidates"][0]["content"]["parts"][0]["text"].to_string(),
-        _ => {
-            error!(
-                "provider {} isn't yet configured for reading the HTTP response properly",
-                params.provider
-            );
-            String::new()
+    String::from_utf8(buffer).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+// sends `request` and parses the JSON response
+pub fn complete(request: &Request, options: &Options) -> Result<serde_json::Value, TllmError> {
+    let timeout = std::time::Duration::from_secs(options.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let proxy = get_proxy(&request.host, options)?;
+    let decoded_body = with_retries(options, || {
+        send_request(request, proxy.as_ref(), timeout)
+            .and_then(|(mut reader, _)| read_body(&mut reader))
+            .map_err(|e| describe_timeout(e, timeout))
+    })?;
+
+    match serde_json::from_str(&decoded_body) {
+        Ok(json) => Ok(json),
+        Err(e) => {
+            error!("Failed to parse JSON from {}: {}", request.provider, e);
+            debug!("response body: {}", decoded_body);
+            Err(TllmError::Provider(format!(
+                "{} sent a response that isn't valid JSON: {}",
+                request.provider, e
+            )))
         }
-    };
+    }
+}
 
-    content = content
-        .replace("\\\"", "\"")
-        .replace("\\'", "'")
-        .replace("\\\\", "\\");
+// past the end of a stream, anything longer isn't worth waiting on to reuse the connection
+const MAX_STREAM_DRAIN: u64 = 64 * 1024;
 
-    if content.starts_with("\"") && content.ends_with("\"") {
-        content = content[1..content.len() - 1].to_string();
-    }
+// a body that ends when the server closes the connection can't be pooled, so there's no reading to its end
+fn is_poolable(headers: &[String]) -> bool {
+    let framed = get_header(headers, "transfer-encoding")
+        .map(|v| v.to_ascii_lowercase().contains("chunked"))
+        .unwrap_or(false)
+        || get_header(headers, "content-length").is_some();
 
-    Ok(Message::new(MessageType::Assistant, content))
+    framed && get_keep_alive(headers).is_some()
 }
 
-pub fn tcp_request(
-    port: &str,
-    payload: Vec<u8>,
-    tx: std::sync::mpsc::Sender<Vec<u8>>,
-) -> Result<(), std::io::Error> {
-    let mut stream = std::net::TcpStream::connect(format!("127.0.0.1:{}", port))?;
+// sends `request` and hands the open response to `process`
+// retries stop once a delta has gone out, see `PartialResponse`
+pub fn stream(
+    request: &Request,
+    options: &Options,
+    mut process: impl FnMut(&mut Reader) -> Result<Message, std::io::Error>,
+) -> Result<Message, TllmError> {
+    let timeout = std::time::Duration::from_secs(options.timeout.unwrap_or(DEFAULT_STREAM_TIMEOUT));
+    let proxy = get_proxy(&request.host, options)?;
+    with_retries(options, || {
+        let (mut reader, headers) = send_request(request, proxy.as_ref(), timeout)
+            .map_err(|e| describe_timeout(e, timeout))?;
+        info!("stream written, headers: {:?}", redact_headers(&headers));
+
+        let message = process(&mut reader).map_err(|e| describe_timeout(e, timeout))?;
+
+        // whatever follows the end of the response is the end of the body, reading it lets the connection be pooled
+        if !message.truncated && is_poolable(&headers) {
+            let mut rest = (&mut reader).take(MAX_STREAM_DRAIN);
+            if let Err(e) = std::io::copy(&mut rest, &mut std::io::sink()) {
+                trace!("couldn't read the rest of the stream: {}", e);
+            }
+        }
 
-    stream.write(&payload)?;
+        Ok(message)
+    })
+    .map_err(TllmError::from)
+}
+
+// both ways, the payload is prefixed with its length as a big-endian u32
+pub fn tcp_request(
+    address: &DeweyAddress,
+    payload: &[u8],
+    timeout: std::time::Duration,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut stream = connect_tcp(&address.host, address.port, timeout)?;
+
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
     stream.flush()?;
 
     let mut length_bytes = [0u8; 4];
@@ -572,7 +1505,437 @@ pub fn tcp_request(
     let mut buffer = vec![0u8; length];
     stream.read_exact(&mut buffer)?;
 
-    tx.send(buffer).expect("Failed to send TCP response");
+    Ok(buffer)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeServer, Response};
+
+    fn get_request(server: &FakeServer) -> Request {
+        Request {
+            provider: "test".to_string(),
+            model: "test".to_string(),
+            tls: false,
+            host: "127.0.0.1".to_string(),
+            port: server.port,
+            path: "/v1/chat".to_string(),
+            headers: vec!["Authorization: Bearer secret".to_string()],
+            body: serde_json::json!({ "hello": "world" }),
+        }
+    }
+
+    fn get_https_request() -> Request {
+        Request {
+            provider: "test".to_string(),
+            model: "test".to_string(),
+            tls: true,
+            host: "api.example.com".to_string(),
+            port: 443,
+            path: "/v1/chat".to_string(),
+            headers: Vec::new(),
+            body: serde_json::Value::Null,
+        }
+    }
+
+    #[test]
+    fn redirect_to_a_path() {
+        let mut request = get_https_request();
+        follow_redirect(&mut request, "/v2/chat").unwrap();
+
+        assert_eq!(request.path, "/v2/chat");
+        assert!(request.tls);
+    }
+
+    #[test]
+    fn redirect_on_the_same_host() {
+        let mut request = get_https_request();
+        follow_redirect(&mut request, "https://API.example.com:8443/v2/chat/").unwrap();
+
+        assert_eq!(request.path, "/v2/chat/");
+        assert_eq!(request.port, 8443);
+        assert!(request.tls);
+    }
+
+    #[test]
+    fn redirect_to_another_host() {
+        let mut request = get_https_request();
+        let e = follow_redirect(&mut request, "https://evil.example.com/v1/chat").unwrap_err();
+
+        assert!(e.to_string().contains("another host"), "{}", e);
+        assert_eq!(request.path, "/v1/chat");
+    }
+
+    #[test]
+    fn redirect_to_plain_http() {
+        let mut request = get_https_request();
+        let e = follow_redirect(&mut request, "http://api.example.com/v1/chat").unwrap_err();
+
+        assert!(e.to_string().contains("plain HTTP"), "{}", e);
+        assert!(request.tls);
+    }
+
+    #[test]
+    fn complete_follows_redirects() {
+        testing::setup();
+        let server = FakeServer::start(vec![
+            Response::with_length("307 Temporary Redirect", &["Location: /v2/chat"], b""),
+            Response::json("200 OK", &serde_json::json!({ "answer": 42 })),
+        ]);
+        let response = complete(&get_request(&server), &server.options()).unwrap();
+
+        assert_eq!(response["answer"], 42);
+        let requests = server.requests();
+        assert!(requests[0].starts_with("POST /v1/chat HTTP/1.1\r\n"));
+        assert!(requests[1].starts_with("POST /v2/chat HTTP/1.1\r\n"));
+        assert!(requests[1].ends_with("{\"hello\":\"world\"}"));
+    }
+
+    #[test]
+    fn stream_follows_redirects() {
+        testing::setup();
+        let server = FakeServer::start(vec![
+            Response::with_length("308 Permanent Redirect", &["Location: /v2/chat"], b""),
+            Response::sse(&["one", "two"]),
+        ]);
+        let message = stream(&get_request(&server), &server.options(), |reader| {
+            let mut body = String::new();
+            reader.read_to_string(&mut body)?;
+            Ok(Message::new(MessageType::Assistant, body))
+        })
+        .unwrap();
+
+        assert_eq!(message.content, "data: one\n\ndata: two\n\n");
+        assert!(server.requests()[1].starts_with("POST /v2/chat HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn error_status() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::json(
+            "401 Unauthorized",
+            &serde_json::json!({ "error": { "message": "Bad key" } }),
+        )]);
+        let e = complete(&get_request(&server), &server.options()).unwrap_err();
+
+        match e {
+            TllmError::Network { status, .. } => assert_eq!(status, 401),
+            e => panic!("expected a network error, got {:?}", e),
+        }
+    }
+
+    // the server leaves the connection open after the end marker, the body only ends when it closes
+    #[test]
+    fn stream_without_framing_ends_at_its_marker() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::raw(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\ndata: one\n\ndata: [DONE]\n\n",
+        )]);
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let cancel = std::sync::atomic::AtomicBool::new(false);
+
+        let started = std::time::Instant::now();
+        let message = stream(&get_request(&server), &server.options(), |reader| {
+            read_stream(reader, &tx, &cancel, true, |line, _| {
+                Ok(match line.trim().strip_prefix("data: ") {
+                    Some("[DONE]") => StreamLine::Done,
+                    Some(data) => StreamLine::Delta(data.to_string()),
+                    None => StreamLine::Skip,
+                })
+            })
+        })
+        .unwrap();
+
+        assert_eq!(message.content, "one");
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    fn get_ok() -> Response {
+        Response::json("200 OK", &serde_json::json!({ "ok": true }))
+    }
+
+    #[test]
+    fn connections_are_reused() {
+        testing::setup();
+        let server = FakeServer::start(vec![get_ok(), get_ok()]);
+        let request = get_request(&server);
+        complete(&request, &server.options()).unwrap();
+        complete(&request, &server.options()).unwrap();
+
+        assert_eq!(server.connections(), [0, 0]);
+    }
+
+    #[test]
+    fn streamed_connections_are_reused() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::sse(&["one", "[DONE]"]), get_ok()]);
+        let request = get_request(&server);
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let cancel = std::sync::atomic::AtomicBool::new(false);
+        stream(&request, &server.options(), |reader| {
+            read_stream(reader, &tx, &cancel, true, |line, _| {
+                Ok(match line.trim().strip_prefix("data: ") {
+                    Some("[DONE]") => StreamLine::Done,
+                    Some(data) => StreamLine::Delta(data.to_string()),
+                    None => StreamLine::Skip,
+                })
+            })
+        })
+        .unwrap();
+        complete(&request, &server.options()).unwrap();
+
+        assert_eq!(server.connections(), [0, 0]);
+    }
+
+    // the server closes the connection while it sits in the pool
+    #[test]
+    fn stale_connections_are_replaced() {
+        testing::setup();
+        let server = FakeServer::start(vec![get_ok().then_close(), get_ok()]);
+        let request = get_request(&server);
+        complete(&request, &server.options()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let response = complete(&request, &server.options()).unwrap();
+
+        assert_eq!(response["ok"], true);
+        assert_eq!(server.connections(), [0, 1]);
+    }
+
+    #[test]
+    fn connection_close_isnt_pooled() {
+        testing::setup();
+        let server = FakeServer::start(vec![
+            Response::with_length("200 OK", &["Connection: close"], b"{}"),
+            get_ok(),
+        ]);
+        let request = get_request(&server);
+        complete(&request, &server.options()).unwrap();
+        complete(&request, &server.options()).unwrap();
+
+        assert_eq!(server.connections(), [0, 1]);
+    }
+
+    #[test]
+    fn keep_alive_timeout() {
+        let headers = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            get_keep_alive(&headers(&[
+                "HTTP/1.1 200 OK",
+                "Keep-Alive: timeout=5, max=100"
+            ])),
+            Some(std::time::Duration::from_secs(5))
+        );
+        assert_eq!(
+            get_keep_alive(&headers(&["HTTP/1.1 200 OK", "Keep-Alive: timeout=600"])),
+            Some(MAX_IDLE)
+        );
+        assert_eq!(get_keep_alive(&headers(&["HTTP/1.0 200 OK"])), None);
+        assert_eq!(
+            get_keep_alive(&headers(&["HTTP/1.1 200 OK", "Connection: Close"])),
+            None
+        );
+    }
+
+    // HTTP/1.0 so the finished body doesn't leave anything in the pool
+    fn decode(headers: &[&str], body: Vec<u8>) -> Result<String, std::io::Error> {
+        let mut headers = headers.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        headers.insert(0, "HTTP/1.0 200 OK".to_string());
+        if !headers.iter().any(|h| h.starts_with("Transfer-Encoding")) {
+            headers.push(format!("Content-Length: {}", body.len()));
+        }
+
+        let connection: Box<dyn Connection> = Box::new(std::io::Cursor::new(body));
+        let mut reader = decode_body(std::io::BufReader::new(connection), &headers, String::new())?;
+        read_body(&mut reader)
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzip_body() {
+        let body = gzip("{\"hello\": \"world\"}");
+        assert_eq!(
+            decode(&["Content-Encoding: gzip"], body.clone()).unwrap(),
+            "{\"hello\": \"world\"}"
+        );
+        assert_eq!(
+            decode(&["Content-Encoding: x-gzip"], body).unwrap(),
+            "{\"hello\": \"world\"}"
+        );
+    }
+
+    #[test]
+    fn chunked_gzip_body() {
+        let body = gzip("data: one\n\ndata: two\n\n");
+        let (first, rest) = body.split_at(7);
+        let mut chunked = Vec::new();
+        for chunk in [first, rest] {
+            chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            chunked.extend_from_slice(chunk);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\n\r\n");
+
+        assert_eq!(
+            decode(
+                &["Transfer-Encoding: chunked", "Content-Encoding: gzip"],
+                chunked
+            )
+            .unwrap(),
+            "data: one\n\ndata: two\n\n"
+        );
+    }
+
+    #[test]
+    fn deflate_body() {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"deflated").unwrap();
+        let body = encoder.finish().unwrap();
+
+        assert_eq!(
+            decode(&["Content-Encoding: deflate"], body).unwrap(),
+            "deflated"
+        );
+    }
+
+    #[test]
+    fn identity_body() {
+        assert_eq!(
+            decode(&["Content-Encoding: identity"], b"plain".to_vec()).unwrap(),
+            "plain"
+        );
+        assert_eq!(decode(&[], b"plain".to_vec()).unwrap(), "plain");
+    }
+
+    #[test]
+    fn unsupported_encoding() {
+        let e = decode(&["Content-Encoding: br"], b"\x1b\x00".to_vec()).unwrap_err();
+
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.to_string(),
+            "The response is encoded with br, which tllm can't decode"
+        );
+    }
+
+    fn dechunk(bytes: &[u8]) -> Result<String, std::io::Error> {
+        let connection: Box<dyn Connection> = Box::new(std::io::Cursor::new(bytes.to_vec()));
+        let mut body = Body {
+            inner: Some(std::io::BufReader::new(connection)),
+            framing: Framing::Chunked {
+                remaining: 0,
+                done: false,
+            },
+            keep_alive: None,
+        };
+
+        let mut decoded = String::new();
+        body.read_to_string(&mut decoded)?;
+        assert!(body.is_finished());
+
+        Ok(decoded)
+    }
+
+    #[test]
+    fn chunks() {
+        assert_eq!(
+            dechunk(b"5\r\nhello\r\n1\r\n \r\nA\r\nworld, 10!\r\n0\r\n\r\n").unwrap(),
+            "hello world, 10!"
+        );
+    }
+
+    #[test]
+    fn chunk_extensions() {
+        assert_eq!(
+            dechunk(b"5;name=value\r\nhello\r\n6 ; other\r\n world\r\n0;last\r\n\r\n").unwrap(),
+            "hello world"
+        );
+    }
+
+    #[test]
+    fn chunk_trailers() {
+        assert_eq!(
+            dechunk(b"5\r\nhello\r\n0\r\nX-Checksum: abc\r\nX-Other: 1\r\n\r\n").unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn malformed_chunk_sizes() {
+        for bytes in [
+            &b"zz\r\nhello\r\n0\r\n\r\n"[..],
+            b"+5\r\nhello\r\n0\r\n\r\n",
+            b"\r\nhello\r\n0\r\n\r\n",
+            b"fffffffffffffffffffff\r\nhello\r\n0\r\n\r\n",
+        ] {
+            let e = dechunk(bytes).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+            assert!(e.to_string().starts_with("Malformed chunk size"), "{}", e);
+        }
+    }
+
+    // a size past what's sent runs into the end of the connection
+    #[test]
+    fn oversized_chunk() {
+        let e = dechunk(b"10\r\nhello\r\n0\r\n\r\n").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn chunk_without_crlf() {
+        let e = dechunk(b"5\r\nhelloXX3\r\nabc\r\n0\r\n\r\n").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "Chunk ended with \"XX\" instead of a CRLF");
+    }
+
+    // neither a length nor chunks, the body is everything until the connection closes
+    #[test]
+    fn body_until_close() {
+        let connection: Box<dyn Connection> =
+            Box::new(std::io::Cursor::new(b"all of it\r\n0\r\n".to_vec()));
+        let mut body = Body {
+            inner: Some(std::io::BufReader::new(connection)),
+            framing: Framing::Close,
+            keep_alive: None,
+        };
+
+        let mut decoded = String::new();
+        body.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "all of it\r\n0\r\n");
+    }
+
+    #[test]
+    fn side_request_options() {
+        let options = Options {
+            model: Some("big".to_string()),
+            timeout: Some(30),
+            print_notes: true,
+            stop: vec!["END".to_string()],
+            prefill: Some("{".to_string()),
+            json_response: true,
+            tools: vec![crate::tools::shell()],
+            cache_prompt: true,
+            ..Default::default()
+        };
+        let side = options.for_side_request();
+
+        // where and how it's sent stays the same
+        assert_eq!(side.model.as_deref(), Some("big"));
+        assert_eq!(side.timeout, Some(30));
+        // what shapes the reply doesn't
+        assert!(!side.print_notes);
+        assert!(side.stop.is_empty());
+        assert!(side.prefill.is_none());
+        assert!(!side.json_response);
+        assert!(side.tools.is_empty());
+        assert!(!side.cache_prompt);
+    }
 }
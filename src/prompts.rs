@@ -0,0 +1,73 @@
+use crate::config;
+use crate::error::TllmError;
+
+// each file in here is a system prompt, picked by its file name with `--prompt`
+pub fn get_prompts_dir() -> std::path::PathBuf {
+    config::get_config_dir().join("prompts")
+}
+
+// nothing that could point outside the prompts directory
+fn validate_name(name: &str) -> Result<(), TllmError> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(TllmError::Config(format!(
+            "Invalid prompt name {:?}, it's a file name in {}",
+            name,
+            get_prompts_dir().display()
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn list() -> Vec<String> {
+    let mut names = std::fs::read_dir(get_prompts_dir())
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().is_file())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .filter(|name| !name.starts_with('.'))
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+
+    names.sort();
+    names
+}
+
+fn missing_prompt(name: &str) -> TllmError {
+    let names = list();
+    TllmError::Config(match names.is_empty() {
+        true => format!(
+            "Unknown prompt {}, there are none in {} yet, create one with `tllm prompts edit {}`",
+            name,
+            get_prompts_dir().display(),
+            name
+        ),
+        false => format!(
+            "Unknown prompt {}, available prompts: {}",
+            name,
+            names.join(", ")
+        ),
+    })
+}
+
+pub fn load(name: &str) -> Result<String, TllmError> {
+    validate_name(name)?;
+    match std::fs::read_to_string(get_prompts_dir().join(name)) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(missing_prompt(name)),
+        Err(e) => Err(TllmError::Config(format!(
+            "Can't read prompt {}: {}",
+            name, e
+        ))),
+    }
+}
+
+// a new name starts an empty prompt
+pub fn edit(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    validate_name(name)?;
+    std::fs::create_dir_all(get_prompts_dir())?;
+    let path = get_prompts_dir().join(name);
+    crate::display::run_editor(&path.to_string_lossy())
+}
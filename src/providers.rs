@@ -0,0 +1,2556 @@
+use std::env;
+
+use crate::error::TllmError;
+use crate::keychain;
+use crate::logger::Logger;
+use crate::network::{self, Message, MessageType, Options, StreamLine, ToolCall, Usage};
+use crate::{config, mock, summary, tokens};
+use crate::{debug, error, info, warn};
+
+pub const PROVIDERS: &[&str] = &[
+    "anthropic",
+    "openai",
+    "gemini",
+    "groq",
+    "openrouter",
+    "ollama",
+    "mistral",
+    "deepseek",
+    "mock",
+];
+
+// anthropic won't cache anything shorter, see `Anthropic::body`
+const MIN_CACHED_TOKENS: u64 = 1024;
+
+const JSON_INSTRUCTION: &str =
+    "Reply with a single JSON object and nothing else, no code fences or commentary.";
+
+// everything that differs between providers
+// the transport, retries, and timeouts all live in `network`
+pub trait Provider: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn default_model(&self) -> &'static str;
+    // something fast and cheap for side requests like naming a conversation
+    fn title_model(&self) -> &'static str;
+    fn key_var(&self) -> &'static str;
+    // local servers run without keys
+    fn needs_key(&self) -> bool {
+        true
+    }
+
+    fn host(&self) -> &'static str;
+    fn port(&self) -> u16 {
+        443
+    }
+
+    fn tls(&self) -> bool {
+        true
+    }
+
+    // what an `--api-base` replaces, e.g. `/v1`
+    fn path_prefix(&self) -> &'static str;
+    fn endpoint(&self, model: &str, stream: bool, key: &str) -> String;
+    fn headers(&self, key: &str, options: &Options) -> Vec<String>;
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value;
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError>;
+    // whether `--prefill` is sent for the model to continue, otherwise it's only added to the response
+    fn supports_prefill(&self) -> bool {
+        false
+    }
+
+    // JSON mode opens the object itself where the model can carry on from it
+    // anthropic refuses an assistant message ending in whitespace
+    fn get_prefill<'a>(&self, options: &'a Options) -> Option<&'a str> {
+        let prefill = match (&options.prefill, options.json_response) {
+            (Some(prefill), _) => prefill.trim_end(),
+            (None, true) if self.supports_prefill() => "{",
+            (None, _) => return None,
+        };
+
+        (!prefill.is_empty()).then_some(prefill)
+    }
+
+    // tool calls are only sent as such while tools are offered, otherwise they're flattened to text
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    // whether `--cache-prompt` does anything
+    fn supports_prompt_caching(&self) -> bool {
+        false
+    }
+
+    // a chance to say what to do about a request that failed
+    fn describe_error(&self, _request: &network::Request, e: TllmError) -> TllmError {
+        e
+    }
+
+    // a stream closed before its end marker was cut off, one without a marker just ends
+    fn has_end_marker(&self) -> bool {
+        true
+    }
+
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error>;
+
+    fn request(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        stream: bool,
+    ) -> Result<network::Request, TllmError> {
+        let model = options.get_model(self.name());
+        let key = get_api_key(self.name(), options)?;
+
+        // a rolling summary stands in for everything before it, see `summary::update`
+        let (system_prompt, start) = match summary::is_enabled() {
+            true => summary::apply(system_prompt, chat_history),
+            false => (system_prompt.to_string(), 0),
+        };
+        let chat_history = &chat_history[start..];
+
+        // openai won't take a JSON response format unless the messages ask for JSON too
+        let system_prompt = match options.json_response {
+            true => format!("{}\n\n{}", system_prompt, JSON_INSTRUCTION)
+                .trim()
+                .to_string(),
+            false => system_prompt,
+        };
+
+        // only what's sent is trimmed, the conversation is saved whole
+        let omitted = tokens::get_omitted(&system_prompt, chat_history, options, &model);
+        if omitted > 0 {
+            info!("left {} older messages out of the request", omitted);
+            if options.print_notes {
+                eprintln!(
+                    "Left {} older messages out of the request, the conversation is still saved whole",
+                    omitted
+                );
+            }
+        }
+        let chat_history = &chat_history[omitted..];
+
+        if !options.tools.is_empty() && !self.supports_tools() {
+            return Err(TllmError::Config(format!(
+                "tllm doesn't support tools with {} yet",
+                self.name()
+            )));
+        }
+
+        if options.cache_prompt && !self.supports_prompt_caching() {
+            warn!(
+                "{} doesn't support prompt caching, ignoring it",
+                self.name()
+            );
+            if options.print_notes {
+                eprintln!(
+                    "{} doesn't support --cache-prompt, sending the request as usual",
+                    self.name()
+                );
+            }
+        }
+
+        if options.prefill.is_some() && !self.supports_prefill() {
+            info!(
+                "{} doesn't support prefill, only adding it to the response",
+                self.name()
+            );
+            if options.print_notes {
+                eprintln!(
+                    "{} doesn't support --prefill, it's only added to the start of the saved response",
+                    self.name()
+                );
+            }
+        }
+
+        let endpoint = self.endpoint(&model, stream, &key);
+
+        let (tls, host, port, path) = match &options.api_base {
+            Some(api_base) => (
+                api_base.tls,
+                api_base.host.clone(),
+                api_base.port,
+                format!("{}{}", api_base.path, endpoint),
+            ),
+            None => (
+                self.tls(),
+                self.host().to_string(),
+                self.port(),
+                format!("{}{}", self.path_prefix(), endpoint),
+            ),
+        };
+
+        // for gateways and proxies that want more than the provider does
+        let mut headers = self.headers(&key, options);
+        if let Some(value) = config::read_config().get(&format!("extra_headers.{}", self.name())) {
+            headers.extend(
+                network::parse_headers(value).map_err(|e| {
+                    TllmError::Config(format!("extra_headers.{}: {}", self.name(), e))
+                })?,
+            );
+        }
+        headers.extend(options.headers.iter().cloned());
+
+        for header in headers.iter() {
+            if let Some((name, value)) = header.split_once(':') {
+                if network::is_secret_header(name) {
+                    Logger::add_secret(value);
+                }
+            }
+        }
+
+        Ok(network::Request {
+            provider: self.name().to_string(),
+            model: model.clone(),
+            tls,
+            host,
+            port,
+            path,
+            headers,
+            body: self.body(&system_prompt, chat_history, options, &model, stream),
+        })
+    }
+
+    fn complete(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+    ) -> Result<Message, TllmError> {
+        let request = self.request(system_prompt, chat_history, options, false)?;
+        let response =
+            network::complete(&request, options).map_err(|e| self.describe_error(&request, e))?;
+
+        let mut message = self.parse_response(&response)?;
+        if let Some(prefill) = self.get_prefill(options) {
+            message.content = format!("{}{}", prefill, message.content);
+        }
+        message.provider = Some(request.provider.clone());
+        message.model = Some(request.model.clone());
+        message.created_at = Some(chrono::Local::now());
+
+        Ok(message)
+    }
+
+    fn stream(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        tx: std::sync::mpsc::Sender<network::StreamEvent>,
+        cancel: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    ) -> Result<Message, TllmError> {
+        let request = self.request(system_prompt, chat_history, options, true)?;
+
+        let prefill = self.get_prefill(options);
+
+        // failures come back with whatever was streamed before them, see `network::interrupt`
+        let mut started = false;
+        let response = network::stream(&request, options, |reader| {
+            info!("processing {} stream", self.name());
+
+            // once, a retry before any delta answers again
+            // the prefill is shown first, the model only sends what comes after it
+            if !started {
+                started = true;
+                if let Err(e) = tx.send(network::StreamEvent::Started) {
+                    error!("error reporting the stream's start: {}", e);
+                }
+
+                if let Some(prefill) = prefill {
+                    if let Err(e) = tx.send(network::StreamEvent::Delta(prefill.to_string())) {
+                        error!("error sending prefill: {}", e);
+                    }
+                }
+            }
+
+            network::read_stream(
+                reader,
+                &tx,
+                &cancel,
+                self.has_end_marker(),
+                |line, usage| self.parse_stream_line(line, usage),
+            )
+        });
+
+        match response {
+            Ok(mut message) => {
+                info!("stream finished, usage: {:?}", message.usage);
+                if let Some(prefill) = prefill {
+                    message.content = format!("{}{}", prefill, message.content);
+                }
+                message.provider = Some(request.provider.clone());
+                message.model = Some(request.model.clone());
+
+                Ok(message)
+            }
+            Err(e) => {
+                error!("Failed to process stream: {}", e);
+                Err(self.describe_error(&request, e))
+            }
+        }
+    }
+}
+
+pub fn get_provider(api: &str) -> Box<dyn Provider> {
+    match api {
+        "openai" => Box::new(OpenAI),
+        "groq" => Box::new(Groq),
+        "openrouter" => Box::new(OpenRouter),
+        "ollama" => Box::new(Ollama),
+        "mistral" => Box::new(Mistral),
+        "deepseek" => Box::new(DeepSeek),
+        "anthropic" => Box::new(Anthropic),
+        "gemini" => Box::new(Gemini),
+        "mock" => Box::new(Mock),
+        _ => panic!("Invalid API: {}--how'd this get here?", api),
+    }
+}
+
+// servers behind a custom API base often don't need a key
+// `api_key_env.<provider>` in the config renames the variable the key is read from
+pub fn get_key_var(api: &str) -> String {
+    crate::config::read_config()
+        .remove(&format!("api_key_env.{}", api))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|| get_provider(api).key_var().to_string())
+}
+
+// tried in order: the environment, `api_key.<provider>` in the config, then the keychain
+// along with where the key was found, e.g. `$OPENAI_API_KEY`
+pub fn find_api_key(api: &str) -> Option<(String, String)> {
+    let key_var = get_key_var(api);
+    let (key, source) = env::var(&key_var)
+        .ok()
+        .filter(|key| !key.trim().is_empty())
+        .map(|key| (key, format!("${}", key_var)))
+        .or_else(|| {
+            crate::config::read_config()
+                .remove(&format!("api_key.{}", api))
+                .map(|key| (key, "the config".to_string()))
+        })
+        .or_else(|| keychain::get_key(api).map(|key| (key, "the keychain".to_string())))
+        .map(|(key, source)| (key.trim().to_string(), source))
+        .filter(|(key, _)| !key.is_empty())?;
+
+    Logger::add_secret(&key);
+    Some((key, source))
+}
+
+pub fn resolve_api_key(api: &str) -> Option<String> {
+    find_api_key(api).map(|(key, _)| key)
+}
+
+fn get_api_key(api: &str, options: &Options) -> Result<String, TllmError> {
+    match resolve_api_key(api) {
+        Some(key) => Ok(key),
+        None if options.api_base.is_some() || !get_provider(api).needs_key() => Ok(String::new()),
+        None => Err(missing_api_key(api)),
+    }
+}
+
+pub fn missing_api_key(api: &str) -> TllmError {
+    TllmError::Config(format!(
+        "No API key for {}: export {}, set api_key under [providers.{}] in config.toml, or run `tllm auth set {}`",
+        api,
+        get_key_var(api),
+        api,
+        api
+    ))
+}
+
+fn get_content(content: &serde_json::Value, response: &serde_json::Value) -> String {
+    match content.as_str() {
+        Some(content) => content.to_string(),
+        None => {
+            error!("No content in response");
+            debug!("response body: {}", response);
+            String::new()
+        }
+    }
+}
+
+fn get_usage(
+    response: &serde_json::Value,
+    prompt_tokens: &str,
+    completion_tokens: &str,
+) -> Option<Usage> {
+    Some(Usage {
+        prompt_tokens: response[prompt_tokens].as_u64()?,
+        completion_tokens: response[completion_tokens].as_u64()?,
+        ..Default::default()
+    })
+}
+
+fn get_data(line: &str) -> Option<&str> {
+    line.strip_prefix("data: ").map(|payload| payload.trim())
+}
+
+// for requests without tools, where the APIs won't take tool calls or results
+// and for providers that don't do tools at all
+fn get_tool_text(message: &Message) -> (MessageType, String) {
+    match message.message_type {
+        MessageType::Tool => (
+            MessageType::User,
+            format!(
+                "[result of tool call {}]\n{}",
+                message.tool_call_id.as_deref().unwrap_or_default(),
+                message.content
+            ),
+        ),
+        _ => {
+            let mut content = message.content.clone();
+            for call in message.tool_calls.iter() {
+                content.push_str(&format!(
+                    "\n[called {} as {} with {}]",
+                    call.name, call.id, call.arguments
+                ));
+            }
+
+            (message.message_type.clone(), content.trim().to_string())
+        }
+    }
+}
+
+// images are read from their saved paths, one that's gone by now is mentioned in the text instead
+fn get_images(message: &Message, content: &str) -> (String, Vec<(&'static str, String)>) {
+    let mut text = content.to_string();
+    let mut images = Vec::new();
+    for path in message.images.iter() {
+        match crate::image::load_for_request(path) {
+            Some(image) => images.push(image),
+            None => text.push_str(&format!("\n[image {} couldn't be read]", path)),
+        }
+    }
+
+    (text, images)
+}
+
+// a plain string, or text and image parts when the message has images
+fn get_openai_content(message: &Message, content: &str) -> serde_json::Value {
+    if message.images.is_empty() {
+        return serde_json::json!(content);
+    }
+
+    let (text, images) = get_images(message, content);
+    let mut parts = vec![serde_json::json!({ "type": "text", "text": text })];
+    for (media_type, data) in images {
+        parts.push(serde_json::json!({
+            "type": "image_url",
+            "image_url": {
+                "url": format!("data:{};base64,{}", media_type, data)
+            }
+        }));
+    }
+
+    serde_json::json!(parts)
+}
+
+// images go before the text, which is how anthropic recommends it
+fn get_anthropic_content(message: &Message, content: &str) -> serde_json::Value {
+    if message.images.is_empty() {
+        return serde_json::json!(content);
+    }
+
+    let (text, images) = get_images(message, content);
+    let mut blocks = Vec::new();
+    for (media_type, data) in images {
+        blocks.push(serde_json::json!({
+            "type": "image",
+            "source": {
+                "type": "base64",
+                "media_type": media_type,
+                "data": data
+            }
+        }));
+    }
+
+    blocks.push(serde_json::json!({ "type": "text", "text": text }));
+    serde_json::json!(blocks)
+}
+
+fn get_openai_message(message: &Message, tools: bool) -> serde_json::Value {
+    if !tools {
+        let (message_type, content) = get_tool_text(message);
+        return serde_json::json!({
+            "role": message_type.to_string(),
+            "content": get_openai_content(message, &content)
+        });
+    }
+
+    let mut value = serde_json::json!({
+        "role": message.message_type.to_string(),
+        "content": get_openai_content(message, &message.content)
+    });
+
+    if !message.tool_calls.is_empty() {
+        value["tool_calls"] = message
+            .tool_calls
+            .iter()
+            .map(|call| {
+                serde_json::json!({
+                    "id": call.id,
+                    "type": "function",
+                    "function": {
+                        "name": call.name,
+                        // a string of JSON, not an object
+                        "arguments": call.arguments.to_string()
+                    }
+                })
+            })
+            .collect();
+    }
+
+    if let Some(id) = &message.tool_call_id {
+        value["tool_call_id"] = serde_json::json!(id);
+    }
+
+    value
+}
+
+// tool calls are content blocks, and their results go back from the user
+fn get_anthropic_message(message: &Message, tools: bool) -> serde_json::Value {
+    if !tools {
+        let (message_type, content) = get_tool_text(message);
+        return serde_json::json!({
+            "role": message_type.to_string(),
+            "content": get_anthropic_content(message, &content)
+        });
+    }
+
+    if message.message_type == MessageType::Tool {
+        return serde_json::json!({
+            "role": "user",
+            "content": [{
+                "type": "tool_result",
+                "tool_use_id": message.tool_call_id,
+                "content": message.content
+            }]
+        });
+    }
+
+    if message.tool_calls.is_empty() {
+        return serde_json::json!({
+            "role": message.message_type.to_string(),
+            "content": get_anthropic_content(message, &message.content)
+        });
+    }
+
+    let mut blocks = Vec::new();
+    if !message.content.is_empty() {
+        blocks.push(serde_json::json!({ "type": "text", "text": message.content }));
+    }
+
+    for call in message.tool_calls.iter() {
+        blocks.push(serde_json::json!({
+            "type": "tool_use",
+            "id": call.id,
+            "name": call.name,
+            "input": call.arguments
+        }));
+    }
+
+    serde_json::json!({
+        "role": "assistant",
+        "content": blocks
+    })
+}
+
+// cache breakpoints on the system prompt and the first user message big enough to cache,
+// which is where attachments usually are, anthropic caches everything up to each breakpoint
+fn cache_anthropic_prompt(
+    body: &mut serde_json::Value,
+    system_prompt: &str,
+    chat_history: &[Message],
+    model: &str,
+) {
+    let cache_control = serde_json::json!({ "type": "ephemeral" });
+
+    if !system_prompt.is_empty() {
+        body["system"] = serde_json::json!([{
+            "type": "text",
+            "text": system_prompt,
+            "cache_control": cache_control
+        }]);
+    }
+
+    let index = chat_history.iter().position(|m| {
+        m.message_type == MessageType::User && tokens::count(&m.content, model) >= MIN_CACHED_TOKENS
+    });
+
+    if let Some(index) = index {
+        let content = &mut body["messages"][index]["content"];
+        if let Some(text) = content.as_str() {
+            *content = serde_json::json!([{ "type": "text", "text": text }]);
+        }
+
+        if let Some(last) = content.as_array_mut().and_then(|blocks| blocks.last_mut()) {
+            last["cache_control"] = cache_control;
+        }
+    }
+}
+
+// on top of the input tokens, which don't include what was written to or read from the cache
+fn get_cache_usage(response: &serde_json::Value, mut usage: Usage) -> Usage {
+    usage.cache_creation_tokens = response["cache_creation_input_tokens"]
+        .as_u64()
+        .unwrap_or(0);
+    usage.cache_read_tokens = response["cache_read_input_tokens"].as_u64().unwrap_or(0);
+    usage
+}
+
+// openai and groq share a wire format, groq is basically a copy
+fn get_openai_body(
+    system_prompt: &str,
+    chat_history: &[Message],
+    options: &Options,
+    model: &str,
+    stream: bool,
+) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "model": model,
+        "messages": std::iter::once(&Message::new(MessageType::System, system_prompt.to_string()))
+            .chain(chat_history.iter())
+            .map(|message| get_openai_message(message, !options.tools.is_empty()))
+            .collect::<Vec<serde_json::Value>>(),
+        "stream": stream,
+    });
+
+    if let Some(max_tokens) = options.max_tokens {
+        body["max_tokens"] = serde_json::json!(max_tokens);
+    }
+
+    // left out entirely when unset so the provider defaults apply
+    if let Some(temperature) = options.temperature {
+        body["temperature"] = serde_json::json!(temperature);
+    }
+
+    if !options.stop.is_empty() {
+        body["stop"] = serde_json::json!(options.stop);
+    }
+
+    if options.json_response {
+        body["response_format"] = serde_json::json!({ "type": "json_object" });
+    }
+
+    if !options.tools.is_empty() {
+        body["tools"] = options
+            .tools
+            .iter()
+            .map(|tool| {
+                serde_json::json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name,
+                        "description": tool.description,
+                        "parameters": tool.parameters
+                    }
+                })
+            })
+            .collect();
+    }
+
+    body
+}
+
+fn parse_openai_response(response: &serde_json::Value) -> Message {
+    let choice = &response["choices"][0]["message"];
+    let tool_calls = choice["tool_calls"]
+        .as_array()
+        .map(|calls| {
+            calls
+                .iter()
+                .map(|call| ToolCall {
+                    id: call["id"].as_str().unwrap_or_default().to_string(),
+                    name: call["function"]["name"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    arguments: call["function"]["arguments"]
+                        .as_str()
+                        .and_then(|arguments| serde_json::from_str(arguments).ok())
+                        .unwrap_or_else(|| serde_json::json!({})),
+                })
+                .collect::<Vec<ToolCall>>()
+        })
+        .unwrap_or_default();
+
+    // there's no content alongside tool calls more often than not
+    let content = match tool_calls.is_empty() {
+        true => get_content(&choice["content"], response),
+        false => choice["content"].as_str().unwrap_or_default().to_string(),
+    };
+
+    let mut message = Message::new(MessageType::Assistant, content);
+    message.tool_calls = tool_calls;
+    message.usage = get_usage(&response["usage"], "prompt_tokens", "completion_tokens");
+
+    message
+}
+
+fn parse_openai_stream_line(
+    line: &str,
+    usage: &mut Option<Usage>,
+) -> Result<StreamLine, std::io::Error> {
+    let payload = match get_data(line) {
+        Some(payload) => payload,
+        None => return Ok(StreamLine::Skip),
+    };
+
+    if payload.is_empty() || payload == "[DONE]" {
+        return Ok(StreamLine::Done);
+    }
+
+    let response_json: serde_json::Value = match serde_json::from_str(payload) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("JSON parse error: {}", e);
+            debug!("Error payload: {}", payload);
+
+            serde_json::Value::Null
+        }
+    };
+
+    // openai sends usage in a final chunk with no choices, groq tucks it under x_groq
+    if let Some(u) = get_usage(
+        &response_json["usage"],
+        "prompt_tokens",
+        "completion_tokens",
+    )
+    .or_else(|| {
+        get_usage(
+            &response_json["x_groq"]["usage"],
+            "prompt_tokens",
+            "completion_tokens",
+        )
+    }) {
+        *usage = Some(u);
+    }
+
+    // role-only and usage-only chunks have no content
+    Ok(
+        match response_json["choices"][0]["delta"]["content"].as_str() {
+            Some(delta) => StreamLine::Delta(delta.to_string()),
+            None => StreamLine::Skip,
+        },
+    )
+}
+
+pub struct OpenAI;
+
+impl Provider for OpenAI {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "gpt-4o-mini"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "gpt-4o-mini"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "OPENAI_API_KEY"
+    }
+
+    fn host(&self) -> &'static str {
+        "api.openai.com"
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        "/v1"
+    }
+
+    fn endpoint(&self, _model: &str, _stream: bool, _key: &str) -> String {
+        "/chat/completions".to_string()
+    }
+
+    fn headers(&self, key: &str, _options: &Options) -> Vec<String> {
+        vec![format!("Authorization: Bearer {}", key)]
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value {
+        let mut body = get_openai_body(system_prompt, chat_history, options, model, stream);
+        if stream {
+            body["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
+
+        body
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        Ok(parse_openai_response(response))
+    }
+
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        parse_openai_stream_line(line, usage)
+    }
+}
+
+pub struct Groq;
+
+impl Provider for Groq {
+    fn name(&self) -> &'static str {
+        "groq"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "llama-3.2-90b-text-preview"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "llama-3.1-8b-instant"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "GROQ_API_KEY"
+    }
+
+    fn host(&self) -> &'static str {
+        "api.groq.com"
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        "/openai/v1"
+    }
+
+    fn endpoint(&self, _model: &str, _stream: bool, _key: &str) -> String {
+        "/chat/completions".to_string()
+    }
+
+    fn headers(&self, key: &str, _options: &Options) -> Vec<String> {
+        vec![format!("Authorization: Bearer {}", key)]
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value {
+        get_openai_body(system_prompt, chat_history, options, model, stream)
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        Ok(parse_openai_response(response))
+    }
+
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        parse_openai_stream_line(line, usage)
+    }
+}
+
+// openai's wire format, usage comes in the last chunk without being asked for
+pub struct Mistral;
+
+impl Provider for Mistral {
+    fn name(&self) -> &'static str {
+        "mistral"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "mistral-large-latest"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "mistral-small-latest"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "MISTRAL_API_KEY"
+    }
+
+    fn host(&self) -> &'static str {
+        "api.mistral.ai"
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        "/v1"
+    }
+
+    fn endpoint(&self, _model: &str, _stream: bool, _key: &str) -> String {
+        "/chat/completions".to_string()
+    }
+
+    fn headers(&self, key: &str, _options: &Options) -> Vec<String> {
+        vec![format!("Authorization: Bearer {}", key)]
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value {
+        get_openai_body(system_prompt, chat_history, options, model, stream)
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        Ok(parse_openai_response(response))
+    }
+
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        parse_openai_stream_line(line, usage)
+    }
+}
+
+// openai's wire format as well
+pub struct DeepSeek;
+
+impl Provider for DeepSeek {
+    fn name(&self) -> &'static str {
+        "deepseek"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "deepseek-chat"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "deepseek-chat"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "DEEPSEEK_API_KEY"
+    }
+
+    fn host(&self) -> &'static str {
+        "api.deepseek.com"
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        ""
+    }
+
+    fn endpoint(&self, _model: &str, _stream: bool, _key: &str) -> String {
+        "/chat/completions".to_string()
+    }
+
+    fn headers(&self, key: &str, _options: &Options) -> Vec<String> {
+        vec![format!("Authorization: Bearer {}", key)]
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value {
+        let mut body = get_openai_body(system_prompt, chat_history, options, model, stream);
+        if stream {
+            body["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
+
+        body
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        Ok(parse_openai_response(response))
+    }
+
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        parse_openai_stream_line(line, usage)
+    }
+}
+
+// one key for models from every vendor, named like `meta-llama/llama-3.1-70b-instruct`
+pub struct OpenRouter;
+
+// openrouter can answer 200 and still carry an error, either as the whole body or as a stream chunk
+fn get_openrouter_error(response: &serde_json::Value) -> Option<String> {
+    match response["error"].is_object() {
+        true => Some(network::get_error_message(&response.to_string())),
+        false => None,
+    }
+}
+
+impl Provider for OpenRouter {
+    fn name(&self) -> &'static str {
+        "openrouter"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "openai/gpt-4o-mini"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "openai/gpt-4o-mini"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "OPENROUTER_API_KEY"
+    }
+
+    fn host(&self) -> &'static str {
+        "openrouter.ai"
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        "/api/v1"
+    }
+
+    fn endpoint(&self, _model: &str, _stream: bool, _key: &str) -> String {
+        "/chat/completions".to_string()
+    }
+
+    // optional, they're how openrouter attributes requests to an app
+    fn headers(&self, key: &str, _options: &Options) -> Vec<String> {
+        vec![
+            format!("Authorization: Bearer {}", key),
+            "HTTP-Referer: https://github.com/JTan2231/tllm".to_string(),
+            "X-Title: tllm".to_string(),
+        ]
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value {
+        let mut body = get_openai_body(system_prompt, chat_history, options, model, stream);
+        if stream {
+            body["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
+
+        body
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        match get_openrouter_error(response) {
+            Some(e) => Err(TllmError::Provider(format!("openrouter: {}", e))),
+            None => Ok(parse_openai_response(response)),
+        }
+    }
+
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        if let Some(payload) = get_data(line) {
+            if let Ok(response) = serde_json::from_str::<serde_json::Value>(payload) {
+                if let Some(e) = get_openrouter_error(&response) {
+                    return Err(std::io::Error::other(format!("openrouter: {}", e)));
+                }
+            }
+        }
+
+        parse_openai_stream_line(line, usage)
+    }
+}
+
+pub struct Anthropic;
+
+impl Provider for Anthropic {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "claude-3-5-sonnet-latest"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "claude-3-5-haiku-latest"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "ANTHROPIC_API_KEY"
+    }
+
+    fn host(&self) -> &'static str {
+        "api.anthropic.com"
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        "/v1"
+    }
+
+    fn endpoint(&self, _model: &str, _stream: bool, _key: &str) -> String {
+        "/messages".to_string()
+    }
+
+    fn headers(&self, key: &str, options: &Options) -> Vec<String> {
+        let mut headers = vec![
+            format!("x-api-key: {}", key),
+            "anthropic-version: 2023-06-01".to_string(),
+        ];
+
+        if options.cache_prompt {
+            headers.push("anthropic-beta: prompt-caching-2024-07-31".to_string());
+        }
+
+        headers
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "model": model,
+            "messages": chat_history.iter()
+                .map(|message| get_anthropic_message(message, !options.tools.is_empty()))
+                .collect::<Vec<serde_json::Value>>(),
+            "stream": stream,
+            // required by the messages API
+            "max_tokens": options.max_tokens.unwrap_or(4096),
+            "system": system_prompt,
+        });
+
+        if let Some(temperature) = options.temperature {
+            body["temperature"] = serde_json::json!(temperature);
+        }
+
+        if !options.stop.is_empty() {
+            body["stop_sequences"] = serde_json::json!(options.stop);
+        }
+
+        if !options.tools.is_empty() {
+            body["tools"] = options
+                .tools
+                .iter()
+                .map(|tool| {
+                    serde_json::json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "input_schema": tool.parameters
+                    })
+                })
+                .collect();
+        }
+
+        if options.cache_prompt {
+            cache_anthropic_prompt(&mut body, system_prompt, chat_history, model);
+        }
+
+        // the reply picks up where this leaves off
+        if let Some(prefill) = self.get_prefill(options) {
+            body["messages"]
+                .as_array_mut()
+                .unwrap()
+                .push(serde_json::json!({
+                    "role": "assistant",
+                    "content": prefill
+                }));
+        }
+
+        body
+    }
+
+    fn supports_prefill(&self) -> bool {
+        true
+    }
+
+    fn supports_prompt_caching(&self) -> bool {
+        true
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        let blocks = response["content"].as_array().cloned().unwrap_or_default();
+        let tool_calls = blocks
+            .iter()
+            .filter(|block| block["type"] == "tool_use")
+            .map(|block| ToolCall {
+                id: block["id"].as_str().unwrap_or_default().to_string(),
+                name: block["name"].as_str().unwrap_or_default().to_string(),
+                arguments: block["input"].clone(),
+            })
+            .collect::<Vec<ToolCall>>();
+
+        // text can come before the tool calls, or not at all
+        let content = match tool_calls.is_empty() {
+            true => get_content(&response["content"][0]["text"], response),
+            false => blocks
+                .iter()
+                .filter_map(|block| block["text"].as_str())
+                .collect::<Vec<&str>>()
+                .join("\n\n"),
+        };
+
+        let mut message = Message::new(MessageType::Assistant, content);
+        message.tool_calls = tool_calls;
+        message.usage = get_usage(&response["usage"], "input_tokens", "output_tokens")
+            .map(|usage| get_cache_usage(&response["usage"], usage));
+
+        Ok(message)
+    }
+
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        if line.starts_with("event: message_stop") {
+            return Ok(StreamLine::Done);
+        }
+
+        let payload = match get_data(line) {
+            Some(payload) => payload,
+            None => return Ok(StreamLine::Skip),
+        };
+
+        if payload.is_empty() || payload == "[DONE]" {
+            return Ok(StreamLine::Done);
+        }
+
+        let response_json: serde_json::Value = serde_json::from_str(payload)?;
+
+        // input tokens arrive with message_start, output tokens with message_delta
+        let usage = usage.get_or_insert_with(Usage::default);
+        if let Some(n) = response_json["message"]["usage"]["input_tokens"].as_u64() {
+            usage.prompt_tokens = n;
+            *usage = get_cache_usage(&response_json["message"]["usage"], usage.clone());
+        }
+
+        if let Some(n) = response_json["usage"]["output_tokens"].as_u64() {
+            usage.completion_tokens = n;
+        }
+
+        if response_json["type"] == "content_block_delta" {
+            if let Some(delta) = response_json["delta"]["text"].as_str() {
+                return Ok(StreamLine::Delta(delta.to_string()));
+            }
+        }
+
+        Ok(StreamLine::Skip)
+    }
+}
+
+pub struct Gemini;
+
+impl Gemini {
+    // a safety block comes back as `promptFeedback` with no candidates
+    fn get_block_error(response: &serde_json::Value) -> Option<String> {
+        if !response["candidates"].is_null() {
+            return None;
+        }
+
+        let reason = response["promptFeedback"]["blockReason"].as_str()?;
+        Some(format!("Gemini blocked the prompt: {}", reason))
+    }
+}
+
+impl Provider for Gemini {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "gemini-1.5-flash-latest"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "gemini-1.5-flash-latest"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "GEMINI_API_KEY"
+    }
+
+    fn host(&self) -> &'static str {
+        "generativelanguage.googleapis.com"
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        "/v1beta"
+    }
+
+    // the key goes in the query string rather than a header
+    fn endpoint(&self, model: &str, stream: bool, key: &str) -> String {
+        if stream {
+            format!(
+                "/models/{}:streamGenerateContent?alt=sse&key={}",
+                model, key
+            )
+        } else {
+            format!("/models/{}:generateContent?key={}", model, key)
+        }
+    }
+
+    fn headers(&self, _key: &str, _options: &Options) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        _model: &str,
+        _stream: bool,
+    ) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "contents": chat_history.iter().map(|m| {
+                let (message_type, content) = get_tool_text(m);
+                let (text, images) = get_images(m, &content);
+                let mut parts = vec![serde_json::json!({ "text": text })];
+                for (media_type, data) in images {
+                    parts.push(serde_json::json!({
+                        "inline_data": {
+                            "mime_type": media_type,
+                            "data": data
+                        }
+                    }));
+                }
+
+                serde_json::json!({
+                    "parts": parts,
+                    // gemini only has the two, tool results are already written out as user text
+                    // and the system prompt goes in `systemInstruction`, so anything else is the user's
+                    "role": match message_type {
+                        MessageType::Assistant => "model",
+                        MessageType::User | MessageType::System | MessageType::Tool => "user",
+                    }
+                })
+            }).collect::<Vec<_>>(),
+            "systemInstruction": {
+                "parts": [{
+                    "text": system_prompt,
+                }]
+            }
+        });
+
+        if let Some(max_tokens) = options.max_tokens {
+            body["generationConfig"]["maxOutputTokens"] = serde_json::json!(max_tokens);
+        }
+
+        if let Some(temperature) = options.temperature {
+            body["generationConfig"]["temperature"] = serde_json::json!(temperature);
+        }
+
+        if !options.stop.is_empty() {
+            body["generationConfig"]["stopSequences"] = serde_json::json!(options.stop);
+        }
+
+        if options.json_response {
+            body["generationConfig"]["responseMimeType"] = serde_json::json!("application/json");
+        }
+
+        body
+    }
+
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    fn has_end_marker(&self) -> bool {
+        false
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        if let Some(e) = Gemini::get_block_error(response) {
+            return Err(TllmError::Provider(e));
+        }
+
+        let mut message = Message::new(
+            MessageType::Assistant,
+            get_content(
+                &response["candidates"][0]["content"]["parts"][0]["text"],
+                response,
+            ),
+        );
+        message.usage = get_usage(
+            &response["usageMetadata"],
+            "promptTokenCount",
+            "candidatesTokenCount",
+        );
+
+        Ok(message)
+    }
+
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        let payload = match get_data(line) {
+            Some(payload) => payload,
+            None => return Ok(StreamLine::Skip),
+        };
+
+        let response_json: serde_json::Value = serde_json::from_str(payload)?;
+        if let Some(e) = Gemini::get_block_error(&response_json) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+        }
+
+        // every chunk carries the running totals
+        if let Some(u) = get_usage(
+            &response_json["usageMetadata"],
+            "promptTokenCount",
+            "candidatesTokenCount",
+        ) {
+            *usage = Some(u);
+        }
+
+        Ok(
+            match response_json["candidates"][0]["content"]["parts"][0]["text"].as_str() {
+                Some(delta) => StreamLine::Delta(delta.to_string()),
+                None => StreamLine::Skip,
+            },
+        )
+    }
+}
+
+// local models over plain HTTP, `api_base.ollama` points it at another machine
+pub struct Ollama;
+
+// errors come as `{"error": "..."}`, in a stream as one of its lines
+fn get_ollama_error(response: &serde_json::Value) -> Option<&str> {
+    response["error"].as_str()
+}
+
+fn get_ollama_usage(response: &serde_json::Value) -> Usage {
+    Usage {
+        // left out when the whole prompt was already in ollama's cache
+        prompt_tokens: response["prompt_eval_count"].as_u64().unwrap_or(0),
+        completion_tokens: response["eval_count"].as_u64().unwrap_or(0),
+        ..Default::default()
+    }
+}
+
+impl Provider for Ollama {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "llama3.2"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "llama3.2"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "OLLAMA_API_KEY"
+    }
+
+    fn needs_key(&self) -> bool {
+        false
+    }
+
+    fn host(&self) -> &'static str {
+        "localhost"
+    }
+
+    fn port(&self) -> u16 {
+        11434
+    }
+
+    fn tls(&self) -> bool {
+        false
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        ""
+    }
+
+    fn endpoint(&self, _model: &str, _stream: bool, _key: &str) -> String {
+        "/api/chat".to_string()
+    }
+
+    // only for an ollama behind a proxy that checks one
+    fn headers(&self, key: &str, _options: &Options) -> Vec<String> {
+        match key.is_empty() {
+            true => Vec::new(),
+            false => vec![format!("Authorization: Bearer {}", key)],
+        }
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value {
+        let mut messages = Vec::new();
+        if !system_prompt.is_empty() {
+            messages.push(serde_json::json!({
+                "role": "system",
+                "content": system_prompt
+            }));
+        }
+
+        for message in chat_history {
+            let (message_type, content) = get_tool_text(message);
+            let (content, images) = get_images(message, &content);
+            let mut value = serde_json::json!({
+                "role": message_type.to_string(),
+                "content": content
+            });
+
+            if !images.is_empty() {
+                value["images"] = images.into_iter().map(|(_, data)| data).collect();
+            }
+
+            messages.push(value);
+        }
+
+        let mut body = serde_json::json!({
+            "model": model,
+            "messages": messages,
+            "stream": stream,
+        });
+
+        if let Some(max_tokens) = options.max_tokens {
+            body["options"]["num_predict"] = serde_json::json!(max_tokens);
+        }
+
+        if let Some(temperature) = options.temperature {
+            body["options"]["temperature"] = serde_json::json!(temperature);
+        }
+
+        if !options.stop.is_empty() {
+            body["options"]["stop"] = serde_json::json!(options.stop);
+        }
+
+        if options.json_response {
+            body["format"] = serde_json::json!("json");
+        }
+
+        body
+    }
+
+    // tool calls are flattened to text, like gemini
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    fn describe_error(&self, request: &network::Request, e: TllmError) -> TllmError {
+        match e {
+            TllmError::Io(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                TllmError::Io(std::io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Nothing is listening at {}:{}, start ollama with `ollama serve` or point api_base.ollama at where it runs",
+                        request.host, request.port
+                    ),
+                ))
+            }
+            e => e,
+        }
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        if let Some(e) = get_ollama_error(response) {
+            return Err(TllmError::Provider(format!("ollama: {}", e)));
+        }
+
+        let mut message = Message::new(
+            MessageType::Assistant,
+            get_content(&response["message"]["content"], response),
+        );
+        message.usage = Some(get_ollama_usage(response));
+
+        Ok(message)
+    }
+
+    // one JSON object a line rather than SSE, the last one has `done` and the token counts
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        if line.trim().is_empty() {
+            return Ok(StreamLine::Skip);
+        }
+
+        let response_json: serde_json::Value = serde_json::from_str(line)?;
+        if let Some(e) = get_ollama_error(&response_json) {
+            return Err(std::io::Error::other(format!("ollama: {}", e)));
+        }
+
+        if response_json["done"].as_bool().unwrap_or(false) {
+            *usage = Some(get_ollama_usage(&response_json));
+            return Ok(StreamLine::Done);
+        }
+
+        Ok(match response_json["message"]["content"].as_str() {
+            Some(delta) if !delta.is_empty() => StreamLine::Delta(delta.to_string()),
+            _ => StreamLine::Skip,
+        })
+    }
+}
+
+// `-a mock` answers the last user message itself, for trying tllm out and scripting against it
+// without a key. the model picks the answer: `echo`, `upper`, or `reverse`
+// `TLLM_MOCK_DELAY_MS` waits before each reply and streamed word,
+// `TLLM_MOCK_FAIL=N` fails a stream after N words and a plain request outright
+// the answers come from a server on loopback, see `mock`
+pub struct Mock;
+
+impl Provider for Mock {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn default_model(&self) -> &'static str {
+        "echo"
+    }
+
+    fn title_model(&self) -> &'static str {
+        "echo"
+    }
+
+    fn key_var(&self) -> &'static str {
+        "MOCK_API_KEY"
+    }
+
+    fn needs_key(&self) -> bool {
+        false
+    }
+
+    fn host(&self) -> &'static str {
+        "127.0.0.1"
+    }
+
+    fn port(&self) -> u16 {
+        mock::get_port()
+    }
+
+    fn tls(&self) -> bool {
+        false
+    }
+
+    fn path_prefix(&self) -> &'static str {
+        ""
+    }
+
+    fn endpoint(&self, _model: &str, _stream: bool, _key: &str) -> String {
+        "/mock".to_string()
+    }
+
+    // the server can't see the environment's settings any other way, it's checked there
+    fn headers(&self, _key: &str, _options: &Options) -> Vec<String> {
+        [
+            ("TLLM_MOCK_DELAY_MS", "X-Mock-Delay-Ms"),
+            ("TLLM_MOCK_FAIL", "X-Mock-Fail"),
+        ]
+        .iter()
+        .filter_map(|(var, header)| {
+            env::var(var)
+                .ok()
+                .map(|value| format!("{}: {}", header, value.trim()))
+        })
+        .collect()
+    }
+
+    fn body(
+        &self,
+        system_prompt: &str,
+        chat_history: &[Message],
+        _options: &Options,
+        model: &str,
+        stream: bool,
+    ) -> serde_json::Value {
+        let mut messages = Vec::new();
+        if !system_prompt.is_empty() {
+            messages.push(serde_json::json!({
+                "role": "system",
+                "content": system_prompt
+            }));
+        }
+
+        for message in chat_history {
+            let (message_type, content) = get_tool_text(message);
+            messages.push(serde_json::json!({
+                "role": message_type.to_string(),
+                "content": content
+            }));
+        }
+
+        serde_json::json!({
+            "model": model,
+            "messages": messages,
+            "stream": stream,
+        })
+    }
+
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    fn parse_response(&self, response: &serde_json::Value) -> Result<Message, TllmError> {
+        let mut message = Message::new(
+            MessageType::Assistant,
+            get_content(&response["content"], response),
+        );
+        message.usage = get_usage(response, "prompt_tokens", "completion_tokens");
+
+        Ok(message)
+    }
+
+    // `{"delta": ...}` a word at a time, then `{"done": true, ...}` with the token counts
+    fn parse_stream_line(
+        &self,
+        line: &str,
+        usage: &mut Option<Usage>,
+    ) -> Result<StreamLine, std::io::Error> {
+        if line.trim().is_empty() {
+            return Ok(StreamLine::Skip);
+        }
+
+        let response_json: serde_json::Value = serde_json::from_str(line)?;
+        if response_json["done"].as_bool().unwrap_or(false) {
+            *usage = get_usage(&response_json, "prompt_tokens", "completion_tokens");
+            return Ok(StreamLine::Done);
+        }
+
+        Ok(match response_json["delta"].as_str() {
+            Some(delta) if !delta.is_empty() => StreamLine::Delta(delta.to_string()),
+            _ => StreamLine::Skip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeServer, Response};
+
+    fn get_history() -> Vec<Message> {
+        vec![Message::new(MessageType::User, "Hi".to_string())]
+    }
+
+    fn get_openai_reply(content: &str) -> serde_json::Value {
+        serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": content } }],
+            "usage": { "prompt_tokens": 12, "completion_tokens": 3 }
+        })
+    }
+
+    // what `name` sends for a one-message conversation, exactly as it goes over the wire
+    fn get_request(name: &str, reply: serde_json::Value) -> String {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::json("200 OK", &reply)]);
+        get_provider(name)
+            .complete("Be brief.", &get_history(), &server.options())
+            .unwrap();
+
+        server.requests().remove(0)
+    }
+
+    // the deltas sent over the channel, and the message the stream ended with
+    fn stream(
+        name: &str,
+        response: Response,
+    ) -> (Vec<String>, Result<Message, TllmError>, FakeServer) {
+        testing::setup();
+        let server = FakeServer::start(vec![response]);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let result = get_provider(name).stream("", &get_history(), &server.options(), tx, cancel);
+
+        let deltas = rx
+            .iter()
+            .filter_map(|event| match event {
+                network::StreamEvent::Delta(delta) => Some(delta),
+                _ => None,
+            })
+            .collect();
+
+        (deltas, result, server)
+    }
+
+    #[test]
+    fn openai_request() {
+        assert_eq!(
+            get_request("openai", get_openai_reply("Hello")),
+            "POST /chat/completions HTTP/1.1\r\n\
+            Host: 127.0.0.1:{port}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: 122\r\n\
+            Accept: */*\r\n\
+            Accept-Encoding: identity\r\n\
+            Connection: keep-alive\r\n\
+            Authorization: Bearer test-key-openai\r\n\
+            \r\n\
+            {\"messages\":[{\"content\":\"Be brief.\",\"role\":\"system\"},{\"content\":\"Hi\",\"role\":\"user\"}],\"model\":\"gpt-4o-mini\",\"stream\":false}"
+        );
+    }
+
+    #[test]
+    fn anthropic_request() {
+        let reply = serde_json::json!({
+            "content": [{ "type": "text", "text": "Hello" }],
+            "usage": { "input_tokens": 12, "output_tokens": 3 }
+        });
+
+        assert_eq!(
+            get_request("anthropic", reply),
+            "POST /messages HTTP/1.1\r\n\
+            Host: 127.0.0.1:{port}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: 134\r\n\
+            Accept: */*\r\n\
+            Accept-Encoding: identity\r\n\
+            Connection: keep-alive\r\n\
+            x-api-key: test-key-anthropic\r\n\
+            anthropic-version: 2023-06-01\r\n\
+            \r\n\
+            {\"max_tokens\":4096,\"messages\":[{\"content\":\"Hi\",\"role\":\"user\"}],\"model\":\"claude-3-5-sonnet-latest\",\"stream\":false,\"system\":\"Be brief.\"}"
+        );
+    }
+
+    #[test]
+    fn groq_request() {
+        assert_eq!(
+            get_request("groq", get_openai_reply("Hello")),
+            "POST /chat/completions HTTP/1.1\r\n\
+            Host: 127.0.0.1:{port}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: 137\r\n\
+            Accept: */*\r\n\
+            Accept-Encoding: identity\r\n\
+            Connection: keep-alive\r\n\
+            Authorization: Bearer test-key-groq\r\n\
+            \r\n\
+            {\"messages\":[{\"content\":\"Be brief.\",\"role\":\"system\"},{\"content\":\"Hi\",\"role\":\"user\"}],\"model\":\"llama-3.2-90b-text-preview\",\"stream\":false}"
+        );
+    }
+
+    #[test]
+    fn gemini_request() {
+        let reply = serde_json::json!({
+            "candidates": [{ "content": { "parts": [{ "text": "Hello" }] } }],
+            "usageMetadata": { "promptTokenCount": 12, "candidatesTokenCount": 3 }
+        });
+
+        assert_eq!(
+            get_request("gemini", reply),
+            "POST /models/gemini-1.5-flash-latest:generateContent?key=test-key-gemini HTTP/1.1\r\n\
+            Host: 127.0.0.1:{port}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: 107\r\n\
+            Accept: */*\r\n\
+            Accept-Encoding: identity\r\n\
+            Connection: keep-alive\r\n\
+            \r\n\
+            {\"contents\":[{\"parts\":[{\"text\":\"Hi\"}],\"role\":\"user\"}],\"systemInstruction\":{\"parts\":[{\"text\":\"Be brief.\"}]}}"
+        );
+    }
+
+    fn get_stop_options() -> Options {
+        Options {
+            stop: vec!["\n\nHuman:".to_string(), "\"end\"\t\\".to_string()],
+            prefill: Some("Sure: ".to_string()),
+            ..Default::default()
+        }
+    }
+
+    // the stops go over the wire exactly as given
+    #[test]
+    fn stop_sequences() {
+        let options = get_stop_options();
+        let stop = serde_json::json!(["\n\nHuman:", "\"end\"\t\\"]);
+
+        let openai = OpenAI.body("", &get_history(), &options, "gpt-4o-mini", false);
+        assert_eq!(openai["stop"], stop);
+
+        let anthropic = Anthropic.body("", &get_history(), &options, "claude", false);
+        assert_eq!(anthropic["stop_sequences"], stop);
+
+        let serialized = openai.to_string();
+        assert!(
+            serialized.contains(r#""stop":["\n\nHuman:","\"end\"\t\\"]"#),
+            "{}",
+            serialized
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(parsed["stop"], stop);
+    }
+
+    // anthropic continues from it, trimmed since it refuses trailing whitespace
+    #[test]
+    fn anthropic_prefill() {
+        let body = Anthropic.body("", &get_history(), &get_stop_options(), "claude", false);
+        let messages = body["messages"].as_array().unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "Sure:");
+    }
+
+    // openai is never sent it, it's only added to the response
+    #[test]
+    fn openai_prefill() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::json("200 OK", &get_openai_reply("Hello"))]);
+        let options = Options {
+            prefill: Some("Sure: ".to_string()),
+            ..server.options()
+        };
+        let message = OpenAI.complete("", &get_history(), &options).unwrap();
+
+        assert_eq!(message.content, "Sure:Hello");
+        assert!(!server.requests()[0].contains("Sure"));
+    }
+
+    // flattened to text, gemini is never offered tools
+    #[test]
+    fn gemini_tool_messages() {
+        let mut call = Message::new(MessageType::Assistant, String::new());
+        call.tool_calls.push(ToolCall {
+            id: "call_1".to_string(),
+            name: "shell".to_string(),
+            arguments: serde_json::json!({ "command": "date" }),
+        });
+        let mut result = Message::new(MessageType::Tool, "Friday".to_string());
+        result.tool_call_id = Some("call_1".to_string());
+        let history = vec![get_history().remove(0), call, result];
+
+        let body = Gemini.body(
+            "",
+            &history,
+            &Options::default(),
+            "gemini-1.5-flash-latest",
+            false,
+        );
+
+        assert_eq!(
+            body["contents"],
+            serde_json::json!([
+                { "parts": [{ "text": "Hi" }], "role": "user" },
+                {
+                    "parts": [{ "text": "[called shell as call_1 with {\"command\":\"date\"}]" }],
+                    "role": "model"
+                },
+                { "parts": [{ "text": "[result of tool call call_1]\nFriday" }], "role": "user" },
+            ])
+        );
+    }
+
+    #[test]
+    fn mistral_request() {
+        assert_eq!(
+            get_request("mistral", get_openai_reply("Hello")),
+            "POST /chat/completions HTTP/1.1\r\n\
+            Host: 127.0.0.1:{port}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: 131\r\n\
+            Accept: */*\r\n\
+            Accept-Encoding: identity\r\n\
+            Connection: keep-alive\r\n\
+            Authorization: Bearer test-key-mistral\r\n\
+            \r\n\
+            {\"messages\":[{\"content\":\"Be brief.\",\"role\":\"system\"},{\"content\":\"Hi\",\"role\":\"user\"}],\"model\":\"mistral-large-latest\",\"stream\":false}"
+        );
+    }
+
+    #[test]
+    fn deepseek_request() {
+        assert_eq!(
+            get_request("deepseek", get_openai_reply("Hello")),
+            "POST /chat/completions HTTP/1.1\r\n\
+            Host: 127.0.0.1:{port}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: 124\r\n\
+            Accept: */*\r\n\
+            Accept-Encoding: identity\r\n\
+            Connection: keep-alive\r\n\
+            Authorization: Bearer test-key-deepseek\r\n\
+            \r\n\
+            {\"messages\":[{\"content\":\"Be brief.\",\"role\":\"system\"},{\"content\":\"Hi\",\"role\":\"user\"}],\"model\":\"deepseek-chat\",\"stream\":false}"
+        );
+    }
+
+    // the provider's own host and path, rather than an API base
+    #[test]
+    fn mistral_and_deepseek_endpoints() {
+        testing::setup();
+        let options = Options::default();
+
+        let request = get_provider("mistral")
+            .request("", &get_history(), &options, true)
+            .unwrap();
+        assert_eq!(
+            (request.host.as_str(), request.path.as_str()),
+            ("api.mistral.ai", "/v1/chat/completions")
+        );
+        assert_eq!(request.body["stream"], true);
+
+        let request = get_provider("deepseek")
+            .request("", &get_history(), &options, true)
+            .unwrap();
+        assert_eq!(
+            (request.host.as_str(), request.path.as_str()),
+            ("api.deepseek.com", "/chat/completions")
+        );
+        assert_eq!(request.body["stream_options"]["include_usage"], true);
+    }
+
+    #[test]
+    fn plain_response() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::json("200 OK", &get_openai_reply("Hello"))]);
+        let message = get_provider("openai")
+            .complete("", &get_history(), &server.options())
+            .unwrap();
+
+        assert_eq!(message.content, "Hello");
+        assert_eq!(message.provider.as_deref(), Some("openai"));
+        assert_eq!(message.model.as_deref(), Some("gpt-4o-mini"));
+
+        let usage = message.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+    }
+
+    #[test]
+    fn chunked_response() {
+        testing::setup();
+        let body = get_openai_reply("Hello in pieces").to_string();
+        let (first, rest) = body.as_bytes().split_at(10);
+        let server = FakeServer::start(vec![Response::chunked(&[], &[first, rest])]);
+        let message = get_provider("openai")
+            .complete("", &get_history(), &server.options())
+            .unwrap();
+
+        assert_eq!(message.content, "Hello in pieces");
+    }
+
+    #[test]
+    fn gzipped_response() {
+        testing::setup();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(
+            &mut encoder,
+            get_openai_reply("Hello, compressed").to_string().as_bytes(),
+        )
+        .unwrap();
+        let body = encoder.finish().unwrap();
+
+        let server = FakeServer::start(vec![Response::with_length(
+            "200 OK",
+            &["Content-Encoding: gzip"],
+            &body,
+        )]);
+        let message = get_provider("openai")
+            .complete("", &get_history(), &server.options())
+            .unwrap();
+
+        assert_eq!(message.content, "Hello, compressed");
+    }
+
+    #[test]
+    fn rate_limited() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::with_length(
+            "429 Too Many Requests",
+            &["Retry-After: 0"],
+            br#"{"error": {"message": "Slow down"}}"#,
+        )]);
+        let e = get_provider("openai")
+            .complete("", &get_history(), &server.options())
+            .unwrap_err();
+
+        assert_eq!(e.exit_code(), 3);
+        assert!(e.to_string().contains("Slow down"), "{}", e);
+    }
+
+    #[test]
+    fn malformed_json() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::with_length(
+            "200 OK",
+            &["Content-Type: application/json"],
+            b"{\"choices\": [",
+        )]);
+        let e = get_provider("openai")
+            .complete("", &get_history(), &server.options())
+            .unwrap_err();
+
+        assert!(matches!(e, TllmError::Provider(_)), "{:?}", e);
+        assert!(e.to_string().contains("isn't valid JSON"), "{}", e);
+    }
+
+    #[test]
+    fn openai_stream() {
+        let (deltas, result, server) = stream(
+            "openai",
+            Response::sse(&[
+                r#"{"choices": [{"delta": {"content": "Hel"}}]}"#,
+                r#"{"choices": [{"delta": {"content": "lo"}}]}"#,
+                r#"{"choices": [], "usage": {"prompt_tokens": 12, "completion_tokens": 3}}"#,
+                "[DONE]",
+            ]),
+        );
+        let message = result.unwrap();
+
+        assert_eq!(deltas, ["Hel", "lo"]);
+        assert_eq!(message.content, "Hello");
+        assert!(!message.truncated);
+        assert_eq!(message.usage.unwrap().completion_tokens, 3);
+        assert!(server.requests()[0].contains("\"stream\":true"));
+    }
+
+    #[test]
+    fn anthropic_stream() {
+        let events = [
+            ("message_start", r#"{"type": "message_start", "message": {"usage": {"input_tokens": 12, "output_tokens": 0}}}"#),
+            ("content_block_delta", r#"{"type": "content_block_delta", "delta": {"type": "text_delta", "text": "Hel"}}"#),
+            ("content_block_delta", r#"{"type": "content_block_delta", "delta": {"type": "text_delta", "text": "lo"}}"#),
+            ("message_delta", r#"{"type": "message_delta", "usage": {"output_tokens": 3}}"#),
+            ("message_stop", r#"{"type": "message_stop"}"#),
+        ]
+        .map(|(name, data)| format!("event: {}\ndata: {}\n\n", name, data).into_bytes());
+        let chunks = events.iter().map(|e| e.as_slice()).collect::<Vec<_>>();
+
+        let (deltas, result, _server) = stream(
+            "anthropic",
+            Response::chunked(&["Content-Type: text/event-stream"], &chunks),
+        );
+        let message = result.unwrap();
+
+        assert_eq!(deltas, ["Hel", "lo"]);
+        assert_eq!(message.content, "Hello");
+        let usage = message.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+    }
+
+    // gemini's streams have no end marker, they just end
+    #[test]
+    fn gemini_stream() {
+        let (deltas, result, _server) = stream(
+            "gemini",
+            Response::sse(&[
+                r#"{"candidates": [{"content": {"parts": [{"text": "Hel"}]}}]}"#,
+                r#"{"candidates": [{"content": {"parts": [{"text": "lo"}]}}], "usageMetadata": {"promptTokenCount": 12, "candidatesTokenCount": 3}}"#,
+            ]),
+        );
+
+        assert_eq!(deltas, ["Hel", "lo"]);
+        assert_eq!(result.unwrap().content, "Hello");
+    }
+
+    // once, before the prefill and anything the provider sends
+    #[test]
+    fn stream_events() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::sse(&[
+            r#"{"choices": [{"delta": {"content": " there"}}]}"#,
+            "[DONE]",
+        ])]);
+        let options = Options {
+            prefill: Some("Hello".to_string()),
+            ..server.options()
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let message = get_provider("openai")
+            .stream("", &get_history(), &options, tx, cancel)
+            .unwrap();
+
+        let events = rx
+            .iter()
+            .map(|event| match event {
+                network::StreamEvent::Started => "started".to_string(),
+                network::StreamEvent::Delta(delta) => delta,
+                _ => "other".to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(events, ["started", "Hello", " there"]);
+        assert_eq!(message.content, "Hello there");
+    }
+
+    // the error `name` makes of one captured response
+    fn get_error(name: &str, response: Response) -> TllmError {
+        testing::setup();
+        let server = FakeServer::start(vec![response]);
+        get_provider(name)
+            .complete("", &get_history(), &server.options())
+            .unwrap_err()
+    }
+
+    #[test]
+    fn openrouter_moderation() {
+        let e = get_error(
+            "openrouter",
+            Response::json(
+                "403 Forbidden",
+                &serde_json::json!({
+                    "error": {
+                        "code": 403,
+                        "message": "openai/gpt-4o-mini requires moderation on OpenRouter",
+                        "metadata": {
+                            "reasons": ["harassment", "violence"],
+                            "flagged_input": "...",
+                            "provider_name": "OpenAI",
+                            "model_slug": "openai/gpt-4o-mini"
+                        }
+                    }
+                }),
+            ),
+        );
+
+        assert!(
+            matches!(e, TllmError::Network { status: 403, .. }),
+            "{:?}",
+            e
+        );
+        assert_eq!(
+            e.to_string(),
+            "provider responded with 403: openai/gpt-4o-mini requires moderation on OpenRouter, flagged for harassment, violence, from OpenAI"
+        );
+    }
+
+    // the model's own provider failed, its error comes along as a string
+    #[test]
+    fn openrouter_upstream_error() {
+        let raw =
+            r#"{"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}"#;
+        let e = get_error(
+            "openrouter",
+            Response::json(
+                "502 Bad Gateway",
+                &serde_json::json!({
+                    "error": {
+                        "code": 502,
+                        "message": "Provider returned error",
+                        "metadata": { "provider_name": "Anthropic", "raw": raw }
+                    }
+                }),
+            ),
+        );
+
+        assert_eq!(
+            e.to_string(),
+            "provider responded with 502: Provider returned error, from Anthropic: Overloaded (overloaded_error)"
+        );
+    }
+
+    #[test]
+    fn openrouter_rate_limited() {
+        let e = get_error(
+            "openrouter",
+            Response::json(
+                "429 Too Many Requests",
+                &serde_json::json!({
+                    "error": {
+                        "code": 429,
+                        "message": "Rate limit exceeded: free-models-per-day",
+                        "metadata": { "headers": { "X-RateLimit-Limit": "50" } }
+                    }
+                }),
+            ),
+        );
+
+        assert_eq!(e.exit_code(), 3);
+        assert!(
+            e.to_string()
+                .contains("Rate limit exceeded: free-models-per-day"),
+            "{}",
+            e
+        );
+    }
+
+    // a 200 can still be an error
+    #[test]
+    fn openrouter_error_body() {
+        let e = get_error(
+            "openrouter",
+            Response::json(
+                "200 OK",
+                &serde_json::json!({
+                    "error": { "code": 502, "message": "Provider returned error" },
+                    "user_id": "user_1"
+                }),
+            ),
+        );
+
+        assert!(matches!(e, TllmError::Provider(_)), "{:?}", e);
+        assert_eq!(e.to_string(), "openrouter: Provider returned error");
+    }
+
+    #[test]
+    fn openrouter_stream_error() {
+        let (deltas, result, _server) = stream(
+            "openrouter",
+            Response::sse(&[
+                r#"{"choices": [{"delta": {"content": "Hel"}}]}"#,
+                r#"{"error": {"code": "server_error", "message": "Provider disconnected unexpectedly"}, "choices": [{"delta": {"content": ""}, "finish_reason": "error"}]}"#,
+            ]),
+        );
+        let e = result.unwrap_err();
+        let partial = get_partial(&e);
+
+        assert_eq!(deltas, ["Hel"]);
+        assert_eq!(partial.content, "Hel");
+        assert!(
+            e.to_string()
+                .contains("openrouter: Provider disconnected unexpectedly"),
+            "{}",
+            e
+        );
+    }
+
+    // what JSON escapes has to come back out exactly as the model wrote it
+    const ESCAPED: &str =
+        "a \\ backslash, \"quotes\", \\n not a newline,\ta tab, \u{1F44D} and \u{1F1EF}\u{1F1F5}";
+
+    fn get_delta(line: StreamLine) -> String {
+        match line {
+            StreamLine::Delta(delta) => delta,
+            _ => panic!("expected a delta"),
+        }
+    }
+
+    #[test]
+    fn escaped_deltas() {
+        let mut usage = None;
+        let openai = format!(
+            "data: {}",
+            serde_json::json!({ "choices": [{ "delta": { "content": ESCAPED } }] })
+        );
+        assert!(openai.contains(r#"\\ backslash, \"quotes\""#));
+        assert_eq!(
+            get_delta(OpenAI.parse_stream_line(&openai, &mut usage).unwrap()),
+            ESCAPED
+        );
+
+        let anthropic = format!(
+            "data: {}",
+            serde_json::json!({
+                "type": "content_block_delta",
+                "delta": { "type": "text_delta", "text": ESCAPED }
+            })
+        );
+        assert_eq!(
+            get_delta(Anthropic.parse_stream_line(&anthropic, &mut usage).unwrap()),
+            ESCAPED
+        );
+    }
+
+    #[test]
+    fn escaped_stream() {
+        let event = serde_json::json!({ "choices": [{ "delta": { "content": ESCAPED } }] });
+        let (deltas, result, _server) =
+            stream("openai", Response::sse(&[&event.to_string(), "[DONE]"]));
+
+        assert_eq!(deltas, [ESCAPED]);
+        assert_eq!(result.unwrap().content, ESCAPED);
+    }
+
+    #[test]
+    fn escaped_response() {
+        testing::setup();
+        let server = FakeServer::start(vec![Response::json("200 OK", &get_openai_reply(ESCAPED))]);
+        let message = get_provider("openai")
+            .complete("", &get_history(), &server.options())
+            .unwrap();
+
+        assert_eq!(message.content, ESCAPED);
+    }
+
+    fn get_partial(e: &TllmError) -> Message {
+        assert!(e.is_partial(), "{:?}", e);
+        e.get_partial().unwrap()
+    }
+
+    // the connection drops in the middle of a chunked stream
+    #[test]
+    fn dropped_stream() {
+        let events = ["Hello ", "there"].map(|delta| {
+            format!(
+                "data: {}\n\n",
+                serde_json::json!({ "choices": [{ "delta": { "content": delta } }] })
+            )
+        });
+        // the second chunk is cut off partway through
+        let bytes = format!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}",
+            events[0].len(),
+            events[0],
+            events[1].len(),
+            &events[1][..20]
+        );
+        let response = Response::raw(bytes.as_bytes()).then_close();
+        let (deltas, result, _server) = stream("openai", response);
+        let e = result.unwrap_err();
+        let partial = get_partial(&e);
+
+        assert_eq!(deltas.concat(), partial.content);
+        assert!(
+            partial.content.starts_with("Hello "),
+            "{:?}",
+            partial.content
+        );
+        assert!(partial.truncated);
+    }
+
+    // the connection closes cleanly, but before the end marker
+    #[test]
+    fn stream_closed_before_its_end() {
+        let response = Response::raw(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n\
+            data: {\"choices\": [{\"delta\": {\"content\": \"Hello\"}}]}\n\n",
+        )
+        .then_close();
+        let (deltas, result, _server) = stream("openai", response);
+        let e = result.unwrap_err();
+        let partial = get_partial(&e);
+
+        assert_eq!(deltas, ["Hello"]);
+        assert_eq!(partial.content, "Hello");
+        assert!(partial.truncated);
+        assert!(
+            e.to_string()
+                .contains("The stream closed before the response was finished"),
+            "{}",
+            e
+        );
+    }
+
+    // nothing arrived, so there's nothing partial to keep
+    #[test]
+    fn stream_closed_before_anything() {
+        let response = Response::raw(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n")
+            .then_close();
+        let (deltas, result, _server) = stream("openai", response);
+        let e = result.unwrap_err();
+
+        assert!(deltas.is_empty());
+        assert!(!e.is_partial());
+    }
+
+    fn get_mock_options(model: &str, headers: &[&str]) -> Options {
+        testing::setup();
+        Options {
+            model: Some(model.to_string()),
+            max_attempts: Some(1),
+            timeout: Some(5),
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn stream_mock(options: &Options) -> (Vec<String>, Result<Message, TllmError>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let history = vec![Message::new(MessageType::User, "one two three".to_string())];
+        let result = Mock.stream("", &history, options, tx, cancel);
+
+        let deltas = rx
+            .iter()
+            .filter_map(|event| match event {
+                network::StreamEvent::Delta(delta) => Some(delta),
+                _ => None,
+            })
+            .collect();
+
+        (deltas, result)
+    }
+
+    #[test]
+    fn mock_complete() {
+        let options = get_mock_options("reverse", &[]);
+        let message = Mock.complete("", &get_history(), &options).unwrap();
+
+        assert_eq!(message.content, "iH");
+        assert_eq!(message.provider.as_deref(), Some("mock"));
+        assert_eq!(message.model.as_deref(), Some("reverse"));
+        assert!(message.usage.is_some());
+    }
+
+    #[test]
+    fn mock_stream() {
+        let options = get_mock_options("upper", &[]);
+        let (deltas, result) = stream_mock(&options);
+        let message = result.unwrap();
+
+        assert_eq!(deltas, ["ONE ", "TWO ", "THREE"]);
+        assert_eq!(message.content, "ONE TWO THREE");
+        assert!(!message.truncated);
+        assert!(message.usage.is_some());
+    }
+
+    #[test]
+    fn mock_stream_fails() {
+        let options = get_mock_options("echo", &["X-Mock-Fail: 2"]);
+        let (deltas, result) = stream_mock(&options);
+        let e = result.unwrap_err();
+        let partial = get_partial(&e);
+
+        assert_eq!(deltas, ["one ", "two "]);
+        assert_eq!(partial.content, "one two ");
+        assert!(partial.truncated);
+    }
+
+    #[test]
+    fn mock_complete_fails() {
+        let options = get_mock_options("echo", &["X-Mock-Fail: 0"]);
+        let e = Mock.complete("", &get_history(), &options).unwrap_err();
+
+        assert!(
+            matches!(e, TllmError::Network { status: 500, .. }),
+            "{:?}",
+            e
+        );
+        assert!(e.to_string().contains("failed on purpose"), "{}", e);
+    }
+
+    #[test]
+    fn mock_unknown_model() {
+        let options = get_mock_options("shout", &[]);
+        let e = Mock.complete("", &get_history(), &options).unwrap_err();
+
+        assert!(
+            matches!(e, TllmError::Network { status: 404, .. }),
+            "{:?}",
+            e
+        );
+        assert!(e.to_string().contains("has no model shout"), "{}", e);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{config, conversation, network};
+
+// USD per million tokens as (prompt, completion)
+// override with `price.<model>=<prompt>,<completion>` in the config file
+// anthropic's cache writes cost a quarter more than prompt tokens and reads a tenth as much
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("claude-3-5-sonnet-latest", 3.00, 15.00),
+    ("llama-3.2-90b-text-preview", 0.90, 0.90),
+    ("gemini-1.5-flash-latest", 0.075, 0.30),
+    ("openai/gpt-4o-mini", 0.15, 0.60),
+    ("mistral-large-latest", 2.00, 6.00),
+    ("mistral-small-latest", 0.20, 0.60),
+    ("deepseek-chat", 0.27, 1.10),
+];
+
+pub fn parse_price(value: &str) -> Option<(f64, f64)> {
+    let (prompt, completion) = value.split_once(',')?;
+    match (prompt.trim().parse(), completion.trim().parse()) {
+        (Ok(p), Ok(c)) => Some((p, c)),
+        _ => None,
+    }
+}
+
+fn get_price(
+    model: &str,
+    config: &std::collections::HashMap<String, String>,
+) -> Option<(f64, f64)> {
+    if let Some(price) = config
+        .get(&format!("price.{}", model))
+        .and_then(|v| parse_price(v))
+    {
+        return Some(price);
+    }
+
+    PRICES
+        .iter()
+        .find(|(m, _, _)| *m == model)
+        .map(|(_, p, c)| (*p, *c))
+}
+
+#[derive(Default)]
+struct Totals {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cache_creation_tokens: u64,
+    cache_read_tokens: u64,
+    cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, usage: &network::Usage, cost: f64) {
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.cache_creation_tokens += usage.cache_creation_tokens;
+        self.cache_read_tokens += usage.cache_read_tokens;
+        self.cost += cost;
+    }
+
+    fn row(&self, label: &str) -> String {
+        format!(
+            "{:<40} {:>12} {:>12} {:>12} {:>12} {:>10}\n",
+            label,
+            self.prompt_tokens,
+            self.completion_tokens,
+            self.cache_creation_tokens,
+            self.cache_read_tokens,
+            format!("${:.4}", self.cost)
+        )
+    }
+}
+
+// everything here comes off of disk, no provider is ever contacted
+pub fn report() -> Result<String, std::io::Error> {
+    let config = config::read_config();
+
+    let mut per_conversation = Vec::new();
+    let mut per_model: std::collections::BTreeMap<String, Totals> =
+        std::collections::BTreeMap::new();
+    let mut total = Totals::default();
+    let mut first: Option<chrono::DateTime<chrono::Local>> = None;
+    let mut last: Option<chrono::DateTime<chrono::Local>> = None;
+
+    for title in conversation::list_titles(true)? {
+        let messages = match conversation::load(&title) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+
+        if let Ok(created_at) = conversation::get_created_at(&title) {
+            first = Some(first.map_or(created_at, |f| f.min(created_at)));
+            last = Some(last.map_or(created_at, |l| l.max(created_at)));
+        }
+
+        let mut totals = Totals::default();
+        for message in messages.iter() {
+            let usage = match &message.usage {
+                Some(u) => u,
+                None => continue,
+            };
+
+            let model = message.model.clone().unwrap_or("unknown".to_string());
+            let cost = match get_price(&model, &config) {
+                Some((prompt, completion)) => {
+                    (usage.prompt_tokens as f64 * prompt
+                        + usage.completion_tokens as f64 * completion
+                        + usage.cache_creation_tokens as f64 * prompt * 1.25
+                        + usage.cache_read_tokens as f64 * prompt * 0.1)
+                        / 1_000_000.0
+                }
+                None => 0.0,
+            };
+
+            let key = format!(
+                "{}/{}",
+                message.provider.clone().unwrap_or("unknown".to_string()),
+                model
+            );
+
+            per_model.entry(key).or_default().add(usage, cost);
+            totals.add(usage, cost);
+            total.add(usage, cost);
+        }
+
+        per_conversation.push((title, totals));
+    }
+
+    let header = format!(
+        "{:<40} {:>12} {:>12} {:>12} {:>12} {:>10}\n",
+        "", "prompt", "completion", "cache write", "cache read", "cost"
+    );
+
+    let mut output = match (first, last) {
+        (Some(f), Some(l)) => format!(
+            "{} conversations from {} to {}\n\n",
+            per_conversation.len(),
+            f.format("%Y-%m-%d"),
+            l.format("%Y-%m-%d")
+        ),
+        _ => "No conversations found\n\n".to_string(),
+    };
+
+    // each profile can keep its conversations somewhere else, so this is only its own
+    if let Some(profile) = config::get_profile() {
+        output.insert_str(0, &format!("Profile {}\n", profile));
+    }
+
+    output.push_str(&header);
+    for (title, totals) in per_conversation.iter() {
+        output.push_str(&totals.row(title));
+    }
+
+    output.push_str(&format!("\n{}", header));
+    for (model, totals) in per_model.iter() {
+        output.push_str(&totals.row(model));
+    }
+
+    output.push('\n');
+    output.push_str(&total.row("total"));
+
+    Ok(output)
+}
@@ -0,0 +1,114 @@
+use crate::error::TllmError;
+use crate::logger::Logger;
+use crate::network::{Message, MessageType, Options};
+use crate::{config, info, providers, tokens};
+
+const SUMMARY_PROMPT: &str = "You keep a running summary of a long conversation so it can go on without the full history. Update the summary with the messages below. Keep the facts, decisions, code, names and open questions a later reply could need, and leave out pleasantries. Reply with the summary alone.";
+
+// it's context for the next replies, not a reply itself
+const MAX_SUMMARY_TOKENS: u32 = 1024;
+
+// set with `summarize_history=true` in the config file
+pub fn is_enabled() -> bool {
+    config::read_config()
+        .get("summarize_history")
+        .map(|v| crate::parse_bool(v).unwrap_or(false))
+        .unwrap_or(false)
+}
+
+// the newest summary and the index of the first message after what it covers
+fn get_latest(messages: &[Message]) -> Option<(usize, &str)> {
+    messages
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, m)| m.summary.as_deref().map(|s| (i + 1, s)))
+}
+
+// the system prompt with the newest summary added, and where the history it doesn't cover starts
+pub fn apply(system_prompt: &str, messages: &[Message]) -> (String, usize) {
+    let (start, summary) = match get_latest(messages) {
+        Some(latest) => latest,
+        None => return (system_prompt.to_string(), 0),
+    };
+
+    let section = format!("Summary of the earlier conversation:\n{}", summary);
+    match system_prompt.trim().is_empty() {
+        true => (section, start),
+        false => (format!("{}\n\n{}", system_prompt, section), start),
+    }
+}
+
+fn summarize(
+    previous: Option<&str>,
+    messages: &[Message],
+    api: &str,
+    options: &Options,
+) -> Result<String, TllmError> {
+    let mut options = options.for_side_request();
+    options.max_tokens = Some(MAX_SUMMARY_TOKENS);
+
+    let mut request = SUMMARY_PROMPT.to_string();
+    if let Some(previous) = previous {
+        request.push_str(&format!("\n\nThe summary so far:\n{}", previous));
+    }
+
+    request.push_str("\n\nThe messages:\n");
+    for message in messages {
+        request.push_str(&format!(
+            "{}: {}\n\n",
+            message.message_type.to_string(),
+            message.content
+        ));
+    }
+
+    let request = Message::new(MessageType::User, request);
+    let response = providers::get_provider(api).complete("", &[request], &options)?;
+
+    let summary = response.content.trim();
+    if summary.is_empty() {
+        return Err(TllmError::Provider(
+            "The generated summary was empty".to_string(),
+        ));
+    }
+
+    Ok(summary.to_string())
+}
+
+// when what the newest summary doesn't cover is over the token budget,
+// the messages that would be left out are folded into it instead
+// the new summary goes on the last of them, its index is returned so callers holding their own copy can save it
+pub fn update(
+    system_prompt: &str,
+    messages: &mut [Message],
+    api: &str,
+    options: &Options,
+) -> Result<Option<usize>, TllmError> {
+    let (prompt, start) = apply(system_prompt, messages);
+
+    // `--last` only narrows what's sent, it isn't a reason to summarize
+    let mut budget_options = options.clone();
+    budget_options.last = None;
+
+    let omitted = tokens::get_omitted(
+        &prompt,
+        &messages[start..],
+        &budget_options,
+        &options.get_model(api),
+    );
+    if omitted == 0 {
+        return Ok(None);
+    }
+
+    let end = start + omitted;
+    let previous = get_latest(messages).map(|(_, s)| s);
+    let summary = summarize(previous, &messages[start..end], api, options)?;
+
+    info!("summarized {} older messages", omitted);
+    if options.print_notes {
+        eprintln!("Summarized {} older messages to fit the request", omitted);
+    }
+
+    messages[end - 1].summary = Some(summary);
+    Ok(Some(end - 1))
+}
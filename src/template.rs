@@ -0,0 +1,76 @@
+use std::io::{IsTerminal, Read};
+use std::sync::OnceLock;
+
+use crate::error::TllmError;
+use crate::{attachment, clipboard};
+
+const SUPPORTED: &str = "{{stdin}}, {{file:PATH}}, {{clipboard}}, {{env:NAME}}";
+
+// stdin can only be read once, any later {{stdin}} gets the same text
+static STDIN: OnceLock<String> = OnceLock::new();
+
+fn read_stdin() -> Result<String, TllmError> {
+    if let Some(stdin) = STDIN.get() {
+        return Ok(stdin.clone());
+    }
+
+    // waiting on a terminal would look like a hang
+    if std::io::stdin().is_terminal() {
+        return Err(TllmError::Config(
+            "{{stdin}} needs input piped in, e.g. `git diff | tllm -i \"Review this: {{stdin}}\"`"
+                .to_string(),
+        ));
+    }
+
+    let mut stdin = String::new();
+    std::io::stdin().read_to_string(&mut stdin)?;
+    Ok(STDIN.get_or_init(|| stdin).clone())
+}
+
+fn expand_placeholder(placeholder: &str, max_size_kb: u64) -> Result<String, TllmError> {
+    match placeholder.split_once(':') {
+        None if placeholder == "stdin" => read_stdin(),
+        None if placeholder == "clipboard" => clipboard::get().map_err(TllmError::Config),
+        Some(("file", path)) if !path.trim().is_empty() => {
+            attachment::read(path.trim(), max_size_kb)
+        }
+        Some(("env", name)) if !name.trim().is_empty() => std::env::var(name.trim())
+            .map_err(|_| TllmError::Config(format!("{{{{{}}}}}: {} isn't set", placeholder, name))),
+        _ => Err(TllmError::Config(format!(
+            "Unknown placeholder {{{{{}}}}}, the supported ones are {}",
+            placeholder, SUPPORTED
+        ))),
+    }
+}
+
+// swaps each `{{...}}` for what it names, `\{{` stays a literal `{{`
+// what's substituted in is never expanded itself, so files can hold braces of their own
+// files are held to the same size limit as attachments
+pub fn expand(text: &str, max_size_kb: u64) -> Result<String, TllmError> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("{{");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        // an unclosed `{{` is just text
+        let end = match rest[start + 2..].find("}}") {
+            Some(end) => start + 2 + end,
+            None => break,
+        };
+
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&expand_placeholder(
+            rest[start + 2..end].trim(),
+            max_size_kb,
+        )?);
+        rest = &rest[end + 2..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
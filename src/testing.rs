@@ -0,0 +1,213 @@
+// shared by the tests: a scratch `TLLM_HOME` with dummy keys, and a local HTTP server to point providers at
+use std::collections::VecDeque;
+use std::io::{BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::{network, providers};
+
+// every test runs against an empty config, dummy keys, and no proxy
+pub fn setup() {
+    static SETUP: std::sync::Once = std::sync::Once::new();
+    SETUP.call_once(|| {
+        let home = std::env::temp_dir().join(format!("tllm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&home).expect("Failed to create the test home");
+        std::env::set_var("TLLM_HOME", &home);
+
+        for name in providers::PROVIDERS {
+            std::env::set_var(
+                providers::get_provider(name).key_var(),
+                format!("test-key-{}", name),
+            );
+        }
+
+        for name in ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"] {
+            std::env::remove_var(name);
+        }
+    });
+}
+
+// one canned response, written whole once a request comes in
+pub struct Response {
+    bytes: Vec<u8>,
+    // closed right after, whatever the response itself says
+    close: bool,
+}
+
+impl Response {
+    pub fn raw(bytes: &[u8]) -> Response {
+        Response {
+            bytes: bytes.to_vec(),
+            close: false,
+        }
+    }
+
+    pub fn with_length(status: &str, headers: &[&str], body: &[u8]) -> Response {
+        let mut bytes = format!("HTTP/1.1 {}\r\n", status);
+        for header in headers {
+            bytes.push_str(&format!("{}\r\n", header));
+        }
+        bytes.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+
+        let mut bytes = bytes.into_bytes();
+        bytes.extend_from_slice(body);
+        Response::raw(&bytes)
+    }
+
+    pub fn json(status: &str, body: &serde_json::Value) -> Response {
+        Response::with_length(
+            status,
+            &["Content-Type: application/json"],
+            body.to_string().as_bytes(),
+        )
+    }
+
+    pub fn chunked(headers: &[&str], chunks: &[&[u8]]) -> Response {
+        let mut bytes = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n".to_vec();
+        for header in headers {
+            bytes.extend_from_slice(format!("{}\r\n", header).as_bytes());
+        }
+        bytes.extend_from_slice(b"\r\n");
+
+        for chunk in chunks {
+            bytes.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            bytes.extend_from_slice(chunk);
+            bytes.extend_from_slice(b"\r\n");
+        }
+        bytes.extend_from_slice(b"0\r\n\r\n");
+
+        Response::raw(&bytes)
+    }
+
+    // `data: ...` lines, a chunk each
+    pub fn sse(events: &[&str]) -> Response {
+        let events = events
+            .iter()
+            .map(|e| format!("data: {}\n\n", e).into_bytes())
+            .collect::<Vec<_>>();
+        let chunks = events.iter().map(|e| e.as_slice()).collect::<Vec<_>>();
+
+        Response::chunked(&["Content-Type: text/event-stream"], &chunks)
+    }
+
+    pub fn then_close(mut self) -> Response {
+        self.close = true;
+        self
+    }
+}
+
+// answers requests with `responses` in order, closing connections once they run out
+// and keeps every request, along with which connection it came in on
+pub struct FakeServer {
+    pub port: u16,
+    requests: Arc<Mutex<Vec<(usize, String)>>>,
+}
+
+impl FakeServer {
+    pub fn start(responses: Vec<Response>) -> FakeServer {
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind the fake server");
+        let port = listener.local_addr().unwrap().port();
+
+        let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let server_requests = requests.clone();
+        std::thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+
+                let responses = responses.clone();
+                let requests = server_requests.clone();
+                std::thread::spawn(move || serve(connection, stream, responses, requests));
+            }
+        });
+
+        FakeServer { port, requests }
+    }
+
+    pub fn api_base(&self) -> network::ApiBase {
+        network::parse_api_base(&format!("http://127.0.0.1:{}", self.port)).unwrap()
+    }
+
+    pub fn options(&self) -> network::Options {
+        network::Options {
+            api_base: Some(self.api_base()),
+            max_attempts: Some(1),
+            timeout: Some(5),
+            ..Default::default()
+        }
+    }
+
+    // with `{port}` in place of the port, so they can be compared as they are
+    pub fn requests(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, request)| request.replace(&self.port.to_string(), "{port}"))
+            .collect()
+    }
+
+    // the connection each request came in on, numbered from 0 in the order they were accepted
+    pub fn connections(&self) -> Vec<usize> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(connection, _)| *connection)
+            .collect()
+    }
+}
+
+fn serve(
+    connection: usize,
+    mut stream: std::net::TcpStream,
+    responses: Arc<Mutex<VecDeque<Response>>>,
+    requests: Arc<Mutex<Vec<(usize, String)>>>,
+) {
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    loop {
+        let mut request = String::new();
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+
+            request.push_str(&line);
+            if line == "\r\n" {
+                break;
+            }
+        }
+
+        let length = request
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                match name.eq_ignore_ascii_case("content-length") {
+                    true => value.trim().parse::<usize>().ok(),
+                    false => None,
+                }
+            })
+            .unwrap_or(0);
+        let mut body = vec![0u8; length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        request.push_str(&String::from_utf8_lossy(&body));
+        requests.lock().unwrap().push((connection, request));
+
+        let response = match responses.lock().unwrap().pop_front() {
+            Some(response) => response,
+            None => return,
+        };
+
+        if stream.write_all(&response.bytes).is_err() || response.close {
+            return;
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::TllmError;
+use crate::logger::Logger;
+use crate::{config, conversation, error, network, providers};
+
+const TITLE_PROMPT: &str = "Write a title of 5 to 8 words for the conversation below. Reply with the title alone, no quotes or punctuation.";
+
+// each message is cut down to this so the request stays cheap
+const MAX_EXCERPT_CHARS: usize = 2000;
+const MAX_SLUG_CHARS: usize = 60;
+
+// set with `auto_title=true` in the config file
+pub fn is_enabled() -> bool {
+    config::read_config()
+        .get("auto_title")
+        .map(|v| crate::parse_bool(v).unwrap_or(false))
+        .unwrap_or(false)
+}
+
+// e.g. `Rust lifetimes in async closures` -> `rust-lifetimes-in-async-closures`
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().to_lowercase().chars() {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    slug.chars()
+        .take(MAX_SLUG_CHARS)
+        .collect::<String>()
+        .trim_end_matches('-')
+        .to_string()
+}
+
+fn generate(
+    api: &str,
+    options: &network::Options,
+    messages: &[network::Message],
+) -> Result<String, TllmError> {
+    let provider = providers::get_provider(api);
+
+    // custom API bases are usually local servers that won't have the cheap model
+    let mut options = options.for_side_request();
+    if options.api_base.is_none() {
+        options.model = Some(provider.title_model().to_string());
+    }
+    options.max_tokens = Some(32);
+
+    let mut transcript = String::new();
+    for message in messages {
+        let excerpt = message
+            .content
+            .chars()
+            .take(MAX_EXCERPT_CHARS)
+            .collect::<String>();
+        transcript.push_str(&format!(
+            "{}: {}\n\n",
+            message.message_type.to_string(),
+            excerpt
+        ));
+    }
+
+    let request = network::Message::new(
+        network::MessageType::User,
+        format!("{}\n\n{}", TITLE_PROMPT, transcript),
+    );
+    let response = provider.complete("", &[request], &options)?;
+
+    let slug = slugify(&response.content);
+    if slug.is_empty() {
+        return Err(TllmError::Provider(
+            "The generated title was empty".to_string(),
+        ));
+    }
+
+    Ok(slug)
+}
+
+// the first free title among `slug`, `slug-2`, `slug-3`, ...
+fn get_unused_title(slug: &str) -> Option<String> {
+    (1..100)
+        .map(|i| match i {
+            1 => slug.to_string(),
+            _ => format!("{}-{}", slug, i),
+        })
+        .find(|t| conversation::get_new_path(t).is_ok())
+}
+
+// renames a newly saved conversation after its contents,
+// any failure just leaves the title it already had
+pub fn apply(title: &str, api: &str, options: &network::Options) -> String {
+    let messages = match conversation::load(title) {
+        Ok(messages) => conversation::split_system_prompt(messages).1,
+        Err(e) => {
+            error!("Failed to load {} for a title: {}", title, e);
+            return title.to_string();
+        }
+    };
+
+    let new_title = match generate(api, options, &messages) {
+        Ok(slug) => get_unused_title(&slug),
+        Err(e) => {
+            error!("Failed to generate a title for {}: {}", title, e);
+            None
+        }
+    };
+
+    match new_title {
+        Some(new_title) => match conversation::rename(title, &new_title) {
+            Ok(_) => new_title,
+            Err(e) => {
+                error!("Failed to rename {} to {}: {}", title, new_title, e);
+                title.to_string()
+            }
+        },
+        None => title.to_string(),
+    }
+}
@@ -0,0 +1,121 @@
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+
+use crate::network::{Message, MessageType, Options};
+
+// context windows in tokens by model name prefix, the first match wins
+// `context_budget` in the config covers anything missing
+const CONTEXT_WINDOWS: &[(&str, u64)] = &[
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1", 128_000),
+    ("claude", 200_000),
+    ("gemini-1.5-pro", 2_000_000),
+    ("gemini", 1_000_000),
+    ("llama-3.1", 131_072),
+    ("llama-3.2", 8_192),
+    ("llama3", 8_192),
+    ("mixtral", 32_768),
+    ("mistral-large", 131_072),
+    ("mistral", 32_768),
+    ("deepseek", 65_536),
+];
+
+// left for the reply when no max tokens is set, matching what the Anthropic body defaults to
+const DEFAULT_REPLY_TOKENS: u64 = 4096;
+
+// the role and separators each API wraps around a message
+const MESSAGE_OVERHEAD: u64 = 4;
+
+// openrouter names models after their vendor, like `openai/gpt-4o`
+pub fn get_base_model(model: &str) -> &str {
+    model.rsplit('/').next().unwrap_or(model)
+}
+
+// exact for OpenAI's tokenizers, roughly four characters a token for everyone else
+pub fn count(text: &str, model: &str) -> u64 {
+    let tokens = match get_tokenizer(get_base_model(model)) {
+        Some(Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton()
+            .lock()
+            .encode_with_special_tokens(text)
+            .len(),
+        Some(Tokenizer::Cl100kBase) => tiktoken_rs::cl100k_base_singleton()
+            .lock()
+            .encode_with_special_tokens(text)
+            .len(),
+        _ => text.chars().count().div_ceil(4),
+    };
+
+    tokens as u64
+}
+
+// `context_budget` when it's set, otherwise what the model's window leaves after the reply
+// unknown models aren't trimmed at all, there's nothing to go on
+fn get_budget(model: &str, options: &Options) -> Option<u64> {
+    if options.context_budget.is_some() {
+        return options.context_budget;
+    }
+
+    let window = CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| get_base_model(model).starts_with(prefix))
+        .map(|(_, window)| *window)?;
+
+    let reply = options
+        .max_tokens
+        .map(|t| t as u64)
+        .unwrap_or(DEFAULT_REPLY_TOKENS);
+    Some(window.saturating_sub(reply))
+}
+
+// how many of the oldest messages to leave out of a request:
+// anything before the last `--last` exchanges, then whole exchanges until it fits the budget
+// the history always starts on a user message and the newest one is always kept,
+// the system prompt is sent separately and never dropped
+pub fn get_omitted(
+    system_prompt: &str,
+    messages: &[Message],
+    options: &Options,
+    model: &str,
+) -> usize {
+    let exchanges = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.message_type == MessageType::User)
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+
+    let mut exchanges = match options.last {
+        Some(last) if last < exchanges.len() => exchanges[exchanges.len() - last..].to_vec(),
+        _ => exchanges,
+    };
+
+    let mut start = match exchanges.first() {
+        Some(first) => *first,
+        None => return 0,
+    };
+
+    let budget = match get_budget(model, options) {
+        Some(budget) => budget,
+        None => return start,
+    };
+
+    let counts = messages
+        .iter()
+        .map(|m| count(&m.content, model) + MESSAGE_OVERHEAD)
+        .collect::<Vec<u64>>();
+    let mut total = count(system_prompt, model) + counts[start..].iter().sum::<u64>();
+
+    exchanges.remove(0);
+    for next in exchanges {
+        if total <= budget {
+            break;
+        }
+
+        total -= counts[start..next].iter().sum::<u64>();
+        start = next;
+    }
+
+    start
+}
@@ -0,0 +1,137 @@
+use std::io::IsTerminal;
+
+use crate::error::TllmError;
+use crate::logger::Logger;
+use crate::network::ToolCall;
+use crate::{error, info};
+
+// built in, only offered with `--allow-shell`
+pub const SHELL: &str = "shell";
+
+// rounds of tool calls before tllm stops waiting for an answer
+pub const MAX_ROUNDS: usize = 10;
+
+// so one noisy command doesn't fill the context
+const MAX_OUTPUT_CHARS: usize = 20_000;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Tool {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    // a JSON schema for the arguments
+    #[serde(default = "get_empty_schema")]
+    pub parameters: serde_json::Value,
+}
+
+fn get_empty_schema() -> serde_json::Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+// `--tools FILE`, a JSON array like `[{"name": ..., "description": ..., "parameters": {...}}]`
+pub fn load(path: &str) -> Result<Vec<Tool>, TllmError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| TllmError::Config(format!("Can't read the tools file {}: {}", path, e)))?;
+
+    let tools = serde_json::from_str::<Vec<Tool>>(&contents)
+        .map_err(|e| TllmError::Config(format!("Invalid tools file {}: {}", path, e)))?;
+
+    if let Some(tool) = tools.iter().find(|t| t.name == SHELL) {
+        return Err(TllmError::Config(format!(
+            "{} names a tool {}, which is built in, use --allow-shell instead",
+            path, tool.name
+        )));
+    }
+
+    Ok(tools)
+}
+
+pub fn shell() -> Tool {
+    Tool {
+        name: SHELL.to_string(),
+        description: "Run a shell command on the user's machine and get back its exit status and output. The user confirms every command first.".to_string(),
+        parameters: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "command": {
+                    "type": "string",
+                    "description": "The command line to run"
+                }
+            },
+            "required": ["command"]
+        }),
+    }
+}
+
+// anything else from `--tools` is handed back to whatever ran tllm
+pub fn can_run(call: &ToolCall, allow_shell: bool) -> bool {
+    call.name == SHELL && allow_shell
+}
+
+// what goes back to the model, `None` for tools tllm can't run itself
+pub fn run(call: &ToolCall, allow_shell: bool) -> Option<String> {
+    match can_run(call, allow_shell) {
+        true => Some(run_shell(call)),
+        false => None,
+    }
+}
+
+fn truncate(output: &str) -> String {
+    match output.char_indices().nth(MAX_OUTPUT_CHARS) {
+        Some((i, _)) => format!(
+            "{}\n[cut off after {} characters]",
+            &output[..i],
+            MAX_OUTPUT_CHARS
+        ),
+        None => output.to_string(),
+    }
+}
+
+// every command is shown and has to be confirmed at the terminal
+fn run_shell(call: &ToolCall) -> String {
+    let command = match call.arguments["command"].as_str() {
+        Some(command) if !command.trim().is_empty() => command,
+        _ => return "Error: the shell tool needs a `command` string".to_string(),
+    };
+
+    eprintln!("The model wants to run:\n\n    {}\n", command);
+    if !std::io::stdin().is_terminal() {
+        eprintln!("Not running it, there's no terminal to confirm it on");
+        return "The command wasn't run, the user has no terminal to confirm it on".to_string();
+    }
+
+    eprint!("Run it? [y/N] ");
+    let mut answer = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut answer) {
+        error!("Failed to read the confirmation: {}", e);
+    }
+
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        info!("declined to run {}", command);
+        return "The user declined to run this command".to_string();
+    }
+
+    info!("running {}", command);
+    let output = match cfg!(windows) {
+        true => std::process::Command::new("cmd")
+            .args(["/C", command])
+            .output(),
+        false => std::process::Command::new("sh")
+            .args(["-c", command])
+            .output(),
+    };
+
+    match output {
+        Ok(output) => truncate(&format!(
+            "exit status: {}\nstdout:\n{}\nstderr:\n{}",
+            output
+                .status
+                .code()
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| "killed by a signal".to_string()),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )),
+        Err(e) => format!("Couldn't run the command: {}", e),
+    }
+}